@@ -0,0 +1,205 @@
+//! Requests of the operators on a running server
+//!
+//! The admin requests act on the server rather than on the keys, so they need
+//! the admin permission. They are answered by the server they are sent to, even
+//! if it runs a cluster node.
+use crate::common::Reply;
+use crate::raft::NodeId;
+use crate::server::Limits;
+use crate::transport::Address;
+use crate::{EngineStats, KvsEngine, KvsError, Result};
+use log::{info, LevelFilter};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A request of an operator, see [`KvsClient`](crate::KvsClient) for each one
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum AdminCommand {
+    /// Reclaims the disk space of the stale data now
+    Compact,
+    /// Syncs the changes to the disk
+    Flush,
+    /// Reports the statistics of the server and its engine
+    Stats,
+    /// Lists the connections of the clients
+    Clients,
+    /// Changes the level of the messages logged by the server
+    SetLogLevel(String),
+    /// Reports the settings the server runs with
+    Config,
+}
+
+/// The statistics of a server
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServerStats {
+    /// How long the server has been running
+    pub uptime: Duration,
+    /// The number of connections open
+    pub connections: u64,
+    /// The number of requests received since the server started
+    pub requests: u64,
+    /// The statistics of the storage engine
+    pub engine: EngineStats,
+}
+
+/// A connection of a client to a server
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClientInfo {
+    /// The number of the connection, unique on the server
+    pub id: u64,
+    /// The address of the client
+    pub addr: String,
+    /// The user the connection is authenticated as
+    pub user: Option<String>,
+    /// How long the connection has been open
+    pub age: Duration,
+    /// The number of requests received on the connection
+    pub requests: u64,
+}
+
+/// The settings a server runs with
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServerConfig {
+    /// The version of the server
+    pub version: String,
+    /// The address the server listens on
+    pub addr: Address,
+    /// The limits on the connections and the requests
+    pub limits: Limits,
+    /// How long the requests in flight may take once the server shuts down
+    pub shutdown_timeout: Duration,
+    /// Whether the connections are served over TLS
+    pub tls: bool,
+    /// Whether the clients must authenticate
+    pub auth: bool,
+    /// The leader the server follows, if any
+    pub leader: Option<Address>,
+    /// The id of the cluster node the server runs, if any
+    pub cluster_node: Option<NodeId>,
+    /// The level of the messages logged
+    pub log_level: String,
+}
+
+/// The state of a server the admin requests report on
+#[derive(Clone)]
+pub(crate) struct Admin(Arc<State>);
+
+struct State {
+    started: Instant,
+    config: ServerConfig,
+    requests: AtomicU64,
+    next_id: AtomicU64,
+    clients: Mutex<BTreeMap<u64, Arc<Client>>>,
+}
+
+struct Client {
+    id: u64,
+    addr: String,
+    connected: Instant,
+    user: Mutex<Option<String>>,
+    requests: AtomicU64,
+}
+
+impl Admin {
+    pub(crate) fn new(config: ServerConfig) -> Self {
+        Admin(Arc::new(State {
+            started: Instant::now(),
+            config,
+            requests: AtomicU64::new(0),
+            next_id: AtomicU64::new(0),
+            clients: Mutex::new(BTreeMap::new()),
+        }))
+    }
+
+    /// Lists a connection from `addr` until the returned guard is dropped
+    pub(crate) fn register(&self, addr: &Address) -> ClientGuard {
+        let client = Arc::new(Client {
+            id: self.0.next_id.fetch_add(1, Ordering::Relaxed) + 1,
+            addr: addr.to_string(),
+            connected: Instant::now(),
+            user: Mutex::new(None),
+            requests: AtomicU64::new(0),
+        });
+        let mut clients = self.0.clients.lock().unwrap();
+        clients.insert(client.id, Arc::clone(&client));
+        ClientGuard {
+            admin: self.clone(),
+            client,
+        }
+    }
+
+    /// Runs an admin request on the server and its engine
+    pub(crate) fn run<E: KvsEngine>(&self, engine: &E, command: AdminCommand) -> Result<Reply> {
+        match command {
+            AdminCommand::Compact => {
+                info!("Compacting on request");
+                engine.compact()?;
+                engine.stats().map(Reply::Stats)
+            }
+            AdminCommand::Flush => engine.flush().map(|()| Reply::Done),
+            AdminCommand::Stats => Ok(Reply::ServerStats(ServerStats {
+                uptime: self.0.started.elapsed(),
+                connections: self.0.clients.lock().unwrap().len() as u64,
+                requests: self.0.requests.load(Ordering::Relaxed),
+                engine: engine.stats()?,
+            })),
+            AdminCommand::Clients => Ok(Reply::Clients(self.clients())),
+            AdminCommand::SetLogLevel(level) => {
+                let level: LevelFilter = level
+                    .parse()
+                    .map_err(|_| KvsError::BadRequest(format!("unknown log level {}", level)))?;
+                log::set_max_level(level);
+                info!("Log level set to {}", level);
+                Ok(Reply::Done)
+            }
+            AdminCommand::Config => {
+                let mut config = self.0.config.clone();
+                config.log_level = log::max_level().to_string();
+                Ok(Reply::Config(config))
+            }
+        }
+    }
+
+    fn clients(&self) -> Vec<ClientInfo> {
+        let clients = self.0.clients.lock().unwrap();
+        clients
+            .values()
+            .map(|client| ClientInfo {
+                id: client.id,
+                addr: client.addr.clone(),
+                user: client.user.lock().unwrap().clone(),
+                age: client.connected.elapsed(),
+                requests: client.requests.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+}
+
+/// Keeps a connection listed, and counts its requests
+pub(crate) struct ClientGuard {
+    admin: Admin,
+    client: Arc<Client>,
+}
+
+impl ClientGuard {
+    /// Counts a request received on the connection
+    pub(crate) fn record(&self) {
+        self.client.requests.fetch_add(1, Ordering::Relaxed);
+        self.admin.0.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records the user the connection is authenticated as
+    pub(crate) fn set_user(&self, user: Option<&str>) {
+        *self.client.user.lock().unwrap() = user.map(str::to_owned);
+    }
+}
+
+impl Drop for ClientGuard {
+    fn drop(&mut self) {
+        let mut clients = self.admin.0.clients.lock().unwrap();
+        clients.remove(&self.client.id);
+    }
+}
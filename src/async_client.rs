@@ -0,0 +1,145 @@
+use crate::client::{decode, unexpected_opcode, unexpected_reply};
+use crate::common::{Reply, Request};
+use crate::protocol::{Frame, Hello, OP_ERROR, OP_HELLO, OP_REQUEST, PROTOCOL_VERSION};
+use crate::{KvsError, Result};
+use log::{debug, error};
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio::sync::{self, oneshot};
+
+/// The requests waiting for their responses, by request id
+#[derive(Default)]
+struct Pending {
+    waiters: HashMap<u32, oneshot::Sender<Result<Reply>>>,
+    closed: bool,
+}
+
+/// asynchronous key value store client
+///
+/// The client can be shared by several tasks: their requests are pipelined on
+/// the same connection and matched with their responses by request id.
+pub struct AsyncKvsClient {
+    writer: sync::Mutex<OwnedWriteHalf>,
+    pending: Arc<Mutex<Pending>>,
+    next_id: AtomicU32,
+    server: Hello,
+}
+
+impl AsyncKvsClient {
+    /// Connect to `addr` to access `KvsServer` or `AsyncKvsServer`
+    ///
+    /// It must be called within a tokio runtime.
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        let tcp = TcpStream::connect(addr).await?;
+        tcp.set_nodelay(true)?;
+        let (reader, mut writer) = tcp.into_split();
+        let mut reader = BufReader::new(reader);
+
+        let hello = Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: Vec::new(),
+        };
+        writer
+            .write_all(&Frame::new(OP_HELLO, 0, &hello)?.to_bytes()?)
+            .await?;
+        let frame = Frame::read_from_async(&mut reader)
+            .await?
+            .ok_or_else(|| KvsError::from(io::Error::from(io::ErrorKind::UnexpectedEof)))?;
+        let server = match frame.opcode {
+            OP_HELLO => frame.parse()?,
+            OP_ERROR => return decode(&frame).and_then(|reply| Err(unexpected_reply(reply))),
+            opcode => return Err(unexpected_opcode(opcode)),
+        };
+
+        let pending = Arc::new(Mutex::new(Pending::default()));
+        tokio::spawn(read_responses(reader, Arc::clone(&pending)));
+        Ok(AsyncKvsClient {
+            writer: sync::Mutex::new(writer),
+            pending,
+            next_id: AtomicU32::new(0),
+            server,
+        })
+    }
+
+    /// The protocol version negotiated with the server
+    pub fn protocol_version(&self) -> u16 {
+        self.server.version
+    }
+
+    /// Send a request and wait for its response
+    async fn call(&self, request: &Request) -> Result<Reply> {
+        let request_id = self.next_id.fetch_add(1, Ordering::Relaxed).wrapping_add(1);
+        let bytes = Frame::new(OP_REQUEST, request_id, request)?.to_bytes()?;
+        let (tx, rx) = oneshot::channel();
+        {
+            let mut pending = self.pending.lock().unwrap();
+            if pending.closed {
+                return Err(connection_closed());
+            }
+            pending.waiters.insert(request_id, tx);
+        }
+        if let Err(e) = self.writer.lock().await.write_all(&bytes).await {
+            self.pending.lock().unwrap().waiters.remove(&request_id);
+            return Err(e.into());
+        }
+        rx.await.map_err(|_| connection_closed())?
+    }
+
+    /// Get the value of the given key from the server
+    pub async fn get(&self, key: String) -> Result<Option<String>> {
+        match self.call(&Request::Get { key }).await? {
+            Reply::Value(value) => Ok(value),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    /// Set the value of a string key in the server.
+    pub async fn set(&self, key: String, value: String) -> Result<()> {
+        match self.call(&Request::Set { key, value }).await? {
+            Reply::Done => Ok(()),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    /// Remove a string key in the server.
+    pub async fn remove(&self, key: String) -> Result<()> {
+        match self.call(&Request::Remove { key }).await? {
+            Reply::Done => Ok(()),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+}
+
+/// Hands the responses to the requests waiting for them until the connection closes
+async fn read_responses(mut reader: BufReader<OwnedReadHalf>, pending: Arc<Mutex<Pending>>) {
+    loop {
+        let frame = match Frame::read_from_async(&mut reader).await {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            Err(e) => {
+                debug!("Connection lost: {}", e);
+                break;
+            }
+        };
+        let waiter = pending.lock().unwrap().waiters.remove(&frame.request_id);
+        match waiter {
+            Some(waiter) => {
+                let _ = waiter.send(decode(&frame));
+            }
+            None => error!("Unexpected response to {}", frame.request_id),
+        }
+    }
+    // dropping the waiters fails the requests still running
+    let mut pending = pending.lock().unwrap();
+    pending.closed = true;
+    pending.waiters.clear();
+}
+
+fn connection_closed() -> KvsError {
+    io::Error::new(io::ErrorKind::ConnectionAborted, "connection closed").into()
+}
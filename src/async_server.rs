@@ -0,0 +1,281 @@
+use crate::common::{Reply, Request, Response};
+use crate::dispatch::Dispatcher;
+use crate::engines::KvsEngine;
+use crate::protocol::{self, Frame, OP_ERROR, OP_EVENT, OP_HELLO, OP_RESPONSE};
+use crate::server::{self, accept_hello, apply, expired, parse_request, Access, Limits};
+use crate::shutdown::ShutdownHandle;
+use crate::transport::{Address, Stream};
+use crate::thread_pool::ThreadPool;
+use crate::{ErrorCode, KvsError, Result};
+use log::{debug, error};
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+use tokio::io::{AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::{mpsc, oneshot};
+
+type FrameSender = mpsc::UnboundedSender<Frame>;
+
+/// The server for key value store, multiplexing its connections on a tokio event loop
+///
+/// A connection only holds a thread while one of its requests runs: the engine
+/// calls are run on the thread pool, with the requests on the same key kept in
+/// order. Clients of the legacy JSON protocol still get a pool thread each.
+pub struct AsyncKvsServer<E: KvsEngine, P: ThreadPool> {
+    engine: E,
+    pool: Arc<P>,
+    shutdown: ShutdownHandle,
+}
+
+impl<E: KvsEngine, P: ThreadPool + Send + Sync + 'static> AsyncKvsServer<E, P> {
+    /// create an `AsyncKvsServer` with given engine
+    pub fn new(engine: E, pool: P) -> Self {
+        Self {
+            engine,
+            pool: Arc::new(pool),
+            shutdown: ShutdownHandle::default(),
+        }
+    }
+
+    /// A handle to stop the server once it runs
+    ///
+    /// The server stops accepting connections and flushes the engine, while the
+    /// connections still open are left to the runtime.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Run the server listening on the given address
+    ///
+    /// It must be polled within a tokio runtime.
+    pub async fn run<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        self.shutdown.bind(Address::Tcp(listener.local_addr()?));
+        while !self.shutdown.is_shutdown() {
+            let (stream, peer_addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    error!("Connection failed: {}", err);
+                    continue;
+                }
+            };
+            if self.shutdown.is_shutdown() {
+                break;
+            }
+            let engine = self.engine.clone();
+            let pool = Arc::clone(&self.pool);
+            tokio::spawn(async move {
+                if let Err(e) = serve(engine, stream, peer_addr, pool).await {
+                    error!("Error on serving client: {}", e);
+                }
+            });
+        }
+        self.engine.flush()
+    }
+}
+
+async fn serve<E, P>(
+    engine: E,
+    stream: TcpStream,
+    peer_addr: SocketAddr,
+    pool: Arc<P>,
+) -> Result<()>
+where
+    E: KvsEngine,
+    P: ThreadPool + Send + Sync + 'static,
+{
+    let mut first_byte = [0; 1];
+    if stream.peek(&mut first_byte).await? == 0 {
+        return Ok(());
+    }
+    if protocol::is_legacy(first_byte[0]) {
+        // the legacy protocol has no framing to read asynchronously
+        let stream = stream.into_std()?;
+        stream.set_nonblocking(false)?;
+        let stream = Stream::Tcp(stream);
+        let legacy_pool = Arc::clone(&pool);
+        pool.spawn(move || {
+            if let Err(e) = server::serve(
+                engine,
+                stream,
+                legacy_pool,
+                &Limits::default(),
+                Access::default(),
+            ) {
+                error!("Error on serving client: {}", e);
+            }
+        });
+        return Ok(());
+    }
+
+    let (reader, writer) = stream.into_split();
+    let (tx, rx) = mpsc::unbounded_channel();
+    let writing = tokio::spawn(write_frames(writer, rx));
+    let result = read_requests(engine, BufReader::new(reader), tx, peer_addr, pool).await;
+    // the writer stops once the requests still running have sent their responses
+    let written = writing
+        .await
+        .unwrap_or_else(|e| Err(KvsError::StringError(e.to_string())));
+    result.and(written)
+}
+
+async fn read_requests<E, P>(
+    engine: E,
+    mut reader: BufReader<OwnedReadHalf>,
+    tx: FrameSender,
+    peer_addr: SocketAddr,
+    pool: Arc<P>,
+) -> Result<()>
+where
+    E: KvsEngine,
+    P: ThreadPool + Send + Sync + 'static,
+{
+    let frame = match Frame::read_from_async(&mut reader).await? {
+        Some(frame) => frame,
+        None => return Ok(()),
+    };
+    match accept_hello(&frame) {
+        Ok(hello) => {
+            debug!("{} speaks protocol version {}", peer_addr, hello.version);
+            send(&tx, OP_HELLO, frame.request_id, &hello);
+        }
+        Err(message) => {
+            send_error(&tx, frame.request_id, message);
+            return Ok(());
+        }
+    }
+
+    let dispatcher = Dispatcher::new(Arc::clone(&pool));
+    loop {
+        let frame = match Frame::read_from_async(&mut reader).await? {
+            Some(frame) => frame,
+            None => return Ok(()),
+        };
+        let request_id = frame.request_id;
+        let (request, deadline) = match parse_request(&frame) {
+            Ok(parsed) => parsed,
+            Err(message) => {
+                send_error(&tx, request_id, message);
+                continue;
+            }
+        };
+        debug!(
+            "Receive request {} from {}: {:?}",
+            request_id, peer_addr, request
+        );
+
+        if let Some(key) = request.key().map(str::to_owned) {
+            let engine = engine.clone();
+            let tx = tx.clone();
+            dispatcher.dispatch(&key, move || {
+                execute(&engine, request, request_id, deadline, &tx);
+            });
+            continue;
+        }
+
+        // requests on several keys or on no key wait for all the previous ones,
+        // and the following ones wait for them
+        let (done_tx, done_rx) = oneshot::channel();
+        let engine = engine.clone();
+        let tx = tx.clone();
+        let dispatcher = dispatcher.clone();
+        pool.spawn(move || {
+            dispatcher.wait();
+            let keep_reading = execute(&engine, request, request_id, deadline, &tx);
+            let _ = done_tx.send(keep_reading);
+        });
+        match done_rx.await {
+            Ok(true) => {}
+            Ok(false) => return Ok(()),
+            Err(_) => return Err(KvsError::StringError("a request panicked".to_owned())),
+        }
+    }
+}
+
+/// Executes a request on a pool thread, returning `false` if no more request should be read
+fn execute<E: KvsEngine>(
+    engine: &E,
+    request: Request,
+    request_id: u32,
+    deadline: Option<Instant>,
+    tx: &FrameSender,
+) -> bool {
+    if expired(deadline) {
+        let resp = Response::from(Err(KvsError::DeadlineExceeded));
+        send(tx, OP_RESPONSE, request_id, &resp);
+        return true;
+    }
+    let prefix = match request {
+        Request::Watch { prefix } => prefix,
+        request => {
+            let resp: Response = apply(engine, request).into();
+            send(tx, OP_RESPONSE, request_id, &resp);
+            return true;
+        }
+    };
+    let watcher = match engine.watch(prefix) {
+        Ok(watcher) => watcher,
+        Err(err) => {
+            send(tx, OP_RESPONSE, request_id, &Response::from(Err(err)));
+            return true;
+        }
+    };
+    send(
+        tx,
+        OP_RESPONSE,
+        request_id,
+        &Response::from(Ok(Reply::Done)),
+    );
+    // the connection is a push stream from now on, waiting for changes on its own thread
+    let tx = tx.clone();
+    thread::spawn(move || {
+        for event in watcher {
+            if tx.is_closed() {
+                break;
+            }
+            send(&tx, OP_EVENT, request_id, &event);
+        }
+    });
+    false
+}
+
+/// Queues a frame to be written, dropping it if the connection is gone
+fn send<T: Serialize>(tx: &FrameSender, opcode: u8, request_id: u32, body: &T) {
+    match Frame::new(opcode, request_id, body) {
+        Ok(frame) => {
+            let _ = tx.send(frame);
+        }
+        Err(e) => error!("Cannot encode the response to {}: {}", request_id, e),
+    }
+}
+
+/// Reports a frame the server cannot handle
+fn send_error(tx: &FrameSender, request_id: u32, message: String) {
+    debug!("Error sent for {}: {}", request_id, message);
+    let resp = Response::Err {
+        code: ErrorCode::BadRequest,
+        message,
+    };
+    send(tx, OP_ERROR, request_id, &resp);
+}
+
+/// Writes the queued frames until every sender is dropped
+async fn write_frames(
+    writer: OwnedWriteHalf,
+    mut rx: mpsc::UnboundedReceiver<Frame>,
+) -> Result<()> {
+    let mut writer = BufWriter::new(writer);
+    while let Some(frame) = rx.recv().await {
+        writer.write_all(&frame.to_bytes()?).await?;
+        // write the responses that are ready together
+        while let Ok(frame) = rx.try_recv() {
+            writer.write_all(&frame.to_bytes()?).await?;
+        }
+        writer.flush().await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,300 @@
+//! Authentication of the clients and access control on the keys
+use crate::common::Request;
+use crate::glob::literal_prefix;
+use crate::{KvsError, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt::{self, Write as _};
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+const PASSWORD_SCHEME: &str = "pbkdf2-sha256";
+const PASSWORD_ROUNDS: u32 = 100_000;
+const SALT_LEN: usize = 16;
+const HASH_LEN: usize = 32;
+
+/// What a client proves its identity with
+#[derive(Clone, Serialize, Deserialize)]
+pub enum Credentials {
+    /// A user name and its password
+    Password {
+        /// The name of the user
+        user: String,
+        /// The password in clear, to be sent over TLS only
+        password: String,
+    },
+    /// A token issued to a user
+    Token(String),
+}
+
+// the secrets are kept out of the logs
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Credentials::Password { user, .. } => write!(f, "Password {{ user: {:?} }}", user),
+            Credentials::Token(_) => write!(f, "Token"),
+        }
+    }
+}
+
+/// What a grant allows on the keys
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    /// Get, scan and watch the keys
+    Read,
+    /// Also set, remove and update the keys
+    Write,
+    /// Also run the server-wide commands
+    Admin,
+}
+
+#[derive(Debug, Deserialize)]
+struct Grant {
+    #[serde(default)]
+    prefix: String,
+    permissions: Vec<Permission>,
+}
+
+#[derive(Debug, Deserialize)]
+struct User {
+    name: String,
+    #[serde(default)]
+    password: Option<String>,
+    #[serde(default)]
+    tokens: Vec<String>,
+    #[serde(default)]
+    grants: Vec<Grant>,
+}
+
+#[derive(Deserialize)]
+struct AuthFile {
+    users: Vec<User>,
+}
+
+/// The users allowed on a server and their permissions
+///
+/// The users are described in a JSON file:
+///
+/// ```json
+/// {
+///   "users": [
+///     {
+///       "name": "alice",
+///       "password": "pbkdf2-sha256$100000$<salt>$<hash>",
+///       "tokens": ["<sha256 of the token>"],
+///       "grants": [
+///         { "prefix": "app/", "permissions": ["write"] },
+///         { "prefix": "", "permissions": ["read"] }
+///       ]
+///     }
+///   ]
+/// }
+/// ```
+///
+/// Passwords are stored as salted PBKDF2 hashes made by [`hash_password`], and
+/// tokens, random by nature, as the hex SHA-256 digest made by [`hash_token`].
+///
+/// A grant applies to the keys starting with its prefix, the empty prefix
+/// covering the whole keyspace. `write` implies `read`, and `admin` implies
+/// both. Requests on no key, like `stats`, need `admin` on the whole keyspace,
+/// except `replicate`, which needs `read` on it.
+#[derive(Clone)]
+pub struct AuthConfig(Arc<Users>);
+
+struct Users {
+    by_name: HashMap<String, Arc<User>>,
+    by_token: HashMap<String, Arc<User>>,
+}
+
+impl AuthConfig {
+    /// Loads the users from a JSON file
+    pub fn load(path: &Path) -> Result<Self> {
+        let file: AuthFile = serde_json::from_slice(&fs::read(path)?)?;
+        let mut users = Users {
+            by_name: HashMap::new(),
+            by_token: HashMap::new(),
+        };
+        for user in file.users {
+            let user = Arc::new(user);
+            for token in &user.tokens {
+                users
+                    .by_token
+                    .insert(token.to_ascii_lowercase(), Arc::clone(&user));
+            }
+            if users
+                .by_name
+                .insert(user.name.clone(), Arc::clone(&user))
+                .is_some()
+            {
+                return Err(KvsError::StringError(format!(
+                    "user {} is defined twice",
+                    user.name
+                )));
+            }
+        }
+        Ok(AuthConfig(Arc::new(users)))
+    }
+}
+
+/// The user a connection is authenticated as
+pub struct Session {
+    config: AuthConfig,
+    user: Option<Arc<User>>,
+}
+
+impl Session {
+    /// A connection not authenticated yet
+    pub fn new(config: AuthConfig) -> Self {
+        Session { config, user: None }
+    }
+
+    /// The name of the user, once authenticated
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_ref().map(|user| user.name.as_str())
+    }
+
+    /// Checks the credentials, the connection acting as their user from now on
+    ///
+    /// Failing credentials leave the connection unauthenticated.
+    pub fn authenticate(&mut self, credentials: &Credentials) -> Result<()> {
+        let users = &self.config.0;
+        let user = match credentials {
+            Credentials::Password { user, password } => users
+                .by_name
+                .get(user)
+                .filter(|u| u.password.as_deref().is_some_and(|h| verify(password, h))),
+            Credentials::Token(token) => users.by_token.get(&hash_token(token)),
+        };
+        self.user = user.cloned();
+        match &self.user {
+            Some(_) => Ok(()),
+            None => Err(KvsError::Unauthorized("invalid credentials".to_owned())),
+        }
+    }
+
+    /// Checks that the connection may make `request`
+    pub fn authorize(&self, request: &Request) -> Result<()> {
+        let user = match &self.user {
+            Some(user) => user,
+            None if matches!(request, Request::Ping) => return Ok(()),
+            None => return Err(KvsError::Unauthorized("authentication required".to_owned())),
+        };
+        let allowed = match request {
+            Request::Get { key } => allows(user, key, Permission::Read),
+            Request::MGet { keys } => keys.iter().all(|key| allows(user, key, Permission::Read)),
+            Request::Scan { prefix, .. } | Request::Watch { prefix } => {
+                allows(user, prefix, Permission::Read)
+            }
+            Request::Set { key, .. }
+            | Request::Remove { key }
+            | Request::Incr { key, .. }
+            | Request::Decr { key, .. }
+            | Request::Append { key, .. } => allows(user, key, Permission::Write),
+            Request::MSet { pairs } => pairs
+                .iter()
+                .all(|(key, _)| allows(user, key, Permission::Write)),
+            Request::Replicate => allows(user, "", Permission::Read),
+            // the channels are granted like the keys with the same names
+            Request::Publish { channel, .. } => allows(user, channel, Permission::Write),
+            Request::Subscribe { channels, patterns } => {
+                channels
+                    .iter()
+                    .all(|channel| allows(user, channel, Permission::Read))
+                    && patterns
+                        .iter()
+                        .all(|pattern| allows(user, literal_prefix(pattern), Permission::Read))
+            }
+            Request::Stats
+            | Request::Promote
+            | Request::Raft(_)
+            | Request::AddNode { .. }
+            | Request::RemoveNode { .. }
+            | Request::ClusterStatus
+            | Request::Admin(_) => allows(user, "", Permission::Admin),
+            Request::Ping | Request::Auth(_) => true,
+        };
+        if allowed {
+            Ok(())
+        } else {
+            Err(KvsError::Unauthorized(format!(
+                "{} may not run {}",
+                user.name,
+                request.name()
+            )))
+        }
+    }
+}
+
+/// Whether a grant of `user` gives `permission` on all the keys starting with `prefix`
+fn allows(user: &User, prefix: &str, permission: Permission) -> bool {
+    user.grants.iter().any(|grant| {
+        prefix.starts_with(&grant.prefix) && grant.permissions.iter().any(|&p| p >= permission)
+    })
+}
+
+/// Hashes a password with a random salt, for the `password` of a user
+pub fn hash_password(password: &str) -> Result<String> {
+    let mut salt = [0; SALT_LEN];
+    getrandom::getrandom(&mut salt).map_err(|e| KvsError::StringError(e.to_string()))?;
+    let hash = derive_key(password, &salt, PASSWORD_ROUNDS);
+    Ok(format!(
+        "{}${}${}${}",
+        PASSWORD_SCHEME,
+        PASSWORD_ROUNDS,
+        to_hex(&salt),
+        to_hex(&hash)
+    ))
+}
+
+/// Hashes a token, for the `tokens` of a user
+pub fn hash_token(token: &str) -> String {
+    to_hex(&Sha256::digest(token.as_bytes()))
+}
+
+/// Whether `password` matches a hash made by `hash_password`
+fn verify(password: &str, hash: &str) -> bool {
+    let parts: Vec<&str> = hash.split('$').collect();
+    let (rounds, salt, expected) = match parts.as_slice() {
+        [PASSWORD_SCHEME, rounds, salt, expected] => (rounds, salt, expected),
+        _ => return false,
+    };
+    let (rounds, salt, expected) = match (rounds.parse(), from_hex(salt), from_hex(expected)) {
+        (Ok(rounds), Some(salt), Some(expected)) => (rounds, salt, expected),
+        _ => return false,
+    };
+    let hash = derive_key(password, &salt, rounds);
+    // compare in constant time, not to leak how much of the hash matches
+    hash.len() == expected.len()
+        && hash
+            .iter()
+            .zip(&expected)
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+fn derive_key(password: &str, salt: &[u8], rounds: u32) -> [u8; HASH_LEN] {
+    let mut hash = [0; HASH_LEN];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, rounds, &mut hash);
+    hash
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        let _ = write!(hex, "{:02x}", byte);
+    }
+    hex
+}
+
+pub(crate) fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
@@ -0,0 +1,151 @@
+use kvs::{Address, Credentials, EngineStats, KvsClient, KvsError, Result};
+use log::LevelFilter;
+use std::process::exit;
+use structopt::StructOpt;
+
+const DEFAULT_ADDR: &str = "127.0.0.1:4000";
+
+#[derive(StructOpt, Debug)]
+#[structopt(about = "Operates a running kvs-server, as a user with the admin permission")]
+struct Opt {
+    #[structopt(
+        long,
+        global = true,
+        default_value = DEFAULT_ADDR,
+        value_name = "IP:PORT|unix:PATH",
+        help = "Sets the server address"
+    )]
+    addr: Address,
+
+    #[structopt(long, global = true, help = "Authenticates as this user", value_name = "NAME")]
+    user: Option<String>,
+
+    #[structopt(
+        long,
+        global = true,
+        env = "KVS_PASSWORD",
+        hide_env_values = true,
+        help = "The password of the user"
+    )]
+    password: Option<String>,
+
+    #[structopt(
+        long,
+        global = true,
+        env = "KVS_TOKEN",
+        hide_env_values = true,
+        help = "Authenticates with this token"
+    )]
+    token: Option<String>,
+
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    #[structopt(name = "compact", about = "Reclaim the disk space of the stale data now")]
+    Compact,
+
+    #[structopt(name = "flush", about = "Sync the changes to the disk")]
+    Flush,
+
+    #[structopt(name = "stats", about = "Show the statistics of the server and its engine")]
+    Stats,
+
+    #[structopt(name = "clients", about = "List the connections of the clients")]
+    Clients,
+
+    #[structopt(name = "log-level", about = "Change the level of the messages logged")]
+    LogLevel {
+        #[structopt(
+            name = "LEVEL",
+            possible_values = &["off", "error", "warn", "info", "debug", "trace"],
+            case_insensitive = true,
+            help = "The most detailed level logged"
+        )]
+        level: LevelFilter,
+    },
+
+    #[structopt(name = "config", about = "Show the settings the server runs with")]
+    Config,
+}
+
+fn main() {
+    let opt = Opt::from_args();
+
+    if let Err(err) = run(opt) {
+        eprintln!("{}", &err);
+        exit(1);
+    }
+}
+
+fn run(opt: Opt) -> Result<()> {
+    let mut client = KvsClient::connect(opt.addr.clone())?;
+    if let Some(credentials) = credentials(&opt)? {
+        client.authenticate(credentials)?;
+    }
+    match opt.command {
+        Command::Compact => {
+            let stats = client.compact()?;
+            print_engine_stats(&stats);
+        }
+        Command::Flush => client.flush()?,
+        Command::Stats => {
+            let stats = client.server_stats()?;
+            println!("uptime: {}s", stats.uptime.as_secs());
+            println!("connections: {}", stats.connections);
+            println!("requests: {}", stats.requests);
+            print_engine_stats(&stats.engine);
+        }
+        Command::Clients => {
+            for client in client.clients()? {
+                println!(
+                    "id={} addr={} user={} age={}s requests={}",
+                    client.id,
+                    client.addr,
+                    client.user.as_deref().unwrap_or("-"),
+                    client.age.as_secs(),
+                    client.requests
+                );
+            }
+        }
+        Command::LogLevel { level } => client.set_log_level(level)?,
+        Command::Config => {
+            let config = client.server_config()?;
+            println!("{}", serde_json::to_string_pretty(&config)?);
+        }
+    }
+    Ok(())
+}
+
+fn print_engine_stats(stats: &EngineStats) {
+    println!("keys: {}", stats.keys);
+    println!("generations: {}", stats.generations);
+    println!("disk_bytes: {}", stats.disk_bytes);
+    println!("live_bytes: {}", stats.live_bytes);
+    println!("garbage_bytes: {}", stats.garbage_bytes);
+    println!("garbage_ratio: {:.4}", stats.garbage_ratio);
+    println!("compactions: {}", stats.compactions);
+    println!("compaction_time: {:?}", stats.compaction_time);
+    if let Some(last) = stats.last_compaction {
+        println!("last_compaction: {:?}", last);
+    }
+}
+
+fn credentials(opt: &Opt) -> Result<Option<Credentials>> {
+    match (&opt.user, &opt.password, &opt.token) {
+        (Some(_), _, Some(_)) => Err(KvsError::StringError(
+            "--user and --token cannot be used together".to_owned(),
+        )),
+        (Some(user), Some(password), None) => Ok(Some(Credentials::Password {
+            user: user.clone(),
+            password: password.clone(),
+        })),
+        (Some(_), None, None) => Err(KvsError::StringError(
+            "--user needs a --password or KVS_PASSWORD".to_owned(),
+        )),
+        (None, _, Some(token)) => Ok(Some(Credentials::Token(token.clone()))),
+        (None, _, None) => Ok(None),
+    }
+}
@@ -1,9 +1,38 @@
-use kvs::{KvsClient, Result};
-use std::{net::SocketAddr, process::exit};
+use kvs::{
+    Address, ClusterClient, Credentials, HashRing, KvsClient, KvsError, Result, ShardedKvsClient,
+};
+use std::process::exit;
 use structopt::StructOpt;
 
 const DEFAUTL_ADDR: &str = "127.0.0.1:4000";
-const ADDRESS_FORMAT: &str = "IP:PORT";
+const ADDRESS_FORMAT: &str = "IP:PORT|unix:PATH";
+
+#[derive(StructOpt, Debug)]
+struct Opt {
+    #[structopt(long, global = true, help = "Authenticates as this user", value_name = "NAME")]
+    user: Option<String>,
+
+    #[structopt(
+        long,
+        global = true,
+        env = "KVS_PASSWORD",
+        hide_env_values = true,
+        help = "The password of the user"
+    )]
+    password: Option<String>,
+
+    #[structopt(
+        long,
+        global = true,
+        env = "KVS_TOKEN",
+        hide_env_values = true,
+        help = "Authenticates with this token"
+    )]
+    token: Option<String>,
+
+    #[structopt(subcommand)]
+    command: Command,
+}
 
 #[derive(StructOpt, Debug)]
 enum Command {
@@ -18,7 +47,7 @@ enum Command {
             value_name = ADDRESS_FORMAT,
             help = "Sets the server address"
         )]
-        addr: SocketAddr,
+        addr: Address,
     },
 
     #[structopt(name = "get", about = "Get the string value of a given string key")]
@@ -31,7 +60,7 @@ enum Command {
             value_name = ADDRESS_FORMAT,
             help = "Sets the server address"
         )]
-        addr: SocketAddr,
+        addr: Address,
     },
 
     #[structopt(name = "rm", about = "Remove a given string key")]
@@ -44,27 +73,217 @@ enum Command {
             value_name = ADDRESS_FORMAT,
             help = "Sets the server address"
         )]
-        addr: SocketAddr,
+        addr: Address,
+    },
+
+    #[structopt(name = "incr", about = "Increment the integer value of a given key")]
+    Incr {
+        #[structopt(name = "KEY", help = "A string key")]
+        key: String,
+        #[structopt(name = "DELTA", default_value = "1", help = "The amount to add")]
+        delta: i64,
+        #[structopt(
+            long,
+            default_value = DEFAUTL_ADDR,
+            value_name = ADDRESS_FORMAT,
+            help = "Sets the server address"
+        )]
+        addr: Address,
+    },
+
+    #[structopt(name = "decr", about = "Decrement the integer value of a given key")]
+    Decr {
+        #[structopt(name = "KEY", help = "A string key")]
+        key: String,
+        #[structopt(name = "DELTA", default_value = "1", help = "The amount to subtract")]
+        delta: i64,
+        #[structopt(
+            long,
+            default_value = DEFAUTL_ADDR,
+            value_name = ADDRESS_FORMAT,
+            help = "Sets the server address"
+        )]
+        addr: Address,
+    },
+
+    #[structopt(name = "append", about = "Append a string to the value of a given key")]
+    Append {
+        #[structopt(name = "KEY", help = "A string key")]
+        key: String,
+        suffix: String,
+        #[structopt(
+            long,
+            default_value = DEFAUTL_ADDR,
+            value_name = ADDRESS_FORMAT,
+            help = "Sets the server address"
+        )]
+        addr: Address,
+    },
+
+    #[structopt(name = "watch", about = "Print the changes of keys with a given prefix")]
+    Watch {
+        #[structopt(name = "PREFIX", default_value = "", help = "A key prefix")]
+        prefix: String,
+        #[structopt(
+            long,
+            default_value = DEFAUTL_ADDR,
+            value_name = ADDRESS_FORMAT,
+            help = "Sets the server address"
+        )]
+        addr: Address,
+    },
+
+    #[structopt(name = "publish", about = "Publish a message on a channel")]
+    Publish {
+        #[structopt(name = "CHANNEL", help = "A channel name")]
+        channel: String,
+        #[structopt(name = "MESSAGE", help = "The message")]
+        message: String,
+        #[structopt(
+            long,
+            default_value = DEFAUTL_ADDR,
+            value_name = ADDRESS_FORMAT,
+            help = "Sets the server address"
+        )]
+        addr: Address,
+    },
+
+    #[structopt(name = "subscribe", about = "Print the messages published on some channels")]
+    Subscribe {
+        #[structopt(name = "CHANNEL", help = "Channel names")]
+        channels: Vec<String>,
+        #[structopt(
+            long = "pattern",
+            number_of_values = 1,
+            value_name = "PATTERN",
+            help = "Also the channels matching this pattern, with * and ? as wildcards"
+        )]
+        patterns: Vec<String>,
+        #[structopt(
+            long,
+            default_value = DEFAUTL_ADDR,
+            value_name = ADDRESS_FORMAT,
+            help = "Sets the server address"
+        )]
+        addr: Address,
+    },
+
+    #[structopt(name = "promote", about = "Promote a follower to leader")]
+    Promote {
+        #[structopt(
+            long,
+            default_value = DEFAUTL_ADDR,
+            value_name = ADDRESS_FORMAT,
+            help = "Sets the server address"
+        )]
+        addr: Address,
+    },
+
+    #[structopt(name = "cluster-status", about = "Show the state of a cluster node")]
+    ClusterStatus {
+        #[structopt(
+            long,
+            default_value = DEFAUTL_ADDR,
+            value_name = ADDRESS_FORMAT,
+            help = "Sets the server address"
+        )]
+        addr: Address,
+    },
+
+    #[structopt(name = "add-node", about = "Add a node to the cluster")]
+    AddNode {
+        #[structopt(name = "ID", help = "The id of the new node")]
+        id: u64,
+        #[structopt(name = "NODE-ADDR", help = "The address the new node serves on")]
+        node_addr: Address,
+        #[structopt(
+            long,
+            default_value = DEFAUTL_ADDR,
+            value_name = ADDRESS_FORMAT,
+            help = "Sets the address of a server of the cluster"
+        )]
+        addr: Address,
+    },
+
+    #[structopt(name = "remove-node", about = "Remove a node from the cluster")]
+    RemoveNode {
+        #[structopt(name = "ID", help = "The id of the node")]
+        id: u64,
+        #[structopt(
+            long,
+            default_value = DEFAUTL_ADDR,
+            value_name = ADDRESS_FORMAT,
+            help = "Sets the address of a server of the cluster"
+        )]
+        addr: Address,
+    },
+
+    #[structopt(
+        name = "rebalance",
+        about = "Move the keys of sharded servers to their owners on a new set of servers"
+    )]
+    Rebalance {
+        #[structopt(
+            long,
+            required = true,
+            use_delimiter = true,
+            value_name = ADDRESS_FORMAT,
+            help = "The servers the keys are spread over now"
+        )]
+        from: Vec<Address>,
+        #[structopt(
+            long,
+            required = true,
+            use_delimiter = true,
+            value_name = ADDRESS_FORMAT,
+            help = "The servers to spread the keys over"
+        )]
+        to: Vec<Address>,
+        #[structopt(
+            long,
+            default_value = "160",
+            help = "The number of points of each server on the hash ring"
+        )]
+        virtual_nodes: usize,
+    },
+
+    #[structopt(name = "stats", about = "Show the statistics of the storage engine")]
+    Stats {
+        #[structopt(
+            long,
+            default_value = DEFAUTL_ADDR,
+            value_name = ADDRESS_FORMAT,
+            help = "Sets the server address"
+        )]
+        addr: Address,
     },
 }
 
 fn main() {
-    let command = Command::from_args();
+    let opt = Opt::from_args();
 
-    if let Err(err) = run(command) {
+    if let Err(err) = run(opt) {
         eprintln!("{}", &err);
         exit(1);
     }
 }
 
-fn run(command: Command) -> Result<()> {
-    match command {
+fn run(opt: Opt) -> Result<()> {
+    let credentials = credentials(&opt)?;
+    let connect = |addr: Address| -> Result<KvsClient> {
+        let mut client = KvsClient::connect(addr)?;
+        if let Some(credentials) = &credentials {
+            client.authenticate(credentials.clone())?;
+        }
+        Ok(client)
+    };
+    match opt.command {
         Command::Set { key, value, addr } => {
-            let mut client = KvsClient::connect(addr)?;
+            let mut client = connect(addr)?;
             client.set(key, value)?;
         }
         Command::Get { key, addr } => {
-            let mut client = KvsClient::connect(addr)?;
+            let mut client = connect(addr)?;
             if let Some(value) = client.get(key)? {
                 println!("{}", value);
             } else {
@@ -72,10 +291,120 @@ fn run(command: Command) -> Result<()> {
             }
         }
         Command::Remove { key, addr } => {
-            let mut client = KvsClient::connect(addr)?;
+            let mut client = connect(addr)?;
             client.rm(key)?;
         }
+        Command::Incr { key, delta, addr } => {
+            let mut client = connect(addr)?;
+            println!("{}", client.incr(key, delta)?);
+        }
+        Command::Decr { key, delta, addr } => {
+            let mut client = connect(addr)?;
+            println!("{}", client.decr(key, delta)?);
+        }
+        Command::Append { key, suffix, addr } => {
+            let mut client = connect(addr)?;
+            println!("{}", client.append(key, suffix)?);
+        }
+        Command::Watch { prefix, addr } => {
+            let client = connect(addr)?;
+            for event in client.watch(prefix)? {
+                println!("{}", event?);
+            }
+        }
+        Command::Publish {
+            channel,
+            message,
+            addr,
+        } => {
+            let mut client = connect(addr)?;
+            println!("{}", client.publish(channel, message)?);
+        }
+        Command::Subscribe {
+            channels,
+            patterns,
+            addr,
+        } => {
+            let client = connect(addr)?;
+            for message in client.subscribe(channels, patterns)? {
+                println!("{}", message?);
+            }
+        }
+        Command::Promote { addr } => {
+            let mut client = connect(addr)?;
+            client.promote()?;
+        }
+        Command::ClusterStatus { addr } => {
+            let mut client = connect(addr)?;
+            let status = client.cluster_status()?;
+            println!("id: {}", status.id);
+            println!("role: {:?}", status.role);
+            println!("term: {}", status.term);
+            match status.leader {
+                Some(leader) => println!("leader: {}", leader),
+                None => println!("leader: unknown"),
+            }
+            for (id, addr) in &status.members {
+                println!("member: {}={}", id, addr);
+            }
+            println!("commit_index: {}", status.commit_index);
+            println!("applied_index: {}", status.applied_index);
+        }
+        Command::AddNode {
+            id,
+            node_addr,
+            addr,
+        } => {
+            ClusterClient::new(vec![addr]).add_node(id, node_addr)?;
+        }
+        Command::RemoveNode { id, addr } => {
+            ClusterClient::new(vec![addr]).remove_node(id)?;
+        }
+        Command::Rebalance {
+            from,
+            to,
+            virtual_nodes,
+        } => {
+            let mut client = ShardedKvsClient::new(from).with_virtual_nodes(virtual_nodes);
+            if let Some(credentials) = &credentials {
+                client = client.with_credentials(credentials.clone());
+            }
+            let moved = client.rebalance(HashRing::new(to, virtual_nodes))?;
+            println!("moved {} keys", moved);
+        }
+        Command::Stats { addr } => {
+            let mut client = connect(addr)?;
+            let stats = client.stats()?;
+            println!("keys: {}", stats.keys);
+            println!("generations: {}", stats.generations);
+            println!("disk_bytes: {}", stats.disk_bytes);
+            println!("live_bytes: {}", stats.live_bytes);
+            println!("garbage_bytes: {}", stats.garbage_bytes);
+            println!("garbage_ratio: {:.4}", stats.garbage_ratio);
+            println!("compactions: {}", stats.compactions);
+            println!("compaction_time: {:?}", stats.compaction_time);
+            if let Some(last) = stats.last_compaction {
+                println!("last_compaction: {:?}", last);
+            }
+        }
     }
 
     Ok(())
 }
+
+fn credentials(opt: &Opt) -> Result<Option<Credentials>> {
+    match (&opt.user, &opt.password, &opt.token) {
+        (Some(_), _, Some(_)) => Err(KvsError::StringError(
+            "--user and --token cannot be used together".to_owned(),
+        )),
+        (Some(user), Some(password), None) => Ok(Some(Credentials::Password {
+            user: user.clone(),
+            password: password.clone(),
+        })),
+        (Some(_), None, None) => Err(KvsError::StringError(
+            "--user needs a --password or KVS_PASSWORD".to_owned(),
+        )),
+        (None, _, Some(token)) => Ok(Some(Credentials::Token(token.clone()))),
+        (None, _, None) => Ok(None),
+    }
+}
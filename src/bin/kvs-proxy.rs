@@ -0,0 +1,87 @@
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{Address, AuthConfig, KvsProxy, KvsServer, ProxyConfig, Result, ShutdownHandle};
+use log::LevelFilter;
+use log::{error, info, warn};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::path::PathBuf;
+use std::process::exit;
+use std::thread;
+use structopt::StructOpt;
+
+const DEFAULT_ADDR: &str = "127.0.0.1:4000";
+
+#[derive(Debug, StructOpt)]
+#[structopt(about = "Routes the requests of kvs clients to backend kvs-servers")]
+struct Command {
+    #[structopt(
+        long,
+        default_value = DEFAULT_ADDR,
+        help = "Set the listening address, IP:PORT or unix:PATH"
+        )]
+    addr: Address,
+
+    #[structopt(
+        long,
+        help = "Route the keys to the backends of this JSON file",
+        value_name = "FILE",
+        parse(from_os_str))]
+    config: PathBuf,
+
+    #[structopt(long, help = "Serve this many connections at once", value_name = "N")]
+    threads: Option<u32>,
+
+    #[structopt(
+        long,
+        help = "Authenticate the clients with the users of this JSON file",
+        value_name = "FILE",
+        parse(from_os_str))]
+    auth_config: Option<PathBuf>,
+}
+
+fn main() {
+    env_logger::builder().filter_level(LevelFilter::Info).init();
+    if let Err(e) = run(Command::from_args()) {
+        error!("{}", e);
+        exit(1);
+    }
+}
+
+fn run(cmd: Command) -> Result<()> {
+    info!("kvs-proxy {}", env!("CARGO_PKG_VERSION"));
+    let config = ProxyConfig::load(&cmd.config)?;
+    info!(
+        "{} backends, {} prefix rules",
+        config.backends.len(),
+        config.rules.len()
+    );
+    let proxy = KvsProxy::new(config)?;
+    let threads = cmd.threads.unwrap_or(num_cpus::get() as u32);
+    let mut server = KvsServer::new(proxy, SharedQueueThreadPool::new(threads)?);
+    if let Some(path) = &cmd.auth_config {
+        info!("Authenticating the clients");
+        server = server.with_auth(AuthConfig::load(path)?);
+    }
+    shutdown_on_signal(server.shutdown_handle())?;
+    info!("Listening on {}", cmd.addr);
+    let result = server.run(cmd.addr);
+    info!("kvs-proxy stopped");
+    result
+}
+
+/// Shuts the proxy down on the first SIGTERM or SIGINT
+fn shutdown_on_signal(handle: ShutdownHandle) -> Result<()> {
+    let mut signals = Signals::new([SIGTERM, SIGINT])?;
+    thread::spawn(move || {
+        let mut signals = signals.forever();
+        if let Some(signal) = signals.next() {
+            info!("Received signal {}, shutting down", signal);
+            handle.shutdown();
+        }
+        if signals.next().is_some() {
+            warn!("Killed before the shutdown completes");
+            exit(1);
+        }
+    });
+    Ok(())
+}
@@ -1,12 +1,22 @@
 use clap::arg_enum;
-use kvs::{KvStore, KvsEngine, KvsServer, Result, SledKvsEngine};
+use kvs::{
+    Address, AsyncKvsServer, AuthConfig, ClusterConfig, Credentials, KvStore, KvsEngine, KvsError,
+    KvsServer, Limits, Members, Metrics, RaftNode, Result, ShutdownHandle, SledKvsEngine,
+    TcpTransport, TlsServerConfig,
+};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
 use log::LevelFilter;
 use log::{error, info, warn};
 use core::num;
 use std::env::current_dir;
 use std::fs;
+use std::io::{self, BufRead};
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::process::exit;
+use std::thread;
+use std::time::Duration;
 use structopt::StructOpt;
 use kvs::thread_pool::{ThreadPool, SharedQueueThreadPool};
 
@@ -18,9 +28,16 @@ struct Command {
     #[structopt(
         long,
         default_value = DEFAUTL_ADDR,
-        help = "Set the listening address"
+        help = "Set the listening address, IP:PORT or unix:PATH"
         )]
-    addr: SocketAddr,
+    addr: Address,
+
+    #[structopt(
+        long,
+        help = "Also serve the protocol on this Unix domain socket",
+        value_name = "PATH",
+        parse(from_os_str))]
+    unix_socket: Option<PathBuf>,
 
     #[structopt(
         long, 
@@ -28,6 +45,119 @@ struct Command {
         help = "Set the storage engine",
         value_name = "ENGINE-NAME", case_insensitive = true)] 
     engine: Option<Engine>, 
+
+    #[structopt(
+        long,
+        possible_values = &Protocol::variants(),
+        default_value = "kvs",
+        help = "Set the wire protocol",
+        value_name = "PROTOCOL", case_insensitive = true)]
+    protocol: Protocol,
+
+    #[structopt(
+        long,
+        help = "Also serve an HTTP/JSON gateway on this address",
+        value_name = "IP-PORT")]
+    http: Option<SocketAddr>,
+
+    #[structopt(
+        long,
+        help = "Serve the metrics in the Prometheus text format over HTTP on this address",
+        value_name = "IP-PORT")]
+    metrics_addr: Option<SocketAddr>,
+
+    #[structopt(
+        long = "async",
+        help = "Multiplex the connections on an event loop (kvs protocol only)")]
+    async_io: bool,
+
+    #[structopt(long, help = "Reject the connections beyond this number", value_name = "N")]
+    max_connections: Option<usize>,
+
+    #[structopt(long, help = "Close the connections idle for this long", value_name = "SECONDS")]
+    idle_timeout: Option<u64>,
+
+    #[structopt(long, help = "Give up on a request not received within this time", value_name = "SECONDS")]
+    read_timeout: Option<u64>,
+
+    #[structopt(long, help = "Give up on a response not sent within this time", value_name = "SECONDS")]
+    write_timeout: Option<u64>,
+
+    #[structopt(long, help = "Reject the requests larger than this", value_name = "BYTES")]
+    max_request_size: Option<u32>,
+
+    #[structopt(
+        long,
+        requires = "tls-key",
+        help = "Serve over TLS with this PEM certificate chain",
+        value_name = "FILE",
+        parse(from_os_str))]
+    tls_cert: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        requires = "tls-cert",
+        help = "The PEM private key of the TLS certificate",
+        value_name = "FILE",
+        parse(from_os_str))]
+    tls_key: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        requires = "tls-cert",
+        help = "Require client certificates signed by a CA in this PEM file",
+        value_name = "FILE",
+        parse(from_os_str))]
+    tls_client_ca: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        help = "Authenticate the clients with the users of this JSON file (kvs protocol only)",
+        value_name = "FILE",
+        parse(from_os_str))]
+    auth_config: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        help = "Follow the leader at this address, serving reads only until promoted",
+        value_name = "IP:PORT|unix:PATH")]
+    replica_of: Option<Address>,
+
+    #[structopt(
+        long,
+        env = "KVS_REPLICA_TOKEN",
+        hide_env_values = true,
+        requires = "replica-of",
+        help = "Authenticate to the leader with this token",
+        value_name = "TOKEN")]
+    replica_token: Option<String>,
+
+    #[structopt(
+        long,
+        requires = "node-id",
+        parse(try_from_str = parse_members),
+        help = "Run as a node of the cluster of these members, e.g. 1=IP:PORT,2=IP:PORT",
+        value_name = "ID=ADDR,...")]
+    cluster: Option<Members>,
+
+    #[structopt(
+        long,
+        requires = "cluster",
+        help = "The id of this node in the cluster",
+        value_name = "ID")]
+    node_id: Option<u64>,
+
+    #[structopt(
+        long,
+        env = "KVS_CLUSTER_TOKEN",
+        hide_env_values = true,
+        requires = "cluster",
+        help = "Authenticate to the other nodes with this token, granted admin by their auth config",
+        value_name = "TOKEN")]
+    cluster_token: Option<String>,
+
+    #[structopt(long, help = "Print the hash of a password read from stdin, and exit")]
+    hash_password: bool,
 }
         
 arg_enum! {
@@ -38,9 +168,28 @@ arg_enum! {
     }
 }
 
+arg_enum! {
+    #[derive(Debug, PartialEq, Clone, Copy)]
+    enum Protocol {
+        Kvs,
+        Resp,
+        Memcache,
+    }
+}
+
 fn main() {
-    env_logger::builder().filter_level(LevelFilter::Info).init();
+    // the logger lets everything through, leaving the filtering to the max level,
+    // which `kvs-admin log-level` changes
+    env_logger::builder().filter_level(LevelFilter::Trace).init();
+    log::set_max_level(LevelFilter::Info);
     let mut cmd = Command::from_args();
+    if cmd.hash_password {
+        if let Err(e) = print_password_hash() {
+            error!("{}", e);
+            exit(1);
+        }
+        return;
+    }
     let result = get_pre_engine().and_then(|op| {
         if cmd.engine.is_none() {
             cmd.engine = op;
@@ -62,20 +211,257 @@ fn run(cmd: Command) -> Result<()> {
     let engine = cmd.engine.unwrap_or(DEFAULT_ENGINE);
     info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
     info!("Storage engine: {}", engine);
-    info!("Listening on {}", cmd.addr);
+    info!("Listening on {} ({})", cmd.addr, cmd.protocol);
 
     fs::write(current_dir()?.join("engine"), format!("{}", engine))?;
 
     match engine {
-        Engine::Kvs => run_with_engine(KvStore::open(current_dir()?)?, cmd.addr),
-        Engine::Sled => run_with_engine(SledKvsEngine::new(sled::open(current_dir()?)?), cmd.addr),
+        Engine::Kvs => run_with_engine(KvStore::open(current_dir()?)?, &cmd),
+        Engine::Sled => run_with_engine(SledKvsEngine::new(sled::open(current_dir()?)?), &cmd),
+    }
+}
+
+fn run_with_engine<E: KvsEngine>(engine: E, cmd: &Command) -> Result<()> {
+    let tls = tls(cmd)?;
+    let auth = match &cmd.auth_config {
+        Some(path) => Some(AuthConfig::load(path)?),
+        None => None,
+    };
+    if auth.is_some() && (cmd.http.is_some() || cmd.protocol != Protocol::Kvs) {
+        return Err(KvsError::StringError(
+            "--auth-config only protects the kvs protocol".to_owned(),
+        ));
+    }
+    if cmd.replica_of.is_some()
+        && (cmd.http.is_some()
+            || cmd.unix_socket.is_some()
+            || cmd.async_io
+            || cmd.protocol != Protocol::Kvs)
+    {
+        return Err(KvsError::StringError(
+            "--replica-of only serves the kvs protocol on --addr".to_owned(),
+        ));
+    }
+    if cmd.cluster.is_some()
+        && (cmd.replica_of.is_some()
+            || cmd.http.is_some()
+            || cmd.unix_socket.is_some()
+            || cmd.async_io
+            || cmd.protocol != Protocol::Kvs)
+    {
+        return Err(KvsError::StringError(
+            "--cluster only serves the kvs protocol on --addr".to_owned(),
+        ));
+    }
+    // the other nodes check the messages of this one against their own auth config
+    if cmd.cluster.is_some() && auth.is_some() && cmd.cluster_token.is_none() {
+        return Err(KvsError::StringError(
+            "--cluster with --auth-config requires --cluster-token".to_owned(),
+        ));
+    }
+    let node = match (&cmd.cluster, cmd.node_id) {
+        (Some(members), Some(id)) => {
+            info!("Cluster node {} of {} members", id, members.len());
+            let config = ClusterConfig::new(id, members.clone(), current_dir()?.join("raft"));
+            let mut transport = TcpTransport::default();
+            if let Some(token) = &cmd.cluster_token {
+                transport = transport.with_credentials(Credentials::Token(token.clone()));
+            }
+            Some(RaftNode::start(config, engine.clone(), transport)?)
+        }
+        _ => None,
+    };
+    if cmd.metrics_addr.is_some() && cmd.async_io {
+        return Err(KvsError::StringError("--async does not record metrics".to_owned()));
+    }
+    let metrics = cmd.metrics_addr.map(|metrics_addr| {
+        let metrics = Metrics::new();
+        let exporter = metrics.clone();
+        thread::spawn(move || {
+            if let Err(e) = exporter.serve(metrics_addr) {
+                error!("Metrics endpoint failed: {}", e);
+                exit(1);
+            }
+        });
+        metrics
+    });
+    let mut handles = Vec::new();
+    let mut threads = Vec::new();
+    if let Some(path) = &cmd.unix_socket {
+        let addr = Address::Unix(path.clone());
+        info!("Also listening on {}", addr);
+        let unix_pool = SharedQueueThreadPool::new(num_cpus::get() as u32)?;
+        let mut unix_server = KvsServer::new(engine.clone(), unix_pool).with_limits(limits(cmd));
+        if let Some(auth) = &auth {
+            unix_server = unix_server.with_auth(auth.clone());
+        }
+        if let Some(metrics) = &metrics {
+            unix_server = unix_server.with_metrics(metrics.clone());
+        }
+        handles.push(unix_server.shutdown_handle());
+        let protocol = cmd.protocol;
+        threads.push(thread::spawn(move || {
+            if let Err(e) = run_protocol(unix_server, protocol, addr) {
+                error!("Unix socket listener failed: {}", e);
+                exit(1);
+            }
+        }));
+    }
+    if let Some(http_addr) = cmd.http {
+        info!("HTTP gateway listening on {}", http_addr);
+        let http_pool = SharedQueueThreadPool::new(num_cpus::get() as u32)?;
+        let mut http_server = KvsServer::new(engine.clone(), http_pool);
+        if let Some(tls) = &tls {
+            http_server = http_server.with_tls(tls.clone());
+        }
+        if let Some(metrics) = &metrics {
+            http_server = http_server.with_metrics(metrics.clone());
+        }
+        handles.push(http_server.shutdown_handle());
+        threads.push(thread::spawn(move || {
+            if let Err(e) = http_server.run_http(http_addr) {
+                error!("HTTP gateway failed: {}", e);
+                exit(1);
+            }
+        }));
+    }
+    // the connections of the other nodes hold a thread each
+    let peers = cmd.cluster.as_ref().map_or(0, |members| members.len());
+    let thread_pool = SharedQueueThreadPool::new((num_cpus::get() + peers) as u32)?;
+    let result = if cmd.async_io {
+        if cmd.protocol != Protocol::Kvs {
+            return Err(KvsError::StringError("--async only serves the kvs protocol".to_owned()));
+        }
+        if tls.is_some() || auth.is_some() {
+            return Err(KvsError::StringError(
+                "--async supports neither TLS nor authentication".to_owned(),
+            ));
+        }
+        let addr = match &cmd.addr {
+            Address::Tcp(addr) => *addr,
+            Address::Unix(_) => {
+                return Err(KvsError::StringError("--async only listens on TCP".to_owned()));
+            }
+        };
+        let server = AsyncKvsServer::new(engine, thread_pool);
+        handles.push(server.shutdown_handle());
+        shutdown_on_signal(handles.clone())?;
+        tokio::runtime::Runtime::new()?.block_on(server.run(addr))
+    } else {
+        let mut server = KvsServer::new(engine, thread_pool).with_limits(limits(cmd));
+        if let Some(tls) = tls {
+            server = server.with_tls(tls);
+        }
+        if let Some(auth) = auth {
+            info!("Authenticating the clients");
+            server = server.with_auth(auth);
+        }
+        if let Some(leader) = &cmd.replica_of {
+            let credentials = cmd.replica_token.clone().map(Credentials::Token);
+            server = server.with_leader(leader.clone(), credentials);
+        }
+        if let Some(node) = &node {
+            server = server.with_cluster(node.clone());
+        }
+        if let Some(metrics) = metrics {
+            server = server.with_metrics(metrics);
+        }
+        handles.push(server.shutdown_handle());
+        shutdown_on_signal(handles.clone())?;
+        run_protocol(server, cmd.protocol, cmd.addr.clone())
+    };
+    if let Some(node) = node {
+        node.stop();
     }
+    // the other listeners stop along with the main server
+    for handle in &handles {
+        handle.shutdown();
+    }
+    for thread in threads {
+        let _ = thread.join();
+    }
+    info!("kvs-server stopped");
+    result
 }
 
-fn run_with_engine<E: KvsEngine>(engine: E, addr: SocketAddr) -> Result<()> {
-    let thread_pool = SharedQueueThreadPool::new(num_cpus::get() as u32)?;
-    let server = KvsServer::new(engine, thread_pool);
-    server.run(addr)
+fn run_protocol<E: KvsEngine>(
+    server: KvsServer<E, SharedQueueThreadPool>,
+    protocol: Protocol,
+    addr: Address,
+) -> Result<()> {
+    match protocol {
+        Protocol::Kvs => server.run(addr),
+        Protocol::Resp => server.run_resp(addr),
+        Protocol::Memcache => server.run_memcache(addr),
+    }
+}
+
+fn limits(cmd: &Command) -> Limits {
+    let default = Limits::default();
+    Limits {
+        max_connections: cmd.max_connections,
+        idle_timeout: cmd.idle_timeout.map(Duration::from_secs),
+        read_timeout: cmd.read_timeout.map(Duration::from_secs),
+        write_timeout: cmd.write_timeout.map(Duration::from_secs),
+        max_request_size: cmd.max_request_size.unwrap_or(default.max_request_size),
+    }
+}
+
+/// Loads the TLS settings if a certificate is given
+fn tls(cmd: &Command) -> Result<Option<TlsServerConfig>> {
+    match (&cmd.tls_cert, &cmd.tls_key) {
+        (Some(cert), Some(key)) => {
+            info!("Serving TLS");
+            let config =
+                TlsServerConfig::from_pem_files(cert, key, cmd.tls_client_ca.as_deref())?;
+            Ok(Some(config))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Parses the members of a cluster written `1=IP:PORT,2=IP:PORT`
+fn parse_members(s: &str) -> std::result::Result<Members, String> {
+    let mut members = Members::new();
+    for member in s.split(',') {
+        let (id, addr) = member
+            .split_once('=')
+            .ok_or_else(|| format!("{} is not ID=ADDR", member))?;
+        let id: u64 = id.trim().parse().map_err(|e| format!("node id {}: {}", id, e))?;
+        let addr: Address = addr.trim().parse().map_err(|e| format!("address {}: {}", addr, e))?;
+        members.insert(id, addr);
+    }
+    Ok(members)
+}
+
+/// Reads a password from the first line of stdin and prints its hash for the auth config
+fn print_password_hash() -> Result<()> {
+    let mut password = String::new();
+    io::stdin().lock().read_line(&mut password)?;
+    let password = password.trim_end_matches(&['\r', '\n'][..]);
+    println!("{}", kvs::hash_password(password)?);
+    Ok(())
+}
+
+/// Shuts the servers down on the first SIGTERM or SIGINT
+///
+/// Another signal received during the shutdown kills the process.
+fn shutdown_on_signal(handles: Vec<ShutdownHandle>) -> Result<()> {
+    let mut signals = Signals::new([SIGTERM, SIGINT])?;
+    thread::spawn(move || {
+        let mut signals = signals.forever();
+        if let Some(signal) = signals.next() {
+            info!("Received signal {}, shutting down", signal);
+            for handle in &handles {
+                handle.shutdown();
+            }
+        }
+        if signals.next().is_some() {
+            warn!("Killed before the shutdown completes");
+            exit(1);
+        }
+    });
+    Ok(())
 }
 
 fn get_pre_engine() -> Result<Option<Engine>> {
@@ -1,66 +1,601 @@
-use crate::common::{GetResponse, Request, RmResponse, SetResponse};
-use crate::{KvsError, Result};
-use serde::Deserialize;
-use serde_json::de::IoRead;
-use serde_json::Deserializer;
+use crate::admin::{AdminCommand, ClientInfo, ServerConfig, ServerStats};
+use crate::auth::Credentials;
+use crate::common::{DeadlineRequest, Replication, Reply, Request, Response};
+use crate::protocol::{
+    Frame, Hello, OP_DEADLINE, OP_ERROR, OP_EVENT, OP_HELLO, OP_MESSAGE, OP_REPLICATE, OP_REQUEST,
+    OP_RESPONSE, PROTOCOL_VERSION,
+};
+use crate::raft::{ClusterStatus, Message, NodeId};
+use crate::transport::{Address, Stream, ToAddress};
+use crate::tls::TlsClientConfig;
+use crate::{ChannelMessage, EngineStats, Event, KvsError, Result};
+use log::LevelFilter;
 use std::{
-    io::{BufReader, BufWriter, Write},
-    net::{TcpStream, ToSocketAddrs},
+    io::{self, BufReader, BufWriter, Read, Write},
+    time::Duration,
 };
 
+/// The number of pipelined requests sent ahead of their responses
+///
+/// Past it, the pipeline reads a response before sending the next request, so
+/// neither end blocks writing to the other while the socket buffers are full.
+const PIPELINE_WINDOW: usize = 64;
+
 /// key value store client
 pub struct KvsClient {
-    reader: Deserializer<IoRead<BufReader<TcpStream>>>,
-    writer: BufWriter<TcpStream>,
+    reader: BufReader<Stream>,
+    writer: BufWriter<Stream>,
+    next_id: u32,
+    server: Hello,
+    request_timeout: Option<Duration>,
 }
 
 impl KvsClient {
     /// Connect to `addr` to access `KvsServer`
-    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
-        let tcp_reader = TcpStream::connect(addr)?;
-        let tcp_writer = tcp_reader.try_clone()?;
+    ///
+    /// The address is a TCP address or `unix:/path` for a Unix domain socket.
+    pub fn connect<A: ToAddress>(addr: A) -> Result<Self> {
+        let stream = addr.to_address()?.connect()?;
+        KvsClient::open(stream)
+    }
 
-        Ok(KvsClient {
-            reader: Deserializer::from_reader(BufReader::new(tcp_reader)),
-            writer: BufWriter::new(tcp_writer),
-        })
+    /// Connect to `addr` over TLS to access a `KvsServer` serving TLS
+    ///
+    /// The server certificate is checked against `tls`, which also holds the
+    /// client certificate for the servers requiring one.
+    pub fn connect_tls<A: ToAddress>(addr: A, tls: &TlsClientConfig) -> Result<Self> {
+        match addr.to_address()?.connect()? {
+            Stream::Tcp(tcp) => KvsClient::open(Stream::Tls(tls.connect(tcp)?)),
+            _ => Err(KvsError::Tls("TLS is only served over TCP".to_owned())),
+        }
     }
 
-    /// Get the value of the given key from the server
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        let request = Request::Get { key };
-        serde_json::to_writer(&mut self.writer, &request)?;
+    fn open(stream: Stream) -> Result<Self> {
+        let mut client = KvsClient {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+            next_id: 0,
+            server: Hello::default(),
+            request_timeout: None,
+        };
+        client.handshake()?;
+        Ok(client)
+    }
+
+    /// The protocol version negotiated with the server
+    pub fn protocol_version(&self) -> u16 {
+        self.server.version
+    }
+
+    /// The features advertised by the server
+    pub fn server_capabilities(&self) -> &[String] {
+        &self.server.capabilities
+    }
+
+    /// Sets how long the server may wait before starting the requests sent from now on
+    ///
+    /// The server answers the requests it cannot start in time with
+    /// `KvsError::DeadlineExceeded`. It is ignored by servers without the
+    /// `deadline` capability.
+    pub fn set_request_timeout(&mut self, timeout: Option<Duration>) {
+        self.request_timeout = timeout;
+    }
+
+    /// Encodes a request, along with its deadline if any
+    fn request_frame(&self, request_id: u32, request: &Request) -> Result<Frame> {
+        let supported = self.server_capabilities().iter().any(|c| c == "deadline");
+        match self.request_timeout {
+            Some(timeout) if supported => {
+                let request = DeadlineRequest {
+                    timeout_ms: timeout.as_millis() as u64,
+                    request,
+                };
+                Frame::new(OP_DEADLINE, request_id, &request)
+            }
+            _ => Frame::new(OP_REQUEST, request_id, request),
+        }
+    }
+
+    fn handshake(&mut self) -> Result<()> {
+        let hello = Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: Vec::new(),
+        };
+        Frame::new(OP_HELLO, 0, &hello)?.write_to(&mut self.writer)?;
         self.writer.flush()?;
 
-        let resp = GetResponse::deserialize(&mut self.reader)?;
-        match resp {
-            GetResponse::Ok(value) => Ok(value),
-            GetResponse::Err(e) => Err(KvsError::StringError(e)),
+        let frame = read_frame(&mut self.reader)?;
+        match frame.opcode {
+            OP_HELLO => {
+                self.server = frame.parse()?;
+                Ok(())
+            }
+            OP_ERROR => frame.parse::<Response>()?.into_result().map(|_| ()),
+            opcode => Err(unexpected_opcode(opcode)),
         }
     }
 
-    /// Set the value of a string key in the server.
-    pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Set { key, value })?;
+    /// Send a request and wait for its response
+    fn call(&mut self, request: &Request) -> Result<Reply> {
+        self.next_id = self.next_id.wrapping_add(1);
+        let request_id = self.next_id;
+        self.request_frame(request_id, request)?
+            .write_to(&mut self.writer)?;
         self.writer.flush()?;
 
-        let resp = SetResponse::deserialize(&mut self.reader)?;
-        match resp {
-            SetResponse::Ok(_) => Ok(()),
-            SetResponse::Err(e) => Err(KvsError::StringError(e)),
+        let frame = read_frame(&mut self.reader)?;
+        if frame.request_id != request_id {
+            return Err(KvsError::Protocol(format!(
+                "expect response to request {}, got {}",
+                request_id, frame.request_id
+            )));
+        }
+        decode(&frame)
+    }
+
+    /// Start a pipeline of requests
+    ///
+    /// The requests are sent together and the server may run them concurrently,
+    /// but the requests on the same key are always run in order.
+    pub fn pipeline(&mut self) -> Pipeline<'_> {
+        Pipeline {
+            client: self,
+            requests: Vec::new(),
+        }
+    }
+
+    /// Get the value of the given key from the server
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.call(&Request::Get { key })? {
+            Reply::Value(value) => Ok(value),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    /// Set the value of a string key in the server.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        match self.call(&Request::Set { key, value })? {
+            Reply::Done => Ok(()),
+            reply => Err(unexpected_reply(reply)),
         }
     }
 
     /// Remove a string key in the server.
     pub fn rm(&mut self, key: String) -> Result<()> {
-        let request = Request::Remove { key };
-        serde_json::to_writer(&mut self.writer, &request)?;
-        self.writer.flush()?;
+        match self.call(&Request::Remove { key })? {
+            Reply::Done => Ok(()),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    /// Get the values of several keys from the server in one round trip.
+    pub fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        match self.call(&Request::MGet { keys })? {
+            Reply::Values(values) => Ok(values),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    /// Set several key/value pairs in the server in one round trip.
+    pub fn set_many(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        match self.call(&Request::MSet { pairs })? {
+            Reply::Done => Ok(()),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    /// Add `delta` to the integer value of a key in the server, returning the new value.
+    pub fn incr(&mut self, key: String, delta: i64) -> Result<i64> {
+        match self.call(&Request::Incr { key, delta })? {
+            Reply::Integer(value) => Ok(value),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    /// Subtract `delta` from the integer value of a key in the server, returning the new value.
+    pub fn decr(&mut self, key: String, delta: i64) -> Result<i64> {
+        match self.call(&Request::Decr { key, delta })? {
+            Reply::Integer(value) => Ok(value),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    /// Append `suffix` to the value of a key in the server, returning the new length.
+    pub fn append(&mut self, key: String, suffix: String) -> Result<u64> {
+        match self.call(&Request::Append { key, suffix })? {
+            Reply::Length(len) => Ok(len),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    /// Get the statistics of the storage engine in the server.
+    pub fn stats(&mut self) -> Result<EngineStats> {
+        match self.call(&Request::Stats)? {
+            Reply::Stats(stats) => Ok(stats),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    /// List at most `limit` keys starting with `prefix`, in order.
+    ///
+    /// Only the keys after `after` are listed, so the last key of a page starts the next one.
+    pub fn scan(
+        &mut self,
+        prefix: String,
+        after: Option<String>,
+        limit: usize,
+    ) -> Result<Vec<String>> {
+        match self.call(&Request::Scan {
+            prefix,
+            after,
+            limit,
+        })? {
+            Reply::Keys(keys) => Ok(keys),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    /// Authenticate the connection, the following requests being made as its user.
+    ///
+    /// Servers without authentication accept any credentials.
+    pub fn authenticate(&mut self, credentials: Credentials) -> Result<()> {
+        match self.call(&Request::Auth(credentials))? {
+            Reply::Done => Ok(()),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    /// Check that the server is still reachable.
+    pub fn ping(&mut self) -> Result<()> {
+        match self.call(&Request::Ping)? {
+            Reply::Done => Ok(()),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    /// Promote a follower to leader, so it stops following and accepts writes.
+    ///
+    /// A server already leading accepts it as well.
+    pub fn promote(&mut self) -> Result<()> {
+        match self.call(&Request::Promote)? {
+            Reply::Done => Ok(()),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    /// Get the state of the cluster node the server runs.
+    pub fn cluster_status(&mut self) -> Result<ClusterStatus> {
+        match self.call(&Request::ClusterStatus)? {
+            Reply::Cluster(status) => Ok(status),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    /// Add a node to the cluster, the server being its leader.
+    pub fn add_node(&mut self, id: NodeId, addr: Address) -> Result<()> {
+        match self.call(&Request::AddNode { id, addr })? {
+            Reply::Done => Ok(()),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    /// Remove a node from the cluster, the server being its leader.
+    pub fn remove_node(&mut self, id: NodeId) -> Result<()> {
+        match self.call(&Request::RemoveNode { id })? {
+            Reply::Done => Ok(()),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    /// Compact the storage of the server now, returning the statistics of its engine after.
+    pub fn compact(&mut self) -> Result<EngineStats> {
+        match self.call(&Request::Admin(AdminCommand::Compact))? {
+            Reply::Stats(stats) => Ok(stats),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    /// Make the server sync its changes to the disk.
+    pub fn flush(&mut self) -> Result<()> {
+        match self.call(&Request::Admin(AdminCommand::Flush))? {
+            Reply::Done => Ok(()),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    /// Get the statistics of the server, along with the ones of its engine.
+    pub fn server_stats(&mut self) -> Result<ServerStats> {
+        match self.call(&Request::Admin(AdminCommand::Stats))? {
+            Reply::ServerStats(stats) => Ok(stats),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    /// List the connections of the clients of the server, this one included.
+    pub fn clients(&mut self) -> Result<Vec<ClientInfo>> {
+        match self.call(&Request::Admin(AdminCommand::Clients))? {
+            Reply::Clients(clients) => Ok(clients),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    /// Change the level of the messages logged by the server.
+    ///
+    /// The server only logs the messages its logger is set up to, whatever the level.
+    pub fn set_log_level(&mut self, level: LevelFilter) -> Result<()> {
+        let command = AdminCommand::SetLogLevel(level.to_string());
+        match self.call(&Request::Admin(command))? {
+            Reply::Done => Ok(()),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    /// Get the settings the server runs with.
+    pub fn server_config(&mut self) -> Result<ServerConfig> {
+        match self.call(&Request::Admin(AdminCommand::Config))? {
+            Reply::Config(config) => Ok(config),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    /// Hand a message to the cluster node the server runs, as another node
+    pub(crate) fn raft(&mut self, message: Message) -> Result<()> {
+        match self.call(&Request::Raft(message))? {
+            Reply::Done => Ok(()),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    /// Turn the connection into the replication stream of the server, as a follower
+    pub(crate) fn replicate(mut self) -> Result<ReplicationStream> {
+        match self.call(&Request::Replicate)? {
+            Reply::Done => Ok(ReplicationStream {
+                reader: self.reader,
+            }),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    /// Subscribe to the changes of all keys starting with `prefix`.
+    ///
+    /// The connection is turned into a stream of changes pushed by the server,
+    /// so the client is consumed.
+    pub fn watch(mut self, prefix: String) -> Result<WatchStream> {
+        match self.call(&Request::Watch { prefix })? {
+            Reply::Done => Ok(WatchStream {
+                reader: self.reader,
+            }),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    /// Publish `message` on `channel`, returning the number of subscribers it reaches.
+    pub fn publish(&mut self, channel: String, message: String) -> Result<u64> {
+        match self.call(&Request::Publish { channel, message })? {
+            Reply::Receivers(count) => Ok(count),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    /// Subscribe to `channels` and to the channels matching `patterns`.
+    ///
+    /// The patterns are globs, as in `SCAN`: `*` matches any sequence of bytes,
+    /// `?` any single byte, `[...]` a set of bytes, and `\` escapes. The
+    /// connection is turned into a stream of the messages published from now
+    /// on, so the client is consumed.
+    pub fn subscribe(
+        mut self,
+        channels: Vec<String>,
+        patterns: Vec<String>,
+    ) -> Result<Subscription> {
+        match self.call(&Request::Subscribe { channels, patterns })? {
+            Reply::Done => Ok(Subscription {
+                reader: self.reader,
+            }),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+}
+
+/// A batch of requests sent to the server in one go
+///
+/// Created by [`KvsClient::pipeline`].
+pub struct Pipeline<'a> {
+    client: &'a mut KvsClient,
+    requests: Vec<Request>,
+}
+
+impl Pipeline<'_> {
+    /// Queue a `get`
+    pub fn get(mut self, key: String) -> Self {
+        self.requests.push(Request::Get { key });
+        self
+    }
+
+    /// Queue a `set`
+    pub fn set(mut self, key: String, value: String) -> Self {
+        self.requests.push(Request::Set { key, value });
+        self
+    }
+
+    /// Queue an `rm`
+    pub fn rm(mut self, key: String) -> Self {
+        self.requests.push(Request::Remove { key });
+        self
+    }
+
+    /// Queue a `get_many`
+    pub fn get_many(mut self, keys: Vec<String>) -> Self {
+        self.requests.push(Request::MGet { keys });
+        self
+    }
+
+    /// Queue a `set_many`
+    pub fn set_many(mut self, pairs: Vec<(String, String)>) -> Self {
+        self.requests.push(Request::MSet { pairs });
+        self
+    }
+
+    /// Queue an `incr`
+    pub fn incr(mut self, key: String, delta: i64) -> Self {
+        self.requests.push(Request::Incr { key, delta });
+        self
+    }
 
-        let resp = RmResponse::deserialize(&mut self.reader)?;
-        match resp {
-            RmResponse::Ok(_) => Ok(()),
-            RmResponse::Err(e) => Err(KvsError::StringError(e)),
+    /// Queue a `decr`
+    pub fn decr(mut self, key: String, delta: i64) -> Self {
+        self.requests.push(Request::Decr { key, delta });
+        self
+    }
+
+    /// Queue an `append`
+    pub fn append(mut self, key: String, suffix: String) -> Self {
+        self.requests.push(Request::Append { key, suffix });
+        self
+    }
+
+    /// Send the queued requests and wait for all the responses
+    ///
+    /// The results are in the order the requests are queued. The outer error is
+    /// returned if the connection fails.
+    pub fn execute(self) -> Result<Vec<Result<Reply>>> {
+        let client = self.client;
+        let first_id = client.next_id.wrapping_add(1);
+        let mut replies: Vec<Option<Result<Reply>>> = self.requests.iter().map(|_| None).collect();
+        let mut received = 0;
+        for (sent, request) in self.requests.iter().enumerate() {
+            if sent - received == PIPELINE_WINDOW {
+                client.writer.flush()?;
+                receive(&mut client.reader, first_id, &mut replies)?;
+                received += 1;
+            }
+            client.next_id = client.next_id.wrapping_add(1);
+            client
+                .request_frame(client.next_id, request)?
+                .write_to(&mut client.writer)?;
+        }
+        client.writer.flush()?;
+
+        for _ in received..self.requests.len() {
+            receive(&mut client.reader, first_id, &mut replies)?;
         }
+        Ok(replies.into_iter().map(Option::unwrap).collect())
+    }
+}
+
+/// Reads the response to one of the requests of a pipeline into its slot
+fn receive<R: Read>(
+    reader: &mut R,
+    first_id: u32,
+    replies: &mut [Option<Result<Reply>>],
+) -> Result<()> {
+    let frame = read_frame(reader)?;
+    // responses may come back in any order
+    let index = frame.request_id.wrapping_sub(first_id) as usize;
+    let slot = replies
+        .get_mut(index)
+        .filter(|slot| slot.is_none())
+        .ok_or_else(|| {
+            KvsError::Protocol(format!("unexpected response to {}", frame.request_id))
+        })?;
+    *slot = Some(decode(&frame));
+    Ok(())
+}
+
+/// Decodes a response frame into the reply or the error it carries
+pub fn decode(frame: &Frame) -> Result<Reply> {
+    match frame.opcode {
+        OP_RESPONSE | OP_ERROR => frame.parse::<Response>()?.into_result(),
+        opcode => Err(unexpected_opcode(opcode)),
     }
 }
+
+/// A blocking iterator over the changes pushed by the server
+///
+/// It ends when the server closes the connection.
+pub struct WatchStream {
+    reader: BufReader<Stream>,
+}
+
+impl Iterator for WatchStream {
+    type Item = Result<Event>;
+
+    fn next(&mut self) -> Option<Result<Event>> {
+        let frame = match Frame::read_from(&mut self.reader) {
+            Ok(frame) => frame?,
+            Err(e) => return Some(Err(e)),
+        };
+        Some(match frame.opcode {
+            OP_EVENT => frame.parse(),
+            OP_ERROR => decode(&frame).and_then(|reply| Err(unexpected_reply(reply))),
+            opcode => Err(unexpected_opcode(opcode)),
+        })
+    }
+}
+
+/// A blocking iterator over the messages published on the channels subscribed to
+///
+/// It ends when the server closes the connection, which it does once the
+/// subscriber lags too far behind.
+pub struct Subscription {
+    reader: BufReader<Stream>,
+}
+
+impl Iterator for Subscription {
+    type Item = Result<ChannelMessage>;
+
+    fn next(&mut self) -> Option<Result<ChannelMessage>> {
+        let frame = match Frame::read_from(&mut self.reader) {
+            Ok(frame) => frame?,
+            Err(e) => return Some(Err(e)),
+        };
+        Some(match frame.opcode {
+            OP_MESSAGE => frame.parse(),
+            OP_ERROR => decode(&frame).and_then(|reply| Err(unexpected_reply(reply))),
+            opcode => Err(unexpected_opcode(opcode)),
+        })
+    }
+}
+
+/// A blocking iterator over the `Replication`s pushed by a leader
+///
+/// It ends when the leader closes the connection.
+pub(crate) struct ReplicationStream {
+    reader: BufReader<Stream>,
+}
+
+impl ReplicationStream {
+    /// The connection to the leader, to close it from another thread
+    pub(crate) fn connection(&self) -> io::Result<Stream> {
+        self.reader.get_ref().try_clone()
+    }
+}
+
+impl Iterator for ReplicationStream {
+    type Item = Result<Replication>;
+
+    fn next(&mut self) -> Option<Result<Replication>> {
+        let frame = match Frame::read_from(&mut self.reader) {
+            Ok(frame) => frame?,
+            Err(e) => return Some(Err(e)),
+        };
+        Some(match frame.opcode {
+            OP_REPLICATE => frame.parse(),
+            OP_ERROR => decode(&frame).and_then(|reply| Err(unexpected_reply(reply))),
+            opcode => Err(unexpected_opcode(opcode)),
+        })
+    }
+}
+
+/// Reads a frame, treating the end of the stream as an error
+fn read_frame<R: Read>(reader: &mut R) -> Result<Frame> {
+    Frame::read_from(reader)?.ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof).into())
+}
+
+pub fn unexpected_opcode(opcode: u8) -> KvsError {
+    KvsError::Protocol(format!("unexpected opcode {}", opcode))
+}
+
+pub fn unexpected_reply(reply: Reply) -> KvsError {
+    KvsError::Protocol(format!("unexpected reply {:?}", reply))
+}
@@ -0,0 +1,296 @@
+use crate::{
+    Address, Credentials, EngineStats, KvsClient, KvsError, Result, TlsClientConfig, WatchStream,
+};
+use log::debug;
+use std::cmp;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const DEFAULT_MAX_IDLE: usize = 8;
+const DEFAULT_HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How a `KvsClientPool` retries the operations failing on a broken connection
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// The number of retries after the first attempt
+    pub max_retries: u32,
+    /// The wait before the first retry, doubled after each one
+    pub initial_backoff: Duration,
+    /// The longest wait between two attempts
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+/// A thread-safe pool of connections to one or more servers
+///
+/// Connections are opened to the servers in turn and kept for reuse. A
+/// connection left idle for long is checked with a ping before it is reused,
+/// and a broken one is replaced by a new connection.
+///
+/// Reads such as `get` and `scan` are retried following the `RetryPolicy`
+/// when the connection fails. Writes are only retried if no connection could
+/// be opened: once a write is sent, its failure is returned, since the server
+/// may have applied it.
+///
+/// Clones of a pool share its connections.
+#[derive(Clone)]
+pub struct KvsClientPool {
+    addrs: Arc<Vec<Address>>,
+    next_addr: Arc<AtomicUsize>,
+    idle: Arc<Mutex<Vec<Idle>>>,
+    max_idle: usize,
+    health_check_interval: Duration,
+    retry: RetryPolicy,
+    tls: Option<TlsClientConfig>,
+    credentials: Option<Credentials>,
+}
+
+struct Idle {
+    client: KvsClient,
+    since: Instant,
+}
+
+impl KvsClientPool {
+    /// Creates a pool of connections to `addrs`, opening them when needed
+    ///
+    /// # Panics
+    /// It panics if `addrs` is empty.
+    pub fn new(addrs: Vec<Address>) -> Self {
+        assert!(!addrs.is_empty(), "a pool needs a server address");
+        KvsClientPool {
+            addrs: Arc::new(addrs),
+            next_addr: Arc::new(AtomicUsize::new(0)),
+            idle: Arc::new(Mutex::new(Vec::new())),
+            max_idle: DEFAULT_MAX_IDLE,
+            health_check_interval: DEFAULT_HEALTH_CHECK_INTERVAL,
+            retry: RetryPolicy::default(),
+            tls: None,
+            credentials: None,
+        }
+    }
+
+    /// Sets how many idle connections are kept
+    pub fn with_max_idle(mut self, max_idle: usize) -> Self {
+        self.max_idle = max_idle;
+        self
+    }
+
+    /// Sets how long a connection may stay idle before it is checked on reuse
+    pub fn with_health_check_interval(mut self, interval: Duration) -> Self {
+        self.health_check_interval = interval;
+        self
+    }
+
+    /// Sets how the failed operations are retried
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Connects to the servers over TLS
+    pub fn with_tls(mut self, tls: TlsClientConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Authenticates the connections with `credentials` once opened
+    pub fn with_credentials(mut self, credentials: Credentials) -> Self {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    /// Opens connections until `count` are idle in the pool
+    pub fn warm_up(&self, count: usize) -> Result<()> {
+        let count = cmp::min(count, self.max_idle);
+        while self.idle.lock().unwrap().len() < count {
+            let client = self.connect()?;
+            self.checkin(client);
+        }
+        Ok(())
+    }
+
+    /// Get the value of the given key, retrying on a broken connection
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        self.with_client(true, |client| client.get(key.clone()))
+    }
+
+    /// Get the values of several keys, retrying on a broken connection
+    pub fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        self.with_client(true, |client| client.get_many(keys.clone()))
+    }
+
+    /// List the keys starting with `prefix`, retrying on a broken connection
+    ///
+    /// See [`KvsClient::scan`].
+    pub fn scan(&self, prefix: String, after: Option<String>, limit: usize) -> Result<Vec<String>> {
+        self.with_client(true, |client| {
+            client.scan(prefix.clone(), after.clone(), limit)
+        })
+    }
+
+    /// Set the value of a string key
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.with_client(false, |client| client.set(key.clone(), value.clone()))
+    }
+
+    /// Remove a string key
+    pub fn rm(&self, key: String) -> Result<()> {
+        self.with_client(false, |client| client.rm(key.clone()))
+    }
+
+    /// Set several key/value pairs at once
+    pub fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        self.with_client(false, |client| client.set_many(pairs.clone()))
+    }
+
+    /// Add `delta` to the integer value of a key, returning the new value
+    pub fn incr(&self, key: String, delta: i64) -> Result<i64> {
+        self.with_client(false, |client| client.incr(key.clone(), delta))
+    }
+
+    /// Subtract `delta` from the integer value of a key, returning the new value
+    pub fn decr(&self, key: String, delta: i64) -> Result<i64> {
+        self.with_client(false, |client| client.decr(key.clone(), delta))
+    }
+
+    /// Append `suffix` to the value of a key, returning the new length
+    pub fn append(&self, key: String, suffix: String) -> Result<u64> {
+        self.with_client(false, |client| client.append(key.clone(), suffix.clone()))
+    }
+
+    /// Get the statistics of the storage engine, retrying on a broken connection
+    pub fn stats(&self) -> Result<EngineStats> {
+        self.with_client(true, |client| client.stats())
+    }
+
+    /// Subscribe to the changes of the keys starting with `prefix`
+    ///
+    /// The stream takes a connection out of the pool for good.
+    pub fn watch(&self, prefix: String) -> Result<WatchStream> {
+        self.checkout()?.watch(prefix)
+    }
+
+    /// Runs `op` on a pooled connection, retrying as the policy allows
+    ///
+    /// Failing to get a connection is always retried, while a failure of `op`
+    /// is only retried if it is `idempotent`.
+    fn with_client<T, F>(&self, idempotent: bool, mut op: F) -> Result<T>
+    where
+        F: FnMut(&mut KvsClient) -> Result<T>,
+    {
+        let retry = &self.retry;
+        let mut backoff = retry.initial_backoff;
+        let mut attempt = 0;
+        loop {
+            let result = self
+                .checkout()
+                .map_err(Failure::Connect)
+                .and_then(|mut client| match op(&mut client) {
+                    Err(e) if is_broken(&e) => Err(Failure::Request(e)),
+                    result => {
+                        self.checkin(client);
+                        result.map_err(Failure::Fatal)
+                    }
+                });
+            let err = match result {
+                Ok(value) => return Ok(value),
+                Err(Failure::Connect(e)) => e,
+                Err(Failure::Request(e)) if idempotent => e,
+                Err(Failure::Request(e)) | Err(Failure::Fatal(e)) => return Err(e),
+            };
+            if attempt == retry.max_retries {
+                return Err(err);
+            }
+            attempt += 1;
+            debug!(
+                "Retrying after {:?} (attempt {}): {}",
+                backoff, attempt, err
+            );
+            thread::sleep(backoff);
+            backoff = cmp::min(backoff * 2, retry.max_backoff);
+        }
+    }
+
+    /// Takes a healthy idle connection, or opens a new one
+    fn checkout(&self) -> Result<KvsClient> {
+        loop {
+            let idle = self.idle.lock().unwrap().pop();
+            let mut idle = match idle {
+                Some(idle) => idle,
+                None => return self.connect(),
+            };
+            if idle.since.elapsed() < self.health_check_interval {
+                return Ok(idle.client);
+            }
+            match idle.client.ping() {
+                Ok(()) => return Ok(idle.client),
+                Err(e) => debug!("Dropping a broken connection: {}", e),
+            }
+        }
+    }
+
+    /// Returns a connection to the pool, unless it is full
+    fn checkin(&self, client: KvsClient) {
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < self.max_idle {
+            idle.push(Idle {
+                client,
+                since: Instant::now(),
+            });
+        }
+    }
+
+    /// Connects to the next server that accepts the connection
+    fn connect(&self) -> Result<KvsClient> {
+        let addrs = &*self.addrs;
+        let first = self.next_addr.fetch_add(1, Ordering::Relaxed);
+        let mut last_err = None;
+        for i in 0..addrs.len() {
+            let addr = &addrs[(first + i) % addrs.len()];
+            let client = match &self.tls {
+                Some(tls) => KvsClient::connect_tls(addr, tls),
+                None => KvsClient::connect(addr),
+            };
+            let client = client.and_then(|mut client| {
+                if let Some(credentials) = &self.credentials {
+                    client.authenticate(credentials.clone())?;
+                }
+                Ok(client)
+            });
+            match client {
+                Ok(client) => return Ok(client),
+                Err(e) => {
+                    debug!("Cannot connect to {}: {}", addr, e);
+                    last_err = Some(e);
+                }
+            }
+        }
+        Err(last_err.unwrap())
+    }
+}
+
+/// Why an attempt fails
+enum Failure {
+    /// No connection could be opened, so nothing is sent
+    Connect(KvsError),
+    /// The connection breaks after the request is sent
+    Request(KvsError),
+    /// The server answers with an error
+    Fatal(KvsError),
+}
+
+/// Whether an error leaves the connection unusable
+pub(crate) fn is_broken(err: &KvsError) -> bool {
+    matches!(err, KvsError::Io(_) | KvsError::Protocol(_))
+}
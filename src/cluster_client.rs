@@ -0,0 +1,179 @@
+use crate::client_pool::is_broken;
+use crate::{Address, ClusterStatus, KvsClient, KvsError, NodeId, Result, RetryPolicy};
+use log::debug;
+use std::cmp;
+use std::thread;
+
+/// A client of a cluster, sending its requests to the leader
+///
+/// The client connects to the servers of `addrs` in turn until one answers.
+/// A server that is not the leader answers with the address of the leader,
+/// which the client connects to at once. While no leader is known, as during
+/// an election, the client waits following its `RetryPolicy` before trying
+/// the next server.
+///
+/// Like with `KvsClientPool`, the reads are retried on a broken connection
+/// but the writes are not, since the cluster may have applied them.
+pub struct ClusterClient {
+    addrs: Vec<Address>,
+    next_addr: usize,
+    client: Option<KvsClient>,
+    // the leader named by the last server, tried next
+    redirect: Option<Address>,
+    retry: RetryPolicy,
+}
+
+impl ClusterClient {
+    /// Creates a client of the cluster served on `addrs`, connecting when needed
+    ///
+    /// # Panics
+    /// It panics if `addrs` is empty.
+    pub fn new(addrs: Vec<Address>) -> Self {
+        assert!(!addrs.is_empty(), "a cluster needs a server address");
+        ClusterClient {
+            addrs,
+            next_addr: 0,
+            client: None,
+            redirect: None,
+            retry: RetryPolicy {
+                max_retries: 10,
+                ..RetryPolicy::default()
+            },
+        }
+    }
+
+    /// Sets how the requests are retried while no leader is known
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Get the value of the given key
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.call(true, |client| client.get(key.clone()))
+    }
+
+    /// Get the values of several keys
+    pub fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        self.call(true, |client| client.get_many(keys.clone()))
+    }
+
+    /// List the keys starting with `prefix`
+    ///
+    /// See [`KvsClient::scan`].
+    pub fn scan(
+        &mut self,
+        prefix: String,
+        after: Option<String>,
+        limit: usize,
+    ) -> Result<Vec<String>> {
+        self.call(true, |client| {
+            client.scan(prefix.clone(), after.clone(), limit)
+        })
+    }
+
+    /// Set the value of a string key
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.call(false, |client| client.set(key.clone(), value.clone()))
+    }
+
+    /// Set several key/value pairs at once
+    pub fn set_many(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        self.call(false, |client| client.set_many(pairs.clone()))
+    }
+
+    /// Remove a string key
+    pub fn rm(&mut self, key: String) -> Result<()> {
+        self.call(false, |client| client.rm(key.clone()))
+    }
+
+    /// Add `delta` to the integer value of a key, returning the new value
+    pub fn incr(&mut self, key: String, delta: i64) -> Result<i64> {
+        self.call(false, |client| client.incr(key.clone(), delta))
+    }
+
+    /// Subtract `delta` from the integer value of a key, returning the new value
+    pub fn decr(&mut self, key: String, delta: i64) -> Result<i64> {
+        self.call(false, |client| client.decr(key.clone(), delta))
+    }
+
+    /// Append `suffix` to the value of a key, returning the new length
+    pub fn append(&mut self, key: String, suffix: String) -> Result<u64> {
+        self.call(false, |client| client.append(key.clone(), suffix.clone()))
+    }
+
+    /// Add node `id`, served on `addr`, to the cluster
+    pub fn add_node(&mut self, id: NodeId, addr: Address) -> Result<()> {
+        self.call(false, |client| client.add_node(id, addr.clone()))
+    }
+
+    /// Remove node `id` from the cluster
+    pub fn remove_node(&mut self, id: NodeId) -> Result<()> {
+        self.call(false, |client| client.remove_node(id))
+    }
+
+    /// The state of the node the client is connected to, the leader if known
+    pub fn status(&mut self) -> Result<ClusterStatus> {
+        self.call(true, |client| client.cluster_status())
+    }
+
+    /// Runs `op` on the leader, following the redirects and retrying as the policy allows
+    ///
+    /// A failure of `op` on a broken connection is only retried if it is `idempotent`.
+    fn call<T, F>(&mut self, idempotent: bool, mut op: F) -> Result<T>
+    where
+        F: FnMut(&mut KvsClient) -> Result<T>,
+    {
+        let retry = self.retry.clone();
+        let mut backoff = retry.initial_backoff;
+        let mut attempt = 0;
+        loop {
+            let result = self.connect().and_then(&mut op);
+            let err = match result {
+                Ok(value) => return Ok(value),
+                Err(KvsError::NotLeader(leader)) => {
+                    self.client = None;
+                    self.redirect = leader.parse().ok();
+                    KvsError::NotLeader(leader)
+                }
+                Err(e) if is_broken(&e) => {
+                    let sent = self.client.take().is_some();
+                    if sent && !idempotent {
+                        return Err(e);
+                    }
+                    e
+                }
+                Err(e) => return Err(e),
+            };
+            if attempt == retry.max_retries {
+                return Err(err);
+            }
+            attempt += 1;
+            // a known leader is tried at once
+            if self.redirect.is_none() {
+                debug!(
+                    "Retrying after {:?} (attempt {}): {}",
+                    backoff, attempt, err
+                );
+                thread::sleep(backoff);
+                backoff = cmp::min(backoff * 2, retry.max_backoff);
+            }
+        }
+    }
+
+    /// The connection to the leader, or to the next server
+    fn connect(&mut self) -> Result<&mut KvsClient> {
+        if self.client.is_none() {
+            let addr = match self.redirect.take() {
+                Some(addr) => addr,
+                None => {
+                    let addr = self.addrs[self.next_addr].clone();
+                    self.next_addr = (self.next_addr + 1) % self.addrs.len();
+                    addr
+                }
+            };
+            self.client = Some(KvsClient::connect(&addr)?);
+        }
+        Ok(self.client.as_mut().unwrap())
+    }
+}
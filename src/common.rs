@@ -1,26 +1,240 @@
+use crate::admin::{AdminCommand, ClientInfo, ServerConfig, ServerStats};
+use crate::auth::Credentials;
+use crate::raft::{ClusterStatus, Message, NodeId};
+use crate::transport::Address;
+use crate::{EngineStats, ErrorCode, Event, KvsError, Result};
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Request {
     Set { key: String, value: String },
     Get { key: String },
     Remove { key: String },
+    MGet { keys: Vec<String> },
+    MSet { pairs: Vec<(String, String)> },
+    Incr { key: String, delta: i64 },
+    Decr { key: String, delta: i64 },
+    Append { key: String, suffix: String },
+    Stats,
+    Watch { prefix: String },
+    Scan {
+        prefix: String,
+        after: Option<String>,
+        limit: usize,
+    },
+    Ping,
+    Auth(Credentials),
+    Replicate,
+    Promote,
+    Raft(Message),
+    AddNode { id: NodeId, addr: Address },
+    RemoveNode { id: NodeId },
+    ClusterStatus,
+    Publish { channel: String, message: String },
+    Subscribe {
+        channels: Vec<String>,
+        patterns: Vec<String>,
+    },
+    Admin(AdminCommand),
 }
 
+/// A request the server gives up on if it cannot start it within `timeout_ms`
+///
+/// The timeout is relative to the moment the server reads the request, so the
+/// clocks of the client and the server need not agree.
 #[derive(Debug, Serialize, Deserialize)]
-pub enum GetResponse {
-    Ok(Option<String>),
-    Err(String),
+pub struct DeadlineRequest<R> {
+    pub timeout_ms: u64,
+    pub request: R,
+}
+
+impl Request {
+    /// The key of a request on a single key
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            Request::Set { key, .. }
+            | Request::Get { key }
+            | Request::Remove { key }
+            | Request::Incr { key, .. }
+            | Request::Decr { key, .. }
+            | Request::Append { key, .. } => Some(key),
+            Request::MGet { .. }
+            | Request::MSet { .. }
+            | Request::Stats
+            | Request::Watch { .. }
+            | Request::Scan { .. }
+            | Request::Ping
+            | Request::Auth(_)
+            | Request::Replicate
+            | Request::Promote
+            | Request::Raft(_)
+            | Request::AddNode { .. }
+            | Request::RemoveNode { .. }
+            | Request::ClusterStatus
+            | Request::Publish { .. }
+            | Request::Subscribe { .. }
+            | Request::Admin(_) => None,
+        }
+    }
+
+    /// Whether the request changes the keys
+    pub fn is_write(&self) -> bool {
+        match self {
+            Request::Set { .. }
+            | Request::Remove { .. }
+            | Request::MSet { .. }
+            | Request::Incr { .. }
+            | Request::Decr { .. }
+            | Request::Append { .. } => true,
+            Request::Get { .. }
+            | Request::MGet { .. }
+            | Request::Stats
+            | Request::Watch { .. }
+            | Request::Scan { .. }
+            | Request::Ping
+            | Request::Auth(_)
+            | Request::Replicate
+            | Request::Promote
+            | Request::Raft(_)
+            | Request::AddNode { .. }
+            | Request::RemoveNode { .. }
+            | Request::ClusterStatus
+            | Request::Publish { .. }
+            | Request::Subscribe { .. }
+            | Request::Admin(_) => false,
+        }
+    }
+
+    /// The name of the request, for the messages
+    pub fn name(&self) -> &'static str {
+        match self {
+            Request::Set { .. } => "set",
+            Request::Get { .. } => "get",
+            Request::Remove { .. } => "rm",
+            Request::MGet { .. } => "mget",
+            Request::MSet { .. } => "mset",
+            Request::Incr { .. } => "incr",
+            Request::Decr { .. } => "decr",
+            Request::Append { .. } => "append",
+            Request::Stats => "stats",
+            Request::Watch { .. } => "watch",
+            Request::Scan { .. } => "scan",
+            Request::Ping => "ping",
+            Request::Auth(_) => "auth",
+            Request::Replicate => "replicate",
+            Request::Promote => "promote",
+            Request::Raft(_) => "raft",
+            Request::AddNode { .. } => "add-node",
+            Request::RemoveNode { .. } => "remove-node",
+            Request::ClusterStatus => "cluster-status",
+            Request::Publish { .. } => "publish",
+            Request::Subscribe { .. } => "subscribe",
+            Request::Admin(_) => "admin",
+        }
+    }
+}
+
+/// The result of a successful request
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Reply {
+    /// The request succeeds without returning anything, e.g. `set`
+    Done,
+    /// The value returned by `get`
+    Value(Option<String>),
+    /// The values returned by `get_many`
+    Values(Vec<Option<String>>),
+    /// The new value returned by `incr` or `decr`
+    Integer(i64),
+    /// The new length returned by `append`
+    Length(u64),
+    /// The statistics returned by `stats`
+    Stats(EngineStats),
+    /// The keys returned by `scan`
+    Keys(Vec<String>),
+    /// The state of a cluster node returned by `cluster_status`
+    Cluster(ClusterStatus),
+    /// The number of subscribers reached by `publish`
+    Receivers(u64),
+    /// The statistics of the server returned by `server_stats`
+    ServerStats(ServerStats),
+    /// The connections returned by `clients`
+    Clients(Vec<ClientInfo>),
+    /// The settings returned by `server_config`
+    Config(ServerConfig),
 }
 
+/// A message pushed by a leader to a follower after answering its `Replicate`
+///
+/// The leader sends its data in `Snapshot` pages up to a `SnapshotEnd`, then
+/// every change made to it since the follower connected.
 #[derive(Debug, Serialize, Deserialize)]
-pub enum SetResponse {
-    Ok(()),
-    Err(String),
+pub enum Replication {
+    /// Some of the keys and their values
+    Snapshot(Vec<(String, String)>),
+    /// The snapshot is complete: the keys it does not list are gone
+    SnapshotEnd,
+    /// A change made on the leader, in the order of their sequence numbers
+    Change(Event),
 }
 
+/// The answer to every request
+///
+/// A `Watch` answered with `Ok` is followed by a stream of `Event`s, a
+/// `Replicate` by a stream of `Replication`s, and a `Subscribe` by a stream of
+/// `ChannelMessage`s.
 #[derive(Debug, Serialize, Deserialize)]
-pub enum RmResponse {
-    Ok(()),
-    Err(String),
+pub enum Response {
+    Ok(Reply),
+    Err { code: ErrorCode, message: String },
+}
+
+impl From<Result<Reply>> for Response {
+    fn from(result: Result<Reply>) -> Self {
+        match result {
+            Ok(reply) => Response::Ok(reply),
+            // the client follows the leader to its address
+            Err(KvsError::NotLeader(leader)) => Response::Err {
+                code: ErrorCode::NotLeader,
+                message: leader,
+            },
+            Err(err) => Response::Err {
+                code: err.code(),
+                message: format!("{}", err),
+            },
+        }
+    }
+}
+
+impl Response {
+    pub fn into_result(self) -> Result<Reply> {
+        match self {
+            Response::Ok(reply) => Ok(reply),
+            Response::Err { code, message } => Err(KvsError::from_code(code, message)),
+        }
+    }
+
+    /// The response in the shape of the legacy protocol: `{"Ok": value}` or `{"Err": message}`
+    pub fn to_legacy(&self) -> serde_json::Value {
+        match self {
+            Response::Ok(reply) => {
+                let value = match reply {
+                    Reply::Done => Value::Null,
+                    Reply::Value(value) => json!(value),
+                    Reply::Values(values) => json!(values),
+                    Reply::Integer(value) => json!(value),
+                    Reply::Length(len) => json!(len),
+                    Reply::Stats(stats) => json!(stats),
+                    Reply::Keys(keys) => json!(keys),
+                    Reply::Cluster(status) => json!(status),
+                    Reply::Receivers(count) => json!(count),
+                    Reply::ServerStats(stats) => json!(stats),
+                    Reply::Clients(clients) => json!(clients),
+                    Reply::Config(config) => json!(config),
+                };
+                json!({ "Ok": value })
+            }
+            Response::Err { message, .. } => json!({ "Err": message }),
+        }
+    }
 }
@@ -0,0 +1,112 @@
+//! Concurrent execution of the requests pipelined on one connection
+use crate::thread_pool::ThreadPool;
+use log::error;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::hash::{Hash, Hasher};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+
+const LANES: usize = 16;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Runs jobs on a thread pool, keeping the jobs on the same key in order
+///
+/// Jobs are queued in lanes chosen by the hash of their keys. A lane is run by
+/// at most one thread at a time, so the jobs of a lane never overlap.
+///
+/// The connection thread may occupy a pool thread itself, so it must call `wait`
+/// before blocking. `wait` runs the jobs no pool thread has picked up yet on the
+/// calling thread, so a connection always makes progress even if the pool is full.
+pub struct Dispatcher<P: ThreadPool> {
+    pool: Arc<P>,
+    lanes: Arc<Vec<Lane>>,
+}
+
+impl<P: ThreadPool> Clone for Dispatcher<P> {
+    fn clone(&self) -> Self {
+        Dispatcher {
+            pool: Arc::clone(&self.pool),
+            lanes: Arc::clone(&self.lanes),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Lane {
+    state: Mutex<LaneState>,
+    idle: Condvar,
+}
+
+#[derive(Default)]
+struct LaneState {
+    jobs: VecDeque<Job>,
+    busy: bool,
+}
+
+impl<P: ThreadPool + Send + Sync + 'static> Dispatcher<P> {
+    pub fn new(pool: Arc<P>) -> Self {
+        Dispatcher {
+            pool,
+            lanes: Arc::new((0..LANES).map(|_| Lane::default()).collect()),
+        }
+    }
+
+    /// Queues a job on the lane of `key`
+    pub fn dispatch<F>(&self, key: &str, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let lane = hasher.finish() as usize % LANES;
+
+        let mut state = self.lanes[lane].state.lock().unwrap();
+        state.jobs.push_back(Box::new(job));
+        if !state.busy && state.jobs.len() == 1 {
+            let lanes = Arc::clone(&self.lanes);
+            self.pool.spawn(move || lanes[lane].run());
+        }
+    }
+
+    /// Runs or waits for all the queued jobs
+    pub fn wait(&self) {
+        for lane in self.lanes.iter() {
+            lane.run();
+            let mut state = lane.state.lock().unwrap();
+            while state.busy {
+                state = lane.idle.wait(state).unwrap();
+            }
+        }
+    }
+}
+
+impl Lane {
+    /// Runs the jobs of the lane until it is empty, unless another thread is already running it
+    fn run(&self) {
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.busy {
+                return;
+            }
+            state.busy = true;
+        }
+        loop {
+            let job = {
+                let mut state = self.state.lock().unwrap();
+                match state.jobs.pop_front() {
+                    Some(job) => job,
+                    None => {
+                        state.busy = false;
+                        self.idle.notify_all();
+                        return;
+                    }
+                }
+            };
+            if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                error!("A request panicked");
+            }
+        }
+    }
+}
@@ -1,21 +1,26 @@
 use crate::{KvsError, Result};
-use crossbeam_skiplist::SkipMap;
+use crossbeam::channel::{self, Sender};
+use crossbeam_skiplist::{SkipMap, SkipSet};
 use serde::{Deserialize, Serialize};
 use serde_json::{self, Deserializer};
 use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::btree_map::Entry;
 use std::ffi::OsStr;
 use std::fs::{self, File, OpenOptions};
 use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Bound;
 use std::path::Path;
-use std::sync::atomic::AtomicU64;
-use std::sync::atomic::Ordering;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use std::{collections::BTreeMap, path::PathBuf};
 use log::error;
 
-use super::KvsEngine;
+use super::{EngineStats, Event, KvsEngine, Op, SetCondition, Watcher, WATCH_BACKLOG};
 
 const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+// the active log file is sealed once it grows beyond this size
+const DEFAULT_MAX_FILE_SIZE: u64 = 4 * 1024 * 1024;
 
 /// The `KvStore` store string key/value pairs
 ///
@@ -43,17 +48,44 @@ pub struct KvStore {
 
 struct KvStoreReader {
     path: Arc<PathBuf>,
-    safe_point: Arc<AtomicU64>,
+    // generations whose log files still exist on disk
+    live_gens: Arc<SkipSet<u64>>,
     readers: RefCell<BTreeMap<u64, BufReaderWithPos<File>>>,
 }
 
 struct KvStoreWriter {
     writer: BufWriterWithPos<File>,
     index: Arc<SkipMap<String, CommandPos>>,
-    uncompacted: u64,
+    gens: BTreeMap<u64, GenStats>,
     cur_gen: u64,
+    max_file_size: u64,
     path: Arc<PathBuf>,
     reader: KvStoreReader,
+    compactions: u64,
+    compaction_time: Duration,
+    last_compaction: Option<Duration>,
+    // sequence number of the latest change
+    seq: u64,
+    watchers: Vec<(String, Sender<Event>)>,
+}
+
+/// Bytes of live and stale commands in a generation
+///
+/// A tombstone is kept apart: it counts as live data, being needed as long as
+/// an older generation may hold a value of its key, which only compaction
+/// finds out.
+#[derive(Default, Clone, Copy)]
+struct GenStats {
+    live: u64,
+    dead: u64,
+    tombstones: u64,
+}
+
+/// Moves the command at `cmd_pos` from the live bytes to the dead bytes of its generation
+fn mark_stale(gens: &mut BTreeMap<u64, GenStats>, cmd_pos: &CommandPos) {
+    let stats = gens.entry(cmd_pos.gen).or_default();
+    stats.live -= cmd_pos.len;
+    stats.dead += cmd_pos.len;
 }
 
 struct BufReaderWithPos<R: Read + Seek> {
@@ -142,34 +174,53 @@ impl Command {
 impl KvStore {
     /// Open a KvStore with given path
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        Self::open_with_max_file_size(path, DEFAULT_MAX_FILE_SIZE)
+    }
+
+    /// Open a KvStore with given path, rolling over to a new log file
+    /// whenever the active one exceeds `max_file_size` bytes
+    pub fn open_with_max_file_size(
+        path: impl Into<PathBuf>,
+        max_file_size: u64,
+    ) -> Result<KvStore> {
         let path = Arc::new(path.into());
         fs::create_dir_all(&*path)?;
 
         let readers = BTreeMap::new();
         let index = Arc::new(SkipMap::new());
-        let mut uncompacted = 0u64;
+        let mut gen_stats = BTreeMap::new();
         let gens = sorted_gen_list(&path)?;
+        let live_gens = Arc::new(SkipSet::new());
 
         for &gen in &gens {
             let mut reader = BufReaderWithPos::new(File::open(join_log(&path, gen))?);
-            uncompacted += load(gen, &mut reader, &*index)?;
+            load(gen, &mut reader, &*index, &mut gen_stats)?;
+            live_gens.insert(gen);
         }
 
         let cur_gen = gens.last().unwrap_or(&0) + 1;
         let writer = new_log_file(&path, cur_gen)?;
+        gen_stats.insert(cur_gen, GenStats::default());
+        live_gens.insert(cur_gen);
 
         let reader = KvStoreReader {
             path: Arc::clone(&path),
-            safe_point: Arc::new(AtomicU64::new(0)),
+            live_gens,
             readers: RefCell::new(readers),
         };
         let writer = KvStoreWriter {
             writer,
             index: Arc::clone(&index),
-            uncompacted,
+            gens: gen_stats,
             cur_gen,
+            max_file_size,
             path: Arc::clone(&path),
             reader: reader.clone(),
+            compactions: 0,
+            compaction_time: Duration::default(),
+            last_compaction: None,
+            seq: 0,
+            watchers: Vec::new(),
         };
 
         Ok(KvStore {
@@ -179,6 +230,23 @@ impl KvStore {
             writer: Arc::new(Mutex::new(writer)),
         })
     }
+
+    /// Reads the value of `key` from the log file holding it
+    ///
+    /// A compaction may move the key and delete the log file between the lookup
+    /// and the read, in which case the key is looked up again.
+    fn read_key(&self, key: &str) -> Result<Option<String>> {
+        loop {
+            let cmd_pos = match self.index.get(key) {
+                Some(entry) => *entry.value(),
+                None => return Ok(None),
+            };
+            match self.reader.read_value(cmd_pos) {
+                Err(e) if self.reader.is_compacted(cmd_pos, &e) => continue,
+                result => return result.map(Some),
+            }
+        }
+    }
 }
 
 fn new_log_file(path: &Path, cur_gen: u64) -> Result<BufWriterWithPos<File>> {
@@ -198,10 +266,11 @@ fn load(
     gen: u64,
     reader: &mut BufReaderWithPos<File>,
     index: &SkipMap<String, CommandPos>,
-) -> Result<u64> {
+    gens: &mut BTreeMap<u64, GenStats>,
+) -> Result<()> {
     reader.seek(SeekFrom::Start(0))?;
 
-    let mut uncompacted = 0;
+    gens.insert(gen, GenStats::default());
     let mut commands = Deserializer::from_reader(reader).into_iter::<Command>();
 
     let mut old_pos = 0;
@@ -211,24 +280,24 @@ fn load(
 
         match command? {
             Command::Set { key, .. } => {
-                if index.contains_key(&key) {
-                    let old_entry = index.get(&key).unwrap();
-                    uncompacted += old_entry.value().len;
+                if let Some(old_entry) = index.get(&key) {
+                    mark_stale(gens, old_entry.value());
                 }
+                gens.entry(gen).or_default().live += new_pos - old_pos;
                 index.insert(key, CommandPos::new(gen, old_pos, new_pos - old_pos));
             }
             Command::Remove { key } => {
                 if let Some(old_entry) = index.remove(&key) {
-                    uncompacted += old_entry.value().len;
+                    mark_stale(gens, old_entry.value());
                 }
-                uncompacted += new_pos - old_pos;
+                gens.entry(gen).or_default().tombstones += new_pos - old_pos;
             }
         }
 
         old_pos = new_pos;
     }
 
-    Ok(uncompacted)
+    Ok(())
 }
 
 fn join_log(path: &Path, gen: u64) -> PathBuf {
@@ -255,15 +324,7 @@ fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
 
 impl KvsEngine for KvStore {
     fn get(&self, key: String) -> Result<Option<String>> {
-        if let Some(cmd_pos) = self.index.get(&key) {
-            if let Command::Set { value, .. } = self.reader.read_command(*cmd_pos.value())? {
-                Ok(Some(value))
-            } else {
-                Err(KvsError::UnexpectedCommandType)
-            }
-        } else {
-            Ok(None)
-        }
+        self.read_key(&key)
     }
 
     fn set(&self, key: String, value: String) -> Result<()> {
@@ -273,24 +334,95 @@ impl KvsEngine for KvStore {
     fn remove(&self, key: String) -> Result<()> {
         self.writer.lock().unwrap().remove(key)
     }
+
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let mut values = vec![None; keys.len()];
+        // read the commands generation by generation, in file order
+        let mut positions: Vec<(usize, CommandPos)> = keys
+            .iter()
+            .enumerate()
+            .filter_map(|(i, key)| self.index.get(key).map(|entry| (i, *entry.value())))
+            .collect();
+        positions.sort_unstable_by_key(|&(_, cmd_pos)| (cmd_pos.gen, cmd_pos.pos));
+
+        for (i, cmd_pos) in positions {
+            values[i] = match self.reader.read_value(cmd_pos) {
+                Err(e) if self.reader.is_compacted(cmd_pos, &e) => self.read_key(&keys[i])?,
+                result => Some(result?),
+            };
+        }
+        Ok(values)
+    }
+
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        self.writer.lock().unwrap().set_many(pairs)
+    }
+
+    fn set_if(&self, key: String, value: String, condition: SetCondition) -> Result<bool> {
+        // the writer lock keeps the key from changing between the check and the write
+        let mut writer = self.writer.lock().unwrap();
+        let exists = self.index.contains_key(&key);
+        if exists != (condition == SetCondition::Present) {
+            return Ok(false);
+        }
+        writer.set(key, value)?;
+        Ok(true)
+    }
+
+    fn scan(&self, prefix: String, after: Option<String>, limit: usize) -> Result<Vec<String>> {
+        let start = match &after {
+            Some(after) if after.as_str() >= prefix.as_str() => Bound::Excluded(after.as_str()),
+            _ => Bound::Included(prefix.as_str()),
+        };
+        Ok(self
+            .index
+            .range::<str, _>((start, Bound::Unbounded))
+            .map(|entry| entry.key().clone())
+            .take_while(|key| key.starts_with(&prefix))
+            .take(limit)
+            .collect())
+    }
+
+    fn incr(&self, key: String, delta: i64) -> Result<i64> {
+        self.writer.lock().unwrap().incr(key, delta)
+    }
+
+    fn append(&self, key: String, suffix: String) -> Result<u64> {
+        self.writer.lock().unwrap().append(key, suffix)
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        Ok(self.writer.lock().unwrap().stats())
+    }
+
+    fn watch(&self, prefix: String) -> Result<Watcher> {
+        Ok(self.writer.lock().unwrap().watch(prefix))
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.writer.lock().unwrap().sync()
+    }
+
+    fn compact(&self) -> Result<()> {
+        self.writer.lock().unwrap().compact(true)
+    }
 }
 
 impl KvStoreWriter {
-    /// Gets the string value of a string key
-    ///
-    /// Returns `None` if given string does not exsist
-
     /// remove the given key
     fn remove(&mut self, key: String) -> Result<()> {
         if let Some(old_cmd) = self.index.remove(&key) {
-            serde_json::to_writer(&mut self.writer, &Command::rm(key))?;
+            let position = self.writer.pos;
+            serde_json::to_writer(&mut self.writer, &Command::rm(key.clone()))?;
             self.writer.flush()?;
 
-            self.uncompacted += old_cmd.value().len;
-            Ok(())
+            mark_stale(&mut self.gens, old_cmd.value());
+            self.gens.entry(self.cur_gen).or_default().tombstones += self.writer.pos - position;
         } else {
-            Err(KvsError::KeyNotFound)
+            return Err(KvsError::KeyNotFound);
         }
+        self.notify(key, Op::Remove);
+        self.after_write()
     }
 
     /// Sets the string value of a string key to a string
@@ -302,58 +434,272 @@ impl KvStoreWriter {
         serde_json::to_writer(&mut self.writer, &command)?;
         self.writer.flush()?;
 
-        if let Command::Set { key, .. } = command {
+        if let Command::Set { key, value } = command {
             if let Some(entry) = self.index.get(&key) {
-                self.uncompacted += entry.value().len;
+                mark_stale(&mut self.gens, entry.value());
             }
             let cur_pos = self.writer.pos;
+            self.gens.entry(self.cur_gen).or_default().live += cur_pos - position;
             self.index.insert(
-                key,
+                key.clone(),
                 CommandPos::new(self.cur_gen, position, cur_pos - position),
             );
+            self.notify(key, Op::Set(value));
+        }
+        self.after_write()
+    }
+
+    /// Sets several key/value pairs with a single flush of the log file
+    fn set_many(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        let mut positions = Vec::with_capacity(pairs.len());
+        for (key, value) in pairs {
+            let position = self.writer.pos;
+            let command = Command::set(key, value);
+            serde_json::to_writer(&mut self.writer, &command)?;
+            if let Command::Set { key, value } = command {
+                positions.push((key, value, position, self.writer.pos - position));
+            }
+        }
+        self.writer.flush()?;
+
+        for (key, value, position, len) in positions {
+            if let Some(entry) = self.index.get(&key) {
+                mark_stale(&mut self.gens, entry.value());
+            }
+            self.gens.entry(self.cur_gen).or_default().live += len;
+            self.index
+                .insert(key.clone(), CommandPos::new(self.cur_gen, position, len));
+            self.notify(key, Op::Set(value));
+        }
+        self.after_write()
+    }
+
+    /// Adds `delta` to the integer value of a key, treating a missing key as 0
+    fn incr(&mut self, key: String, delta: i64) -> Result<i64> {
+        let value = match self.get(&key)? {
+            Some(value) => value.parse::<i64>().map_err(|_| KvsError::NotAnInteger)?,
+            None => 0,
+        };
+        let value = value.checked_add(delta).ok_or(KvsError::Overflow)?;
+        self.set(key, value.to_string())?;
+        Ok(value)
+    }
+
+    /// Appends `suffix` to the value of a key, treating a missing key as empty
+    fn append(&mut self, key: String, suffix: String) -> Result<u64> {
+        let mut value = self.get(&key)?.unwrap_or_default();
+        value.push_str(&suffix);
+        let len = value.len() as u64;
+        self.set(key, value)?;
+        Ok(len)
+    }
+
+    /// Registers a watcher of the keys starting with `prefix`
+    fn watch(&mut self, prefix: String) -> Watcher {
+        let (tx, rx) = channel::bounded(WATCH_BACKLOG);
+        self.watchers.push((prefix, tx));
+        Watcher::new(rx)
+    }
+
+    /// Sends a change to the interested watchers, dropping the disconnected or lagging ones
+    fn notify(&mut self, key: String, op: Op) {
+        self.seq += 1;
+        let seq = self.seq;
+        self.watchers.retain(|(prefix, tx)| {
+            !key.starts_with(prefix.as_str())
+                || tx
+                    .try_send(Event {
+                        seq,
+                        key: key.clone(),
+                        op: op.clone(),
+                    })
+                    .is_ok()
+        });
+    }
+
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        self.index
+            .get(key)
+            .map(|cmd_pos| self.reader.read_value(*cmd_pos.value()))
+            .transpose()
+    }
+
+    /// Flushes the active log file and waits for it to reach the disk
+    fn sync(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.inner.get_ref().sync_all()?;
+        Ok(())
+    }
+
+    /// Rolls the active log file if it is full, and compacts the store
+    /// if there are too many stale commands
+    fn after_write(&mut self) -> Result<()> {
+        if self.writer.pos > self.max_file_size {
+            self.roll()?;
         }
-        if self.uncompacted > COMPACTION_THRESHOLD {
-            self.compact()?;
+        if self.garbage() > COMPACTION_THRESHOLD {
+            self.compact(false)?;
         }
         Ok(())
     }
 
-    fn compact(&mut self) -> Result<()> {
-        let gens = sorted_gen_list(&self.path)?;
-        let compaction_gen = self.cur_gen + 1;
-        self.cur_gen = compaction_gen;
+    /// Seals the active log file and starts writing to a new generation
+    fn roll(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.cur_gen += 1;
+        self.writer = new_log_file(&self.path, self.cur_gen)?;
+        self.gens.insert(self.cur_gen, GenStats::default());
+        self.reader.live_gens.insert(self.cur_gen);
+        Ok(())
+    }
 
-        let mut compact_writer = new_log_file(&self.path, compaction_gen)?;
+    /// Total bytes of stale commands in the store
+    fn garbage(&self) -> u64 {
+        self.gens.values().map(|stats| stats.dead).sum()
+    }
 
-        let mut cur_pos = 0u64;
-        for entry in self.index.iter() {
-            let len = self.reader.read_and(*entry.value(), |mut command| {
-                Ok(io::copy(&mut command, &mut compact_writer)?)
-            })?;
-            let new_pos = CommandPos::new(compaction_gen, cur_pos, len);
-            self.index.insert(entry.key().to_owned(), new_pos);
-            cur_pos += len;
+    fn stats(&self) -> EngineStats {
+        let live_bytes = self
+            .gens
+            .values()
+            .map(|stats| stats.live + stats.tombstones)
+            .sum();
+        let garbage_bytes = self.garbage();
+        let disk_bytes = live_bytes + garbage_bytes;
+        EngineStats {
+            keys: self.index.len() as u64,
+            generations: self.gens.len() as u64,
+            disk_bytes,
+            live_bytes,
+            garbage_bytes,
+            garbage_ratio: if disk_bytes == 0 {
+                0.0
+            } else {
+                garbage_bytes as f64 / disk_bytes as f64
+            },
+            compactions: self.compactions,
+            compaction_time: self.compaction_time,
+            last_compaction: self.last_compaction,
         }
-        compact_writer.flush()?;
-        self.writer = compact_writer;
+    }
 
-        self.reader
-            .safe_point
-            .store(compaction_gen, Ordering::SeqCst);
-        self.reader.close_stale_handler();
+    /// Picks the generations holding the most stale data, until they cover at
+    /// least half of all the stale data in the store, or all of it along with
+    /// the tombstones
+    fn pick_victims(&self, all: bool) -> Vec<u64> {
+        let mut candidates: Vec<(u64, u64)> = self
+            .gens
+            .iter()
+            .filter(|(_, stats)| stats.dead > 0 || (all && stats.tombstones > 0))
+            .map(|(&gen, stats)| (gen, stats.dead))
+            .collect();
+        candidates.sort_unstable_by_key(|&(_, garbage)| Reverse(garbage));
+
+        let total = self.garbage();
+        let mut picked = 0;
+        let mut victims = Vec::new();
+        for (gen, garbage) in candidates {
+            if !all && picked * 2 >= total {
+                break;
+            }
+            picked += garbage;
+            victims.push(gen);
+        }
+        victims.sort_unstable();
+        victims
+    }
+
+    /// Rewrites the live commands of the most fragmented generations, or of all
+    /// the generations holding stale data or tombstones, into the active log
+    /// file, then deletes those generations
+    ///
+    /// It does nothing if no generation holds stale data.
+    fn compact(&mut self, all: bool) -> Result<()> {
+        let start = Instant::now();
+        let victims = self.pick_victims(all);
+        if victims.is_empty() {
+            return Ok(());
+        }
+        if victims.binary_search(&self.cur_gen).is_ok() {
+            // seal the active file so that it can be compacted as well
+            self.roll()?;
+        }
+        let oldest_survivor = self
+            .gens
+            .keys()
+            .find(|gen| victims.binary_search(gen).is_err())
+            .copied();
+
+        let mut moved = Vec::new();
+        for &gen in &victims {
+            let reader = BufReader::new(File::open(join_log(&self.path, gen))?);
+            let mut commands = Deserializer::from_reader(reader).into_iter::<Command>();
+            let mut old_pos = 0;
+
+            while let Some(command) = commands.next() {
+                let command = command?;
+                let new_pos = commands.byte_offset() as u64;
+
+                let keep = match &command {
+                    Command::Set { key, .. } => self
+                        .index
+                        .get(key)
+                        .map(|entry| {
+                            let cmd_pos = entry.value();
+                            cmd_pos.gen == gen && cmd_pos.pos == old_pos
+                        })
+                        .unwrap_or(false),
+                    // a tombstone must survive as long as an older generation
+                    // may still hold a value of the removed key
+                    Command::Remove { key } => {
+                        !self.index.contains_key(key)
+                            && oldest_survivor.is_some_and(|survivor| survivor < gen)
+                    }
+                };
+                old_pos = new_pos;
+                if !keep {
+                    continue;
+                }
+
+                let position = self.writer.pos;
+                serde_json::to_writer(&mut self.writer, &command)?;
+                let len = self.writer.pos - position;
+                let stats = self.gens.entry(self.cur_gen).or_default();
+                match command {
+                    Command::Set { key, .. } => {
+                        stats.live += len;
+                        moved.push((key, CommandPos::new(self.cur_gen, position, len)));
+                    }
+                    Command::Remove { .. } => stats.tombstones += len,
+                }
+                if self.writer.pos > self.max_file_size {
+                    self.roll()?;
+                }
+            }
+        }
+        self.writer.flush()?;
+        // the readers only see the new positions once they are on disk
+        for (key, cmd_pos) in moved {
+            self.index.insert(key, cmd_pos);
+        }
 
-        // let reader = BufReaderWithPos::new(File::open(join_log(&self.path, compaction_gen))?);
-        // self.reader.readers.borrow_mut().insert(compaction_gen, reader);
+        for &gen in &victims {
+            self.gens.remove(&gen);
+            self.reader.live_gens.remove(&gen);
+        }
+        self.reader.close_stale_handler();
 
-        for &gen in gens.iter() {
+        for &gen in &victims {
             let log_path = join_log(&self.path, gen);
-            // fs::remove_file(log_path)?;
             if let Err(e) = fs::remove_file(&log_path) {
                 error!("{:?} cannot be deleted: {}", log_path, e);
             }
         }
-        self.uncompacted = 0;
 
+        let elapsed = start.elapsed();
+        self.compactions += 1;
+        self.compaction_time += elapsed;
+        self.last_compaction = Some(elapsed);
         Ok(())
     }
 }
@@ -363,32 +709,45 @@ impl KvStoreReader {
         self.read_and(com_pos, |command| Ok(serde_json::from_reader(command)?))
     }
 
+    fn read_value(&self, com_pos: CommandPos) -> Result<String> {
+        if let Command::Set { value, .. } = self.read_command(com_pos)? {
+            Ok(value)
+        } else {
+            Err(KvsError::UnexpectedCommandType)
+        }
+    }
+
     fn read_and<F, R>(&self, com_pos: CommandPos, f: F) -> Result<R>
     where
         F: FnOnce(io::Take<&mut BufReaderWithPos<File>>) -> Result<R>,
     {
         let mut readers = self.readers.borrow_mut();
-        if !readers.contains_key(&com_pos.gen) {
-            let reader = BufReaderWithPos::new(File::open(join_log(&*self.path, com_pos.gen))?);
-            readers.insert(com_pos.gen, reader);
-        }
-
-        let reader = readers.get_mut(&com_pos.gen).unwrap();
+        let reader = match readers.entry(com_pos.gen) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let file = File::open(join_log(&self.path, com_pos.gen))?;
+                entry.insert(BufReaderWithPos::new(file))
+            }
+        };
         reader.seek(SeekFrom::Start(com_pos.pos))?;
         let cmd_reader = reader.take(com_pos.len);
 
         f(cmd_reader)
     }
 
+    /// Returns `true` if reading at `com_pos` failed with `err` because a
+    /// compaction deleted the log file in the meantime
+    fn is_compacted(&self, com_pos: CommandPos, err: &KvsError) -> bool {
+        let not_found = matches!(err, KvsError::Io(e) if e.kind() == io::ErrorKind::NotFound);
+        not_found && !self.live_gens.contains(&com_pos.gen)
+    }
+
+    /// Closes file handles of generations that have been compacted away
     fn close_stale_handler(&self) {
-        let mut readers = self.readers.borrow_mut();
-        while !readers.is_empty() {
-            let &gen = readers.keys().next().unwrap();
-            if self.safe_point.load(Ordering::SeqCst) <= gen {
-                break;
-            }
-            readers.remove(&gen);
-        }
+        let live_gens = &self.live_gens;
+        self.readers
+            .borrow_mut()
+            .retain(|gen, _| live_gens.contains(gen));
     }
 }
 
@@ -396,7 +755,7 @@ impl Clone for KvStoreReader {
     fn clone(&self) -> Self {
         Self {
             path: self.path.clone(),
-            safe_point: self.safe_point.clone(),
+            live_gens: self.live_gens.clone(),
             readers: RefCell::new(BTreeMap::new()),
         }
     }
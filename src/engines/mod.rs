@@ -1,6 +1,13 @@
 mod kvs;
 mod sled;
-use crate::Result;
+use crate::{KvsError, Result};
+use crossbeam::channel::{Receiver, RecvTimeoutError};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::Duration;
+
+/// The number of changes a watcher may lag behind before it is dropped
+const WATCH_BACKLOG: usize = 1024;
 
 /// Trait for key value storage engine
 pub trait KvsEngine: Clone + Send + 'static{
@@ -19,6 +26,163 @@ pub trait KvsEngine: Clone + Send + 'static{
     /// # Errors
     /// It returns `KvsError::KeyNotFound` if the given key is not found.
     fn remove(&self, key: String) -> Result<()>;
+
+    /// Gets the values of several keys at once.
+    ///
+    /// The values are returned in the order of `keys`, with `None` for each key that does not exist.
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        keys.into_iter().map(|key| self.get(key)).collect()
+    }
+
+    /// Sets several key/value pairs at once.
+    ///
+    /// Later pairs win if a key appears more than once.
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        for (key, value) in pairs {
+            self.set(key, value)?;
+        }
+        Ok(())
+    }
+
+    /// Atomically sets the value of a key if `condition` holds, returning whether it is set.
+    fn set_if(&self, key: String, value: String, condition: SetCondition) -> Result<bool>;
+
+    /// Lists up to `limit` keys starting with `prefix` in ascending order.
+    ///
+    /// Only the keys greater than `after` are listed, so a scan can resume from
+    /// the last key of the previous one.
+    fn scan(&self, prefix: String, after: Option<String>, limit: usize) -> Result<Vec<String>>;
+
+    /// Atomically adds `delta` to the integer value of a key and returns the new value.
+    ///
+    /// A missing key is treated as `0`.
+    ///
+    /// # Errors
+    /// It returns `KvsError::NotAnInteger` if the value cannot be parsed as an `i64`,
+    /// and `KvsError::Overflow` if the result does not fit in an `i64`.
+    fn incr(&self, key: String, delta: i64) -> Result<i64>;
+
+    /// Atomically subtracts `delta` from the integer value of a key and returns the new value.
+    ///
+    /// See [`incr`](KvsEngine::incr) for the error cases.
+    fn decr(&self, key: String, delta: i64) -> Result<i64> {
+        self.incr(key, delta.checked_neg().ok_or(KvsError::Overflow)?)
+    }
+
+    /// Atomically appends `suffix` to the value of a key and returns the new length in bytes.
+    ///
+    /// A missing key is treated as an empty string.
+    fn append(&self, key: String, suffix: String) -> Result<u64>;
+
+    /// Reports the statistics of the storage engine
+    fn stats(&self) -> Result<EngineStats>;
+
+    /// Subscribes to the changes of all keys starting with `prefix`.
+    ///
+    /// The returned `Watcher` blocks until the next change happens. It ends
+    /// once it lags `WATCH_BACKLOG` changes behind.
+    fn watch(&self, prefix: String) -> Result<Watcher>;
+
+    /// Writes the buffered changes out and syncs them to the disk.
+    fn flush(&self) -> Result<()>;
+
+    /// Reclaims the disk space of all the stale data now, rather than when the engine decides to.
+    ///
+    /// The engines managing their space on their own do nothing.
+    fn compact(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Condition of `KvsEngine::set_if`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetCondition {
+    /// The key does not exist yet
+    Absent,
+    /// The key already exists
+    Present,
+}
+
+/// A change made to a key
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Event {
+    /// Sequence number of the change, shared by all the watchers of the engine
+    pub seq: u64,
+    /// The changed key
+    pub key: String,
+    /// What happened to the key
+    pub op: Op,
+}
+
+/// Kind of change in an `Event`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Op {
+    /// The key is set to the value
+    Set(String),
+    /// The key is removed
+    Remove,
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.op {
+            Op::Set(value) => write!(f, "{} set {} {}", self.seq, self.key, value),
+            Op::Remove => write!(f, "{} rm {}", self.seq, self.key),
+        }
+    }
+}
+
+/// A blocking iterator over the changes of a key prefix
+///
+/// It ends once the engine drops the sending end of its channel.
+pub struct Watcher(Receiver<Event>);
+
+impl Watcher {
+    /// Creates a `Watcher` receiving the events sent on a channel
+    pub fn new(events: Receiver<Event>) -> Self {
+        Watcher(events)
+    }
+
+    /// Waits at most `timeout` for the next change
+    pub(crate) fn recv_timeout(
+        &self,
+        timeout: Duration,
+    ) -> std::result::Result<Event, RecvTimeoutError> {
+        self.0.recv_timeout(timeout)
+    }
+}
+
+impl Iterator for Watcher {
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Event> {
+        self.0.recv().ok()
+    }
+}
+
+/// Statistics of a storage engine
+///
+/// Engines fill in the fields they are able to track and leave the rest zeroed.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct EngineStats {
+    /// Number of live keys
+    pub keys: u64,
+    /// Number of log files on disk
+    pub generations: u64,
+    /// Bytes taken on disk
+    pub disk_bytes: u64,
+    /// Bytes of commands still referenced by the index
+    pub live_bytes: u64,
+    /// Bytes of stale commands waiting for compaction
+    pub garbage_bytes: u64,
+    /// `garbage_bytes / disk_bytes`
+    pub garbage_ratio: f64,
+    /// Number of compactions since the engine was opened
+    pub compactions: u64,
+    /// Total time spent on compaction since the engine was opened
+    pub compaction_time: Duration,
+    /// Time spent on the latest compaction
+    pub last_compaction: Option<Duration>,
 }
 
 pub use self::kvs::KvStore;
@@ -1,15 +1,62 @@
-use super::KvsEngine;
+use super::{EngineStats, Event, KvsEngine, Op, SetCondition, Watcher, WATCH_BACKLOG};
 use crate::{KvsError, Result};
-use sled::{Db, Tree};
+use crossbeam::channel::{self, Sender};
+use sled::{Batch, Db, Subscriber, Tree};
+use std::ops::Bound;
+use std::sync::{Arc, Mutex, Once};
+use std::thread;
 
 /// Wrapper of `sled::Db`
 #[derive(Clone)]
-pub struct SledKvsEngine(Db);
+pub struct SledKvsEngine(Db, Arc<Feed>);
 
 impl SledKvsEngine {
     /// Creates a `SledKvsEngine` from `sled::Db`.
     pub fn new(db: Db) -> Self {
-        SledKvsEngine(db)
+        let feed = Feed {
+            started: Once::new(),
+            watchers: Mutex::default(),
+        };
+        SledKvsEngine(db, Arc::new(feed))
+    }
+}
+
+/// Numbers the changes of the database in the order they happen, and hands
+/// them out to the watchers
+///
+/// It watches the whole database from the first `watch` on, on a thread of
+/// its own, so that all the watchers share the same sequence numbers.
+struct Feed {
+    started: Once,
+    watchers: Mutex<Vec<(String, Sender<Event>)>>,
+}
+
+impl Feed {
+    /// Sends each change to the interested watchers, dropping the disconnected or lagging ones
+    fn run(&self, subscriber: Subscriber) {
+        for (seq, event) in (1..).zip(subscriber) {
+            let (key, op) = match event {
+                sled::Event::Insert { key, value } => match String::from_utf8(value.to_vec()) {
+                    Ok(value) => (key, Op::Set(value)),
+                    Err(_) => continue,
+                },
+                sled::Event::Remove { key } => (key, Op::Remove),
+            };
+            let key = match String::from_utf8(key.to_vec()) {
+                Ok(key) => key,
+                Err(_) => continue,
+            };
+            self.watchers.lock().unwrap().retain(|(prefix, tx)| {
+                !key.starts_with(prefix.as_str())
+                    || tx
+                        .try_send(Event {
+                            seq,
+                            key: key.clone(),
+                            op: op.clone(),
+                        })
+                        .is_ok()
+            });
+        }
     }
 }
 
@@ -36,4 +83,113 @@ impl KvsEngine for SledKvsEngine {
         tree.flush()?;
         Ok(())
     }
+
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        let tree: &Tree = &self.0;
+        let mut batch = Batch::default();
+        for (key, value) in pairs {
+            batch.insert(key.as_bytes(), value.into_bytes());
+        }
+        tree.apply_batch(batch)?;
+        tree.flush()?;
+        Ok(())
+    }
+
+    fn set_if(&self, key: String, value: String, condition: SetCondition) -> Result<bool> {
+        let tree: &Tree = &self.0;
+        loop {
+            let old = tree.get(&key)?;
+            if old.is_some() != (condition == SetCondition::Present) {
+                return Ok(false);
+            }
+            // retry if the key changed since it was read
+            if tree
+                .compare_and_swap(&key, old, Some(value.as_bytes()))?
+                .is_ok()
+            {
+                tree.flush()?;
+                return Ok(true);
+            }
+        }
+    }
+
+    fn scan(&self, prefix: String, after: Option<String>, limit: usize) -> Result<Vec<String>> {
+        let tree: &Tree = &self.0;
+        let start = match after {
+            Some(after) if after >= prefix => Bound::Excluded(after.into_bytes()),
+            _ => Bound::Included(prefix.clone().into_bytes()),
+        };
+        let mut keys = Vec::new();
+        for entry in tree.range::<Vec<u8>, _>((start, Bound::Unbounded)).take(limit) {
+            let key = String::from_utf8(entry?.0.to_vec())?;
+            if !key.starts_with(&prefix) {
+                break;
+            }
+            keys.push(key);
+        }
+        Ok(keys)
+    }
+
+    fn incr(&self, key: String, delta: i64) -> Result<i64> {
+        let tree: &Tree = &self.0;
+        let mut result = Ok(0);
+        tree.update_and_fetch(key, |old| {
+            result = parse_i64(old).and_then(|value| {
+                value.checked_add(delta).ok_or(KvsError::Overflow)
+            });
+            match result {
+                Ok(value) => Some(value.to_string().into_bytes()),
+                // leave the value untouched
+                Err(_) => old.map(<[u8]>::to_vec),
+            }
+        })?;
+        tree.flush()?;
+        result
+    }
+
+    fn append(&self, key: String, suffix: String) -> Result<u64> {
+        let tree: &Tree = &self.0;
+        let mut len = 0;
+        tree.update_and_fetch(key, |old| {
+            let mut value = old.map(<[u8]>::to_vec).unwrap_or_default();
+            value.extend_from_slice(suffix.as_bytes());
+            len = value.len() as u64;
+            Some(value)
+        })?;
+        tree.flush()?;
+        Ok(len)
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        Ok(EngineStats {
+            keys: self.0.len() as u64,
+            disk_bytes: self.0.size_on_disk()?,
+            ..EngineStats::default()
+        })
+    }
+
+    fn watch(&self, prefix: String) -> Result<Watcher> {
+        self.1.started.call_once(|| {
+            let subscriber = self.0.watch_prefix(Vec::new());
+            let feed = Arc::clone(&self.1);
+            thread::spawn(move || feed.run(subscriber));
+        });
+        let (tx, rx) = channel::bounded(WATCH_BACKLOG);
+        self.1.watchers.lock().unwrap().push((prefix, tx));
+        Ok(Watcher::new(rx))
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.0.flush()?;
+        Ok(())
+    }
+}
+
+fn parse_i64(value: Option<&[u8]>) -> Result<i64> {
+    match value {
+        Some(bytes) => String::from_utf8(bytes.to_vec())?
+            .parse()
+            .map_err(|_| KvsError::NotAnInteger),
+        None => Ok(0),
+    }
 }
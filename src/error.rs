@@ -1,4 +1,5 @@
 use failure::Fail;
+use serde::{Deserialize, Serialize};
 use serde_json;
 use std::io;
 use std::string::FromUtf8Error;
@@ -33,6 +34,60 @@ pub enum KvsError {
     /// Key or value is invalid UTF-8 sequence
     #[fail(display = "UTF-8 error: {}", _0)]
     Utf8(#[cause] FromUtf8Error),
+
+    /// The value cannot be parsed as an integer
+    #[fail(display = "Value is not an integer")]
+    NotAnInteger,
+
+    /// The result of an arithmetic operation does not fit in an `i64`
+    #[fail(display = "Integer overflow")]
+    Overflow,
+
+    /// The peer violates the wire protocol
+    #[fail(display = "protocol error: {}", _0)]
+    Protocol(String),
+
+    /// The request conflicts with the current state of the key
+    #[fail(display = "conflict: {}", _0)]
+    Conflict(String),
+
+    /// The stored data is corrupted
+    #[fail(display = "data corruption: {}", _0)]
+    Corruption(String),
+
+    /// The client is not allowed to make the request
+    #[fail(display = "unauthorized: {}", _0)]
+    Unauthorized(String),
+
+    /// The server is too busy to serve the request
+    #[fail(display = "server overloaded: {}", _0)]
+    Overloaded(String),
+
+    /// The server cannot understand the request
+    #[fail(display = "bad request: {}", _0)]
+    BadRequest(String),
+
+    /// The request cannot be started before its deadline
+    #[fail(display = "deadline exceeded")]
+    DeadlineExceeded,
+
+    /// The TLS settings are invalid or the TLS session fails
+    #[fail(display = "TLS error: {}", _0)]
+    Tls(String),
+
+    /// The server follows a leader, so it refuses the writes
+    #[fail(display = "read only: {}", _0)]
+    ReadOnly(String),
+
+    /// The cluster node is not the leader, which is at the given address or `unknown`
+    #[fail(display = "not the leader, the leader is {}", _0)]
+    NotLeader(String),
+
+    /// The server fails to read or write its storage
+    ///
+    /// It is the `Io` error of the server, which leaves the connection to it usable.
+    #[fail(display = "server storage error: {}", _0)]
+    Storage(String),
 }
 
 impl From<io::Error> for KvsError {
@@ -53,11 +108,99 @@ impl From<sled::Error> for KvsError {
     }
 }
 
+impl From<rustls::Error> for KvsError {
+    fn from(err: rustls::Error) -> Self {
+        KvsError::Tls(err.to_string())
+    }
+}
+
 impl From<FromUtf8Error> for KvsError {
     fn from(err: FromUtf8Error) -> Self {
         KvsError::Utf8(err)
     }
 }
 
+/// Error code sent by the server along with the error message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorCode {
+    /// See `KvsError::KeyNotFound`
+    KeyNotFound,
+    /// See `KvsError::Conflict`
+    Conflict,
+    /// See `KvsError::Io`, rebuilt as `KvsError::Storage` by the clients
+    Io,
+    /// See `KvsError::Corruption`
+    Corruption,
+    /// See `KvsError::Unauthorized`
+    Unauthorized,
+    /// See `KvsError::Overloaded`
+    Overloaded,
+    /// See `KvsError::BadRequest`
+    BadRequest,
+    /// See `KvsError::NotAnInteger`
+    NotAnInteger,
+    /// See `KvsError::Overflow`
+    Overflow,
+    /// See `KvsError::DeadlineExceeded`
+    DeadlineExceeded,
+    /// See `KvsError::ReadOnly`
+    ReadOnly,
+    /// See `KvsError::NotLeader`
+    NotLeader,
+    /// Any other failure of the server
+    Internal,
+}
+
+impl KvsError {
+    /// The code reporting this error to a client
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            KvsError::KeyNotFound => ErrorCode::KeyNotFound,
+            KvsError::Io(_) | KvsError::Storage(_) => ErrorCode::Io,
+            // the server only fails to parse the data it has stored
+            KvsError::Serde(_) | KvsError::UnexpectedCommandType | KvsError::Utf8(_) => {
+                ErrorCode::Corruption
+            }
+            KvsError::Sled(sled::Error::Io(_)) => ErrorCode::Io,
+            KvsError::Sled(sled::Error::Corruption { .. }) => ErrorCode::Corruption,
+            KvsError::Sled(sled::Error::Unsupported(_)) => ErrorCode::BadRequest,
+            KvsError::Sled(_) | KvsError::StringError(_) => ErrorCode::Internal,
+            KvsError::NotAnInteger => ErrorCode::NotAnInteger,
+            KvsError::Overflow => ErrorCode::Overflow,
+            KvsError::Protocol(_) | KvsError::BadRequest(_) | KvsError::Tls(_) => {
+                ErrorCode::BadRequest
+            }
+            KvsError::Conflict(_) => ErrorCode::Conflict,
+            KvsError::Corruption(_) => ErrorCode::Corruption,
+            KvsError::Unauthorized(_) => ErrorCode::Unauthorized,
+            KvsError::Overloaded(_) => ErrorCode::Overloaded,
+            KvsError::DeadlineExceeded => ErrorCode::DeadlineExceeded,
+            KvsError::ReadOnly(_) => ErrorCode::ReadOnly,
+            KvsError::NotLeader(_) => ErrorCode::NotLeader,
+        }
+    }
+
+    /// Rebuilds the error reported by the server
+    pub fn from_code(code: ErrorCode, message: String) -> KvsError {
+        match code {
+            ErrorCode::KeyNotFound => KvsError::KeyNotFound,
+            ErrorCode::Conflict => KvsError::Conflict(message),
+            // not `KvsError::Io`, which would be taken for a broken connection
+            ErrorCode::Io => KvsError::Storage(message),
+            ErrorCode::Corruption => KvsError::Corruption(message),
+            ErrorCode::Unauthorized => KvsError::Unauthorized(message),
+            ErrorCode::Overloaded => KvsError::Overloaded(message),
+            ErrorCode::BadRequest => KvsError::BadRequest(message),
+            ErrorCode::NotAnInteger => KvsError::NotAnInteger,
+            ErrorCode::Overflow => KvsError::Overflow,
+            ErrorCode::DeadlineExceeded => KvsError::DeadlineExceeded,
+            ErrorCode::ReadOnly => KvsError::ReadOnly(message),
+            // the message is the address of the leader
+            ErrorCode::NotLeader => KvsError::NotLeader(message),
+            ErrorCode::Internal => KvsError::StringError(message),
+        }
+    }
+}
+
 /// custume Result type for kvs
 pub type Result<T> = std::result::Result<T, KvsError>;
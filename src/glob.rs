@@ -0,0 +1,87 @@
+//! Glob patterns, as used by `SCAN` and by the subscriptions to channels
+//!
+//! A pattern matches any sequence of bytes with `*`, any single byte with `?`,
+//! one of a set of bytes with `[...]` (or any byte but them with `[^...]`, and
+//! ranges such as `[a-z]`), and escapes a special character with `\`, like
+//! Redis does.
+
+/// The part of a glob pattern before its first special character
+pub(crate) fn literal_prefix(pattern: &str) -> &str {
+    let end = pattern
+        .find(&['*', '?', '[', '\\'][..])
+        .unwrap_or(pattern.len());
+    &pattern[..end]
+}
+
+/// Matches `s` against a glob pattern with `*`, `?`, `[...]` and `\` escapes, like Redis does
+///
+/// Every element of the pattern but `*` matches a single byte, so on a mismatch
+/// it is enough to go back to the latest `*` and let it match one more byte.
+pub(crate) fn glob_match(pattern: &[u8], s: &[u8]) -> bool {
+    let (mut p, mut i) = (0, 0);
+    // the position in the pattern after the latest `*`, and in `s` where it stops matching
+    let mut star = None;
+    while i < s.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            star = Some((p, i));
+            continue;
+        }
+        match match_one(&pattern[p..], s[i]) {
+            Some((true, len)) => {
+                p += len;
+                i += 1;
+            }
+            _ => match star {
+                Some((after_star, stop)) => {
+                    p = after_star;
+                    i = stop + 1;
+                    star = Some((after_star, i));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&x| x == b'*')
+}
+
+/// Matches `c` against the first element of a glob pattern other than `*`,
+/// returning whether it matches and the length of the element
+fn match_one(pattern: &[u8], c: u8) -> Option<(bool, usize)> {
+    match pattern {
+        [] => None,
+        [b'?', ..] => Some((true, 1)),
+        [b'\\', x, ..] => Some((*x == c, 2)),
+        [b'[', class @ ..] => {
+            let (negate, mut rest) = match class.split_first() {
+                Some((b'^', rest)) => (true, rest),
+                _ => (false, class),
+            };
+            let mut matched = false;
+            loop {
+                match rest {
+                    [] => break,
+                    [b']', tail @ ..] => {
+                        rest = tail;
+                        break;
+                    }
+                    [b'\\', x, tail @ ..] => {
+                        matched |= *x == c;
+                        rest = tail;
+                    }
+                    [lo, b'-', hi, tail @ ..] if *hi != b']' => {
+                        let (lo, hi) = if lo <= hi { (lo, hi) } else { (hi, lo) };
+                        matched |= *lo <= c && c <= *hi;
+                        rest = tail;
+                    }
+                    [x, tail @ ..] => {
+                        matched |= *x == c;
+                        rest = tail;
+                    }
+                }
+            }
+            Some((matched != negate, pattern.len() - rest.len()))
+        }
+        [x, ..] => Some((*x == c, 1)),
+    }
+}
@@ -0,0 +1,456 @@
+//! An HTTP/1.1 gateway exposing the engine as a JSON API
+//!
+//! * `GET /v1/keys/{key}` returns `{"key": ..., "value": ...}`
+//! * `PUT /v1/keys/{key}` sets the key to the request body
+//! * `DELETE /v1/keys/{key}` removes the key
+//! * `GET /v1/keys?prefix=&after=&limit=` lists the keys starting with `prefix`
+//!   in order, with their values, as `{"items": [...], "next": ...}`. `next` is
+//!   the `after` of the following page, or `null` on the last page.
+//! * `POST /v1/batch` runs a JSON array of requests of the TCP protocol in order
+//!   and returns the array of their responses
+//! * `GET /health` and `GET /metrics`, the latter in the Prometheus text format
+//!
+//! Errors are returned as `{"error": {"code": ..., "message": ...}}` with the
+//! HTTP status matching the `ErrorCode`.
+use crate::common::{Request, Response};
+use crate::engines::KvsEngine;
+use crate::metrics::{self, Metrics};
+use crate::server::apply;
+use crate::transport::Stream;
+use crate::{ErrorCode, KvsError, Result};
+use log::debug;
+use serde::Serialize;
+use serde_json::json;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+
+const MAX_LINE_LEN: u64 = 8 * 1024;
+const MAX_HEADERS: usize = 100;
+const MAX_BODY_LEN: usize = 16 * 1024 * 1024;
+const DEFAULT_SCAN_LIMIT: usize = 100;
+const MAX_SCAN_LIMIT: usize = 1000;
+
+struct HttpRequest {
+    method: String,
+    path: String,
+    query: Vec<(String, String)>,
+    body: Vec<u8>,
+    keep_alive: bool,
+}
+
+impl HttpRequest {
+    fn param(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+struct HttpResponse {
+    status: u16,
+    content_type: &'static str,
+    headers: Vec<(&'static str, String)>,
+    body: Vec<u8>,
+}
+
+impl HttpResponse {
+    fn json<T: Serialize>(status: u16, body: &T) -> HttpResponse {
+        HttpResponse {
+            status,
+            content_type: "application/json",
+            headers: Vec::new(),
+            body: serde_json::to_vec(body).unwrap_or_default(),
+        }
+    }
+
+    fn no_content() -> HttpResponse {
+        HttpResponse {
+            status: 204,
+            content_type: "application/json",
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    fn error(status: u16, code: ErrorCode, message: impl Into<String>) -> HttpResponse {
+        let body = json!({ "error": { "code": code, "message": message.into() } });
+        HttpResponse::json(status, &body)
+    }
+
+    fn metrics(body: String) -> HttpResponse {
+        HttpResponse {
+            status: 200,
+            content_type: "text/plain; version=0.0.4",
+            headers: Vec::new(),
+            body: body.into_bytes(),
+        }
+    }
+
+    fn bad_request(message: impl Into<String>) -> HttpResponse {
+        HttpResponse::error(400, ErrorCode::BadRequest, message)
+    }
+
+    fn not_found() -> HttpResponse {
+        HttpResponse::error(404, ErrorCode::BadRequest, "no such endpoint")
+    }
+
+    fn method_not_allowed(allow: &str) -> HttpResponse {
+        let mut resp = HttpResponse::error(405, ErrorCode::BadRequest, "method not allowed");
+        resp.headers.push(("Allow", allow.to_owned()));
+        resp
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W, keep_alive: bool) -> Result<()> {
+        write!(
+            writer,
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: {}\r\n",
+            self.status,
+            reason(self.status),
+            self.content_type,
+            self.body.len(),
+            if keep_alive { "keep-alive" } else { "close" },
+        )?;
+        for (name, value) in &self.headers {
+            write!(writer, "{}: {}\r\n", name, value)?;
+        }
+        writer.write_all(b"\r\n")?;
+        writer.write_all(&self.body)?;
+        Ok(())
+    }
+}
+
+impl From<KvsError> for HttpResponse {
+    fn from(err: KvsError) -> Self {
+        let code = err.code();
+        let status = match code {
+            ErrorCode::KeyNotFound => 404,
+            ErrorCode::BadRequest | ErrorCode::NotAnInteger | ErrorCode::Overflow => 400,
+            ErrorCode::Conflict => 409,
+            ErrorCode::Unauthorized => 401,
+            ErrorCode::ReadOnly => 403,
+            ErrorCode::Overloaded | ErrorCode::NotLeader => 503,
+            ErrorCode::DeadlineExceeded => 504,
+            ErrorCode::Io | ErrorCode::Corruption | ErrorCode::Internal => 500,
+        };
+        HttpResponse::error(status, code, err.to_string())
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "",
+    }
+}
+
+/// The response to a connection beyond the limit of the server
+pub fn overloaded() -> Result<Vec<u8>> {
+    let resp = HttpResponse::from(KvsError::Overloaded("too many connections".to_owned()));
+    let mut bytes = Vec::new();
+    resp.write_to(&mut bytes, false)?;
+    Ok(bytes)
+}
+
+/// Serves a connection speaking HTTP/1.1 until the client or the server closes it
+pub fn serve<E: KvsEngine>(engine: E, stream: Stream) -> Result<()> {
+    serve_with(stream, |request| route(&engine, request))
+}
+
+/// Serves the metrics of a server, at `GET /metrics` only
+pub(crate) fn serve_metrics(metrics: &Metrics, stream: Stream) -> Result<()> {
+    serve_with(stream, |request| {
+        match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/metrics") => HttpResponse::metrics(metrics.render()),
+            (_, "/metrics") => HttpResponse::method_not_allowed("GET"),
+            _ => HttpResponse::not_found(),
+        }
+    })
+}
+
+/// Serves a connection, answering each request with `route`
+fn serve_with<F>(stream: Stream, route: F) -> Result<()>
+where
+    F: Fn(&HttpRequest) -> HttpResponse,
+{
+    let peer_addr = stream.peer_addr()?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    loop {
+        let request = match read_request(&mut reader, &mut writer)? {
+            Some(Ok(request)) => request,
+            Some(Err(rejection)) => {
+                // the rest of the stream cannot be trusted
+                rejection.write_to(&mut writer, false)?;
+                writer.flush()?;
+                return Ok(());
+            }
+            None => return Ok(()),
+        };
+        debug!(
+            "Receive {} {} from {}",
+            request.method, request.path, peer_addr
+        );
+        let resp = route(&request);
+        debug!("Response {} sent to {}", resp.status, peer_addr);
+        resp.write_to(&mut writer, request.keep_alive)?;
+        writer.flush()?;
+        if !request.keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+/// Reads a request, returning `None` if the client closes the connection before it
+///
+/// A request the server cannot make sense of is returned as the response rejecting it.
+fn read_request<R: BufRead, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+) -> Result<Option<std::result::Result<HttpRequest, HttpResponse>>> {
+    let request_line = match read_line(reader)? {
+        Some(line) if line.is_empty() => match read_line(reader)? {
+            // tolerate a stray CRLF between requests
+            Some(line) => line,
+            None => return Ok(None),
+        },
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let mut parts = request_line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None) if version.starts_with("HTTP/1.") => {
+            (method.to_owned(), target.to_owned(), version.to_owned())
+        }
+        _ => {
+            return Ok(Some(Err(HttpResponse::bad_request(
+                "malformed request line",
+            ))))
+        }
+    };
+
+    let mut headers = Vec::new();
+    loop {
+        let line = match read_line(reader)? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        if line.is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADERS {
+            return Ok(Some(Err(HttpResponse::bad_request("too many headers"))));
+        }
+        match line.split_once(':') {
+            Some((name, value)) => {
+                headers.push((name.trim().to_ascii_lowercase(), value.trim().to_owned()))
+            }
+            None => return Ok(Some(Err(HttpResponse::bad_request("malformed header")))),
+        }
+    }
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    };
+
+    let connection = header("connection").map(str::to_ascii_lowercase);
+    let keep_alive = match connection.as_deref() {
+        Some("close") => false,
+        Some("keep-alive") => true,
+        _ => version == "HTTP/1.1",
+    };
+    if header("transfer-encoding").is_some() {
+        return Ok(Some(Err(HttpResponse::error(
+            501,
+            ErrorCode::BadRequest,
+            "only bodies with a Content-Length are supported",
+        ))));
+    }
+    let len = match header("content-length").map(str::parse::<usize>) {
+        None => 0,
+        Some(Ok(len)) if len <= MAX_BODY_LEN => len,
+        Some(Ok(_)) => {
+            return Ok(Some(Err(HttpResponse::error(
+                413,
+                ErrorCode::BadRequest,
+                format!("the body is larger than {} bytes", MAX_BODY_LEN),
+            ))))
+        }
+        Some(Err(_)) => {
+            return Ok(Some(Err(HttpResponse::bad_request(
+                "invalid Content-Length",
+            ))))
+        }
+    };
+    if len > 0 && header("expect").is_some_and(|expect| expect.eq_ignore_ascii_case("100-continue"))
+    {
+        writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+        writer.flush()?;
+    }
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body)?;
+
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, query),
+        None => (target.as_str(), ""),
+    };
+    let query = match parse_query(query) {
+        Some(query) => query,
+        None => {
+            return Ok(Some(Err(HttpResponse::bad_request(
+                "malformed query string",
+            ))))
+        }
+    };
+    Ok(Some(Ok(HttpRequest {
+        method,
+        path: path.to_owned(),
+        query,
+        body,
+        keep_alive,
+    })))
+}
+
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<String>> {
+    let mut line = String::new();
+    if reader.by_ref().take(MAX_LINE_LEN).read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with('\n') {
+        return Err(KvsError::Protocol("HTTP line too long".to_owned()));
+    }
+    let len = line.trim_end_matches(&['\r', '\n'][..]).len();
+    line.truncate(len);
+    Ok(Some(line))
+}
+
+fn parse_query(query: &str) -> Option<Vec<(String, String)>> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            Some((decode(key, true)?, decode(value, true)?))
+        })
+        .collect()
+}
+
+/// Percent-decodes a component of the URL, with `+` standing for a space in query strings
+fn decode(s: &str, plus_is_space: bool) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut iter = s.bytes();
+    while let Some(b) = iter.next() {
+        match b {
+            b'%' => {
+                let hex = [iter.next()?, iter.next()?];
+                bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            }
+            b'+' if plus_is_space => bytes.push(b' '),
+            b => bytes.push(b),
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+fn route<E: KvsEngine>(engine: &E, request: &HttpRequest) -> HttpResponse {
+    let method = request.method.as_str();
+    if let Some(key) = request.path.strip_prefix("/v1/keys/") {
+        let key = match decode(key, false) {
+            Some(key) if !key.is_empty() => key,
+            _ => return HttpResponse::bad_request("invalid key"),
+        };
+        return match method {
+            "GET" => match engine.get(key.clone()) {
+                Ok(Some(value)) => HttpResponse::json(200, &json!({ "key": key, "value": value })),
+                Ok(None) => KvsError::KeyNotFound.into(),
+                Err(e) => e.into(),
+            },
+            "PUT" => match String::from_utf8(request.body.clone()) {
+                Ok(value) => match engine.set(key, value) {
+                    Ok(()) => HttpResponse::no_content(),
+                    Err(e) => e.into(),
+                },
+                Err(_) => HttpResponse::bad_request("the value is not valid UTF-8"),
+            },
+            "DELETE" => match engine.remove(key) {
+                Ok(()) => HttpResponse::no_content(),
+                Err(e) => e.into(),
+            },
+            _ => HttpResponse::method_not_allowed("GET, PUT, DELETE"),
+        };
+    }
+
+    match (method, request.path.as_str()) {
+        ("GET", "/v1/keys") => scan(engine, request).unwrap_or_else(HttpResponse::from),
+        (_, "/v1/keys") => HttpResponse::method_not_allowed("GET"),
+        ("POST", "/v1/batch") => batch(engine, &request.body),
+        (_, "/v1/batch") => HttpResponse::method_not_allowed("POST"),
+        ("GET", "/health") => match engine.stats() {
+            Ok(_) => HttpResponse::json(200, &json!({ "status": "ok" })),
+            Err(e) => HttpResponse::json(503, &json!({ "status": e.to_string() })),
+        },
+        ("GET", "/metrics") => engine_metrics(engine).unwrap_or_else(HttpResponse::from),
+        (_, "/health") | (_, "/metrics") => HttpResponse::method_not_allowed("GET"),
+        _ => HttpResponse::not_found(),
+    }
+}
+
+fn scan<E: KvsEngine>(engine: &E, request: &HttpRequest) -> Result<HttpResponse> {
+    let prefix = request.param("prefix").unwrap_or_default().to_owned();
+    let after = request.param("after").map(str::to_owned);
+    let limit = match request.param("limit").map(str::parse::<usize>) {
+        None => DEFAULT_SCAN_LIMIT,
+        Some(Ok(limit)) if limit > 0 => limit.min(MAX_SCAN_LIMIT),
+        Some(_) => return Ok(HttpResponse::bad_request("invalid limit")),
+    };
+
+    let keys = engine.scan(prefix, after, limit)?;
+    let next = if keys.len() == limit {
+        keys.last().cloned()
+    } else {
+        None
+    };
+    let values = engine.get_many(keys.clone())?;
+    let items: Vec<_> = keys
+        .into_iter()
+        .zip(values)
+        // skip the keys removed since they were listed
+        .filter_map(|(key, value)| Some(json!({ "key": key, "value": value? })))
+        .collect();
+    Ok(HttpResponse::json(
+        200,
+        &json!({ "items": items, "next": next }),
+    ))
+}
+
+fn batch<E: KvsEngine>(engine: &E, body: &[u8]) -> HttpResponse {
+    let requests: Vec<Request> = match serde_json::from_slice(body) {
+        Ok(requests) => requests,
+        Err(e) => return HttpResponse::bad_request(format!("malformed batch: {}", e)),
+    };
+    let responses: Vec<Response> = requests
+        .into_iter()
+        .map(|request| apply(engine, request).into())
+        .collect();
+    HttpResponse::json(200, &responses)
+}
+
+fn engine_metrics<E: KvsEngine>(engine: &E) -> Result<HttpResponse> {
+    let stats = engine.stats()?;
+    let mut body = String::new();
+    metrics::write_engine_stats(&mut body, &stats);
+    Ok(HttpResponse::metrics(body))
+}
@@ -1,13 +1,54 @@
 #![deny(missing_docs)]
 //! A simple key/value store
+mod admin;
+mod auth;
 mod engines;
 mod error;
 mod client;
+mod client_pool;
+mod cluster_client;
+mod sharding;
+mod proxy;
 mod server;
 mod common;
+mod protocol;
+mod dispatch;
+mod resp;
+mod memcache;
+mod http;
+mod metrics;
+mod async_server;
+mod async_client;
+mod shutdown;
+mod replication;
+mod pubsub;
+mod glob;
+mod raft;
+mod transport;
+mod tls;
 pub mod thread_pool;
 
-pub use error::{Result, KvsError};
-pub use client::KvsClient;
-pub use server::KvsServer;
-pub use engines::{KvStore, KvsEngine, SledKvsEngine};
\ No newline at end of file
+pub use error::{ErrorCode, Result, KvsError};
+pub use client::{KvsClient, Pipeline, Subscription, WatchStream};
+pub use async_client::AsyncKvsClient;
+pub use client_pool::{KvsClientPool, RetryPolicy};
+pub use cluster_client::ClusterClient;
+pub use sharding::{HashRing, ShardedKvsClient, DEFAULT_VIRTUAL_NODES};
+pub use proxy::{KvsProxy, PrefixRule, ProxyConfig};
+pub use raft::{
+    ClusterConfig, ClusterStatus, Members, MemoryNetwork, Message, NodeId, RaftNode, Role,
+    TcpTransport, Transport,
+};
+pub use admin::{AdminCommand, ClientInfo, ServerConfig, ServerStats};
+pub use common::Reply;
+pub use server::{KvsServer, Limits};
+pub use metrics::Metrics;
+pub use shutdown::ShutdownHandle;
+pub use pubsub::ChannelMessage;
+pub use tls::{TlsClientConfig, TlsServerConfig};
+pub use transport::{Address, ToAddress};
+pub use auth::{hash_password, hash_token, AuthConfig, Credentials};
+pub use async_server::AsyncKvsServer;
+pub use engines::{
+    EngineStats, Event, KvStore, KvsEngine, Op, SetCondition, SledKvsEngine, Watcher,
+};
\ No newline at end of file
@@ -0,0 +1,444 @@
+//! A memcached compatible front end speaking the text protocol
+//!
+//! The values are stored in `KvsEngine` as they are, so they must be valid
+//! UTF-8 and are shared with the clients of the other protocols. The flags,
+//! expiration times and CAS uniques of the items live in the memory of the
+//! server: they are lost on restart, after which the items read back with
+//! flags `0` and no expiry.
+//!
+//! The expiry only applies to the memcached clients. An expired item is removed
+//! from the engine the next time a command touches it, or by the sweep the
+//! writes run every `SWEEP_INTERVAL`, so the clients of the other protocols may
+//! read it until then. The server keeps the metadata of `MAX_ITEMS` items at
+//! most: past it, the items with no flags nor expiry are forgotten, which only
+//! gives them a new CAS unique, and the writes of other items fail with
+//! `SERVER_ERROR` if there is still no room.
+//!
+//! The commands changing an item run one at a time, so `cas`, `incr` and `decr`
+//! are atomic with respect to other memcached clients. A change made through
+//! another protocol gives the item a new CAS unique the next time it is read.
+use crate::engines::{KvsEngine, SetCondition};
+use crate::transport::Stream;
+use crate::{KvsError, Result};
+use log::debug;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const MAX_KEY_LEN: usize = 250;
+const MAX_LINE_LEN: u64 = 2048;
+const MAX_VALUE_LEN: usize = 1024 * 1024;
+// expiration times larger than this are unix timestamps rather than offsets
+const MAX_RELATIVE_EXPTIME: i64 = 60 * 60 * 24 * 30;
+/// The number of items whose metadata the server keeps
+const MAX_ITEMS: usize = 1 << 20;
+/// How often the writes remove the expired items
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The reply to a connection beyond the limit of the server, as sent by memcached
+pub const OVERLOADED: &[u8] = b"SERVER_ERROR too many open connections\r\n";
+
+/// The metadata of the items, shared by all the memcached connections
+#[derive(Clone, Default)]
+pub struct Items(Arc<Mutex<ItemTable>>);
+
+#[derive(Default)]
+struct ItemTable {
+    items: HashMap<String, Item>,
+    last_cas: u64,
+    next_sweep: Option<Instant>,
+}
+
+#[derive(Clone, Copy)]
+struct Item {
+    flags: u32,
+    cas: u64,
+    deadline: Option<Instant>,
+    // digest of the value the item was stored with, to notice changes made by other protocols
+    digest: u64,
+}
+
+impl ItemTable {
+    /// Removes `key` from the engine if it has expired
+    fn purge<E: KvsEngine>(&mut self, engine: &E, key: &str) -> Result<()> {
+        let expired = match self.items.get(key) {
+            Some(Item {
+                deadline: Some(deadline),
+                ..
+            }) => *deadline <= Instant::now(),
+            _ => false,
+        };
+        if expired {
+            self.items.remove(key);
+            match engine.remove(key.to_owned()) {
+                Ok(()) | Err(KvsError::KeyNotFound) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the expired items, and with `plain` the items with no flags nor expiry
+    fn sweep<E: KvsEngine>(&mut self, engine: &E, plain: bool) -> Result<()> {
+        let now = Instant::now();
+        let expired: Vec<String> = self
+            .items
+            .iter()
+            .filter(|(_, item)| item.deadline.is_some_and(|deadline| deadline <= now))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired {
+            self.purge(engine, &key)?;
+        }
+        if plain {
+            self.items
+                .retain(|_, item| item.flags != 0 || item.deadline.is_some());
+        }
+        Ok(())
+    }
+
+    /// Whether the item of `key` fits in the table, sweeping it if it is full
+    fn has_room<E: KvsEngine>(&mut self, engine: &E, key: &str) -> Result<bool> {
+        if self.items.len() >= MAX_ITEMS && !self.items.contains_key(key) {
+            self.sweep(engine, true)?;
+        }
+        Ok(self.items.len() < MAX_ITEMS || self.items.contains_key(key))
+    }
+
+    /// Sweeps the table if it is time to, and fails if the item of `key` does not fit
+    ///
+    /// It runs before writing `key` to the engine.
+    fn prepare_write<E: KvsEngine>(&mut self, engine: &E, key: &str) -> Result<()> {
+        let now = Instant::now();
+        if self.next_sweep.is_none_or(|next_sweep| next_sweep <= now) {
+            self.sweep(engine, false)?;
+            self.next_sweep = Some(now + SWEEP_INTERVAL);
+        }
+        if !self.has_room(engine, key)? {
+            return Err(KvsError::StringError(
+                "out of memory storing object".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Reads an item, purging it first if it has expired
+    fn get<E: KvsEngine>(&mut self, engine: &E, key: &str) -> Result<Option<(String, Item)>> {
+        self.purge(engine, key)?;
+        let value = match engine.get(key.to_owned())? {
+            Some(value) => value,
+            None => {
+                self.items.remove(key);
+                return Ok(None);
+            }
+        };
+        let digest = digest(&value);
+        match self.items.get(key) {
+            Some(item) if item.digest == digest => Ok(Some((value, *item))),
+            // first seen, or changed by another protocol since
+            _ => {
+                self.last_cas += 1;
+                let item = Item {
+                    flags: 0,
+                    cas: self.last_cas,
+                    deadline: None,
+                    digest,
+                };
+                // without room, the item gets a new CAS unique on each read
+                if self.has_room(engine, key)? {
+                    self.items.insert(key.to_owned(), item);
+                }
+                Ok(Some((value, item)))
+            }
+        }
+    }
+
+    /// Records an item just written to the engine
+    fn stored(&mut self, key: String, value: &str, flags: u32, deadline: Option<Instant>) {
+        self.last_cas += 1;
+        let item = Item {
+            flags,
+            cas: self.last_cas,
+            deadline,
+            digest: digest(value),
+        };
+        self.items.insert(key, item);
+    }
+}
+
+fn digest(value: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Serves a connection speaking the memcached text protocol until the client disconnects
+pub fn serve<E: KvsEngine>(engine: E, stream: Stream, items: Items) -> Result<()> {
+    let peer_addr = stream.peer_addr()?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    loop {
+        let mut line = String::new();
+        if reader.by_ref().take(MAX_LINE_LEN).read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        if !line.ends_with('\n') {
+            writer.write_all(b"CLIENT_ERROR line too long\r\n")?;
+            writer.flush()?;
+            return Ok(());
+        }
+        let args: Vec<&str> = line.split_whitespace().collect();
+        if args.is_empty() {
+            writer.write_all(b"ERROR\r\n")?;
+            writer.flush()?;
+            continue;
+        }
+        debug!("Receive command from {}: {:?}", peer_addr, args);
+
+        let mut out = Vec::new();
+        match execute(&engine, &items, &args, &mut reader, &mut out) {
+            Ok(true) => {}
+            Ok(false) => {
+                writer.write_all(&out)?;
+                writer.flush()?;
+                return Ok(());
+            }
+            Err(KvsError::Protocol(message)) => {
+                write!(out, "CLIENT_ERROR {}\r\n", message)?;
+            }
+            Err(KvsError::Io(e)) => return Err(KvsError::Io(e)),
+            Err(e) => write!(out, "SERVER_ERROR {}\r\n", e)?,
+        }
+        writer.write_all(&out)?;
+        // answer pipelined commands in one go
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+}
+
+/// Runs a command, returning `false` if the connection should be closed
+fn execute<E: KvsEngine, R: BufRead>(
+    engine: &E,
+    items: &Items,
+    args: &[&str],
+    reader: &mut R,
+    out: &mut Vec<u8>,
+) -> Result<bool> {
+    match args[0] {
+        "get" | "gets" if args.len() > 1 => {
+            let mut table = items.0.lock().unwrap();
+            for &key in &args[1..] {
+                check_key(key)?;
+                if let Some((value, item)) = table.get(engine, key)? {
+                    write!(out, "VALUE {} {} {}", key, item.flags, value.len())?;
+                    if args[0] == "gets" {
+                        write!(out, " {}", item.cas)?;
+                    }
+                    write!(out, "\r\n{}\r\n", value)?;
+                }
+            }
+            out.extend_from_slice(b"END\r\n");
+        }
+        "set" | "add" | "replace" | "cas" => {
+            let cas = args[0] == "cas";
+            let (fixed, noreply) = split_noreply(&args[1..]);
+            if fixed.len() != if cas { 5 } else { 4 } {
+                out.extend_from_slice(b"ERROR\r\n");
+                return Ok(true);
+            }
+            let key = fixed[0];
+            let len: usize = match fixed[3].parse() {
+                Ok(len) => len,
+                // the data block cannot be told apart from the next commands
+                Err(_) => {
+                    out.extend_from_slice(b"CLIENT_ERROR bad data chunk\r\n");
+                    return Ok(false);
+                }
+            };
+            if len > MAX_VALUE_LEN {
+                skip_data(reader, len)?;
+                return Err(protocol_error("object too large for cache"));
+            }
+            let (flags, exptime): (u32, i64) = match (parse(fixed[1]), parse(fixed[2])) {
+                (Ok(flags), Ok(exptime)) => (flags, exptime),
+                (Err(e), _) | (_, Err(e)) => {
+                    skip_data(reader, len)?;
+                    return Err(e);
+                }
+            };
+            let deadline = deadline(exptime);
+            let value = read_data(reader, len)?;
+            check_key(key)?;
+
+            let reply = {
+                let mut table = items.0.lock().unwrap();
+                table.purge(engine, key)?;
+                table.prepare_write(engine, key)?;
+                let stored = match args[0] {
+                    "set" => {
+                        engine.set(key.to_owned(), value.clone())?;
+                        "STORED"
+                    }
+                    "add" | "replace" => {
+                        let condition = if args[0] == "add" {
+                            SetCondition::Absent
+                        } else {
+                            SetCondition::Present
+                        };
+                        if engine.set_if(key.to_owned(), value.clone(), condition)? {
+                            "STORED"
+                        } else {
+                            "NOT_STORED"
+                        }
+                    }
+                    _ => {
+                        let unique: u64 = parse(fixed[4])?;
+                        match table.get(engine, key)? {
+                            None => "NOT_FOUND",
+                            Some((_, item)) if item.cas != unique => "EXISTS",
+                            Some(_) => {
+                                engine.set(key.to_owned(), value.clone())?;
+                                "STORED"
+                            }
+                        }
+                    }
+                };
+                if stored == "STORED" {
+                    // an exptime in the past stores an item that is expired at once
+                    table.stored(key.to_owned(), &value, flags, deadline);
+                }
+                stored
+            };
+            if !noreply {
+                write!(out, "{}\r\n", reply)?;
+            }
+        }
+        "delete" => {
+            let (fixed, noreply) = split_noreply(&args[1..]);
+            // memcached before 1.4 accepted a hold time of 0
+            if fixed.is_empty() || fixed.len() > 2 || fixed.get(1).is_some_and(|&t| t != "0") {
+                out.extend_from_slice(b"ERROR\r\n");
+                return Ok(true);
+            }
+            check_key(fixed[0])?;
+            let reply = {
+                let mut table = items.0.lock().unwrap();
+                table.purge(engine, fixed[0])?;
+                table.items.remove(fixed[0]);
+                match engine.remove(fixed[0].to_owned()) {
+                    Ok(()) => "DELETED",
+                    Err(KvsError::KeyNotFound) => "NOT_FOUND",
+                    Err(e) => return Err(e),
+                }
+            };
+            if !noreply {
+                write!(out, "{}\r\n", reply)?;
+            }
+        }
+        "incr" | "decr" => {
+            let (fixed, noreply) = split_noreply(&args[1..]);
+            if fixed.len() != 2 {
+                out.extend_from_slice(b"ERROR\r\n");
+                return Ok(true);
+            }
+            let key = fixed[0];
+            check_key(key)?;
+            let delta: u64 = fixed[1]
+                .parse()
+                .map_err(|_| protocol_error("invalid numeric delta argument"))?;
+
+            let reply = {
+                let mut table = items.0.lock().unwrap();
+                match table.get(engine, key)? {
+                    None => "NOT_FOUND".to_owned(),
+                    Some((value, item)) => {
+                        let (flags, deadline) = (item.flags, item.deadline);
+                        table.prepare_write(engine, key)?;
+                        let current: u64 = value.parse().map_err(|_| {
+                            protocol_error("cannot increment or decrement non-numeric value")
+                        })?;
+                        // incr wraps around at 2^64 and decr stops at 0, like memcached
+                        let value = if args[0] == "incr" {
+                            current.wrapping_add(delta)
+                        } else {
+                            current.saturating_sub(delta)
+                        }
+                        .to_string();
+                        engine.set(key.to_owned(), value.clone())?;
+                        table.stored(key.to_owned(), &value, flags, deadline);
+                        value
+                    }
+                }
+            };
+            if !noreply {
+                write!(out, "{}\r\n", reply)?;
+            }
+        }
+        "version" => write!(out, "VERSION {}\r\n", env!("CARGO_PKG_VERSION"))?,
+        "quit" => return Ok(false),
+        _ => out.extend_from_slice(b"ERROR\r\n"),
+    }
+    Ok(true)
+}
+
+fn split_noreply<'a>(args: &'a [&'a str]) -> (&'a [&'a str], bool) {
+    match args.split_last() {
+        Some((&"noreply", fixed)) => (fixed, true),
+        _ => (args, false),
+    }
+}
+
+fn check_key(key: &str) -> Result<()> {
+    if key.len() > MAX_KEY_LEN || key.chars().any(char::is_control) {
+        return Err(protocol_error("invalid key"));
+    }
+    Ok(())
+}
+
+fn parse<T: std::str::FromStr>(arg: &str) -> Result<T> {
+    arg.parse()
+        .map_err(|_| protocol_error("bad command line format"))
+}
+
+/// Turns an exptime into a deadline, following the rules of memcached
+fn deadline(exptime: i64) -> Option<Instant> {
+    let now = Instant::now();
+    if exptime == 0 {
+        None
+    } else if exptime < 0 {
+        Some(now)
+    } else if exptime <= MAX_RELATIVE_EXPTIME {
+        Some(now + Duration::from_secs(exptime as u64))
+    } else {
+        let unix_now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64;
+        Some(now + Duration::from_secs((exptime - unix_now).max(0) as u64))
+    }
+}
+
+/// Reads the data block following a storage command
+fn read_data<R: BufRead>(reader: &mut R, len: usize) -> Result<String> {
+    let mut data = vec![0; len + 2];
+    reader.read_exact(&mut data)?;
+    if !data.ends_with(b"\r\n") {
+        return Err(protocol_error("bad data chunk"));
+    }
+    data.truncate(len);
+    String::from_utf8(data).map_err(|_| protocol_error("bad data chunk"))
+}
+
+/// Skips the data block of a storage command rejected before reading it
+fn skip_data<R: BufRead>(reader: &mut R, len: usize) -> Result<()> {
+    io::copy(&mut reader.take(len as u64 + 2), &mut io::sink())?;
+    Ok(())
+}
+
+fn protocol_error(message: &str) -> KvsError {
+    KvsError::Protocol(message.to_owned())
+}
@@ -0,0 +1,344 @@
+//! Metrics of a server, exposed in the Prometheus text format
+//!
+//! * `kvs_requests_total{op}` and `kvs_request_duration_seconds{op}`, the
+//!   requests of the kvs protocol and the time taken to answer them
+//! * `kvs_errors_total{code}`, the requests answered with an error
+//! * `kvs_connections`, the connections open, whatever the protocol
+//! * `kvs_pool_queued_jobs{pool}`, `kvs_pool_busy_threads{pool}` and
+//!   `kvs_pool_panics_total{pool}`, the thread pool of each listener
+//! * `kvs_keys`, `kvs_disk_bytes`, `kvs_garbage_bytes`, `kvs_compactions_total`
+//!   and the other statistics of the engine, read when scraped
+use crate::common::Response;
+use crate::engines::{EngineStats, KvsEngine};
+use crate::http;
+use crate::thread_pool::{PoolStats, ThreadPool};
+use crate::transport::ToAddress;
+use crate::Result;
+use log::{error, info, warn};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The upper bounds of the buckets of the latency histograms, in seconds
+const BUCKETS: [f64; 12] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5, 1.0,
+];
+
+type PoolSource = Box<dyn Fn() -> Option<PoolStats> + Send>;
+type EngineSource = Box<dyn Fn() -> Result<EngineStats> + Send>;
+
+/// The metrics of the servers sharing it, see `KvsServer::with_metrics`
+#[derive(Clone, Default)]
+pub struct Metrics(Arc<Registry>);
+
+#[derive(Default)]
+struct Registry {
+    requests: RwLock<BTreeMap<&'static str, Arc<Histogram>>>,
+    errors: Mutex<BTreeMap<String, u64>>,
+    connections: AtomicUsize,
+    pools: Mutex<Vec<(String, PoolSource)>>,
+    engine: Mutex<Option<EngineSource>>,
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [AtomicU64; BUCKETS.len()],
+    count: AtomicU64,
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        if let Some(i) = BUCKETS.iter().position(|&bound| seconds <= bound) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    /// Creates a registry with no metric recorded yet
+    pub fn new() -> Self {
+        Metrics::default()
+    }
+
+    /// Renders the metrics in the Prometheus text format
+    pub fn render(&self) -> String {
+        let mut body = String::new();
+        self.render_requests(&mut body);
+
+        let errors = self.0.errors.lock().unwrap();
+        header(
+            &mut body,
+            "kvs_errors_total",
+            "counter",
+            "Requests answered with an error, by code",
+        );
+        for (code, count) in errors.iter() {
+            let _ = writeln!(body, "kvs_errors_total{{code=\"{}\"}} {}", code, count);
+        }
+        drop(errors);
+
+        header(&mut body, "kvs_connections", "gauge", "Connections open");
+        let connections = self.0.connections.load(Ordering::Relaxed);
+        let _ = writeln!(body, "kvs_connections {}", connections);
+
+        self.render_pools(&mut body);
+
+        let engine = self.0.engine.lock().unwrap();
+        if let Some(stats) = engine.as_ref().map(|stats| stats()) {
+            match stats {
+                Ok(stats) => write_engine_stats(&mut body, &stats),
+                Err(e) => warn!("Failed to read the statistics of the engine: {}", e),
+            }
+        }
+        body
+    }
+
+    /// Serves the metrics over HTTP at `GET /metrics` on `addr`
+    ///
+    /// It never returns unless it fails to listen. Each connection is served
+    /// on a thread of its own.
+    pub fn serve<A: ToAddress>(&self, addr: A) -> Result<()> {
+        let addr = addr.to_address()?;
+        let listener = addr.bind()?;
+        info!("Serving the metrics on {}", addr);
+        loop {
+            let stream = match listener.accept() {
+                Ok(stream) => stream,
+                Err(err) => {
+                    error!("Connection failed: {}", err);
+                    continue;
+                }
+            };
+            let metrics = self.clone();
+            thread::spawn(move || {
+                if let Err(e) = http::serve_metrics(&metrics, stream) {
+                    error!("Error on serving the metrics: {}", e);
+                }
+            });
+        }
+    }
+
+    /// Starts timing a request of kind `op`
+    pub(crate) fn start(&self, op: &'static str) -> RequestTimer {
+        RequestTimer {
+            metrics: self.clone(),
+            op,
+            started: Instant::now(),
+        }
+    }
+
+    /// Counts a connection as open until the returned guard is dropped
+    pub(crate) fn connect(&self) -> ConnectionGauge {
+        self.0.connections.fetch_add(1, Ordering::Relaxed);
+        ConnectionGauge(self.clone())
+    }
+
+    /// Reports the statistics of `pool` as `name` for as long as it lives
+    pub(crate) fn add_pool<P>(&self, name: String, pool: Weak<P>)
+    where
+        P: ThreadPool + Send + Sync + 'static,
+    {
+        let stats = Box::new(move || pool.upgrade().map(|pool| pool.stats()));
+        self.0.pools.lock().unwrap().push((name, stats));
+    }
+
+    /// Reports the statistics of `engine`, in place of any engine added before
+    pub(crate) fn set_engine<E: KvsEngine>(&self, engine: E) {
+        *self.0.engine.lock().unwrap() = Some(Box::new(move || engine.stats()));
+    }
+
+    fn record(&self, op: &'static str, resp: &Response, elapsed: Duration) {
+        let histogram = self.0.requests.read().unwrap().get(op).cloned();
+        let histogram = match histogram {
+            Some(histogram) => histogram,
+            None => {
+                let mut requests = self.0.requests.write().unwrap();
+                Arc::clone(requests.entry(op).or_default())
+            }
+        };
+        histogram.observe(elapsed);
+        if let Response::Err { code, .. } = resp {
+            let mut errors = self.0.errors.lock().unwrap();
+            *errors.entry(format!("{:?}", code)).or_default() += 1;
+        }
+    }
+
+    fn render_requests(&self, body: &mut String) {
+        let requests = self.0.requests.read().unwrap();
+        header(
+            body,
+            "kvs_requests_total",
+            "counter",
+            "Requests received, by operation",
+        );
+        for (op, histogram) in requests.iter() {
+            let count = histogram.count.load(Ordering::Relaxed);
+            let _ = writeln!(body, "kvs_requests_total{{op=\"{}\"}} {}", op, count);
+        }
+
+        header(
+            body,
+            "kvs_request_duration_seconds",
+            "histogram",
+            "Time taken to answer the requests, by operation",
+        );
+        for (op, histogram) in requests.iter() {
+            let name = "kvs_request_duration_seconds";
+            let mut cumulative = 0;
+            for (bound, bucket) in BUCKETS.iter().zip(&histogram.buckets) {
+                cumulative += bucket.load(Ordering::Relaxed);
+                let _ = writeln!(
+                    body,
+                    "{}_bucket{{op=\"{}\",le=\"{}\"}} {}",
+                    name, op, bound, cumulative
+                );
+            }
+            let count = histogram.count.load(Ordering::Relaxed);
+            let sum = histogram.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9;
+            let _ = writeln!(
+                body,
+                "{}_bucket{{op=\"{}\",le=\"+Inf\"}} {}",
+                name, op, count
+            );
+            let _ = writeln!(body, "{}_sum{{op=\"{}\"}} {}", name, op, sum);
+            let _ = writeln!(body, "{}_count{{op=\"{}\"}} {}", name, op, count);
+        }
+    }
+
+    fn render_pools(&self, body: &mut String) {
+        let mut pools = self.0.pools.lock().unwrap();
+        let mut stats = Vec::new();
+        // the pools of the servers stopped are forgotten
+        pools.retain(|(name, source)| match source() {
+            Some(pool) => {
+                stats.push((name.clone(), pool));
+                true
+            }
+            None => false,
+        });
+        drop(pools);
+
+        let mut metric = |name: &str, kind: &str, help: &str, value: fn(&PoolStats) -> u64| {
+            header(body, name, kind, help);
+            for (pool, stats) in &stats {
+                let _ = writeln!(body, "{}{{pool=\"{}\"}} {}", name, pool, value(stats));
+            }
+        };
+        metric(
+            "kvs_pool_queued_jobs",
+            "gauge",
+            "Jobs waiting for a thread of the pool",
+            |stats| stats.queued as u64,
+        );
+        metric(
+            "kvs_pool_busy_threads",
+            "gauge",
+            "Threads of the pool running a job",
+            |stats| stats.busy as u64,
+        );
+        metric(
+            "kvs_pool_panics_total",
+            "counter",
+            "Jobs of the pool that panicked",
+            |stats| stats.panics,
+        );
+    }
+}
+
+/// Times a request until its response is sent
+pub(crate) struct RequestTimer {
+    metrics: Metrics,
+    op: &'static str,
+    started: Instant,
+}
+
+impl RequestTimer {
+    /// Records the request as answered with `resp`
+    pub(crate) fn finish(self, resp: &Response) {
+        let elapsed = self.started.elapsed();
+        self.metrics.record(self.op, resp, elapsed);
+    }
+}
+
+/// Keeps a connection counted as open
+pub(crate) struct ConnectionGauge(Metrics);
+
+impl Drop for ConnectionGauge {
+    fn drop(&mut self) {
+        (self.0).0.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn header(body: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = write!(
+        body,
+        "# HELP {name} {help}\n# TYPE {name} {kind}\n",
+        name = name,
+        kind = kind,
+        help = help
+    );
+}
+
+/// Writes the statistics of an engine as metrics
+pub(crate) fn write_engine_stats(body: &mut String, stats: &EngineStats) {
+    let mut metric = |name: &str, kind: &str, help: &str, value: f64| {
+        header(body, name, kind, help);
+        let _ = writeln!(body, "{} {}", name, value);
+    };
+    metric(
+        "kvs_keys",
+        "gauge",
+        "Number of live keys",
+        stats.keys as f64,
+    );
+    metric(
+        "kvs_generations",
+        "gauge",
+        "Number of log files",
+        stats.generations as f64,
+    );
+    metric(
+        "kvs_disk_bytes",
+        "gauge",
+        "Bytes used on disk",
+        stats.disk_bytes as f64,
+    );
+    metric(
+        "kvs_live_bytes",
+        "gauge",
+        "Bytes of live data",
+        stats.live_bytes as f64,
+    );
+    metric(
+        "kvs_garbage_bytes",
+        "gauge",
+        "Bytes of stale data waiting for compaction",
+        stats.garbage_bytes as f64,
+    );
+    metric(
+        "kvs_compactions_total",
+        "counter",
+        "Number of compactions",
+        stats.compactions as f64,
+    );
+    metric(
+        "kvs_compaction_seconds_total",
+        "counter",
+        "Time spent compacting",
+        stats.compaction_time.as_secs_f64(),
+    );
+    metric(
+        "kvs_last_compaction_seconds",
+        "gauge",
+        "Time taken by the latest compaction",
+        stats.last_compaction.map_or(0.0, |last| last.as_secs_f64()),
+    );
+}
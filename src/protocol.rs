@@ -0,0 +1,227 @@
+//! Framing of the binary wire protocol
+//!
+//! Every message is a frame:
+//!
+//! ```text
+//! +------------+--------+----------------+---------------+
+//! | len: u32   | opcode | request id     | payload       |
+//! | big endian | u8     | u32 big endian | len - 5 bytes |
+//! +------------+--------+----------------+---------------+
+//! ```
+//!
+//! The payload is a JSON document whose type depends on the opcode. A client
+//! opens the conversation with a `OP_HELLO` frame carrying a `Hello`, and the
+//! server answers with its own `Hello`, or an `OP_ERROR` frame if it cannot talk
+//! to the client.
+//!
+//! A connection whose first byte starts a JSON value speaks the legacy protocol
+//! instead: back-to-back JSON requests and responses without any framing.
+use crate::{KvsError, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read, Write};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Version of the protocol spoken by this crate
+pub const PROTOCOL_VERSION: u16 = 1;
+/// Oldest protocol version the server still accepts
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+/// Frames longer than this are rejected
+pub const MAX_FRAME_LEN: u32 = 64 * 1024 * 1024;
+/// Features advertised by the server in the handshake
+pub const CAPABILITIES: &[&str] = &[
+    "auth",
+    "cluster",
+    "deadline",
+    "incr",
+    "mget",
+    "ping",
+    "pubsub",
+    "replication",
+    "scan",
+    "stats",
+    "watch",
+];
+
+/// Handshake, the payload is a `Hello`
+pub const OP_HELLO: u8 = 1;
+/// The payload is a `Request`
+pub const OP_REQUEST: u8 = 2;
+/// The payload is the response to the request with the same id
+pub const OP_RESPONSE: u8 = 3;
+/// The payload is an `Event` pushed by the server
+pub const OP_EVENT: u8 = 4;
+/// The payload is an error message about the frame with the same id
+pub const OP_ERROR: u8 = 5;
+/// The payload is a `DeadlineRequest`, answered like `OP_REQUEST`
+pub const OP_DEADLINE: u8 = 6;
+/// The payload is a `Replication` pushed by a leader to a follower
+pub const OP_REPLICATE: u8 = 7;
+/// The payload is a `ChannelMessage` pushed to a subscriber
+pub const OP_MESSAGE: u8 = 8;
+
+// length of the opcode and the request id
+const HEADER_LEN: u32 = 5;
+
+/// Payload of the handshake frames
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Hello {
+    pub version: u16,
+    pub capabilities: Vec<String>,
+}
+
+impl Hello {
+    /// The `Hello` sent by the server
+    pub fn server(client_version: u16) -> Self {
+        Hello {
+            version: client_version.min(PROTOCOL_VERSION),
+            capabilities: CAPABILITIES.iter().map(|&c| c.to_owned()).collect(),
+        }
+    }
+}
+
+/// The beginning of a frame, to decide what to do with the payload before reading it
+#[derive(Debug)]
+pub struct Header {
+    pub len: u32,
+    pub opcode: u8,
+    pub request_id: u32,
+}
+
+impl Header {
+    /// Reads the header of a frame, returning `None` if the stream ends before it
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Option<Header>> {
+        let mut len = [0; 4];
+        match reader.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let len = u32::from_be_bytes(len);
+        if !(HEADER_LEN..=MAX_FRAME_LEN).contains(&len) {
+            return Err(KvsError::Protocol(format!("invalid frame length {}", len)));
+        }
+
+        let mut header = [0; HEADER_LEN as usize];
+        reader.read_exact(&mut header)?;
+        let mut request_id = [0; 4];
+        request_id.copy_from_slice(&header[1..]);
+        Ok(Some(Header {
+            len,
+            opcode: header[0],
+            request_id: u32::from_be_bytes(request_id),
+        }))
+    }
+
+    /// The length of the payload
+    pub fn payload_len(&self) -> u32 {
+        self.len - HEADER_LEN
+    }
+
+    /// Reads the payload following the header
+    pub fn read_payload<R: Read>(self, reader: &mut R) -> Result<Frame> {
+        let mut payload = vec![0; self.payload_len() as usize];
+        reader.read_exact(&mut payload)?;
+        Ok(Frame {
+            opcode: self.opcode,
+            request_id: self.request_id,
+            payload,
+        })
+    }
+
+    /// Reads and drops the payload following the header
+    pub fn skip_payload<R: Read>(self, reader: &mut R) -> Result<()> {
+        let len = u64::from(self.payload_len());
+        if io::copy(&mut reader.take(len), &mut io::sink())? < len {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct Frame {
+    pub opcode: u8,
+    pub request_id: u32,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    /// Creates a frame with `body` encoded as its payload
+    pub fn new<T: Serialize>(opcode: u8, request_id: u32, body: &T) -> Result<Frame> {
+        Ok(Frame {
+            opcode,
+            request_id,
+            payload: serde_json::to_vec(body)?,
+        })
+    }
+
+    /// Decodes the payload
+    pub fn parse<T: DeserializeOwned>(&self) -> Result<T> {
+        Ok(serde_json::from_slice(&self.payload)?)
+    }
+
+    /// Reads a frame, returning `None` if the stream ends before it
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Option<Frame>> {
+        match Header::read_from(reader)? {
+            Some(header) => Ok(Some(header.read_payload(reader)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Reads a frame from an async stream, returning `None` if the stream ends before it
+    pub async fn read_from_async<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<Frame>> {
+        let len = match reader.read_u32().await {
+            Ok(len) => len,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        if !(HEADER_LEN..=MAX_FRAME_LEN).contains(&len) {
+            return Err(KvsError::Protocol(format!("invalid frame length {}", len)));
+        }
+        let opcode = reader.read_u8().await?;
+        let request_id = reader.read_u32().await?;
+        let mut payload = vec![0; (len - HEADER_LEN) as usize];
+        reader.read_exact(&mut payload).await?;
+        Ok(Some(Frame {
+            opcode,
+            request_id,
+            payload,
+        }))
+    }
+
+    /// The frame as written on the wire
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut bytes = Vec::with_capacity(self.payload.len() + 4 + HEADER_LEN as usize);
+        self.write_to(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// Writes the frame without flushing the writer
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let len = self.payload.len() as u32 + HEADER_LEN;
+        if len > MAX_FRAME_LEN {
+            return Err(KvsError::Protocol(format!(
+                "frame of {} bytes is too long",
+                len
+            )));
+        }
+        writer.write_all(&len.to_be_bytes())?;
+        writer.write_all(&[self.opcode])?;
+        writer.write_all(&self.request_id.to_be_bytes())?;
+        writer.write_all(&self.payload)?;
+        Ok(())
+    }
+}
+
+/// Returns `true` if a connection starting with `byte` speaks the legacy JSON protocol
+///
+/// A frame starts with the high byte of its length, which is never larger than
+/// `MAX_FRAME_LEN >> 24`, so it cannot be mistaken for any byte a JSON value
+/// may start with, be it an object, a string such as a unit request, or whitespace.
+pub fn is_legacy(byte: u8) -> bool {
+    matches!(
+        byte,
+        b'{' | b'[' | b'"' | b'-' | b'0'..=b'9' | b't' | b'f' | b'n' | b' ' | b'\t' | b'\n' | b'\r'
+    )
+}
@@ -0,0 +1,297 @@
+//! A proxy spreading the keys of its clients over backend servers
+//!
+//! `KvsProxy` is an engine storing the keys on backend `kvs-server`s, so a
+//! `KvsServer` running it speaks the kvs protocol to plain `KvsClient`s. Each
+//! key goes to one backend, over a pool of connections to it, and the requests
+//! on several keys are split between the backends and their answers merged.
+use crate::sharding::{HashRing, DEFAULT_VIRTUAL_NODES};
+use crate::{
+    Address, EngineStats, Event, KvsClientPool, KvsEngine, KvsError, Result, SetCondition, Watcher,
+};
+use crossbeam::channel;
+use log::warn;
+use serde::Deserialize;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+
+/// How a `KvsProxy` routes the keys, loaded from a JSON file
+///
+/// ```json
+/// {
+///   "backends": ["10.0.0.1:4000", "10.0.0.2:4000"],
+///   "virtual_nodes": 160,
+///   "rules": [
+///     { "prefix": "session/", "backends": ["10.0.0.3:4000"] }
+///   ],
+///   "max_idle": 8
+/// }
+/// ```
+///
+/// A key is routed by the rule with the longest prefix it starts with, or by
+/// `backends` if none matches, to the backend owning it on a consistent-hash
+/// ring of the backends of the rule.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProxyConfig {
+    /// The backends of the keys matching no rule
+    pub backends: Vec<Address>,
+    /// The number of points of each backend on the rings
+    #[serde(default = "default_virtual_nodes")]
+    pub virtual_nodes: usize,
+    /// The backends of the keys with some prefixes
+    #[serde(default)]
+    pub rules: Vec<PrefixRule>,
+    /// The idle connections kept to each backend, see [`KvsClientPool::with_max_idle`]
+    #[serde(default)]
+    pub max_idle: Option<usize>,
+}
+
+/// Routes the keys starting with `prefix` to `backends`
+#[derive(Debug, Clone, Deserialize)]
+pub struct PrefixRule {
+    /// The prefix of the keys
+    pub prefix: String,
+    /// The backends of the keys
+    pub backends: Vec<Address>,
+}
+
+fn default_virtual_nodes() -> usize {
+    DEFAULT_VIRTUAL_NODES
+}
+
+impl ProxyConfig {
+    /// The routes to `backends`, without rules
+    pub fn new(backends: Vec<Address>) -> Self {
+        ProxyConfig {
+            backends,
+            virtual_nodes: DEFAULT_VIRTUAL_NODES,
+            rules: Vec::new(),
+            max_idle: None,
+        }
+    }
+
+    /// Loads the routes from a JSON file
+    pub fn load(path: &Path) -> Result<Self> {
+        Ok(serde_json::from_slice(&fs::read(path)?)?)
+    }
+}
+
+/// An engine storing the keys on backend servers, as routed by a `ProxyConfig`
+///
+/// The requests on several keys are split by backend: `set_many` is only
+/// atomic on each backend, and `scan` and `watch` ask all of them. `stats`
+/// adds up the statistics of the backends. Clones share the connections.
+#[derive(Clone)]
+pub struct KvsProxy(Arc<Routes>);
+
+struct Routes {
+    default: HashRing,
+    // longest prefix first
+    rules: Vec<(String, HashRing)>,
+    pools: HashMap<Address, KvsClientPool>,
+}
+
+impl KvsProxy {
+    /// A proxy to the backends of `config`, connecting to them when needed
+    pub fn new(config: ProxyConfig) -> Result<Self> {
+        if config.backends.is_empty() {
+            return Err(KvsError::StringError(
+                "the proxy needs a backend".to_owned(),
+            ));
+        }
+        let mut pools = HashMap::new();
+        let all = config
+            .rules
+            .iter()
+            .flat_map(|rule| rule.backends.iter())
+            .chain(&config.backends);
+        for addr in all {
+            let mut pool = KvsClientPool::new(vec![addr.clone()]);
+            if let Some(max_idle) = config.max_idle {
+                pool = pool.with_max_idle(max_idle);
+            }
+            pools.entry(addr.clone()).or_insert(pool);
+        }
+        let mut rules = Vec::new();
+        for rule in config.rules {
+            if rule.backends.is_empty() {
+                return Err(KvsError::StringError(format!(
+                    "the rule of prefix {:?} needs a backend",
+                    rule.prefix
+                )));
+            }
+            rules.push((
+                rule.prefix,
+                HashRing::new(rule.backends, config.virtual_nodes),
+            ));
+        }
+        rules.sort_by_key(|(prefix, _)| Reverse(prefix.len()));
+        Ok(KvsProxy(Arc::new(Routes {
+            default: HashRing::new(config.backends, config.virtual_nodes),
+            rules,
+            pools,
+        })))
+    }
+
+    /// The backend of `key`
+    pub fn backend(&self, key: &str) -> &Address {
+        let routes = &*self.0;
+        let ring = routes
+            .rules
+            .iter()
+            .find(|(prefix, _)| key.starts_with(prefix.as_str()))
+            .map_or(&routes.default, |(_, ring)| ring);
+        // the rings are never empty
+        ring.server_for(key).unwrap()
+    }
+
+    fn pool(&self, key: &str) -> &KvsClientPool {
+        &self.0.pools[self.backend(key)]
+    }
+
+    /// Splits `keys` by backend, along with their positions
+    fn split(&self, keys: Vec<String>) -> HashMap<&Address, (Vec<usize>, Vec<String>)> {
+        let mut by_backend: HashMap<&Address, (Vec<usize>, Vec<String>)> = HashMap::new();
+        for (position, key) in keys.into_iter().enumerate() {
+            let (positions, keys) = by_backend.entry(self.backend(&key)).or_default();
+            positions.push(position);
+            keys.push(key);
+        }
+        by_backend
+    }
+}
+
+impl KvsEngine for KvsProxy {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.pool(&key).set(key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.pool(&key).get(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.pool(&key).rm(key)
+    }
+
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let mut values = vec![None; keys.len()];
+        for (addr, (positions, keys)) in self.split(keys) {
+            let found = self.0.pools[addr].get_many(keys)?;
+            for (position, value) in positions.into_iter().zip(found) {
+                values[position] = value;
+            }
+        }
+        Ok(values)
+    }
+
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        let mut by_backend: HashMap<&Address, Vec<(String, String)>> = HashMap::new();
+        for (key, value) in pairs {
+            by_backend
+                .entry(self.backend(&key))
+                .or_default()
+                .push((key, value));
+        }
+        for (addr, pairs) in by_backend {
+            self.0.pools[addr].set_many(pairs)?;
+        }
+        Ok(())
+    }
+
+    fn set_if(&self, _key: String, _value: String, _condition: SetCondition) -> Result<bool> {
+        Err(KvsError::BadRequest(
+            "conditional sets are not supported by the proxy".to_owned(),
+        ))
+    }
+
+    fn scan(&self, prefix: String, after: Option<String>, limit: usize) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        for pool in self.0.pools.values() {
+            keys.extend(pool.scan(prefix.clone(), after.clone(), limit)?);
+        }
+        // each backend lists its first keys, so the first ones overall are among them
+        keys.sort();
+        keys.dedup();
+        keys.truncate(limit);
+        Ok(keys)
+    }
+
+    fn incr(&self, key: String, delta: i64) -> Result<i64> {
+        self.pool(&key).incr(key, delta)
+    }
+
+    fn decr(&self, key: String, delta: i64) -> Result<i64> {
+        self.pool(&key).decr(key, delta)
+    }
+
+    fn append(&self, key: String, suffix: String) -> Result<u64> {
+        self.pool(&key).append(key, suffix)
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        let mut total = EngineStats::default();
+        for pool in self.0.pools.values() {
+            let stats = pool.stats()?;
+            total.keys += stats.keys;
+            total.generations += stats.generations;
+            total.disk_bytes += stats.disk_bytes;
+            total.live_bytes += stats.live_bytes;
+            total.garbage_bytes += stats.garbage_bytes;
+            total.compactions += stats.compactions;
+            total.compaction_time += stats.compaction_time;
+            total.last_compaction = total.last_compaction.max(stats.last_compaction);
+        }
+        if total.disk_bytes > 0 {
+            total.garbage_ratio = total.garbage_bytes as f64 / total.disk_bytes as f64;
+        }
+        Ok(total)
+    }
+
+    /// Merges the changes of all the backends, numbered again in the order they arrive
+    ///
+    /// The watcher ends as soon as the stream of a backend fails.
+    fn watch(&self, prefix: String) -> Result<Watcher> {
+        let (tx, rx) = channel::unbounded();
+        let mut streams = Vec::new();
+        for (addr, pool) in &self.0.pools {
+            streams.push((addr.clone(), pool.watch(prefix.clone())?));
+        }
+        for (addr, stream) in streams {
+            let tx = tx.clone();
+            thread::spawn(move || {
+                for event in stream {
+                    let result = event.map(|event| tx.send(Some(event)));
+                    match result {
+                        Ok(Ok(())) => {}
+                        // the watcher is dropped
+                        Ok(Err(_)) => return,
+                        Err(e) => {
+                            warn!("Watching {} failed: {}", addr, e);
+                            break;
+                        }
+                    }
+                }
+                let _ = tx.send(None);
+            });
+        }
+        let (events_tx, events) = channel::unbounded();
+        thread::spawn(move || {
+            let events = rx.into_iter().map_while(|event: Option<Event>| event);
+            for (event, seq) in events.zip(1..) {
+                if events_tx.send(Event { seq, ..event }).is_err() {
+                    return;
+                }
+            }
+        });
+        Ok(Watcher::new(events))
+    }
+
+    fn flush(&self) -> Result<()> {
+        // the backends flush their own engines
+        Ok(())
+    }
+}
@@ -0,0 +1,142 @@
+//! Publish/subscribe channels
+//!
+//! A connection subscribing to some channels, or to the channels matching
+//! some patterns, becomes a stream of the messages published on them. The
+//! messages are not stored: a message reaches the connections subscribed when
+//! it is published, and only them. The channels are separate from the keys and
+//! are not replicated.
+//!
+//! The patterns are globs, as in `SCAN`.
+use crate::glob::glob_match;
+use crate::{KvsError, Result};
+use crossbeam::channel::{self, Receiver, RecvTimeoutError, Sender, TrySendError};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The number of messages a subscriber may lag behind before it is dropped
+const MAX_BACKLOG: usize = 1024;
+
+/// A message published on a channel, as pushed to a subscriber
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChannelMessage {
+    /// The channel the message is published on
+    pub channel: String,
+    /// The pattern the channel matches, if not subscribed by name
+    pub pattern: Option<String>,
+    /// The message
+    pub message: String,
+}
+
+impl fmt::Display for ChannelMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.channel, self.message)
+    }
+}
+
+/// The subscriptions of the connections of a server
+#[derive(Clone, Default)]
+pub(crate) struct Channels(Arc<Mutex<Vec<Subscriber>>>);
+
+struct Subscriber {
+    channels: Vec<String>,
+    patterns: Vec<String>,
+    tx: Sender<ChannelMessage>,
+    gone: Arc<AtomicBool>,
+}
+
+impl Subscriber {
+    /// The message to push to the subscriber, if it listens to `channel`
+    fn message_for(&self, channel: &str, message: &str) -> Option<ChannelMessage> {
+        let pattern = if self.channels.iter().any(|c| c == channel) {
+            None
+        } else {
+            let pattern = self
+                .patterns
+                .iter()
+                .find(|p| glob_match(p.as_bytes(), channel.as_bytes()))?;
+            Some(pattern.clone())
+        };
+        Some(ChannelMessage {
+            channel: channel.to_owned(),
+            pattern,
+            message: message.to_owned(),
+        })
+    }
+
+    /// Whether the connection of the subscriber is closed
+    fn is_gone(&self) -> bool {
+        self.gone.load(Ordering::Relaxed)
+    }
+}
+
+/// The messages pushed to a subscriber, which unsubscribes once dropped
+pub(crate) struct Inbox {
+    rx: Receiver<ChannelMessage>,
+    gone: Arc<AtomicBool>,
+}
+
+impl Inbox {
+    /// Waits at most `timeout` for the next message
+    pub(crate) fn recv_timeout(
+        &self,
+        timeout: Duration,
+    ) -> std::result::Result<ChannelMessage, RecvTimeoutError> {
+        self.rx.recv_timeout(timeout)
+    }
+}
+
+impl Drop for Inbox {
+    fn drop(&mut self) {
+        self.gone.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Channels {
+    /// Subscribes to `channels` and to the channels matching `patterns`
+    ///
+    /// The messages end once the subscriber lags `MAX_BACKLOG` messages behind.
+    pub(crate) fn subscribe(&self, channels: Vec<String>, patterns: Vec<String>) -> Result<Inbox> {
+        if channels.is_empty() && patterns.is_empty() {
+            return Err(KvsError::BadRequest(
+                "subscribe needs a channel or a pattern".to_owned(),
+            ));
+        }
+        let (tx, rx) = channel::bounded(MAX_BACKLOG);
+        let gone = Arc::new(AtomicBool::new(false));
+        self.0.lock().unwrap().push(Subscriber {
+            channels,
+            patterns,
+            tx,
+            gone: Arc::clone(&gone),
+        });
+        Ok(Inbox { rx, gone })
+    }
+
+    /// Pushes `message` to the subscribers of `channel`, returning how many there are
+    ///
+    /// A subscriber listening to the channel in several ways gets the message once.
+    pub(crate) fn publish(&self, channel: &str, message: &str) -> u64 {
+        let mut receivers = 0;
+        self.0.lock().unwrap().retain(|subscriber| {
+            if subscriber.is_gone() {
+                return false;
+            }
+            let message = match subscriber.message_for(channel, message) {
+                Some(message) => message,
+                None => return true,
+            };
+            match subscriber.tx.try_send(message) {
+                Ok(()) => {
+                    receivers += 1;
+                    true
+                }
+                // the connection is closed, or too slow to keep
+                Err(TrySendError::Disconnected(_)) | Err(TrySendError::Full(_)) => false,
+            }
+        });
+        receivers
+    }
+}
@@ -0,0 +1,753 @@
+//! The Raft consensus algorithm, without any I/O
+//!
+//! `Raft` is driven by the node with `tick`, `step`, `propose` and `read`.
+//! What it wants done in return is left in its fields for the node to collect:
+//! the messages to send, the log to persist, the snapshot to restore and the
+//! reads that may be served.
+//!
+//! Membership changes add or remove one node at a time, and take effect as
+//! soon as they are appended to the log. A new one is only accepted once the
+//! previous one is committed.
+use super::{Body, Entry, EntryData, Members, Message, NodeId, Role, Snapshot};
+use crate::KvsError;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The most entries sent in one `AppendEntries`
+const MAX_BATCH: usize = 256;
+
+/// The log of a node, starting after its snapshot
+pub(crate) struct Log {
+    snapshot: Arc<Snapshot>,
+    entries: Vec<Entry>,
+    /// The entries up to this index are persisted
+    pub(crate) stable: u64,
+    /// Whether the persisted log must be written again, after a truncation or a compaction
+    pub(crate) rewrite: bool,
+}
+
+impl Log {
+    /// A log loaded from the disk
+    pub(crate) fn new(snapshot: Snapshot, entries: Vec<Entry>) -> Self {
+        let mut log = Log {
+            snapshot: Arc::new(snapshot),
+            entries,
+            stable: 0,
+            rewrite: false,
+        };
+        log.stable = log.last_index();
+        log
+    }
+
+    pub(crate) fn snapshot(&self) -> &Arc<Snapshot> {
+        &self.snapshot
+    }
+
+    pub(crate) fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /// The entries not persisted yet
+    pub(crate) fn unstable(&self) -> &[Entry] {
+        let stable = self.stable.clamp(self.snapshot.index, self.last_index());
+        &self.entries[(stable - self.snapshot.index) as usize..]
+    }
+
+    pub(crate) fn last_index(&self) -> u64 {
+        self.snapshot.index + self.entries.len() as u64
+    }
+
+    fn last_term(&self) -> u64 {
+        self.entries.last().map_or(self.snapshot.term, |e| e.term)
+    }
+
+    /// The term of the entry at `index`, unless it is compacted or missing
+    pub(crate) fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot.index {
+            return Some(self.snapshot.term);
+        }
+        self.entry(index).map(|e| e.term)
+    }
+
+    pub(crate) fn entry(&self, index: u64) -> Option<&Entry> {
+        if index <= self.snapshot.index {
+            return None;
+        }
+        self.entries.get((index - self.snapshot.index - 1) as usize)
+    }
+
+    fn entries_from(&self, index: u64, max: usize) -> Vec<Entry> {
+        let start = (index - self.snapshot.index - 1) as usize;
+        let end = self.entries.len().min(start + max);
+        self.entries[start..end].to_vec()
+    }
+
+    fn push(&mut self, entry: Entry) {
+        debug_assert_eq!(entry.index, self.last_index() + 1);
+        self.entries.push(entry);
+    }
+
+    /// Removes the entries from `index` on
+    fn truncate_from(&mut self, index: u64) {
+        self.entries
+            .truncate((index - self.snapshot.index - 1) as usize);
+        if self.stable >= index {
+            self.stable = index - 1;
+            self.rewrite = true;
+        }
+    }
+
+    /// Replaces the entries up to the index of `snapshot` with it
+    fn compact(&mut self, snapshot: Snapshot) {
+        let len = (snapshot.index - self.snapshot.index) as usize;
+        self.entries.drain(..len);
+        self.snapshot = Arc::new(snapshot);
+        self.rewrite = true;
+    }
+
+    /// Installs a snapshot received from the leader
+    ///
+    /// The entries following it are kept if they agree with it.
+    fn install(&mut self, snapshot: Snapshot) {
+        if self.term_at(snapshot.index) == Some(snapshot.term) {
+            self.compact(snapshot);
+        } else {
+            self.entries.clear();
+            self.snapshot = Arc::new(snapshot);
+            self.rewrite = true;
+        }
+    }
+
+    /// The latest members in the log, and the index of the entry setting them
+    fn latest_members(&self) -> (u64, &Members) {
+        self.members_at(self.last_index())
+    }
+
+    /// The members once the entries up to `index` are applied
+    pub(crate) fn members_at(&self, index: u64) -> (u64, &Members) {
+        self.entries
+            .iter()
+            .rev()
+            .filter(|e| e.index <= index)
+            .find_map(|e| match &e.data {
+                EntryData::Members(members) => Some((e.index, members)),
+                _ => None,
+            })
+            .unwrap_or((self.snapshot.index, &self.snapshot.members))
+    }
+}
+
+/// What the leader knows about a follower
+struct Progress {
+    /// The next entry to send
+    next: u64,
+    /// The last entry known to match the leader
+    matched: u64,
+    /// The latest read sequence number echoed back
+    read_seq: u64,
+    /// Whether it answered since the last quorum check
+    active: bool,
+}
+
+/// A read waiting for a majority to confirm the leadership
+struct PendingRead {
+    id: u64,
+    seq: u64,
+}
+
+/// The durable state of a node
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct HardState {
+    pub(crate) term: u64,
+    pub(crate) voted_for: Option<NodeId>,
+}
+
+pub(crate) struct Raft {
+    id: NodeId,
+    pub(crate) term: u64,
+    voted_for: Option<NodeId>,
+    pub(crate) role: Role,
+    pub(crate) leader: Option<NodeId>,
+    pub(crate) log: Log,
+    pub(crate) commit: u64,
+    /// The latest members in the log, committed or not
+    pub(crate) members: Members,
+    votes: HashSet<NodeId>,
+    progress: HashMap<NodeId, Progress>,
+    heartbeat_ticks: u32,
+    election_ticks: u32,
+    heartbeat_elapsed: u32,
+    election_elapsed: u32,
+    randomized_election_ticks: u32,
+    read_seq: u64,
+    reads: VecDeque<PendingRead>,
+    rng: u64,
+    /// The messages to send once the state is persisted
+    pub(crate) messages: Vec<Message>,
+    /// The reads that may be served once the entries up to the index are applied
+    pub(crate) ready_reads: Vec<(u64, u64)>,
+    /// The reads to fail, the node having lost its leadership
+    pub(crate) dropped_reads: Vec<u64>,
+    /// A snapshot received from the leader, to restore into the engine
+    pub(crate) restore: Option<Arc<Snapshot>>,
+}
+
+impl Raft {
+    pub(crate) fn new(
+        id: NodeId,
+        hard_state: HardState,
+        log: Log,
+        heartbeat_ticks: u32,
+        election_ticks: u32,
+    ) -> Self {
+        let members = log.latest_members().1.clone();
+        let commit = log.snapshot().index;
+        let mut seed = [0; 8];
+        if getrandom::getrandom(&mut seed).is_err() {
+            let nanos = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.subsec_nanos());
+            seed = (u64::from(nanos) ^ id).to_le_bytes();
+        }
+        let mut raft = Raft {
+            id,
+            term: hard_state.term,
+            voted_for: hard_state.voted_for,
+            role: Role::Follower,
+            leader: None,
+            log,
+            commit,
+            members,
+            votes: HashSet::new(),
+            progress: HashMap::new(),
+            heartbeat_ticks: heartbeat_ticks.max(1),
+            election_ticks: election_ticks.max(2),
+            heartbeat_elapsed: 0,
+            election_elapsed: 0,
+            randomized_election_ticks: 0,
+            read_seq: 0,
+            reads: VecDeque::new(),
+            // xorshift needs a seed other than 0
+            rng: u64::from_le_bytes(seed) | 1,
+            messages: Vec::new(),
+            ready_reads: Vec::new(),
+            dropped_reads: Vec::new(),
+            restore: None,
+        };
+        raft.reset_election_timer();
+        raft
+    }
+
+    pub(crate) fn hard_state(&self) -> HardState {
+        HardState {
+            term: self.term,
+            voted_for: self.voted_for,
+        }
+    }
+
+    /// Advances the clocks by one tick
+    pub(crate) fn tick(&mut self) {
+        self.election_elapsed += 1;
+        if self.role == Role::Leader {
+            self.heartbeat_elapsed += 1;
+            if self.heartbeat_elapsed >= self.heartbeat_ticks {
+                self.heartbeat_elapsed = 0;
+                self.broadcast_append();
+            }
+            if self.election_elapsed >= self.election_ticks {
+                self.election_elapsed = 0;
+                self.check_quorum();
+            }
+        } else if self.election_elapsed >= self.randomized_election_ticks && self.is_voter() {
+            self.campaign();
+        }
+    }
+
+    /// Handles a message from another node
+    pub(crate) fn step(&mut self, msg: Message) {
+        // a node hearing from its leader ignores the elections, so that a
+        // removed node cannot disrupt the cluster
+        let leased = self.leader.is_some() && self.election_elapsed < self.election_ticks;
+        if matches!(msg.body, Body::RequestVote { .. }) && msg.term > self.term && leased {
+            return;
+        }
+        if msg.term > self.term {
+            let leader = match msg.body {
+                Body::AppendEntries { .. } | Body::InstallSnapshot { .. } => Some(msg.from),
+                _ => None,
+            };
+            self.become_follower(msg.term, leader);
+        }
+        if msg.term < self.term {
+            // let a stale node learn about the new term
+            match msg.body {
+                Body::RequestVote { .. } => self.send(msg.from, Body::Vote { granted: false }),
+                Body::AppendEntries { .. } | Body::InstallSnapshot { .. } => self.send(
+                    msg.from,
+                    Body::AppendResponse {
+                        success: false,
+                        match_index: self.log.last_index(),
+                        read_seq: 0,
+                    },
+                ),
+                _ => {}
+            }
+            return;
+        }
+        match msg.body {
+            Body::RequestVote {
+                last_index,
+                last_term,
+            } => {
+                let up_to_date =
+                    (last_term, last_index) >= (self.log.last_term(), self.log.last_index());
+                let granted = up_to_date && self.voted_for.is_none_or(|id| id == msg.from);
+                if granted {
+                    self.voted_for = Some(msg.from);
+                    self.election_elapsed = 0;
+                }
+                self.send(msg.from, Body::Vote { granted });
+            }
+            Body::Vote { granted } => {
+                if self.role == Role::Candidate && granted {
+                    self.votes.insert(msg.from);
+                    if self.has_quorum(|id| self.votes.contains(&id)) {
+                        self.become_leader();
+                    }
+                }
+            }
+            Body::AppendEntries {
+                prev_index,
+                prev_term,
+                entries,
+                commit,
+                read_seq,
+            } => {
+                self.follow(msg.from);
+                self.append(msg.from, prev_index, prev_term, entries, commit, read_seq);
+            }
+            Body::InstallSnapshot { snapshot, read_seq } => {
+                self.follow(msg.from);
+                self.install(msg.from, snapshot, read_seq);
+            }
+            Body::AppendResponse {
+                success,
+                match_index,
+                read_seq,
+            } => {
+                if self.role == Role::Leader {
+                    self.append_response(msg.from, success, match_index, read_seq);
+                }
+            }
+        }
+    }
+
+    /// Appends an entry to the log of the leader, returning its index
+    pub(crate) fn propose(&mut self, data: EntryData) -> Result<u64, KvsError> {
+        if self.role != Role::Leader {
+            return Err(self.not_leader());
+        }
+        if let EntryData::Members(members) = &data {
+            if members.is_empty() {
+                return Err(KvsError::BadRequest("a cluster needs a member".to_owned()));
+            }
+            if self.log.latest_members().0 > self.commit {
+                return Err(KvsError::Conflict(
+                    "a membership change is in progress".to_owned(),
+                ));
+            }
+            let changed = members
+                .keys()
+                .chain(self.members.keys())
+                .filter(|id| members.get(id) != self.members.get(id))
+                .collect::<HashSet<_>>();
+            if changed.len() > 1 {
+                return Err(KvsError::BadRequest(
+                    "the members change one at a time".to_owned(),
+                ));
+            }
+        }
+        let index = self.log.last_index() + 1;
+        let data_members = match &data {
+            EntryData::Members(members) => Some(members.clone()),
+            _ => None,
+        };
+        self.log.push(Entry {
+            index,
+            term: self.term,
+            data,
+        });
+        if let Some(members) = data_members {
+            self.set_members(members);
+        }
+        self.maybe_commit();
+        self.broadcast_append();
+        Ok(index)
+    }
+
+    /// Starts a read, to be served once a majority confirms the leadership
+    ///
+    /// The read shows up in `ready_reads` along with the index to apply first,
+    /// or in `dropped_reads` if the leadership is lost meanwhile.
+    pub(crate) fn read(&mut self, id: u64) -> Result<(), KvsError> {
+        if self.role != Role::Leader {
+            return Err(self.not_leader());
+        }
+        self.read_seq += 1;
+        self.reads.push_back(PendingRead {
+            id,
+            seq: self.read_seq,
+        });
+        self.advance_reads();
+        if !self.reads.is_empty() {
+            self.broadcast_append();
+        }
+        Ok(())
+    }
+
+    /// Replaces the entries up to `index`, all applied, with a snapshot of their result
+    pub(crate) fn compact(&mut self, index: u64, data: Vec<(String, String)>) {
+        let term = match self.log.term_at(index) {
+            Some(term) => term,
+            None => return,
+        };
+        let members = self.log.members_at(index).1.clone();
+        self.log.compact(Snapshot {
+            index,
+            term,
+            members,
+            data,
+        });
+    }
+
+    /// The error telling the client where the leader is
+    pub(crate) fn not_leader(&self) -> KvsError {
+        let leader = self
+            .leader
+            .and_then(|id| self.members.get(&id))
+            .map_or_else(|| "unknown".to_owned(), |addr| addr.to_string());
+        KvsError::NotLeader(leader)
+    }
+
+    fn is_voter(&self) -> bool {
+        self.members.contains_key(&self.id)
+    }
+
+    /// Whether the members for which `agrees` holds are a majority
+    fn has_quorum<F: Fn(NodeId) -> bool>(&self, agrees: F) -> bool {
+        let count = self.members.keys().filter(|&&id| agrees(id)).count();
+        count > self.members.len() / 2
+    }
+
+    fn send(&mut self, to: NodeId, body: Body) {
+        self.messages.push(Message {
+            from: self.id,
+            to,
+            term: self.term,
+            body,
+        });
+    }
+
+    fn peers(&self) -> Vec<NodeId> {
+        self.progress.keys().copied().collect()
+    }
+
+    fn reset_election_timer(&mut self) {
+        self.election_elapsed = 0;
+        // xorshift64
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        let jitter = (self.rng % u64::from(self.election_ticks)) as u32;
+        self.randomized_election_ticks = self.election_ticks + jitter;
+    }
+
+    fn campaign(&mut self) {
+        self.term += 1;
+        self.role = Role::Candidate;
+        self.leader = None;
+        self.voted_for = Some(self.id);
+        self.votes = HashSet::new();
+        self.votes.insert(self.id);
+        self.reset_election_timer();
+        if self.has_quorum(|id| self.votes.contains(&id)) {
+            self.become_leader();
+            return;
+        }
+        let body = Body::RequestVote {
+            last_index: self.log.last_index(),
+            last_term: self.log.last_term(),
+        };
+        let peers: Vec<NodeId> = self
+            .members
+            .keys()
+            .copied()
+            .filter(|&id| id != self.id)
+            .collect();
+        for peer in peers {
+            self.send(peer, body.clone());
+        }
+    }
+
+    fn become_follower(&mut self, term: u64, leader: Option<NodeId>) {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+        }
+        self.role = Role::Follower;
+        self.leader = leader;
+        self.progress.clear();
+        self.dropped_reads
+            .extend(self.reads.drain(..).map(|read| read.id));
+        self.reset_election_timer();
+    }
+
+    fn become_leader(&mut self) {
+        self.role = Role::Leader;
+        self.leader = Some(self.id);
+        self.heartbeat_elapsed = 0;
+        self.election_elapsed = 0;
+        self.progress.clear();
+        let members = self.members.clone();
+        self.set_members(members);
+        // commit the entries of the previous terms along with one of this term
+        let index = self.log.last_index() + 1;
+        self.log.push(Entry {
+            index,
+            term: self.term,
+            data: EntryData::Noop,
+        });
+        self.maybe_commit();
+        self.broadcast_append();
+    }
+
+    /// Records the leader of the current term
+    fn follow(&mut self, leader: NodeId) {
+        if self.role != Role::Follower {
+            self.become_follower(self.term, Some(leader));
+        } else {
+            self.leader = Some(leader);
+            self.election_elapsed = 0;
+        }
+    }
+
+    /// Updates the members and, on the leader, the followers tracked
+    fn set_members(&mut self, members: Members) {
+        if self.role == Role::Leader {
+            let next = self.log.last_index() + 1;
+            let me = self.id;
+            self.progress.retain(|id, _| members.contains_key(id));
+            for &id in members.keys().filter(|&&id| id != me) {
+                self.progress.entry(id).or_insert(Progress {
+                    next,
+                    matched: 0,
+                    read_seq: 0,
+                    active: true,
+                });
+            }
+        }
+        self.members = members;
+    }
+
+    fn append(
+        &mut self,
+        leader: NodeId,
+        mut prev_index: u64,
+        mut prev_term: u64,
+        mut entries: Vec<Entry>,
+        commit: u64,
+        read_seq: u64,
+    ) {
+        let snapshot_index = self.log.snapshot().index;
+        if prev_index < snapshot_index {
+            // the entries up to the snapshot are committed, so they match
+            let skip = ((snapshot_index - prev_index) as usize).min(entries.len());
+            entries.drain(..skip);
+            prev_index = snapshot_index;
+            prev_term = self.log.snapshot().term;
+        }
+        if self.log.term_at(prev_index) != Some(prev_term) {
+            let hint = self.log.last_index().min(prev_index.saturating_sub(1));
+            self.send(
+                leader,
+                Body::AppendResponse {
+                    success: false,
+                    match_index: hint,
+                    read_seq,
+                },
+            );
+            return;
+        }
+        let last_new = prev_index + entries.len() as u64;
+        let mut members_changed = false;
+        for entry in entries {
+            match self.log.term_at(entry.index) {
+                Some(term) if term == entry.term => continue,
+                Some(_) => {
+                    self.log.truncate_from(entry.index);
+                    members_changed = true;
+                }
+                None => {}
+            }
+            members_changed |= matches!(entry.data, EntryData::Members(_));
+            self.log.push(entry);
+        }
+        if members_changed {
+            self.members = self.log.latest_members().1.clone();
+        }
+        if commit > self.commit {
+            self.commit = commit.min(last_new).max(self.commit);
+        }
+        self.send(
+            leader,
+            Body::AppendResponse {
+                success: true,
+                match_index: last_new,
+                read_seq,
+            },
+        );
+    }
+
+    fn install(&mut self, leader: NodeId, snapshot: Snapshot, read_seq: u64) {
+        let index = snapshot.index;
+        if index > self.commit {
+            self.log.install(snapshot);
+            self.commit = index;
+            self.members = self.log.latest_members().1.clone();
+            self.restore = Some(Arc::clone(self.log.snapshot()));
+        }
+        self.send(
+            leader,
+            Body::AppendResponse {
+                success: true,
+                match_index: index,
+                read_seq,
+            },
+        );
+    }
+
+    fn append_response(&mut self, from: NodeId, success: bool, match_index: u64, read_seq: u64) {
+        let last_index = self.log.last_index();
+        let progress = match self.progress.get_mut(&from) {
+            Some(progress) => progress,
+            None => return,
+        };
+        progress.active = true;
+        progress.read_seq = progress.read_seq.max(read_seq);
+        if success {
+            progress.matched = progress.matched.max(match_index);
+            progress.next = progress.next.max(progress.matched + 1);
+            let behind = progress.next <= last_index;
+            self.maybe_commit();
+            if behind {
+                self.send_append(from);
+            }
+        } else {
+            let next = (match_index + 1).min(progress.next.saturating_sub(1));
+            progress.next = next.max(progress.matched + 1);
+            self.send_append(from);
+        }
+        self.advance_reads();
+    }
+
+    fn broadcast_append(&mut self) {
+        for peer in self.peers() {
+            self.send_append(peer);
+        }
+    }
+
+    fn send_append(&mut self, to: NodeId) {
+        let snapshot_index = self.log.snapshot().index;
+        let last_index = self.log.last_index();
+        let read_seq = self.read_seq;
+        let progress = match self.progress.get_mut(&to) {
+            Some(progress) => progress,
+            None => return,
+        };
+        if progress.next <= snapshot_index {
+            // the entries the follower misses are compacted
+            progress.next = snapshot_index + 1;
+            let snapshot = Snapshot::clone(self.log.snapshot());
+            self.send(to, Body::InstallSnapshot { snapshot, read_seq });
+            return;
+        }
+        let prev_index = progress.next - 1;
+        let entries = self.log.entries_from(progress.next, MAX_BATCH);
+        // keep sending to a follower matching the log, without waiting for the answers
+        if progress.matched == prev_index {
+            progress.next = prev_index + entries.len() as u64 + 1;
+        }
+        debug_assert!(progress.next <= last_index + 1);
+        let prev_term = self.log.term_at(prev_index).unwrap_or(0);
+        let body = Body::AppendEntries {
+            prev_index,
+            prev_term,
+            entries,
+            commit: self.commit,
+            read_seq,
+        };
+        self.send(to, body);
+    }
+
+    /// Commits the entries of the current term stored by a majority
+    fn maybe_commit(&mut self) {
+        let last_index = self.log.last_index();
+        for index in (self.commit + 1..=last_index).rev() {
+            // the earlier terms are only committed along with the current one
+            if self.log.term_at(index) != Some(self.term) {
+                break;
+            }
+            let stored = self.has_quorum(|id| {
+                if id == self.id {
+                    true
+                } else {
+                    self.progress.get(&id).is_some_and(|p| p.matched >= index)
+                }
+            });
+            if stored {
+                self.commit = index;
+                break;
+            }
+        }
+        // a leader removed from the members leads until the change is committed
+        if !self.is_voter() && self.log.latest_members().0 <= self.commit {
+            self.become_follower(self.term, None);
+            return;
+        }
+        self.advance_reads();
+    }
+
+    /// Moves the reads confirmed by a majority to `ready_reads`
+    fn advance_reads(&mut self) {
+        // the commit index is only known once an entry of this term is committed
+        if self.role != Role::Leader || self.log.term_at(self.commit) != Some(self.term) {
+            return;
+        }
+        while let Some(read) = self.reads.front() {
+            let seq = read.seq;
+            let confirmed = self.has_quorum(|id| {
+                id == self.id || self.progress.get(&id).is_some_and(|p| p.read_seq >= seq)
+            });
+            if !confirmed {
+                break;
+            }
+            let id = read.id;
+            self.reads.pop_front();
+            self.ready_reads.push((id, self.commit));
+        }
+    }
+
+    /// Steps down unless a majority answered since the last check
+    fn check_quorum(&mut self) {
+        let active =
+            self.has_quorum(|id| id == self.id || self.progress.get(&id).is_some_and(|p| p.active));
+        for progress in self.progress.values_mut() {
+            progress.active = false;
+        }
+        if !active {
+            self.become_follower(self.term, None);
+        }
+    }
+}
@@ -0,0 +1,168 @@
+//! A cluster of servers replicating an engine with the Raft consensus algorithm
+//!
+//! Every write is appended to a replicated log and applied to the engine of
+//! each node once a majority of the nodes has stored it, so the cluster keeps
+//! working as long as a majority of its nodes can talk to each other. Reads
+//! are served by the leader once a majority confirms it still leads.
+//!
+//! The pieces:
+//! * `consensus`: the algorithm itself, without any I/O
+//! * `storage`: the term, the vote, the log and the snapshots on disk
+//! * `node`: the thread running a node, applying the log to the engine
+//! * `network`: how the nodes reach each other, over TCP or in memory
+use crate::common::Request;
+use crate::transport::Address;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+mod consensus;
+mod network;
+mod node;
+mod storage;
+
+pub use self::network::{MemoryNetwork, TcpTransport, Transport};
+pub use self::node::RaftNode;
+
+/// The id of a node, unique within its cluster
+pub type NodeId = u64;
+
+/// The voting nodes of a cluster and their addresses
+pub type Members = BTreeMap<NodeId, Address>;
+
+/// The settings of a node
+#[derive(Debug, Clone)]
+pub struct ClusterConfig {
+    /// The id of this node
+    pub id: NodeId,
+    /// The members the cluster starts with, the same on all of them
+    ///
+    /// A node joining a running cluster is left out: it waits for the leader
+    /// to add it, see [`RaftNode::add_node`].
+    pub members: Members,
+    /// Where the node keeps its log and snapshots
+    pub dir: PathBuf,
+    /// How often the leader contacts the followers
+    pub heartbeat_interval: Duration,
+    /// How long a follower waits for the leader before calling an election, at least
+    pub election_timeout: Duration,
+    /// The number of entries applied before the log is compacted into a snapshot
+    pub snapshot_threshold: u64,
+    /// How long a request waits for the cluster to commit it
+    pub request_timeout: Duration,
+}
+
+impl ClusterConfig {
+    /// The settings of node `id`, with the default timings
+    pub fn new(id: NodeId, members: Members, dir: PathBuf) -> Self {
+        ClusterConfig {
+            id,
+            members,
+            dir,
+            heartbeat_interval: Duration::from_millis(50),
+            election_timeout: Duration::from_millis(500),
+            snapshot_threshold: 10_000,
+            request_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// What a node does in the cluster
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Role {
+    /// It follows the leader
+    Follower,
+    /// It asks the others to elect it
+    Candidate,
+    /// It replicates its log to the others
+    Leader,
+}
+
+/// The state of a node, as returned by `RaftNode::status`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClusterStatus {
+    /// The id of the node
+    pub id: NodeId,
+    /// What the node does
+    pub role: Role,
+    /// The current term
+    pub term: u64,
+    /// The leader of the current term, if known
+    pub leader: Option<NodeId>,
+    /// The members of the cluster, as last seen by the node
+    pub members: Members,
+    /// The last entry known to be committed
+    pub commit_index: u64,
+    /// The last entry applied to the engine
+    pub applied_index: u64,
+}
+
+/// A message between two nodes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Message {
+    /// The sending node
+    pub from: NodeId,
+    /// The receiving node
+    pub to: NodeId,
+    pub(crate) term: u64,
+    pub(crate) body: Body,
+}
+
+/// The remote procedure calls of Raft, and their answers
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) enum Body {
+    RequestVote {
+        last_index: u64,
+        last_term: u64,
+    },
+    Vote {
+        granted: bool,
+    },
+    AppendEntries {
+        prev_index: u64,
+        prev_term: u64,
+        entries: Vec<Entry>,
+        commit: u64,
+        // echoed back, to confirm the leadership for the reads
+        read_seq: u64,
+    },
+    InstallSnapshot {
+        snapshot: Snapshot,
+        read_seq: u64,
+    },
+    // `match_index` is the last entry matching the leader on success, and a
+    // hint of where the logs may match otherwise
+    AppendResponse {
+        success: bool,
+        match_index: u64,
+        read_seq: u64,
+    },
+}
+
+/// An entry of the replicated log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Entry {
+    pub(crate) index: u64,
+    pub(crate) term: u64,
+    pub(crate) data: EntryData,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) enum EntryData {
+    /// Appended by a new leader, to commit the entries of the previous terms
+    Noop,
+    /// A write applied to the engine
+    Write(Request),
+    /// The members from now on
+    Members(Members),
+}
+
+/// The data of the engine once the entries up to `index` are applied
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Snapshot {
+    pub(crate) index: u64,
+    pub(crate) term: u64,
+    pub(crate) members: Members,
+    pub(crate) data: Vec<(String, String)>,
+}
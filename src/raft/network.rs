@@ -0,0 +1,145 @@
+use super::{Message, NodeId, RaftNode};
+use crate::transport::Address;
+use crate::{Credentials, KvsClient, Result};
+use crossbeam::channel::{self, Receiver, Sender, TrySendError};
+use log::debug;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The messages queued for a node before new ones are dropped
+const QUEUE_SIZE: usize = 1024;
+/// How long the messages to a node are dropped after failing to connect to it
+const RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// How a node sends messages to the others
+///
+/// Sending must not block the node, and may lose messages: Raft sends them again.
+pub trait Transport: Send + 'static {
+    /// Sends `message` to the node listening on `addr`
+    fn send(&self, addr: &Address, message: Message);
+}
+
+/// Sends the messages to the servers of the other nodes, with the `kvs` protocol
+///
+/// Each node is sent its messages in order by a thread of its own, over one
+/// connection opened again after a failure.
+#[derive(Clone, Default)]
+pub struct TcpTransport {
+    peers: Arc<Mutex<HashMap<Address, Sender<Message>>>>,
+    credentials: Option<Credentials>,
+}
+
+impl TcpTransport {
+    /// Authenticates each connection with `credentials` before sending messages
+    ///
+    /// The servers of a cluster with authentication only take the messages
+    /// of the users granted the `admin` permission.
+    pub fn with_credentials(mut self, credentials: Credentials) -> Self {
+        self.credentials = Some(credentials);
+        self
+    }
+}
+
+impl Transport for TcpTransport {
+    fn send(&self, addr: &Address, message: Message) {
+        let mut peers = self.peers.lock().unwrap();
+        let tx = peers.entry(addr.clone()).or_insert_with(|| {
+            let (tx, rx) = channel::bounded(QUEUE_SIZE);
+            let addr = addr.clone();
+            let credentials = self.credentials.clone();
+            thread::spawn(move || send_to(addr, credentials, rx));
+            tx
+        });
+        if let Err(TrySendError::Full(message)) = tx.try_send(message) {
+            debug!(
+                "Dropping a message to node {}: its queue is full",
+                message.to
+            );
+        }
+    }
+}
+
+/// Sends the messages of `rx` to `addr`, until the transport is dropped
+fn send_to(addr: Address, credentials: Option<Credentials>, rx: Receiver<Message>) {
+    let mut client: Option<KvsClient> = None;
+    let mut retry_at = Instant::now();
+    for message in rx {
+        if client.is_none() {
+            if Instant::now() < retry_at {
+                continue;
+            }
+            match connect(&addr, credentials.as_ref()) {
+                Ok(connected) => client = Some(connected),
+                Err(e) => {
+                    debug!("Cannot connect to node {} at {}: {}", message.to, addr, e);
+                    retry_at = Instant::now() + RETRY_DELAY;
+                    continue;
+                }
+            }
+        }
+        if let Some(Err(e)) = client.as_mut().map(|client| client.raft(message)) {
+            debug!("Cannot send a message to {}: {}", addr, e);
+            client = None;
+        }
+    }
+}
+
+fn connect(addr: &Address, credentials: Option<&Credentials>) -> Result<KvsClient> {
+    let mut client = KvsClient::connect(addr)?;
+    if let Some(credentials) = credentials {
+        client.authenticate(credentials.clone())?;
+    }
+    Ok(client)
+}
+
+/// A network of nodes in the same process, for testing
+///
+/// The nodes are reached by their id, whatever their address. A node may be
+/// isolated from the others, and healed later.
+#[derive(Clone, Default)]
+pub struct MemoryNetwork {
+    inner: Arc<Mutex<Network>>,
+}
+
+#[derive(Default)]
+struct Network {
+    nodes: HashMap<NodeId, RaftNode>,
+    isolated: HashSet<NodeId>,
+}
+
+impl MemoryNetwork {
+    /// A network without nodes
+    pub fn new() -> Self {
+        MemoryNetwork::default()
+    }
+
+    /// Connects a started node to the network
+    pub fn add(&self, node: &RaftNode) {
+        let mut network = self.inner.lock().unwrap();
+        network.nodes.insert(node.id(), node.clone());
+    }
+
+    /// Drops the messages from and to node `id`, until it is healed
+    pub fn isolate(&self, id: NodeId) {
+        self.inner.lock().unwrap().isolated.insert(id);
+    }
+
+    /// Delivers the messages from and to node `id` again
+    pub fn heal(&self, id: NodeId) {
+        self.inner.lock().unwrap().isolated.remove(&id);
+    }
+}
+
+impl Transport for MemoryNetwork {
+    fn send(&self, _addr: &Address, message: Message) {
+        let network = self.inner.lock().unwrap();
+        if network.isolated.contains(&message.from) || network.isolated.contains(&message.to) {
+            return;
+        }
+        if let Some(node) = network.nodes.get(&message.to) {
+            node.deliver(message);
+        }
+    }
+}
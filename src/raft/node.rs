@@ -0,0 +1,426 @@
+use super::consensus::{HardState, Raft};
+use super::storage::Storage;
+use super::{ClusterConfig, ClusterStatus, EntryData, Members, Message, NodeId, Transport};
+use crate::common::{Reply, Request};
+use crate::replication::{self, SNAPSHOT_PAGE};
+use crate::server;
+use crate::transport::Address;
+use crate::{KvsEngine, KvsError, Result};
+use crossbeam::channel::{self, Receiver, RecvTimeoutError, Sender};
+use log::{debug, error, info};
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// The most inputs handled before the state is persisted
+const MAX_INPUTS: usize = 64;
+
+/// A running node of a cluster, replicating writes to its engine through Raft
+///
+/// The node owns its engine: when it starts, the data of the engine is
+/// replaced with the latest snapshot of the node, and the committed entries
+/// of the log are applied on top of it.
+///
+/// Writes are proposed to the leader and answered once committed and applied.
+/// Reads are answered by the leader once a majority confirms it still leads.
+/// A node that is not the leader answers with `KvsError::NotLeader`, naming
+/// the leader if it knows it.
+///
+/// Clones are handles to the same node.
+#[derive(Clone)]
+pub struct RaftNode {
+    tx: Sender<Input>,
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    id: NodeId,
+    request_timeout: Duration,
+    status: Mutex<ClusterStatus>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+enum Input {
+    Message(Message),
+    Propose(Proposal, Sender<Result<Reply>>),
+    Read(Request, Sender<Result<Reply>>),
+    Stop,
+}
+
+enum Proposal {
+    Write(Request),
+    AddNode(NodeId, Address),
+    RemoveNode(NodeId),
+}
+
+impl RaftNode {
+    /// Starts a node with `engine` as its state machine, reaching the others with `transport`
+    pub fn start<E: KvsEngine, T: Transport>(
+        config: ClusterConfig,
+        engine: E,
+        transport: T,
+    ) -> Result<RaftNode> {
+        let (storage, hard_state, log) = Storage::open(&config.dir, &config.members)?;
+        restore(&engine, &log.snapshot().data)?;
+        let applied = log.snapshot().index;
+
+        let tick = config.heartbeat_interval;
+        let election_ticks = config.election_timeout.as_millis() / tick.as_millis().max(1);
+        let raft = Raft::new(config.id, hard_state, log, 1, election_ticks as u32);
+        let status = ClusterStatus {
+            id: config.id,
+            role: raft.role,
+            term: raft.term,
+            leader: None,
+            members: raft.members.clone(),
+            commit_index: raft.commit,
+            applied_index: applied,
+        };
+        let shared = Arc::new(Shared {
+            id: config.id,
+            request_timeout: config.request_timeout,
+            status: Mutex::new(status),
+            thread: Mutex::new(None),
+        });
+        let driver = Driver {
+            raft,
+            storage,
+            engine,
+            transport,
+            shared: Arc::clone(&shared),
+            hard_state,
+            addresses: Members::new(),
+            applied,
+            snapshot_threshold: config.snapshot_threshold.max(1),
+            proposals: HashMap::new(),
+            reads: HashMap::new(),
+            next_read: 0,
+        };
+        let (tx, rx) = channel::unbounded();
+        let handle = thread::spawn(move || driver.run(rx, tick));
+        *shared.thread.lock().unwrap() = Some(handle);
+        info!("Raft node {} started", config.id);
+        Ok(RaftNode { tx, shared })
+    }
+
+    /// The id of the node
+    pub fn id(&self) -> NodeId {
+        self.shared.id
+    }
+
+    /// The state of the node
+    pub fn status(&self) -> ClusterStatus {
+        self.shared.status.lock().unwrap().clone()
+    }
+
+    /// Hands a message received from another node to this one
+    pub fn deliver(&self, message: Message) {
+        let _ = self.tx.send(Input::Message(message));
+    }
+
+    /// Gets the value of a key, on the leader
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        match self.execute(Request::Get { key })? {
+            Reply::Value(value) => Ok(value),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    /// Sets the value of a key once a majority stores it, on the leader
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.execute(Request::Set { key, value }).map(|_| ())
+    }
+
+    /// Removes a key once a majority stores the removal, on the leader
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.execute(Request::Remove { key }).map(|_| ())
+    }
+
+    /// Adds a member to the cluster, on the leader
+    ///
+    /// The new node is started without itself among its members, so it waits
+    /// for the leader instead of calling elections.
+    pub fn add_node(&self, id: NodeId, addr: Address) -> Result<()> {
+        self.execute(Request::AddNode { id, addr }).map(|_| ())
+    }
+
+    /// Removes a member from the cluster, on the leader
+    pub fn remove_node(&self, id: NodeId) -> Result<()> {
+        self.execute(Request::RemoveNode { id }).map(|_| ())
+    }
+
+    /// Stops the node and waits for its thread
+    pub fn stop(&self) {
+        let _ = self.tx.send(Input::Stop);
+        if let Some(handle) = self.shared.thread.lock().unwrap().take() {
+            let _ = handle.join();
+        }
+    }
+
+    /// Runs a request through the cluster
+    ///
+    /// A write may still be applied after failing with a timeout.
+    pub(crate) fn execute(&self, request: Request) -> Result<Reply> {
+        let (tx, rx) = channel::bounded(1);
+        let input = match request {
+            Request::Raft(message) => {
+                self.deliver(message);
+                return Ok(Reply::Done);
+            }
+            Request::ClusterStatus => return Ok(Reply::Cluster(self.status())),
+            Request::Ping | Request::Auth(_) => return Ok(Reply::Done),
+            Request::Watch { .. } | Request::Replicate | Request::Promote => {
+                return Err(KvsError::BadRequest(format!(
+                    "{} is not supported by a cluster",
+                    request.name()
+                )));
+            }
+            Request::AddNode { id, addr } => Input::Propose(Proposal::AddNode(id, addr), tx),
+            Request::RemoveNode { id } => Input::Propose(Proposal::RemoveNode(id), tx),
+            request if request.is_write() => Input::Propose(Proposal::Write(request), tx),
+            request => Input::Read(request, tx),
+        };
+        // a stopped node leads nothing, so the client may go to another one
+        if self.tx.send(input).is_err() {
+            return Err(KvsError::NotLeader("unknown".to_owned()));
+        }
+        match rx.recv_timeout(self.shared.request_timeout) {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) => Err(KvsError::StringError(
+                "the cluster did not answer in time".to_owned(),
+            )),
+            Err(RecvTimeoutError::Disconnected) => Err(stopped()),
+        }
+    }
+}
+
+/// The thread of a node, feeding Raft and carrying out what it asks for
+struct Driver<E: KvsEngine, T: Transport> {
+    raft: Raft,
+    storage: Storage,
+    engine: E,
+    transport: T,
+    shared: Arc<Shared>,
+    // as last persisted
+    hard_state: HardState,
+    // every node ever seen, to answer the nodes removed meanwhile
+    addresses: Members,
+    applied: u64,
+    snapshot_threshold: u64,
+    // the clients waiting for their entry, by index, along with its term
+    proposals: HashMap<u64, (u64, Sender<Result<Reply>>)>,
+    reads: HashMap<u64, (Request, Sender<Result<Reply>>)>,
+    next_read: u64,
+}
+
+impl<E: KvsEngine, T: Transport> Driver<E, T> {
+    fn run(mut self, rx: Receiver<Input>, tick: Duration) {
+        let mut next_tick = Instant::now() + tick;
+        loop {
+            let mut inputs = Vec::new();
+            match rx.recv_timeout(next_tick.saturating_duration_since(Instant::now())) {
+                Ok(input) => inputs.push(input),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+            inputs.extend(rx.try_iter().take(MAX_INPUTS));
+            let mut stop = false;
+            for input in inputs {
+                match input {
+                    Input::Stop => stop = true,
+                    input => self.handle(input),
+                }
+            }
+            if Instant::now() >= next_tick {
+                self.raft.tick();
+                next_tick = Instant::now() + tick;
+            }
+            if let Err(e) = self.process() {
+                error!("Raft node {} fails: {}", self.shared.id, e);
+                break;
+            }
+            if stop {
+                break;
+            }
+        }
+        info!("Raft node {} stopped", self.shared.id);
+    }
+
+    fn handle(&mut self, input: Input) {
+        match input {
+            Input::Message(message) => self.raft.step(message),
+            Input::Propose(proposal, tx) => {
+                let mut members = self.raft.members.clone();
+                let data = match proposal {
+                    Proposal::Write(request) => EntryData::Write(request),
+                    Proposal::AddNode(id, addr) => {
+                        members.insert(id, addr);
+                        EntryData::Members(members)
+                    }
+                    Proposal::RemoveNode(id) => {
+                        if members.remove(&id).is_none() {
+                            let message = format!("node {} is not a member", id);
+                            let _ = tx.send(Err(KvsError::BadRequest(message)));
+                            return;
+                        }
+                        EntryData::Members(members)
+                    }
+                };
+                match self.raft.propose(data) {
+                    Ok(index) => {
+                        self.proposals.insert(index, (self.raft.term, tx));
+                    }
+                    Err(e) => {
+                        let _ = tx.send(Err(e));
+                    }
+                }
+            }
+            Input::Read(request, tx) => {
+                self.next_read += 1;
+                match self.raft.read(self.next_read) {
+                    Ok(()) => {
+                        self.reads.insert(self.next_read, (request, tx));
+                    }
+                    Err(e) => {
+                        let _ = tx.send(Err(e));
+                    }
+                }
+            }
+            Input::Stop => {}
+        }
+    }
+
+    /// Persists the state, then sends the messages and applies the committed entries
+    fn process(&mut self) -> Result<()> {
+        let hard_state = self.raft.hard_state();
+        if hard_state != self.hard_state {
+            self.storage.save_state(&hard_state)?;
+            self.hard_state = hard_state;
+        }
+        if let Some(snapshot) = self.raft.restore.take() {
+            info!(
+                "Raft node {} installs the snapshot at {}",
+                self.shared.id, snapshot.index
+            );
+            self.storage.save_snapshot(&snapshot)?;
+            restore(&self.engine, &snapshot.data)?;
+            self.applied = snapshot.index;
+        }
+        self.storage.save_log(&mut self.raft.log)?;
+
+        for (id, addr) in &self.raft.members {
+            self.addresses.insert(*id, addr.clone());
+        }
+        for message in self.raft.messages.drain(..) {
+            match self.addresses.get(&message.to) {
+                Some(addr) => self.transport.send(addr, message),
+                None => debug!("No address for node {}", message.to),
+            }
+        }
+
+        self.apply();
+        for (id, index) in self.raft.ready_reads.drain(..) {
+            debug_assert!(self.applied >= index);
+            if let Some((request, tx)) = self.reads.remove(&id) {
+                let _ = tx.send(server::apply(&self.engine, request));
+            }
+        }
+        for id in mem::take(&mut self.raft.dropped_reads) {
+            if let Some((_, tx)) = self.reads.remove(&id) {
+                let _ = tx.send(Err(self.raft.not_leader()));
+            }
+        }
+
+        if self.applied - self.raft.log.snapshot().index >= self.snapshot_threshold {
+            self.compact()?;
+        }
+        *self.shared.status.lock().unwrap() = ClusterStatus {
+            id: self.shared.id,
+            role: self.raft.role,
+            term: self.raft.term,
+            leader: self.raft.leader,
+            members: self.raft.members.clone(),
+            commit_index: self.raft.commit,
+            applied_index: self.applied,
+        };
+        Ok(())
+    }
+
+    /// Applies the committed entries to the engine, answering the clients waiting for them
+    fn apply(&mut self) {
+        while self.applied < self.raft.commit {
+            let index = self.applied + 1;
+            let entry = match self.raft.log.entry(index) {
+                Some(entry) => entry.clone(),
+                None => break,
+            };
+            let result = match entry.data {
+                EntryData::Write(request) => server::apply(&self.engine, request),
+                EntryData::Noop | EntryData::Members(_) => Ok(Reply::Done),
+            };
+            if let Err(e) = &result {
+                debug!("Entry {} fails: {}", index, e);
+            }
+            self.applied = index;
+            if let Some((term, tx)) = self.proposals.remove(&index) {
+                // another leader replaced the entry, so the request is not applied
+                let result = if term == entry.term {
+                    result
+                } else {
+                    Err(self.raft.not_leader())
+                };
+                let _ = tx.send(result);
+            }
+        }
+    }
+
+    /// Replaces the applied entries with a snapshot of the engine
+    fn compact(&mut self) -> Result<()> {
+        let data = dump(&self.engine)?;
+        self.raft.compact(self.applied, data);
+        self.storage.save_snapshot(self.raft.log.snapshot())?;
+        self.storage.save_log(&mut self.raft.log)?;
+        debug!(
+            "Raft node {} compacts its log up to {}",
+            self.shared.id, self.applied
+        );
+        Ok(())
+    }
+}
+
+/// All the keys of the engine and their values
+fn dump<E: KvsEngine>(engine: &E) -> Result<Vec<(String, String)>> {
+    let mut data = Vec::new();
+    let mut after = None;
+    loop {
+        let keys = engine.scan(String::new(), after.take(), SNAPSHOT_PAGE)?;
+        let last = match keys.last() {
+            Some(last) => last.clone(),
+            None => return Ok(data),
+        };
+        let values = engine.get_many(keys.clone())?;
+        data.extend(
+            keys.into_iter()
+                .zip(values)
+                .filter_map(|(key, value)| value.map(|value| (key, value))),
+        );
+        after = Some(last);
+    }
+}
+
+/// Replaces the data of the engine with `data`
+fn restore<E: KvsEngine>(engine: &E, data: &[(String, String)]) -> Result<()> {
+    let keys: HashSet<String> = data.iter().map(|(key, _)| key.clone()).collect();
+    replication::remove_missing(engine, &keys)?;
+    engine.set_many(data.to_vec())
+}
+
+fn stopped() -> KvsError {
+    KvsError::StringError("the Raft node is stopped".to_owned())
+}
+
+fn unexpected(reply: Reply) -> KvsError {
+    KvsError::Protocol(format!("unexpected reply {:?}", reply))
+}
@@ -0,0 +1,151 @@
+//! The durable state of a node, in its directory:
+//!
+//! * `state.json`: the current term and the vote
+//! * `snapshot.json`: the latest snapshot
+//! * `log.jsonl`: the entries following the snapshot, one JSON document a line
+//!
+//! The state and the snapshot are replaced atomically by renaming a new file
+//! over them. The log is appended to, and rewritten the same way when entries
+//! are truncated or compacted. Everything is synced to the disk before the node
+//! answers a message.
+use super::consensus::{HardState, Log};
+use super::{Entry, Members, Snapshot};
+use crate::{KvsError, Result};
+use log::warn;
+use serde::Serialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+const STATE_FILE: &str = "state.json";
+const SNAPSHOT_FILE: &str = "snapshot.json";
+const LOG_FILE: &str = "log.jsonl";
+
+pub(crate) struct Storage {
+    dir: PathBuf,
+    log_file: File,
+}
+
+impl Storage {
+    /// Opens the state in `dir`, or starts a new one with `members`
+    pub(crate) fn open(dir: &Path, members: &Members) -> Result<(Storage, HardState, Log)> {
+        fs::create_dir_all(dir)?;
+        let hard_state = match fs::read(dir.join(STATE_FILE)) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HardState::default(),
+            Err(e) => return Err(e.into()),
+        };
+        let snapshot = match fs::read(dir.join(SNAPSHOT_FILE)) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Snapshot {
+                index: 0,
+                term: 0,
+                members: members.clone(),
+                data: Vec::new(),
+            },
+            Err(e) => return Err(e.into()),
+        };
+        let entries = read_log(&dir.join(LOG_FILE), snapshot.index)?;
+        let log = Log::new(snapshot, entries);
+        let log_file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(LOG_FILE))?;
+        let mut storage = Storage {
+            dir: dir.to_owned(),
+            log_file,
+        };
+        // drop a torn last line, and the entries covered by the snapshot
+        storage.rewrite_log(&log)?;
+        Ok((storage, hard_state, log))
+    }
+
+    pub(crate) fn save_state(&self, hard_state: &HardState) -> Result<()> {
+        self.replace(STATE_FILE, hard_state)
+    }
+
+    pub(crate) fn save_snapshot(&self, snapshot: &Snapshot) -> Result<()> {
+        self.replace(SNAPSHOT_FILE, snapshot)
+    }
+
+    /// Persists the entries of `log` not persisted yet
+    pub(crate) fn save_log(&mut self, log: &mut Log) -> Result<()> {
+        if log.rewrite {
+            self.rewrite_log(log)?;
+        } else if !log.unstable().is_empty() {
+            let mut writer = BufWriter::new(&self.log_file);
+            for entry in log.unstable() {
+                serde_json::to_writer(&mut writer, entry)?;
+                writer.write_all(b"\n")?;
+            }
+            writer.flush()?;
+            drop(writer);
+            self.log_file.sync_data()?;
+        }
+        log.stable = log.last_index();
+        log.rewrite = false;
+        Ok(())
+    }
+
+    fn rewrite_log(&mut self, log: &Log) -> Result<()> {
+        let tmp = self.dir.join(format!("{}.tmp", LOG_FILE));
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        for entry in log.entries() {
+            serde_json::to_writer(&mut writer, entry)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        fs::rename(&tmp, self.dir.join(LOG_FILE))?;
+        self.log_file = OpenOptions::new()
+            .append(true)
+            .open(self.dir.join(LOG_FILE))?;
+        Ok(())
+    }
+
+    /// Replaces `name` with the JSON of `value`
+    fn replace<T: Serialize>(&self, name: &str, value: &T) -> Result<()> {
+        let tmp = self.dir.join(format!("{}.tmp", name));
+        let mut file = File::create(&tmp)?;
+        serde_json::to_writer(&mut file, value)?;
+        file.sync_all()?;
+        fs::rename(&tmp, self.dir.join(name))?;
+        Ok(())
+    }
+}
+
+/// Reads the entries following `snapshot_index`
+///
+/// A last line that cannot be parsed was torn by a crash while being written,
+/// so it is ignored.
+fn read_log(path: &Path, snapshot_index: u64) -> Result<Vec<Entry>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut entries: Vec<Entry> = Vec::new();
+    let mut lines = BufReader::new(file).lines().peekable();
+    while let Some(line) = lines.next() {
+        let entry: Entry = match serde_json::from_str(&line?) {
+            Ok(entry) => entry,
+            Err(e) if lines.peek().is_none() => {
+                warn!("Dropping the torn end of the Raft log: {}", e);
+                break;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if entry.index <= snapshot_index {
+            continue;
+        }
+        let expected = entries.last().map_or(snapshot_index + 1, |e| e.index + 1);
+        if entry.index != expected {
+            return Err(KvsError::Corruption(format!(
+                "Raft log entry {} found where {} is expected",
+                entry.index, expected
+            )));
+        }
+        entries.push(entry);
+    }
+    Ok(entries)
+}
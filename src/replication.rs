@@ -0,0 +1,198 @@
+//! Leader–follower replication
+//!
+//! A follower connects to its leader and sends a `Replicate` request. The
+//! leader answers with a snapshot of its data, then pushes every change made to
+//! it, as the engine logs them, in the order of their sequence numbers. The
+//! follower applies them to its own engine and refuses the writes of its
+//! clients until it is promoted to leader.
+//!
+//! The leader starts watching its changes before taking the snapshot, so the
+//! changes made meanwhile are both in the snapshot and in the stream. Applying
+//! them again in order leaves the follower with the data of the leader.
+//!
+//! A follower losing its leader reconnects and starts over from a new snapshot.
+use crate::auth::Credentials;
+use crate::common::{Replication, Request};
+use crate::transport::{Address, Stream};
+use crate::{Event, KvsClient, KvsEngine, KvsError, Op, Result};
+use log::{info, warn};
+use std::collections::HashSet;
+use std::net::Shutdown;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// The number of keys in a page of the snapshot
+pub(crate) const SNAPSHOT_PAGE: usize = 1000;
+/// How long a follower waits before reconnecting to its leader at first
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+/// The longest a follower waits before reconnecting to its leader
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Whether a server follows a leader
+///
+/// It is shared by the server, its connections and the replication thread.
+#[derive(Clone, Default)]
+pub(crate) struct Replica(Arc<Mutex<State>>);
+
+#[derive(Default)]
+struct State {
+    leader: Option<Address>,
+    // the connection to the leader, shut down to stop the replication
+    connection: Option<Stream>,
+}
+
+impl Replica {
+    /// A replica following `leader`
+    pub(crate) fn following(leader: Address) -> Self {
+        let state = State {
+            leader: Some(leader),
+            connection: None,
+        };
+        Replica(Arc::new(Mutex::new(state)))
+    }
+
+    /// The leader followed, if any
+    pub(crate) fn leader(&self) -> Option<Address> {
+        self.0.lock().unwrap().leader.clone()
+    }
+
+    /// Refuses the writes while following a leader
+    pub(crate) fn check(&self, request: &Request) -> Result<()> {
+        match &self.0.lock().unwrap().leader {
+            Some(leader) if request.is_write() => {
+                Err(KvsError::ReadOnly(format!("the server follows {}", leader)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Stops following the leader, returning whether the replica was following one
+    pub(crate) fn stop(&self) -> bool {
+        let mut state = self.0.lock().unwrap();
+        if let Some(connection) = state.connection.take() {
+            let _ = connection.shutdown(Shutdown::Both);
+        }
+        state.leader.take().is_some()
+    }
+
+    /// Records the connection to the leader, returning `false` if the replica stopped following it
+    fn attach(&self, connection: Stream) -> bool {
+        let mut state = self.0.lock().unwrap();
+        if state.leader.is_none() {
+            return false;
+        }
+        state.connection = Some(connection);
+        true
+    }
+}
+
+/// Copies the data of the leader into `engine` and applies its changes, until the replica stops following
+///
+/// With `credentials`, the follower authenticates to the leader.
+pub(crate) fn follow<E: KvsEngine>(engine: E, replica: Replica, credentials: Option<Credentials>) {
+    let mut backoff = MIN_BACKOFF;
+    while let Some(leader) = replica.leader() {
+        let result = sync(
+            &engine,
+            &replica,
+            &leader,
+            credentials.as_ref(),
+            &mut backoff,
+        );
+        if replica.leader().is_none() {
+            break;
+        }
+        match result {
+            Ok(()) => warn!("Leader {} closed the replication stream", leader),
+            Err(e) => warn!("Replication from {} failed: {}", leader, e),
+        }
+        thread::sleep(backoff);
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+    info!("Replication stopped");
+}
+
+/// Replicates the leader over one connection, until it fails or the replica stops following
+fn sync<E: KvsEngine>(
+    engine: &E,
+    replica: &Replica,
+    leader: &Address,
+    credentials: Option<&Credentials>,
+    backoff: &mut Duration,
+) -> Result<()> {
+    let mut client = KvsClient::connect(leader)?;
+    if let Some(credentials) = credentials {
+        client.authenticate(credentials.clone())?;
+    }
+    let stream = client.replicate()?;
+    if !replica.attach(stream.connection()?) {
+        return Ok(());
+    }
+    info!("Replicating {}", leader);
+
+    let mut snapshot = HashSet::new();
+    let mut last_seq = 0;
+    for message in stream {
+        // the messages already read are dropped once promoted
+        if replica.leader().is_none() {
+            break;
+        }
+        match message? {
+            Replication::Snapshot(pairs) => {
+                snapshot.extend(pairs.iter().map(|(key, _)| key.clone()));
+                engine.set_many(pairs)?;
+            }
+            Replication::SnapshotEnd => {
+                remove_missing(engine, &snapshot)?;
+                snapshot.clear();
+                *backoff = MIN_BACKOFF;
+                info!("In sync with {}", leader);
+            }
+            Replication::Change(event) => {
+                if event.seq <= last_seq {
+                    return Err(KvsError::Protocol(format!(
+                        "change {} received after {}",
+                        event.seq, last_seq
+                    )));
+                }
+                last_seq = event.seq;
+                apply_change(engine, event)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Removes the keys of `engine` missing from the snapshot of the leader
+pub(crate) fn remove_missing<E: KvsEngine>(engine: &E, snapshot: &HashSet<String>) -> Result<()> {
+    let mut after = None;
+    loop {
+        let keys = engine.scan(String::new(), after.take(), SNAPSHOT_PAGE)?;
+        let last = match keys.last() {
+            Some(last) => last.clone(),
+            None => return Ok(()),
+        };
+        for key in keys {
+            if !snapshot.contains(&key) {
+                ignore_missing(engine.remove(key))?;
+            }
+        }
+        after = Some(last);
+    }
+}
+
+fn apply_change<E: KvsEngine>(engine: &E, event: Event) -> Result<()> {
+    match event.op {
+        Op::Set(value) => engine.set(event.key, value),
+        // the snapshot may already miss the key
+        Op::Remove => ignore_missing(engine.remove(event.key)),
+    }
+}
+
+fn ignore_missing(result: Result<()>) -> Result<()> {
+    match result {
+        Err(KvsError::KeyNotFound) => Ok(()),
+        result => result,
+    }
+}
@@ -0,0 +1,469 @@
+//! A Redis compatible front end speaking RESP2
+//!
+//! Commands are arrays of bulk strings, or inline commands separated by spaces
+//! for humans typing into a terminal. They are mapped onto `KvsEngine`, so keys
+//! and values must be valid UTF-8.
+//!
+//! The engines have no notion of expiry, so the deadlines set by `SET ... EX`
+//! live in the memory of the server and are lost on restart. An expired key is
+//! removed from the engine the next time a RESP command touches it.
+use crate::auth::{from_hex, to_hex};
+use crate::engines::{KvsEngine, SetCondition};
+use crate::glob::{glob_match, literal_prefix};
+use crate::transport::Stream;
+use crate::{KvsError, Result};
+use log::debug;
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// inline commands and the headers of multi bulk requests are never longer than this
+const MAX_LINE_LEN: u64 = 64 * 1024;
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// The reply to a connection beyond the limit of the server, as sent by Redis
+pub const OVERLOADED: &[u8] = b"-ERR max number of clients reached\r\n";
+
+/// A RESP2 value
+#[derive(Debug)]
+enum Value {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<String>),
+    Array(Vec<Value>),
+}
+
+impl From<Option<String>> for Value {
+    fn from(value: Option<String>) -> Self {
+        Value::Bulk(value)
+    }
+}
+
+impl Value {
+    fn ok() -> Value {
+        Value::Simple("OK")
+    }
+
+    fn error(message: impl Into<String>) -> Value {
+        Value::Error(format!("ERR {}", message.into()))
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        match self {
+            Value::Simple(s) => write!(writer, "+{}\r\n", s),
+            Value::Error(message) => write!(writer, "-{}\r\n", message),
+            Value::Integer(n) => write!(writer, ":{}\r\n", n),
+            Value::Bulk(None) => writer.write_all(b"$-1\r\n"),
+            Value::Bulk(Some(s)) => write!(writer, "${}\r\n{}\r\n", s.len(), s),
+            Value::Array(values) => {
+                write!(writer, "*{}\r\n", values.len())?;
+                values.iter().try_for_each(|value| value.write_to(writer))
+            }
+        }
+    }
+}
+
+/// Deadlines of the keys set with an expiry, shared by all the RESP connections
+#[derive(Clone, Default)]
+pub struct Expirations(Arc<Mutex<HashMap<String, Instant>>>);
+
+impl Expirations {
+    fn set(&self, key: &str, deadline: Option<Instant>) {
+        let mut deadlines = self.0.lock().unwrap();
+        match deadline {
+            Some(deadline) => deadlines.insert(key.to_owned(), deadline),
+            None => deadlines.remove(key),
+        };
+    }
+
+    /// Removes `key` from the engine if its deadline has passed
+    fn purge<E: KvsEngine>(&self, engine: &E, key: &str) -> Result<()> {
+        let mut deadlines = self.0.lock().unwrap();
+        match deadlines.get(key) {
+            Some(&deadline) if deadline <= Instant::now() => {
+                deadlines.remove(key);
+                ignore_not_found(engine.remove(key.to_owned()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+}
+
+fn ignore_not_found(result: Result<()>) -> Result<()> {
+    match result {
+        Err(KvsError::KeyNotFound) => Ok(()),
+        result => result,
+    }
+}
+
+/// Serves a connection speaking RESP2 until the client disconnects or sends `QUIT`
+///
+/// The commands whose bulk strings add up to more than `max_request_size`
+/// bytes are rejected, closing the connection.
+pub fn serve<E: KvsEngine>(
+    engine: E,
+    stream: Stream,
+    expirations: Expirations,
+    max_request_size: u32,
+) -> Result<()> {
+    let peer_addr = stream.peer_addr()?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    let max_request_size = (max_request_size as usize).min(MAX_BULK_LEN);
+    loop {
+        let reply = match read_command(&mut reader, max_request_size) {
+            Ok(Some(args)) if args.is_empty() => continue,
+            Ok(Some(args)) => {
+                debug!("Receive command from {}: {:?}", peer_addr, args);
+                if args[0].eq_ignore_ascii_case("quit") {
+                    Value::ok().write_to(&mut writer)?;
+                    writer.flush()?;
+                    return Ok(());
+                }
+                execute(&engine, &expirations, args)
+            }
+            Ok(None) => return Ok(()),
+            // the stream cannot be resynchronized after a protocol error
+            Err(KvsError::Protocol(message)) => {
+                Value::Error(format!("ERR Protocol error: {}", message)).write_to(&mut writer)?;
+                writer.flush()?;
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        reply.write_to(&mut writer)?;
+        // answer pipelined commands in one go
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+}
+
+/// Reads a command, returning `None` if the client disconnects before it
+///
+/// The bulk strings are read as they arrive rather than allocated upfront from
+/// their announced length, and may not add up to more than `max_size` bytes.
+fn read_command<R: BufRead>(reader: &mut R, max_size: usize) -> Result<Option<Vec<String>>> {
+    let line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    if !line.starts_with('*') {
+        return Ok(Some(line.split_whitespace().map(str::to_owned).collect()));
+    }
+
+    let count = parse_len(&line[1..])?;
+    let mut args = Vec::with_capacity(count.min(1024));
+    let mut remaining = max_size;
+    for _ in 0..count {
+        let header = read_line(reader)?.ok_or_else(unexpected_eof)?;
+        if !header.starts_with('$') {
+            return Err(protocol_error(format!("expected '$', got '{}'", header)));
+        }
+        let len = parse_len(&header[1..])?;
+        if len > remaining {
+            return Err(protocol_error(format!(
+                "request exceeds {} bytes",
+                max_size
+            )));
+        }
+        remaining -= len;
+        let mut bulk = Vec::new();
+        reader
+            .by_ref()
+            .take(len as u64 + 2)
+            .read_to_end(&mut bulk)?;
+        if bulk.len() < len + 2 {
+            return Err(unexpected_eof());
+        }
+        if !bulk.ends_with(b"\r\n") {
+            return Err(protocol_error("bulk string is not terminated by CRLF"));
+        }
+        bulk.truncate(len);
+        args.push(String::from_utf8(bulk)?);
+    }
+    Ok(Some(args))
+}
+
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<String>> {
+    let mut line = String::new();
+    if reader.by_ref().take(MAX_LINE_LEN).read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with('\n') {
+        return Err(protocol_error("too big request"));
+    }
+    let len = line.trim_end_matches(&['\r', '\n'][..]).len();
+    line.truncate(len);
+    Ok(Some(line))
+}
+
+fn parse_len(s: &str) -> Result<usize> {
+    s.parse()
+        .map_err(|_| protocol_error(format!("invalid length '{}'", s)))
+}
+
+fn protocol_error(message: impl Into<String>) -> KvsError {
+    KvsError::Protocol(message.into())
+}
+
+fn unexpected_eof() -> KvsError {
+    io::Error::from(io::ErrorKind::UnexpectedEof).into()
+}
+
+/// Runs a command, turning the failures into RESP errors
+fn execute<E: KvsEngine>(engine: &E, expirations: &Expirations, args: Vec<String>) -> Value {
+    let name = args[0].to_ascii_uppercase();
+    match run(engine, expirations, &name, args) {
+        Ok(reply) => reply,
+        Err(KvsError::NotAnInteger) => Value::error("value is not an integer or out of range"),
+        Err(KvsError::Overflow) => Value::error("increment or decrement would overflow"),
+        Err(KvsError::StringError(message)) => Value::error(message),
+        Err(e) => Value::error(e.to_string()),
+    }
+}
+
+fn run<E: KvsEngine>(
+    engine: &E,
+    expirations: &Expirations,
+    name: &str,
+    mut args: Vec<String>,
+) -> Result<Value> {
+    let arity_ok = match name {
+        "PING" => args.len() <= 2,
+        "GET" | "INCR" => args.len() == 2,
+        "SET" => args.len() >= 3,
+        "DEL" | "EXISTS" | "MGET" => args.len() >= 2,
+        "MSET" => args.len() >= 3 && args.len() % 2 == 1,
+        "SCAN" => args.len() >= 2,
+        "INFO" => args.len() <= 2,
+        _ => {
+            return Err(KvsError::StringError(format!(
+                "unknown command '{}'",
+                args[0]
+            )))
+        }
+    };
+    if !arity_ok {
+        return Err(KvsError::StringError(format!(
+            "wrong number of arguments for '{}' command",
+            args[0].to_ascii_lowercase()
+        )));
+    }
+    let mut args = args.drain(1..);
+
+    match name {
+        "PING" => Ok(match args.next() {
+            Some(message) => Value::Bulk(Some(message)),
+            None => Value::Simple("PONG"),
+        }),
+        "GET" => {
+            let key = args.next().unwrap();
+            expirations.purge(engine, &key)?;
+            Ok(engine.get(key)?.into())
+        }
+        "SET" => {
+            let key = args.next().unwrap();
+            let value = args.next().unwrap();
+            set(engine, expirations, key, value, args.collect())
+        }
+        "DEL" => {
+            let mut removed = 0;
+            for key in args {
+                expirations.purge(engine, &key)?;
+                expirations.set(&key, None);
+                match engine.remove(key) {
+                    Ok(()) => removed += 1,
+                    Err(KvsError::KeyNotFound) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(Value::Integer(removed))
+        }
+        "EXISTS" => {
+            let mut found = 0;
+            for key in args {
+                expirations.purge(engine, &key)?;
+                if engine.get(key)?.is_some() {
+                    found += 1;
+                }
+            }
+            Ok(Value::Integer(found))
+        }
+        "INCR" => {
+            let key = args.next().unwrap();
+            expirations.purge(engine, &key)?;
+            Ok(Value::Integer(engine.incr(key, 1)?))
+        }
+        "MGET" => {
+            let keys: Vec<String> = args.collect();
+            for key in &keys {
+                expirations.purge(engine, key)?;
+            }
+            let values = engine.get_many(keys)?;
+            Ok(Value::Array(values.into_iter().map(Value::from).collect()))
+        }
+        "MSET" => {
+            let args: Vec<String> = args.collect();
+            let pairs: Vec<(String, String)> = args
+                .chunks(2)
+                .map(|pair| (pair[0].clone(), pair[1].clone()))
+                .collect();
+            for (key, _) in &pairs {
+                expirations.set(key, None);
+            }
+            engine.set_many(pairs)?;
+            Ok(Value::ok())
+        }
+        "SCAN" => {
+            let cursor = args.next().unwrap();
+            scan(engine, expirations, &cursor, args.collect())
+        }
+        "INFO" => info(engine, expirations),
+        _ => unreachable!(),
+    }
+}
+
+/// `SET key value [EX seconds | PX milliseconds | KEEPTTL] [NX | XX]`
+fn set<E: KvsEngine>(
+    engine: &E,
+    expirations: &Expirations,
+    key: String,
+    value: String,
+    options: Vec<String>,
+) -> Result<Value> {
+    let mut condition = None;
+    let mut expiry = None;
+    let mut keep_ttl = false;
+    let mut options = options.into_iter();
+    while let Some(option) = options.next() {
+        match option.to_ascii_uppercase().as_str() {
+            "NX" if condition.is_none() => condition = Some(SetCondition::Absent),
+            "XX" if condition.is_none() => condition = Some(SetCondition::Present),
+            "KEEPTTL" if expiry.is_none() => keep_ttl = true,
+            unit @ "EX" | unit @ "PX" if expiry.is_none() && !keep_ttl => {
+                let amount = options
+                    .next()
+                    .and_then(|amount| amount.parse::<u64>().ok())
+                    .filter(|&amount| amount > 0)
+                    .ok_or_else(|| {
+                        KvsError::StringError("invalid expire time in 'set' command".to_owned())
+                    })?;
+                expiry = Some(if unit == "EX" {
+                    Duration::from_secs(amount)
+                } else {
+                    Duration::from_millis(amount)
+                });
+            }
+            _ => return Err(KvsError::StringError("syntax error".to_owned())),
+        }
+    }
+
+    expirations.purge(engine, &key)?;
+    let is_set = match condition {
+        Some(condition) => engine.set_if(key.clone(), value, condition)?,
+        None => {
+            engine.set(key.clone(), value)?;
+            true
+        }
+    };
+    if !is_set {
+        return Ok(Value::Bulk(None));
+    }
+    if !keep_ttl {
+        expirations.set(&key, expiry.map(|expiry| Instant::now() + expiry));
+    }
+    Ok(Value::ok())
+}
+
+/// `SCAN cursor [MATCH pattern] [COUNT count]`
+///
+/// The cursor is `0` to start, then the last key the previous call went past,
+/// in hex, so a scan resumes where it stopped however long it is. Keys added or
+/// removed during a scan may be missed, but none is returned twice.
+fn scan<E: KvsEngine>(
+    engine: &E,
+    expirations: &Expirations,
+    cursor: &str,
+    options: Vec<String>,
+) -> Result<Value> {
+    let after = match cursor {
+        "0" => None,
+        cursor => {
+            let key = from_hex(cursor).and_then(|key| String::from_utf8(key).ok());
+            Some(key.ok_or_else(|| KvsError::StringError("invalid cursor".to_owned()))?)
+        }
+    };
+    let mut pattern = "*".to_owned();
+    let mut count = DEFAULT_SCAN_COUNT;
+    let mut options = options.into_iter();
+    while let Some(option) = options.next() {
+        let arg = options.next();
+        match (option.to_ascii_uppercase().as_str(), arg) {
+            ("MATCH", Some(arg)) => pattern = arg,
+            ("COUNT", Some(arg)) => {
+                count = arg
+                    .parse()
+                    .ok()
+                    .filter(|&count| count > 0)
+                    .ok_or_else(|| KvsError::StringError("syntax error".to_owned()))?;
+            }
+            _ => return Err(KvsError::StringError("syntax error".to_owned())),
+        }
+    }
+
+    let prefix = literal_prefix(&pattern);
+    let keys = engine.scan(prefix.to_owned(), after, count)?;
+    let next_cursor = match keys.last() {
+        Some(last) if keys.len() == count => to_hex(last.as_bytes()),
+        _ => "0".to_owned(),
+    };
+    let mut found = Vec::new();
+    for key in keys {
+        if !glob_match(pattern.as_bytes(), key.as_bytes()) {
+            continue;
+        }
+        expirations.purge(engine, &key)?;
+        if engine.get(key.clone())?.is_some() {
+            found.push(Value::Bulk(Some(key)));
+        }
+    }
+    Ok(Value::Array(vec![
+        Value::Bulk(Some(next_cursor)),
+        Value::Array(found),
+    ]))
+}
+
+fn info<E: KvsEngine>(engine: &E, expirations: &Expirations) -> Result<Value> {
+    let stats = engine.stats()?;
+    let info = format!(
+        "# Server\r\n\
+         kvs_version:{}\r\n\
+         \r\n\
+         # Keyspace\r\n\
+         db0:keys={},expires={}\r\n\
+         \r\n\
+         # Storage\r\n\
+         generations:{}\r\n\
+         disk_bytes:{}\r\n\
+         live_bytes:{}\r\n\
+         garbage_bytes:{}\r\n\
+         compactions:{}\r\n",
+        env!("CARGO_PKG_VERSION"),
+        stats.keys,
+        expirations.len(),
+        stats.generations,
+        stats.disk_bytes,
+        stats.live_bytes,
+        stats.garbage_bytes,
+        stats.compactions,
+    );
+    Ok(Value::Bulk(Some(info)))
+}
@@ -1,80 +1,1086 @@
-use crate::common::{GetResponse, Request, RmResponse, SetResponse};
+use crate::admin::{Admin, ClientGuard, ServerConfig};
+use crate::auth::{AuthConfig, Credentials, Session};
+use crate::common::{DeadlineRequest, Replication, Reply, Request, Response};
+use crate::dispatch::Dispatcher;
 use crate::engines::KvsEngine;
+use crate::http;
+use crate::memcache::{self, Items};
+use crate::metrics::{Metrics, RequestTimer};
+use crate::protocol::{
+    self, Frame, Header, Hello, MAX_FRAME_LEN, MIN_PROTOCOL_VERSION, OP_DEADLINE, OP_ERROR,
+    OP_EVENT, OP_HELLO, OP_MESSAGE, OP_REPLICATE, OP_REQUEST, OP_RESPONSE,
+};
+use crate::pubsub::{ChannelMessage, Channels};
+use crate::raft::RaftNode;
+use crate::replication::{self, Replica, SNAPSHOT_PAGE};
+use crate::resp::{self, Expirations};
+use crate::shutdown::{ConnectionGuard, ShutdownHandle};
+use crate::transport::{Address, Stream, ToAddress};
 use crate::thread_pool::ThreadPool;
-use crate::Result;
-use log::{debug, error};
+use crate::tls::TlsServerConfig;
+use crate::{ErrorCode, Event, KvsError, Result};
+use crossbeam::channel::RecvTimeoutError;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
 use serde_json::Deserializer;
 use std::{
-    io::{BufReader, BufWriter, Write},
-    net::{TcpListener, TcpStream, ToSocketAddrs},
+    cell::Cell,
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    net::Shutdown,
+    rc::Rc,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
 };
 
+/// How long a server waits for the requests in flight when shutting down, by default
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often a push stream waiting for something to push checks that it is still wanted
+const PUSH_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+/// How long sending the rejection of a connection may block the listener
+const REJECT_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Limits protecting a server from misbehaving clients
+///
+/// The timeouts apply to all the protocols, while `max_request_size` only
+/// applies to the kvs and RESP protocols. The default sets no limit.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Limits {
+    /// Connections beyond this number are rejected with `KvsError::Overloaded`
+    pub max_connections: Option<usize>,
+    /// How long a connection may stay without sending a request
+    pub idle_timeout: Option<Duration>,
+    /// How long the server waits for the rest of a request once it has started
+    pub read_timeout: Option<Duration>,
+    /// How long the server waits for a response to be sent
+    pub write_timeout: Option<Duration>,
+    /// Requests larger than this number of bytes are rejected
+    pub max_request_size: u32,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_connections: None,
+            idle_timeout: None,
+            read_timeout: None,
+            write_timeout: None,
+            max_request_size: MAX_FRAME_LEN,
+        }
+    }
+}
+
 /// The server for key value store
 pub struct KvsServer<E: KvsEngine, P: ThreadPool> {
     engine: E,
-    pool: P,
+    pool: Arc<P>,
+    shutdown: ShutdownHandle,
+    shutdown_timeout: Duration,
+    limits: Limits,
+    tls: Option<TlsServerConfig>,
+    auth: Option<AuthConfig>,
+    replica: Replica,
+    leader_credentials: Option<Credentials>,
+    cluster: Option<RaftNode>,
+    channels: Channels,
+    metrics: Option<Metrics>,
 }
 
 /// connect backend, and serve the client
-impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
+impl<E: KvsEngine, P: ThreadPool + Send + Sync + 'static> KvsServer<E, P> {
     /// create a `KvsServer` with given engine
     pub fn new(engine: E, pool: P) -> Self {
-        Self { engine, pool }
+        Self {
+            engine,
+            pool: Arc::new(pool),
+            shutdown: ShutdownHandle::default(),
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            limits: Limits::default(),
+            tls: None,
+            auth: None,
+            replica: Replica::default(),
+            leader_credentials: None,
+            cluster: None,
+            channels: Channels::default(),
+            metrics: None,
+        }
+    }
+
+    /// Sets the limits on the connections and the requests
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Serves the connections over TLS, whatever the protocol
+    ///
+    /// The handshake runs on the thread serving the connection and is bound by
+    /// the idle timeout.
+    pub fn with_tls(mut self, tls: TlsServerConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Requires the clients to authenticate, and checks their requests against their grants
+    ///
+    /// Only the kvs protocol authenticates its clients, so `run` is the only way
+    /// to run a server with authentication.
+    pub fn with_auth(mut self, auth: AuthConfig) -> Self {
+        self.auth = Some(auth);
+        self
+    }
+
+    /// Follows the leader at `leader`, authenticating with `credentials` if given
+    ///
+    /// The server copies the data of the leader and applies its changes as
+    /// they happen, refusing the writes of its clients with
+    /// `KvsError::ReadOnly` until it is promoted. Like authentication, it is
+    /// only supported by `run`.
+    pub fn with_leader(mut self, leader: Address, credentials: Option<Credentials>) -> Self {
+        self.replica = Replica::following(leader);
+        self.leader_credentials = credentials;
+        self
+    }
+
+    /// Serves the clients and the other nodes of a cluster through `node`
+    ///
+    /// The node must run on the engine of the server, which then changes only
+    /// through the log of the cluster. The nodes that are not the leader answer
+    /// with `KvsError::NotLeader`. Like authentication, it is only supported
+    /// by `run`. With authentication, the messages of the other nodes are only
+    /// taken from admins, see `TcpTransport::with_credentials`.
+    pub fn with_cluster(mut self, node: RaftNode) -> Self {
+        self.cluster = Some(node);
+        self
+    }
+
+    /// Records the metrics of the server, its thread pool and its engine in `metrics`
+    ///
+    /// The connections are counted whatever the protocol, but only the
+    /// requests served by `run` are. Several servers may share the same
+    /// metrics, their pools told apart by the address they listen on.
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Sets how long the requests in flight may take to finish once the server shuts down
+    ///
+    /// The connections still open after it are closed.
+    pub fn with_shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// A handle to stop the server once it runs
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
     }
 
     /// Run the serve listening on the given address
-    pub fn run<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
-        let listener = TcpListener::bind(addr)?;
-        for stream in listener.incoming() {
+    ///
+    /// The address is a TCP address or the path of a Unix domain socket written
+    /// `unix:/path`, see [`Address`](crate::Address).
+    ///
+    /// Each connection is served by a thread of the pool. The requests pipelined
+    /// on a connection are run concurrently on the pool as well. A connection
+    /// subscribed to some channels holds its thread until it is closed.
+    ///
+    /// It is the only way to run a server answering the admin requests.
+    pub fn run<A: ToAddress>(self, addr: A) -> Result<()> {
+        let addr = addr.to_address()?;
+        let limits = self.limits.clone();
+        let resp = Response::Err {
+            code: ErrorCode::Overloaded,
+            message: "too many connections".to_owned(),
+        };
+        let overloaded = Frame::new(OP_ERROR, 0, &resp)?.to_bytes()?;
+        let auth = self.auth.clone();
+        let replica = self.replica.clone();
+        let cluster = self.cluster.clone();
+        let channels = self.channels.clone();
+        let metrics = self.metrics.clone();
+        let shutdown = self.shutdown.clone();
+        let admin = Admin::new(ServerConfig {
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            addr: addr.clone(),
+            limits: limits.clone(),
+            shutdown_timeout: self.shutdown_timeout,
+            tls: self.tls.is_some(),
+            auth: auth.is_some(),
+            leader: replica.leader(),
+            cluster_node: cluster.as_ref().map(RaftNode::id),
+            log_level: log::max_level().to_string(),
+        });
+        if let Some(leader) = replica.leader() {
+            info!("Following {}", leader);
             let engine = self.engine.clone();
-            self.pool.spawn(|| match stream {
-                Ok(stream) => {
-                    if let Err(e) = serve(engine, stream) {
-                        error!("Error on serving client: {}", e);
-                    }
+            let credentials = self.leader_credentials.clone();
+            let replica = replica.clone();
+            thread::spawn(move || replication::follow(engine, replica, credentials));
+        }
+        self.listen(addr, overloaded, move |engine, stream, pool| {
+            let access = Access {
+                session: auth.clone().map(Session::new),
+                replica: replica.clone(),
+                cluster: cluster.clone(),
+                channels: channels.clone(),
+                metrics: metrics.clone(),
+                client: Some(admin.register(&stream.peer_addr()?)),
+                admin: Some(admin.clone()),
+                shutdown: shutdown.clone(),
+            };
+            serve(engine, stream, pool, &limits, access)
+        })
+    }
+
+    /// Run the server speaking the Redis protocol (RESP2) on the given address
+    ///
+    /// Existing Redis clients can use the store without modification, with the
+    /// commands supported listed in the `resp` module.
+    pub fn run_resp<A: ToAddress>(self, addr: A) -> Result<()> {
+        self.kvs_only("RESP")?;
+        let expirations = Expirations::default();
+        let max_request_size = self.limits.max_request_size;
+        self.listen(addr, resp::OVERLOADED.to_vec(), move |engine, stream, _| {
+            resp::serve(engine, stream, expirations.clone(), max_request_size)
+        })
+    }
+
+    /// Run the server speaking the memcached text protocol on the given address
+    pub fn run_memcache<A: ToAddress>(self, addr: A) -> Result<()> {
+        self.kvs_only("memcached")?;
+        let items = Items::default();
+        self.listen(
+            addr,
+            memcache::OVERLOADED.to_vec(),
+            move |engine, stream, _| memcache::serve(engine, stream, items.clone()),
+        )
+    }
+
+    /// Run an HTTP gateway with a JSON API on the given address
+    ///
+    /// See the `http` module for the endpoints.
+    pub fn run_http<A: ToAddress>(self, addr: A) -> Result<()> {
+        self.kvs_only("HTTP")?;
+        let overloaded = http::overloaded()?;
+        self.listen(addr, overloaded, |engine, stream, _| {
+            http::serve(engine, stream)
+        })
+    }
+
+    /// Fails for the protocols that support neither authentication, replication nor clustering
+    fn kvs_only(&self, protocol: &str) -> Result<()> {
+        let feature = if self.auth.is_some() {
+            "authentication"
+        } else if self.replica.leader().is_some() {
+            "replication"
+        } else if self.cluster.is_some() {
+            "clustering"
+        } else {
+            return Ok(());
+        };
+        Err(KvsError::StringError(format!(
+            "the {} protocol does not support {}",
+            protocol, feature
+        )))
+    }
+
+    /// Accepts connections on `addr`, serving each one on a thread of the pool
+    ///
+    /// The connections beyond the limit are sent `overloaded` and closed. It
+    /// returns once the server is shut down and the connections are drained.
+    fn listen<A, F>(self, addr: A, overloaded: Vec<u8>, serve: F) -> Result<()>
+    where
+        A: ToAddress,
+        F: Fn(E, Stream, Arc<P>) -> Result<()> + Clone + Send + 'static,
+    {
+        let addr = addr.to_address()?;
+        if self.tls.is_some() && matches!(addr, Address::Unix(_)) {
+            return Err(KvsError::Tls("TLS is only served over TCP".to_owned()));
+        }
+        let listener = addr.bind()?;
+        self.shutdown.bind(listener.local_addr()?);
+        if let Some(metrics) = &self.metrics {
+            metrics.set_engine(self.engine.clone());
+            metrics.add_pool(addr.to_string(), Arc::downgrade(&self.pool));
+        }
+        while !self.shutdown.is_shutdown() {
+            let stream = match listener.accept() {
+                Ok(stream) => stream,
+                Err(err) => {
+                    error!("Connection failed: {}", err);
+                    continue;
                 }
+            };
+            let full = self
+                .limits
+                .max_connections
+                .is_some_and(|max| self.shutdown.connections() >= max);
+            if full && !self.shutdown.is_shutdown() {
+                warn!("Too many connections, rejecting {:?}", stream.peer_addr());
+                reject(stream, &overloaded);
+                continue;
+            }
+            let guard = match self.admit(&stream) {
+                Ok(Some(guard)) => guard,
+                // woken up by the shutdown
+                Ok(None) => break,
                 Err(err) => {
                     error!("Connection failed: {}", err);
+                    continue;
+                }
+            };
+            let engine = self.engine.clone();
+            let pool = Arc::clone(&self.pool);
+            let serve = serve.clone();
+            let tls = self.tls.clone();
+            let metrics = self.metrics.clone();
+            self.pool.spawn(move || {
+                let _guard = guard;
+                let _connection = metrics.as_ref().map(Metrics::connect);
+                let result = match (tls, stream) {
+                    (Some(tls), Stream::Tcp(tcp)) => tls.accept(tcp).map(Stream::Tls),
+                    (_, stream) => Ok(stream),
+                };
+                if let Err(e) = result.and_then(|stream| serve(engine, stream, pool)) {
+                    error!("Error on serving client: {}", e);
                 }
             })
         }
+        self.finish()
+    }
+
+    /// Applies the timeouts to an accepted connection and tracks it
+    ///
+    /// It returns `None` if the server is shutting down.
+    fn admit(&self, stream: &Stream) -> io::Result<Option<ConnectionGuard>> {
+        stream.set_read_timeout(self.limits.idle_timeout)?;
+        stream.set_write_timeout(self.limits.write_timeout)?;
+        self.shutdown.track(stream)
+    }
+
+    /// Lets the requests in flight finish, then flushes the engine and joins the pool
+    fn finish(self) -> Result<()> {
+        info!("Shutting down");
+        self.replica.stop();
+        let closed = self.shutdown.drain(self.shutdown_timeout);
+        if closed > 0 {
+            warn!(
+                "{} connections are closed after {:?}",
+                closed, self.shutdown_timeout
+            );
+        }
+        self.engine.flush()?;
+        // the connections closed forcibly may still hold the pool
+        match Arc::try_unwrap(self.pool) {
+            Ok(pool) => drop(pool),
+            Err(_) => warn!("Some requests are still running"),
+        }
         Ok(())
     }
 }
 
-fn serve<E: KvsEngine>(engine: E, tcp: TcpStream) -> Result<()> {
-    let peer_addr = tcp.peer_addr()?;
-    let reader = BufReader::new(&tcp);
-    let mut writer = BufWriter::new(&tcp);
-    let request_reader = Deserializer::from_reader(reader).into_iter::<Request>();
+/// Sends the rejection of a connection, without waiting for the client
+fn reject(mut stream: Stream, overloaded: &[u8]) {
+    let _ = stream.set_write_timeout(Some(REJECT_TIMEOUT));
+    let _ = stream.write_all(overloaded);
+    let _ = stream.shutdown(Shutdown::Write);
+}
+
+/// What the requests of a connection are checked against
+#[derive(Default)]
+pub struct Access {
+    /// The requests are only run once allowed by the session, if any
+    pub(crate) session: Option<Session>,
+    /// The writes are refused while the server follows a leader
+    pub(crate) replica: Replica,
+    /// The requests allowed are run through the cluster node, if any
+    pub(crate) cluster: Option<RaftNode>,
+    /// The channels the connection publishes and subscribes to
+    pub(crate) channels: Channels,
+    /// The requests are counted and timed, if any
+    pub(crate) metrics: Option<Metrics>,
+    /// The admin requests are answered with the state of the server, if any
+    pub(crate) admin: Option<Admin>,
+    /// The connection as listed to the admin requests
+    pub(crate) client: Option<ClientGuard>,
+    /// The push streams end once the server shuts down
+    pub(crate) shutdown: ShutdownHandle,
+}
+
+/// Tells whether a push stream is still wanted, while it has nothing to push
+///
+/// The client sends nothing once its connection is a push stream, so the
+/// stream ends as soon as the connection can be read from, be it closed by the
+/// client or shut down by the server.
+#[derive(Clone)]
+struct Liveness {
+    shutdown: ShutdownHandle,
+    stream: Arc<Stream>,
+}
+
+impl Liveness {
+    fn new(shutdown: ShutdownHandle, stream: &Stream) -> io::Result<Self> {
+        Ok(Liveness {
+            shutdown,
+            stream: Arc::new(stream.try_clone()?),
+        })
+    }
+
+    /// Returns `true` if the push stream should end
+    fn is_gone(&self) -> bool {
+        if self.shutdown.is_shutdown() {
+            return true;
+        }
+        let mut stream = match self.stream.try_clone() {
+            Ok(stream) => stream,
+            Err(_) => return true,
+        };
+        let timeout = Some(Duration::from_millis(1));
+        if stream.set_read_timeout(timeout).is_err() {
+            return true;
+        }
+        match stream.read(&mut [0]) {
+            Err(e) => !matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ),
+            Ok(_len) => true,
+        }
+    }
+
+    /// Waits for the next item `recv` gives, checking every `PUSH_CHECK_INTERVAL`
+    /// that the push stream is still wanted
+    ///
+    /// It returns `None` once there is no more item or the stream should end.
+    fn wait<T>(
+        &self,
+        recv: impl Fn(Duration) -> std::result::Result<T, RecvTimeoutError>,
+    ) -> Option<T> {
+        loop {
+            match recv(PUSH_CHECK_INTERVAL) {
+                Ok(item) => return Some(item),
+                Err(RecvTimeoutError::Timeout) if !self.is_gone() => {}
+                Err(_) => return None,
+            }
+        }
+    }
+}
+
+/// Serves a connection speaking the framed or the legacy protocol
+pub fn serve<E, P>(
+    engine: E,
+    stream: Stream,
+    pool: Arc<P>,
+    limits: &Limits,
+    mut access: Access,
+) -> Result<()>
+where
+    E: KvsEngine,
+    P: ThreadPool + Send + Sync + 'static,
+{
+    let peer_addr = stream.peer_addr()?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let writer = BufWriter::new(stream.try_clone()?);
+
+    let first_byte = match wait_request(&mut reader, &peer_addr)? {
+        Some(byte) => byte,
+        None => return Ok(()),
+    };
+    if protocol::is_legacy(first_byte) {
+        debug!("{} speaks the legacy protocol", peer_addr);
+        let live = Liveness::new(access.shutdown.clone(), &stream)?;
+        let mut responder = JsonResponder {
+            writer,
+            peer_addr: peer_addr.clone(),
+            timer: None,
+        };
+        let count = Rc::new(Cell::new(0));
+        let reader = LimitedReader {
+            inner: reader,
+            count: Rc::clone(&count),
+            limit: u64::from(limits.max_request_size),
+            stream: &stream,
+            limits,
+            started: false,
+        };
+        let request_reader = Deserializer::from_reader(reader).into_iter::<Request>();
+        for request in request_reader {
+            let request = match request {
+                Ok(request) => request,
+                Err(_) if count.get() >= u64::from(limits.max_request_size) => {
+                    let message = format!("request exceeds {} bytes", limits.max_request_size);
+                    responder.respond(&Err(KvsError::BadRequest(message)).into())?;
+                    return Ok(());
+                }
+                Err(e) if e.is_io() => {
+                    debug!("Connection to {} is closed: {}", peer_addr, e);
+                    return Ok(());
+                }
+                Err(e) => return Err(e.into()),
+            };
+            count.set(0);
+            debug!("Receive request from {}: {:?}", peer_addr, request);
+            responder.timer = start_timer(&access, &request);
+            let request = match check_access(&engine, &mut access, request, &mut responder)? {
+                Some(request) => request,
+                None => continue,
+            };
+            let channels = &access.channels;
+            if !execute(&engine, channels, &live, request, None, &mut responder)? {
+                break;
+            }
+        }
+        return Ok(());
+    }
+
+    let writer = Arc::new(Mutex::new(writer));
+    let mut responder = FrameResponder {
+        writer: Arc::clone(&writer),
+        peer_addr,
+        request_id: 0,
+        timer: None,
+    };
+    if !handshake(&mut reader, &mut responder)? {
+        return Ok(());
+    }
+
+    let dispatcher = Dispatcher::new(pool);
+    let result = serve_frames(
+        &engine,
+        &stream,
+        &mut reader,
+        &writer,
+        limits,
+        &dispatcher,
+        &mut access,
+    );
+    // let the pipelined requests finish before closing the connection
+    dispatcher.wait();
+    result
+}
 
-    macro_rules! send_resp {
-        ($resp:expr) => {{
-            let resp = $resp;
-            serde_json::to_writer(&mut writer, &resp)?;
-            writer.flush()?;
-            debug!("Response sent to {}: {:?}", peer_addr, resp);
-        };};
+/// Waits for the next request, returning its first byte or `None` if the connection is closed
+///
+/// A connection idle for longer than its read timeout is closed.
+fn wait_request<R: Read>(reader: &mut BufReader<R>, peer_addr: &Address) -> Result<Option<u8>> {
+    match reader.fill_buf() {
+        Ok(buf) => Ok(buf.first().copied()),
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ) =>
+        {
+            debug!("Connection to {} is idle, closing it", peer_addr);
+            Ok(None)
+        }
+        Err(e) => Err(e.into()),
     }
-    for request in request_reader {
-        let request = request?;
-        debug!("Receive request from {}: {:?}", peer_addr, request);
+}
 
-        match request {
-            Request::Set { key, value } => send_resp!(match engine.set(key, value) {
-                Ok(_) => SetResponse::Ok(()),
-                Err(err) => SetResponse::Err(format!("{}", err)),
-            }),
-            Request::Get { key } => send_resp!(match engine.get(key) {
-                Ok(value) => GetResponse::Ok(value),
-                Err(err) => GetResponse::Err(format!("{}", err)),
-            }),
-            Request::Remove { key } => send_resp!(match engine.remove(key) {
-                Ok(()) => RmResponse::Ok(()),
-                Err(err) => RmResponse::Err(format!("{}", err)),
-            }),
+fn serve_frames<E, P, W>(
+    engine: &E,
+    stream: &Stream,
+    reader: &mut BufReader<Stream>,
+    writer: &Arc<Mutex<W>>,
+    limits: &Limits,
+    dispatcher: &Dispatcher<P>,
+    access: &mut Access,
+) -> Result<()>
+where
+    E: KvsEngine,
+    P: ThreadPool + Send + Sync + 'static,
+    W: Write + Send + 'static,
+{
+    let peer_addr = stream.peer_addr()?;
+    let live = Liveness::new(access.shutdown.clone(), stream)?;
+    loop {
+        if reader.buffer().is_empty() {
+            // the client may be waiting for the responses before sending more
+            dispatcher.wait();
+            stream.set_read_timeout(limits.idle_timeout)?;
+            if wait_request(reader, &peer_addr)?.is_none() {
+                return Ok(());
+            }
+            stream.set_read_timeout(limits.read_timeout.or(limits.idle_timeout))?;
+        }
+        let header = match Header::read_from(reader)? {
+            Some(header) => header,
+            None => return Ok(()),
+        };
+        if header.len > limits.max_request_size {
+            let mut responder = FrameResponder {
+                writer: Arc::clone(writer),
+                peer_addr: peer_addr.clone(),
+                request_id: header.request_id,
+                timer: None,
+            };
+            let message = format!(
+                "frame of {} bytes exceeds the limit of {}",
+                header.len, limits.max_request_size
+            );
+            header.skip_payload(reader)?;
+            responder.error(&message)?;
+            continue;
+        }
+        let frame = header.read_payload(reader)?;
+        let mut responder = FrameResponder {
+            writer: Arc::clone(writer),
+            peer_addr: peer_addr.clone(),
+            request_id: frame.request_id,
+            timer: None,
+        };
+        let (request, deadline) = match parse_request(&frame) {
+            Ok(parsed) => parsed,
+            Err(message) => {
+                responder.error(&message)?;
+                continue;
+            }
+        };
+        debug!(
+            "Receive request {} from {}: {:?}",
+            frame.request_id, peer_addr, request
+        );
+        responder.timer = start_timer(access, &request);
+        let request = match check_access(engine, access, request, &mut responder)? {
+            Some(request) => request,
+            None => continue,
+        };
+
+        match request.key().map(str::to_owned) {
+            Some(key) => {
+                let engine = engine.clone();
+                let channels = access.channels.clone();
+                let live = live.clone();
+                dispatcher.dispatch(&key, move || {
+                    let out = &mut responder;
+                    if let Err(e) = execute(&engine, &channels, &live, request, deadline, out) {
+                        error!("Error on serving client: {}", e);
+                    }
+                });
+            }
+            // requests on several keys or on no key wait for all the previous ones
+            None => {
+                dispatcher.wait();
+                let channels = &access.channels;
+                if !execute(engine, channels, &live, request, deadline, &mut responder)? {
+                    return Ok(());
+                }
+            }
         }
     }
+}
 
+/// Decodes a request frame into the request and its deadline, or why it is refused
+pub fn parse_request(frame: &Frame) -> std::result::Result<(Request, Option<Instant>), String> {
+    let malformed = |e: KvsError| format!("malformed request: {}", e);
+    match frame.opcode {
+        OP_REQUEST => Ok((frame.parse().map_err(malformed)?, None)),
+        OP_DEADLINE => {
+            let DeadlineRequest::<Request> {
+                timeout_ms,
+                request,
+            } = frame.parse().map_err(malformed)?;
+            let deadline = Instant::now() + Duration::from_millis(timeout_ms);
+            Ok((request, Some(deadline)))
+        }
+        opcode => Err(format!("unexpected opcode {}", opcode)),
+    }
+}
+
+/// Starts timing a request until its response is sent, if the connection records metrics
+fn start_timer(access: &Access, request: &Request) -> Option<RequestTimer> {
+    let metrics = access.metrics.as_ref()?;
+    Some(metrics.start(request.name()))
+}
+
+/// Whether a request with `deadline` must not be started anymore
+pub fn expired(deadline: Option<Instant>) -> bool {
+    deadline.is_some_and(|deadline| Instant::now() >= deadline)
+}
+
+/// Fails the reads going past `limit` bytes since `count` is reset
+///
+/// As for frames, the first byte of a request is awaited for the idle timeout
+/// of `limits`, and the rest of it for the read timeout.
+struct LimitedReader<'a, R> {
+    inner: R,
+    count: Rc<Cell<u64>>,
+    limit: u64,
+    stream: &'a Stream,
+    limits: &'a Limits,
+    // whether a request has started since `count` was reset
+    started: bool,
+}
+
+impl<R: Read> Read for LimitedReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.started && self.count.get() == 0 {
+            self.stream.set_read_timeout(self.limits.idle_timeout)?;
+            self.started = false;
+        }
+        let left = self.limit.saturating_sub(self.count.get());
+        if left == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "request too large",
+            ));
+        }
+        let len = buf.len().min(left as usize);
+        let len = self.inner.read(&mut buf[..len])?;
+        self.count.set(self.count.get() + len as u64);
+        if !self.started && buf[..len].iter().any(|b| !b.is_ascii_whitespace()) {
+            let timeout = self.limits.read_timeout.or(self.limits.idle_timeout);
+            self.stream.set_read_timeout(timeout)?;
+            self.started = true;
+        }
+        Ok(len)
+    }
+}
+
+/// Exchanges `Hello`s with the client, returning `false` if the connection should be closed
+fn handshake<R: Read, W: Write>(reader: &mut R, responder: &mut FrameResponder<W>) -> Result<bool> {
+    let frame = match Frame::read_from(reader)? {
+        Some(frame) => frame,
+        None => return Ok(false),
+    };
+    match accept_hello(&frame) {
+        Ok(hello) => {
+            debug!(
+                "{} speaks protocol version {}",
+                responder.peer_addr, hello.version
+            );
+            responder.send(OP_HELLO, &hello)?;
+            Ok(true)
+        }
+        Err(message) => {
+            responder.error(&message)?;
+            Ok(false)
+        }
+    }
+}
+
+/// Checks the first frame of a client, returning the `Hello` to answer with or why it is refused
+pub fn accept_hello(frame: &Frame) -> std::result::Result<Hello, String> {
+    if frame.opcode != OP_HELLO {
+        return Err("expect a handshake".to_owned());
+    }
+    let hello = frame
+        .parse::<Hello>()
+        .map_err(|e| format!("malformed handshake: {}", e))?;
+    if hello.version < MIN_PROTOCOL_VERSION {
+        return Err(format!("unsupported protocol version {}", hello.version));
+    }
+    Ok(Hello::server(hello.version))
+}
+
+/// Sends the responses of a connection
+trait Responder {
+    /// Sends the response to the current request
+    fn respond(&mut self, resp: &Response) -> Result<()>;
+
+    /// Pushes a change to a watching client
+    fn push(&mut self, event: &Event) -> Result<()>;
+
+    /// Pushes a message to a follower
+    fn replicate(&mut self, message: &Replication) -> Result<()>;
+
+    /// Pushes a message published on a channel to a subscriber
+    fn deliver(&mut self, message: &ChannelMessage) -> Result<()>;
+}
+
+/// Writes back-to-back JSON values, for the legacy protocol
+struct JsonResponder<W: Write> {
+    writer: W,
+    peer_addr: Address,
+    timer: Option<RequestTimer>,
+}
+
+impl<W: Write> Responder for JsonResponder<W> {
+    fn respond(&mut self, resp: &Response) -> Result<()> {
+        // recorded first, so the metrics count the request once the client has the reply
+        if let Some(timer) = self.timer.take() {
+            timer.finish(resp);
+        }
+        serde_json::to_writer(&mut self.writer, &resp.to_legacy())?;
+        self.writer.flush()?;
+        debug!("Response sent to {}: {:?}", self.peer_addr, resp);
+        Ok(())
+    }
+
+    fn push(&mut self, event: &Event) -> Result<()> {
+        serde_json::to_writer(&mut self.writer, event)?;
+        self.writer.flush()?;
+        debug!("Event sent to {}: {:?}", self.peer_addr, event);
+        Ok(())
+    }
+
+    fn replicate(&mut self, message: &Replication) -> Result<()> {
+        serde_json::to_writer(&mut self.writer, message)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn deliver(&mut self, message: &ChannelMessage) -> Result<()> {
+        serde_json::to_writer(&mut self.writer, message)?;
+        self.writer.flush()?;
+        debug!("Message sent to {}: {:?}", self.peer_addr, message);
+        Ok(())
+    }
+}
+
+/// Writes frames tagged with the id of the request being served
+///
+/// The writer is shared by the requests of a connection running concurrently.
+struct FrameResponder<W: Write> {
+    writer: Arc<Mutex<W>>,
+    peer_addr: Address,
+    request_id: u32,
+    timer: Option<RequestTimer>,
+}
+
+impl<W: Write> FrameResponder<W> {
+    fn send<T: Serialize>(&mut self, opcode: u8, body: &T) -> Result<()> {
+        let frame = Frame::new(opcode, self.request_id, body)?;
+        let mut writer = self.writer.lock().unwrap();
+        frame.write_to(&mut *writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Reports a frame the server cannot handle
+    fn error(&mut self, message: &str) -> Result<()> {
+        debug!("Error sent to {}: {}", self.peer_addr, message);
+        let resp = Response::Err {
+            code: ErrorCode::BadRequest,
+            message: message.to_owned(),
+        };
+        self.send(OP_ERROR, &resp)
+    }
+}
+
+impl<W: Write> Responder for FrameResponder<W> {
+    fn respond(&mut self, resp: &Response) -> Result<()> {
+        // recorded first, so the metrics count the request once the client has the reply
+        if let Some(timer) = self.timer.take() {
+            timer.finish(resp);
+        }
+        self.send(OP_RESPONSE, resp)?;
+        debug!("Response sent to {}: {:?}", self.peer_addr, resp);
+        Ok(())
+    }
+
+    fn push(&mut self, event: &Event) -> Result<()> {
+        self.send(OP_EVENT, event)?;
+        debug!("Event sent to {}: {:?}", self.peer_addr, event);
+        Ok(())
+    }
+
+    fn replicate(&mut self, message: &Replication) -> Result<()> {
+        self.send(OP_REPLICATE, message)
+    }
+
+    fn deliver(&mut self, message: &ChannelMessage) -> Result<()> {
+        self.send(OP_MESSAGE, message)?;
+        debug!("Message sent to {}: {:?}", self.peer_addr, message);
+        Ok(())
+    }
+}
+
+/// Answers the requests `access` does not allow, the authentication, the
+/// promotion and the admin requests
+///
+/// It returns the request to execute, if any. Without a session, any
+/// credentials are accepted. A cluster node answers all the requests allowed
+/// but the ones on channels, which stay local to the server.
+fn check_access<E: KvsEngine, R: Responder>(
+    engine: &E,
+    access: &mut Access,
+    request: Request,
+    out: &mut R,
+) -> Result<Option<Request>> {
+    if let Some(client) = &access.client {
+        client.record();
+    }
+    let result = match (&request, &mut access.session) {
+        (Request::Auth(credentials), Some(session)) => session.authenticate(credentials),
+        (Request::Auth(_), None) => Ok(()),
+        (_, Some(session)) => session.authorize(&request),
+        (_, None) => Ok(()),
+    };
+    let result = result.and_then(|()| access.replica.check(&request));
+    match (result, request) {
+        (Ok(()), Request::Auth(_)) => {
+            if let (Some(client), Some(session)) = (&access.client, &access.session) {
+                client.set_user(session.user());
+            }
+            out.respond(&Response::Ok(Reply::Done))?;
+            Ok(None)
+        }
+        (Ok(()), Request::Admin(command)) => {
+            let result = match &access.admin {
+                Some(admin) => admin.run(engine, command),
+                None => Err(KvsError::BadRequest("admin is not supported here".to_owned())),
+            };
+            out.respond(&result.into())?;
+            Ok(None)
+        }
+        (Ok(()), Request::Promote) if access.cluster.is_none() => {
+            if access.replica.stop() {
+                info!("Promoted to leader");
+            }
+            out.respond(&Response::Ok(Reply::Done))?;
+            Ok(None)
+        }
+        (Ok(()), request @ Request::Publish { .. })
+        | (Ok(()), request @ Request::Subscribe { .. }) => Ok(Some(request)),
+        (Ok(()), request) => match &access.cluster {
+            Some(node) => {
+                out.respond(&node.execute(request).into())?;
+                Ok(None)
+            }
+            None => Ok(Some(request)),
+        },
+        (Err(err), _) => {
+            out.respond(&Err(err).into())?;
+            Ok(None)
+        }
+    }
+}
+
+/// Executes a request, returning `false` if no more request should be read from the connection
+///
+/// A request whose deadline has passed is answered with `KvsError::DeadlineExceeded`.
+fn execute<E: KvsEngine, R: Responder>(
+    engine: &E,
+    channels: &Channels,
+    live: &Liveness,
+    request: Request,
+    deadline: Option<Instant>,
+    out: &mut R,
+) -> Result<bool> {
+    if expired(deadline) {
+        out.respond(&Err(KvsError::DeadlineExceeded).into())?;
+        return Ok(true);
+    }
+    if let Request::Watch { prefix } = request {
+        let watcher = match engine.watch(prefix) {
+            Ok(watcher) => watcher,
+            Err(err) => {
+                out.respond(&Err(err).into())?;
+                return Ok(true);
+            }
+        };
+        out.respond(&Response::Ok(Reply::Done))?;
+        // the connection is a push stream from now on
+        while let Some(event) = live.wait(|timeout| watcher.recv_timeout(timeout)) {
+            out.push(&event)?;
+        }
+        return Ok(false);
+    }
+    if let Request::Replicate = request {
+        replicate(engine, live, out)?;
+        return Ok(false);
+    }
+    if let Request::Publish { channel, message } = request {
+        let receivers = channels.publish(&channel, &message);
+        out.respond(&Response::Ok(Reply::Receivers(receivers)))?;
+        return Ok(true);
+    }
+    if let Request::Subscribe {
+        channels: names,
+        patterns,
+    } = request
+    {
+        let messages = match channels.subscribe(names, patterns) {
+            Ok(messages) => messages,
+            Err(err) => {
+                out.respond(&Err(err).into())?;
+                return Ok(true);
+            }
+        };
+        out.respond(&Response::Ok(Reply::Done))?;
+        // the connection is a push stream from now on
+        while let Some(message) = live.wait(|timeout| messages.recv_timeout(timeout)) {
+            out.deliver(&message)?;
+        }
+        return Ok(false);
+    }
+    out.respond(&apply(engine, request).into())?;
+    Ok(true)
+}
+
+/// Sends a snapshot of the data to a follower, then the changes made from now on
+///
+/// The changes are watched before the snapshot is taken, so none is missed.
+fn replicate<E: KvsEngine, R: Responder>(engine: &E, live: &Liveness, out: &mut R) -> Result<()> {
+    let watcher = match engine.watch(String::new()) {
+        Ok(watcher) => watcher,
+        Err(err) => return out.respond(&Err(err).into()),
+    };
+    out.respond(&Response::Ok(Reply::Done))?;
+    let mut after = None;
+    loop {
+        let keys = engine.scan(String::new(), after.take(), SNAPSHOT_PAGE)?;
+        let last = match keys.last() {
+            Some(last) => last.clone(),
+            None => break,
+        };
+        let values = engine.get_many(keys.clone())?;
+        // the keys removed since the scan are left to the changes
+        let pairs = keys
+            .into_iter()
+            .zip(values)
+            .filter_map(|(key, value)| value.map(|value| (key, value)))
+            .collect();
+        out.replicate(&Replication::Snapshot(pairs))?;
+        after = Some(last);
+    }
+    out.replicate(&Replication::SnapshotEnd)?;
+    // the connection is a push stream from now on
+    while let Some(event) = live.wait(|timeout| watcher.recv_timeout(timeout)) {
+        out.replicate(&Replication::Change(event))?;
+    }
     Ok(())
 }
+
+/// Runs a request on the engine
+///
+/// `Watch`, `Replicate` and `Subscribe` turn a connection into a stream, so
+/// they are left to the caller along with `Publish`, and the cluster requests
+/// to the servers running a node.
+/// `Auth` and `Promote` are answered by the servers checking credentials or
+/// following a leader before this, so the others accept them.
+pub fn apply<E: KvsEngine>(engine: &E, request: Request) -> Result<Reply> {
+    match request {
+        Request::Set { key, value } => engine.set(key, value).map(|_| Reply::Done),
+        Request::Get { key } => engine.get(key).map(Reply::Value),
+        Request::Remove { key } => engine.remove(key).map(|_| Reply::Done),
+        Request::MGet { keys } => engine.get_many(keys).map(Reply::Values),
+        Request::MSet { pairs } => engine.set_many(pairs).map(|_| Reply::Done),
+        Request::Incr { key, delta } => engine.incr(key, delta).map(Reply::Integer),
+        Request::Decr { key, delta } => engine.decr(key, delta).map(Reply::Integer),
+        Request::Append { key, suffix } => engine.append(key, suffix).map(Reply::Length),
+        Request::Stats => engine.stats().map(Reply::Stats),
+        Request::Scan {
+            prefix,
+            after,
+            limit,
+        } => engine.scan(prefix, after, limit).map(Reply::Keys),
+        Request::Ping | Request::Auth(_) | Request::Promote => Ok(Reply::Done),
+        Request::Watch { .. }
+        | Request::Replicate
+        | Request::Raft(_)
+        | Request::AddNode { .. }
+        | Request::RemoveNode { .. }
+        | Request::ClusterStatus
+        | Request::Publish { .. }
+        | Request::Subscribe { .. }
+        | Request::Admin(_) => Err(KvsError::BadRequest(format!(
+            "{} is not supported here",
+            request.name()
+        ))),
+    }
+}
@@ -0,0 +1,375 @@
+//! Spreading the keys over several servers with consistent hashing
+//!
+//! Each server owns many points of a ring of 64-bit hashes, its virtual nodes,
+//! and a key belongs to the server of the first point following its hash. A
+//! server joining or leaving the ring only takes or gives away the keys next
+//! to its points, about `1 / servers` of them, spread evenly over the others.
+use crate::client::unexpected_reply;
+use crate::client_pool::is_broken;
+use crate::common::Reply;
+use crate::{Address, Credentials, KvsClient, KvsError, Result, TlsClientConfig};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+
+/// The default number of points of a server on the ring
+pub const DEFAULT_VIRTUAL_NODES: usize = 160;
+/// The number of keys moved at once while rebalancing
+const REBALANCE_PAGE: usize = 1000;
+
+/// A consistent-hash ring telling which server owns a key
+///
+/// The hashes only depend on the addresses of the servers and the keys, so
+/// rings built from the same servers agree, whatever their order.
+#[derive(Debug, Clone)]
+pub struct HashRing {
+    virtual_nodes: usize,
+    servers: Vec<Address>,
+    points: BTreeMap<u64, Address>,
+}
+
+impl HashRing {
+    /// A ring of `servers`, each one owning `virtual_nodes` points
+    pub fn new(servers: Vec<Address>, virtual_nodes: usize) -> Self {
+        let mut ring = HashRing {
+            virtual_nodes: virtual_nodes.max(1),
+            servers: Vec::new(),
+            points: BTreeMap::new(),
+        };
+        for server in servers {
+            ring.add(server);
+        }
+        ring
+    }
+
+    /// The servers of the ring, in the order they were added
+    pub fn servers(&self) -> &[Address] {
+        &self.servers
+    }
+
+    /// The number of points of each server
+    pub fn virtual_nodes(&self) -> usize {
+        self.virtual_nodes
+    }
+
+    /// Adds a server to the ring, returning `false` if it is already on it
+    pub fn add(&mut self, server: Address) -> bool {
+        if self.servers.contains(&server) {
+            return false;
+        }
+        for i in 0..self.virtual_nodes {
+            let point = hash(format!("{}#{}", server, i).as_bytes());
+            self.points.insert(point, server.clone());
+        }
+        self.servers.push(server);
+        true
+    }
+
+    /// Removes a server from the ring, returning `false` if it is not on it
+    pub fn remove(&mut self, server: &Address) -> bool {
+        let len = self.servers.len();
+        self.servers.retain(|s| s != server);
+        self.points.retain(|_, s| s != server);
+        self.servers.len() != len
+    }
+
+    /// The server owning `key`, unless the ring is empty
+    pub fn server_for(&self, key: &str) -> Option<&Address> {
+        let point = hash(key.as_bytes());
+        self.points
+            .range(point..)
+            .next()
+            .or_else(|| self.points.iter().next())
+            .map(|(_, server)| server)
+    }
+}
+
+/// The first 8 bytes of the SHA-256 digest, the same on every platform and version
+fn hash(data: &[u8]) -> u64 {
+    let digest = Sha256::digest(data);
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&digest[..8]);
+    u64::from_be_bytes(bytes)
+}
+
+/// A client spreading the keys over several servers
+///
+/// Each key is stored on the server owning it on a `HashRing`. The requests
+/// on several keys are split by server, and `scan` asks every server. The
+/// connections are opened when first needed, and opened again after breaking.
+///
+/// `add_server` and `remove_server` move the keys changing server before the
+/// client routes to the new ring. The writes made meanwhile by other clients
+/// to the keys being moved may be lost, so they are best paused.
+pub struct ShardedKvsClient {
+    ring: HashRing,
+    clients: HashMap<Address, KvsClient>,
+    tls: Option<TlsClientConfig>,
+    credentials: Option<Credentials>,
+}
+
+impl ShardedKvsClient {
+    /// Creates a client of `addrs`, with `DEFAULT_VIRTUAL_NODES` points each
+    ///
+    /// # Panics
+    /// It panics if `addrs` is empty.
+    pub fn new(addrs: Vec<Address>) -> Self {
+        assert!(!addrs.is_empty(), "a sharded client needs a server address");
+        ShardedKvsClient {
+            ring: HashRing::new(addrs, DEFAULT_VIRTUAL_NODES),
+            clients: HashMap::new(),
+            tls: None,
+            credentials: None,
+        }
+    }
+
+    /// Sets the number of points of each server on the ring
+    ///
+    /// All the clients of the same servers must use the same number.
+    pub fn with_virtual_nodes(mut self, virtual_nodes: usize) -> Self {
+        self.ring = HashRing::new(self.ring.servers().to_vec(), virtual_nodes);
+        self
+    }
+
+    /// Connects to the servers over TLS
+    pub fn with_tls(mut self, tls: TlsClientConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Authenticates the connections with `credentials` once opened
+    pub fn with_credentials(mut self, credentials: Credentials) -> Self {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    /// The ring the keys are routed with
+    pub fn ring(&self) -> &HashRing {
+        &self.ring
+    }
+
+    /// Get the value of the given key
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        let addr = self.owner(&key);
+        self.call(&addr, |client| client.get(key))
+    }
+
+    /// Set the value of a string key
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        let addr = self.owner(&key);
+        self.call(&addr, |client| client.set(key, value))
+    }
+
+    /// Remove a string key
+    pub fn rm(&mut self, key: String) -> Result<()> {
+        let addr = self.owner(&key);
+        self.call(&addr, |client| client.rm(key))
+    }
+
+    /// Add `delta` to the integer value of a key, returning the new value
+    pub fn incr(&mut self, key: String, delta: i64) -> Result<i64> {
+        let addr = self.owner(&key);
+        self.call(&addr, |client| client.incr(key, delta))
+    }
+
+    /// Subtract `delta` from the integer value of a key, returning the new value
+    pub fn decr(&mut self, key: String, delta: i64) -> Result<i64> {
+        let addr = self.owner(&key);
+        self.call(&addr, |client| client.decr(key, delta))
+    }
+
+    /// Append `suffix` to the value of a key, returning the new length
+    pub fn append(&mut self, key: String, suffix: String) -> Result<u64> {
+        let addr = self.owner(&key);
+        self.call(&addr, |client| client.append(key, suffix))
+    }
+
+    /// Get the values of several keys, asking each server for its own
+    pub fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let mut values = vec![None; keys.len()];
+        for (addr, (positions, keys)) in self.split(keys) {
+            let found = self.call(&addr, |client| client.get_many(keys))?;
+            for (position, value) in positions.into_iter().zip(found) {
+                values[position] = value;
+            }
+        }
+        Ok(values)
+    }
+
+    /// Set several key/value pairs, sending each server its own
+    ///
+    /// The pairs are set atomically on each server, but not across servers.
+    pub fn set_many(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        let mut by_server: HashMap<Address, Vec<(String, String)>> = HashMap::new();
+        for (key, value) in pairs {
+            by_server
+                .entry(self.owner(&key))
+                .or_default()
+                .push((key, value));
+        }
+        for (addr, pairs) in by_server {
+            self.call(&addr, |client| client.set_many(pairs))?;
+        }
+        Ok(())
+    }
+
+    /// List at most `limit` keys starting with `prefix`, in order, from all the servers
+    ///
+    /// See [`KvsClient::scan`].
+    pub fn scan(
+        &mut self,
+        prefix: String,
+        after: Option<String>,
+        limit: usize,
+    ) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        for addr in self.ring.servers().to_vec() {
+            let (prefix, after) = (prefix.clone(), after.clone());
+            keys.extend(self.call(&addr, |client| client.scan(prefix, after, limit))?);
+        }
+        // each server lists its first keys, so the first ones overall are among them
+        keys.sort();
+        keys.dedup();
+        keys.truncate(limit);
+        Ok(keys)
+    }
+
+    /// Adds a server, moving to it the keys it owns from now on, and returns their number
+    pub fn add_server(&mut self, addr: Address) -> Result<u64> {
+        let mut ring = self.ring.clone();
+        if !ring.add(addr) {
+            return Ok(0);
+        }
+        self.rebalance(ring)
+    }
+
+    /// Removes a server, moving its keys to the others, and returns their number
+    pub fn remove_server(&mut self, addr: &Address) -> Result<u64> {
+        let mut ring = self.ring.clone();
+        if !ring.remove(addr) {
+            return Ok(0);
+        }
+        if ring.servers().is_empty() {
+            return Err(KvsError::StringError(
+                "cannot remove the last server".to_owned(),
+            ));
+        }
+        let moved = self.rebalance(ring)?;
+        self.clients.remove(addr);
+        Ok(moved)
+    }
+
+    /// Moves the keys whose server differs on `ring`, then routes with it
+    ///
+    /// A key is set on its new server before it is removed from the old one,
+    /// so a failure leaves it on both, and rebalancing again moves it. An empty
+    /// ring is refused, as it leaves the keys nowhere to go.
+    pub fn rebalance(&mut self, ring: HashRing) -> Result<u64> {
+        if ring.servers().is_empty() {
+            return Err(KvsError::StringError(
+                "cannot rebalance onto an empty ring".to_owned(),
+            ));
+        }
+        let mut moved = 0;
+        for source in self.ring.servers().to_vec() {
+            let mut after = None;
+            loop {
+                let keys = self.call(&source, |client| {
+                    client.scan(String::new(), after.take(), REBALANCE_PAGE)
+                })?;
+                let last = match keys.last() {
+                    Some(last) => last.clone(),
+                    None => break,
+                };
+                let moving: Vec<String> = keys
+                    .into_iter()
+                    .filter(|key| ring.server_for(key) != Some(&source))
+                    .collect();
+                if !moving.is_empty() {
+                    moved += self.move_keys(&source, moving, &ring)?;
+                }
+                after = Some(last);
+            }
+        }
+        self.ring = ring;
+        Ok(moved)
+    }
+
+    /// Copies `keys` from `source` to their servers on `ring`, then removes them from `source`
+    fn move_keys(&mut self, source: &Address, keys: Vec<String>, ring: &HashRing) -> Result<u64> {
+        let values = self.call(source, |client| client.get_many(keys.clone()))?;
+        let mut by_server: HashMap<Address, Vec<(String, String)>> = HashMap::new();
+        for (key, value) in keys.iter().zip(values) {
+            // removed since the scan
+            if let (Some(value), Some(target)) = (value, ring.server_for(key)) {
+                by_server
+                    .entry(target.clone())
+                    .or_default()
+                    .push((key.clone(), value));
+            }
+        }
+        let mut moved = 0;
+        for (target, pairs) in by_server {
+            moved += pairs.len() as u64;
+            self.call(&target, |client| client.set_many(pairs))?;
+        }
+        let results = self.call(source, |client| {
+            keys.into_iter()
+                .fold(client.pipeline(), |pipeline, key| pipeline.rm(key))
+                .execute()
+        })?;
+        for result in results {
+            match result {
+                Ok(Reply::Done) | Err(KvsError::KeyNotFound) => {}
+                Ok(reply) => return Err(unexpected_reply(reply)),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(moved)
+    }
+
+    /// The server owning `key`
+    fn owner(&self, key: &str) -> Address {
+        // the ring is never empty
+        self.ring.server_for(key).unwrap().clone()
+    }
+
+    /// Splits `keys` by server, along with their positions
+    fn split(&self, keys: Vec<String>) -> HashMap<Address, (Vec<usize>, Vec<String>)> {
+        let mut by_server: HashMap<Address, (Vec<usize>, Vec<String>)> = HashMap::new();
+        for (position, key) in keys.into_iter().enumerate() {
+            let (positions, keys) = by_server.entry(self.owner(&key)).or_default();
+            positions.push(position);
+            keys.push(key);
+        }
+        by_server
+    }
+
+    /// Runs `op` on the connection to `addr`, dropping it once broken
+    fn call<T, F>(&mut self, addr: &Address, op: F) -> Result<T>
+    where
+        F: FnOnce(&mut KvsClient) -> Result<T>,
+    {
+        if !self.clients.contains_key(addr) {
+            let client = self.connect(addr)?;
+            self.clients.insert(addr.clone(), client);
+        }
+        let result = op(self.clients.get_mut(addr).unwrap());
+        if let Err(e) = &result {
+            if is_broken(e) {
+                self.clients.remove(addr);
+            }
+        }
+        result
+    }
+
+    fn connect(&self, addr: &Address) -> Result<KvsClient> {
+        let mut client = match &self.tls {
+            Some(tls) => KvsClient::connect_tls(addr, tls)?,
+            None => KvsClient::connect(addr)?,
+        };
+        if let Some(credentials) = &self.credentials {
+            client.authenticate(credentials.clone())?;
+        }
+        Ok(client)
+    }
+}
@@ -0,0 +1,134 @@
+use crate::transport::{Address, Stream};
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// A handle to stop a running server gracefully
+///
+/// Once `shutdown` is called, the server stops accepting connections and stops
+/// reading requests from the open ones. It then waits for the requests in
+/// flight to finish, flushes the engine and returns from `run`.
+#[derive(Clone, Default)]
+pub struct ShutdownHandle(Arc<State>);
+
+#[derive(Default)]
+struct State {
+    requested: AtomicBool,
+    // the address of the listener, to wake it up from `accept`
+    addr: Mutex<Option<Address>>,
+    connections: Mutex<Connections>,
+    closed: Condvar,
+}
+
+#[derive(Default)]
+struct Connections {
+    next_id: u64,
+    streams: HashMap<u64, Stream>,
+}
+
+impl ShutdownHandle {
+    /// Asks the server to shut down, without waiting for it
+    pub fn shutdown(&self) {
+        {
+            let connections = self.0.connections.lock().unwrap();
+            if self.0.requested.swap(true, Ordering::SeqCst) {
+                return;
+            }
+            // a blocked read sees the end of the stream, so no more request is read
+            for stream in connections.streams.values() {
+                let _ = stream.shutdown(Shutdown::Read);
+            }
+        }
+        if let Some(addr) = &*self.0.addr.lock().unwrap() {
+            let _ = addr.connect();
+        }
+    }
+
+    /// Whether the shutdown has been requested
+    pub fn is_shutdown(&self) -> bool {
+        self.0.requested.load(Ordering::SeqCst)
+    }
+
+    /// Records the address the server listens on
+    pub(crate) fn bind(&self, addr: Address) {
+        let addr = match addr {
+            Address::Tcp(addr) => Address::Tcp(loopback(addr)),
+            addr => addr,
+        };
+        *self.0.addr.lock().unwrap() = Some(addr);
+    }
+
+    /// The number of connections open
+    pub(crate) fn connections(&self) -> usize {
+        self.0.connections.lock().unwrap().streams.len()
+    }
+
+    /// Tracks an accepted connection until the returned guard is dropped
+    ///
+    /// It returns `None` if the server is shutting down.
+    pub(crate) fn track(&self, stream: &Stream) -> io::Result<Option<ConnectionGuard>> {
+        let stream = stream.try_clone()?;
+        let mut connections = self.0.connections.lock().unwrap();
+        if self.is_shutdown() {
+            return Ok(None);
+        }
+        connections.next_id += 1;
+        let id = connections.next_id;
+        connections.streams.insert(id, stream);
+        Ok(Some(ConnectionGuard {
+            handle: self.clone(),
+            id,
+        }))
+    }
+
+    /// Waits for the tracked connections to close, closing those left after `timeout`
+    ///
+    /// It returns the number of connections closed forcibly.
+    pub(crate) fn drain(&self, timeout: Duration) -> usize {
+        let deadline = Instant::now() + timeout;
+        let mut connections = self.0.connections.lock().unwrap();
+        while !connections.streams.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            connections = self
+                .0
+                .closed
+                .wait_timeout(connections, deadline - now)
+                .unwrap()
+                .0;
+        }
+        for stream in connections.streams.values() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        connections.streams.len()
+    }
+}
+
+/// Stops tracking a connection when dropped
+pub(crate) struct ConnectionGuard {
+    handle: ShutdownHandle,
+    id: u64,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let state = &self.handle.0;
+        state.connections.lock().unwrap().streams.remove(&self.id);
+        state.closed.notify_all();
+    }
+}
+
+/// The address to connect to for reaching a listener bound to `addr`
+fn loopback(mut addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => addr.set_ip(Ipv4Addr::LOCALHOST.into()),
+        IpAddr::V6(ip) if ip.is_unspecified() => addr.set_ip(Ipv6Addr::LOCALHOST.into()),
+        _ => {}
+    }
+    addr
+}
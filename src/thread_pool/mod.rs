@@ -9,6 +9,9 @@ pub use self::naive::NaiveThreadPool;
 pub use self::rayon::RayonThreadPool;
 pub use self::shared_queue::SharedQueueThreadPool;
 use crate::Result;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
 
 /// The trait that all thread pool should implement.
 pub trait ThreadPool {
@@ -24,4 +27,71 @@ pub trait ThreadPool {
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static;
+
+    /// What the pool is busy with
+    ///
+    /// The pools that do not keep track report nothing.
+    fn stats(&self) -> PoolStats {
+        PoolStats::default()
+    }
+}
+
+/// What a thread pool is busy with
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Jobs spawned but not started yet
+    pub queued: usize,
+    /// Jobs running
+    pub busy: usize,
+    /// Jobs that panicked, whose threads the `SharedQueueThreadPool` restarts
+    pub panics: u64,
+}
+
+/// Counts the jobs of a pool as they are queued, run and finished
+#[derive(Default, Clone)]
+struct Tracker(Arc<Counters>);
+
+#[derive(Default)]
+struct Counters {
+    queued: AtomicUsize,
+    busy: AtomicUsize,
+    panics: AtomicU64,
+}
+
+impl Tracker {
+    /// Wraps a job about to be spawned so that it is counted
+    fn track<F>(&self, job: F) -> impl FnOnce() + Send + 'static
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.0.queued.fetch_add(1, Ordering::Relaxed);
+        let tracker = self.clone();
+        move || {
+            tracker.0.queued.fetch_sub(1, Ordering::Relaxed);
+            tracker.0.busy.fetch_add(1, Ordering::Relaxed);
+            let _busy = Busy(tracker);
+            job()
+        }
+    }
+
+    fn stats(&self) -> PoolStats {
+        PoolStats {
+            queued: self.0.queued.load(Ordering::Relaxed),
+            busy: self.0.busy.load(Ordering::Relaxed),
+            panics: self.0.panics.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Counts a job as finished once dropped, even if it panics
+struct Busy(Tracker);
+
+impl Drop for Busy {
+    fn drop(&mut self) {
+        let counters = &(self.0).0;
+        counters.busy.fetch_sub(1, Ordering::Relaxed);
+        if thread::panicking() {
+            counters.panics.fetch_add(1, Ordering::Relaxed);
+        }
+    }
 }
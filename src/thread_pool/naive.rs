@@ -1,21 +1,25 @@
 use std::thread;
 
-use super::ThreadPool;
+use super::{PoolStats, ThreadPool, Tracker};
 use crate::Result;
 
 /// It is actually not a thread pool. It spawns a new thread every time
 /// the `spawn` method is called.
-pub struct NaiveThreadPool;
+pub struct NaiveThreadPool(Tracker);
 
 impl ThreadPool for NaiveThreadPool {
     fn new(_threads: u32) -> Result<Self> {
-        Ok(NaiveThreadPool)
+        Ok(NaiveThreadPool(Tracker::default()))
     }
 
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        thread::spawn(job);
+        thread::spawn(self.0.track(job));
+    }
+
+    fn stats(&self) -> PoolStats {
+        self.0.stats()
     }
 }
@@ -1,8 +1,8 @@
-use super::ThreadPool;
+use super::{PoolStats, ThreadPool, Tracker};
 use crate::{KvsError, Result};
 
 /// Wrapper of rayon::ThreadPool
-pub struct RayonThreadPool(rayon::ThreadPool);
+pub struct RayonThreadPool(rayon::ThreadPool, Tracker);
 
 impl ThreadPool for RayonThreadPool {
     fn new(threads: u32) -> Result<Self> {
@@ -10,13 +10,17 @@ impl ThreadPool for RayonThreadPool {
             .num_threads(threads as usize)
             .build()
             .map_err(|e| KvsError::StringError(format!("{}", e)))?;
-        Ok(RayonThreadPool(pool))
+        Ok(RayonThreadPool(pool, Tracker::default()))
     }
 
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        self.0.spawn(job)
+        self.0.spawn(self.1.track(job))
+    }
+
+    fn stats(&self) -> PoolStats {
+        self.1.stats()
     }
 }
@@ -1,13 +1,17 @@
-use std::thread;
+use std::thread::{self, JoinHandle};
 
-use super::ThreadPool;
+use super::{PoolStats, ThreadPool, Tracker};
 use crate::Result;
 use crossbeam::channel::{self, Receiver, Sender};
 use log::{debug, error};
 
 /// A thread pool using a shared queue inside.
+///
+/// Dropping the pool waits for the queued jobs to finish and joins its threads.
 pub struct SharedQueueThreadPool {
-    tx: Sender<Box<dyn FnOnce() + Send + 'static>>,
+    tx: Option<Sender<Box<dyn FnOnce() + Send + 'static>>>,
+    threads: Vec<JoinHandle<()>>,
+    tracker: Tracker,
 }
 
 impl ThreadPool for SharedQueueThreadPool {
@@ -16,11 +20,16 @@ impl ThreadPool for SharedQueueThreadPool {
         Self: Sized,
     {
         let (tx, rx) = channel::unbounded();
+        let mut handles = Vec::new();
         for _ in 0..threads {
             let rx = TaskReceiver(rx.clone());
-            thread::Builder::new().spawn(move || run_task(rx))?;
+            handles.push(thread::Builder::new().spawn(move || run_task(rx))?);
         }
-        Ok(SharedQueueThreadPool { tx })
+        Ok(SharedQueueThreadPool {
+            tx: Some(tx),
+            threads: handles,
+            tracker: Tracker::default(),
+        })
     }
 
     fn spawn<F>(&self, job: F)
@@ -28,9 +37,29 @@ impl ThreadPool for SharedQueueThreadPool {
         F: FnOnce() + Send + 'static,
     {
         self.tx
-            .send(Box::new(job))
+            .as_ref()
+            .expect("the thread pool is dropped")
+            .send(Box::new(self.tracker.track(job)))
             .expect("the thread pool has no thread");
     }
+
+    fn stats(&self) -> PoolStats {
+        self.tracker.stats()
+    }
+}
+
+impl Drop for SharedQueueThreadPool {
+    fn drop(&mut self) {
+        // the threads exit once the queue is closed and drained
+        self.tx.take();
+        let current = thread::current().id();
+        for handle in self.threads.drain(..) {
+            // the last job holding the pool may drop it from one of its threads
+            if handle.thread().id() != current {
+                let _ = handle.join();
+            }
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -52,7 +81,10 @@ fn run_task(rx: TaskReceiver) {
             Ok(task) => {
                 task();
             }
-            Err(_) => debug!("Thread exits because the thread pool is destroyed."),
+            Err(_) => {
+                debug!("Thread exits because the thread pool is destroyed.");
+                return;
+            }
         }
     }
 }
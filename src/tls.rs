@@ -0,0 +1,233 @@
+use crate::{KvsError, Result};
+use rustls::server::AllowAnyAuthenticatedClient;
+use rustls::{
+    Certificate, ClientConfig, ClientConnection, Connection, PrivateKey, RootCertStore,
+    ServerConfig, ServerConnection, ServerName,
+};
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// The most TLS bytes read from the socket at once
+///
+/// It stays below the limit of plaintext a session buffers, so the bytes read
+/// can always be handed to the session.
+const READ_BUF_LEN: usize = 8 * 1024;
+
+/// The TLS settings of a server
+///
+/// Created from PEM files, see [`KvsServer::with_tls`](crate::KvsServer::with_tls).
+#[derive(Clone)]
+pub struct TlsServerConfig(Arc<ServerConfig>);
+
+impl TlsServerConfig {
+    /// Loads the certificate chain and the private key of the server
+    ///
+    /// With `client_ca`, clients must present a certificate signed by one of
+    /// the CAs in this file (mutual TLS).
+    pub fn from_pem_files(cert: &Path, key: &Path, client_ca: Option<&Path>) -> Result<Self> {
+        let builder = ServerConfig::builder().with_safe_defaults();
+        let builder = match client_ca {
+            Some(ca) => {
+                let verifier = AllowAnyAuthenticatedClient::new(load_roots(ca)?);
+                builder.with_client_cert_verifier(verifier.boxed())
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let config = builder.with_single_cert(load_certs(cert)?, load_key(key)?)?;
+        Ok(TlsServerConfig(Arc::new(config)))
+    }
+
+    /// Runs the server side of the handshake on an accepted connection
+    pub(crate) fn accept(&self, tcp: TcpStream) -> Result<TlsStream> {
+        let session = ServerConnection::new(Arc::clone(&self.0))?;
+        TlsStream::handshake(session.into(), tcp)
+    }
+}
+
+/// The TLS settings of a client
+///
+/// See [`KvsClient::connect_tls`](crate::KvsClient::connect_tls).
+#[derive(Clone)]
+pub struct TlsClientConfig {
+    config: Arc<ClientConfig>,
+    server_name: ServerName,
+}
+
+impl TlsClientConfig {
+    /// Trusts the servers whose certificate is signed by a CA in `ca` and is valid for `server_name`
+    ///
+    /// With `identity`, the client presents the certificate chain and the
+    /// private key in these files to the servers asking for one.
+    pub fn from_pem_files(
+        ca: &Path,
+        server_name: &str,
+        identity: Option<(&Path, &Path)>,
+    ) -> Result<Self> {
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(load_roots(ca)?);
+        let config = match identity {
+            Some((cert, key)) => {
+                builder.with_client_auth_cert(load_certs(cert)?, load_key(key)?)?
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let server_name = ServerName::try_from(server_name)
+            .map_err(|_| KvsError::Tls(format!("invalid server name {}", server_name)))?;
+        Ok(TlsClientConfig {
+            config: Arc::new(config),
+            server_name,
+        })
+    }
+
+    /// Runs the client side of the handshake on a new connection
+    pub(crate) fn connect(&self, tcp: TcpStream) -> Result<TlsStream> {
+        let session = ClientConnection::new(Arc::clone(&self.config), self.server_name.clone())?;
+        TlsStream::handshake(session.into(), tcp)
+    }
+}
+
+/// A TLS session over a TCP connection
+///
+/// The clones share the session, so one may read while another writes from
+/// another thread: the socket is only read or written without holding the
+/// session, which is locked to decrypt the bytes read or encrypt those to write.
+pub struct TlsStream {
+    shared: Arc<Shared>,
+    tcp: TcpStream,
+}
+
+struct Shared {
+    session: Mutex<Connection>,
+    // held while sending records, so they leave in the order they are made
+    sending: Mutex<()>,
+}
+
+impl TlsStream {
+    fn handshake(mut session: Connection, mut tcp: TcpStream) -> Result<Self> {
+        while session.is_handshaking() {
+            if session.complete_io(&mut tcp)? == (0, 0) {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+        }
+        let shared = Shared {
+            session: Mutex::new(session),
+            sending: Mutex::new(()),
+        };
+        Ok(TlsStream {
+            shared: Arc::new(shared),
+            tcp,
+        })
+    }
+
+    pub(crate) fn try_clone(&self) -> io::Result<TlsStream> {
+        Ok(TlsStream {
+            shared: Arc::clone(&self.shared),
+            tcp: self.tcp.try_clone()?,
+        })
+    }
+
+    pub(crate) fn tcp(&self) -> &TcpStream {
+        &self.tcp
+    }
+
+    /// Sends the records queued by the session
+    fn send_pending(&self) -> io::Result<()> {
+        let _sending = self.shared.sending.lock().unwrap();
+        let mut records = Vec::new();
+        {
+            let mut session = self.shared.session.lock().unwrap();
+            while session.wants_write() {
+                session.write_tls(&mut records)?;
+            }
+        }
+        (&self.tcp).write_all(&records)
+    }
+
+    /// Decrypts the records read from the socket
+    fn receive(&self, mut records: &[u8]) -> io::Result<()> {
+        let mut session = self.shared.session.lock().unwrap();
+        while !records.is_empty() {
+            session.read_tls(&mut records)?;
+            if let Err(e) = session.process_new_packets() {
+                drop(session);
+                // let the peer know with an alert
+                let _ = self.send_pending();
+                return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut records = [0; READ_BUF_LEN];
+        loop {
+            match self.shared.session.lock().unwrap().reader().read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                result => return result,
+            }
+            let len = self.tcp.read(&mut records)?;
+            if len == 0 {
+                return Ok(0);
+            }
+            self.receive(&records[..len])?;
+            // the session may have to answer, e.g. to a key update
+            self.send_pending()?;
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.shared.session.lock().unwrap().writer().write(buf)?;
+        self.send_pending()?;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.shared.session.lock().unwrap().writer().flush()?;
+        self.send_pending()
+    }
+}
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader)?;
+    if certs.is_empty() {
+        return Err(KvsError::Tls(format!(
+            "no certificate found in {}",
+            path.display()
+        )));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &Path) -> Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+    for item in rustls_pemfile::read_all(&mut reader)? {
+        match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => {}
+        }
+    }
+    Err(KvsError::Tls(format!(
+        "no private key found in {}",
+        path.display()
+    )))
+}
+
+fn load_roots(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(&cert)?;
+    }
+    Ok(roots)
+}
@@ -0,0 +1,302 @@
+//! The connections between the clients and the servers, over TCP or Unix domain sockets
+use crate::tls::TlsStream;
+use serde::de::{self, Deserialize, Deserializer};
+use serde::{Serialize, Serializer};
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+const UNIX_SCHEME: &str = "unix:";
+
+/// Where a server listens and a client connects
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Address {
+    /// A TCP address
+    Tcp(SocketAddr),
+    /// The path of a Unix domain socket, written `unix:/path`
+    Unix(PathBuf),
+}
+
+impl Address {
+    /// Opens a plaintext connection to the address
+    pub(crate) fn connect(&self) -> io::Result<Stream> {
+        match self {
+            Address::Tcp(addr) => TcpStream::connect(addr).map(Stream::Tcp),
+            #[cfg(unix)]
+            Address::Unix(path) => UnixStream::connect(path).map(Stream::Unix),
+            #[cfg(not(unix))]
+            Address::Unix(_) => Err(unix_unsupported()),
+        }
+    }
+
+    /// Listens on the address
+    ///
+    /// A Unix socket file left by a server that is gone is replaced, and the
+    /// file is removed once the listener is dropped.
+    pub(crate) fn bind(&self) -> io::Result<Listener> {
+        match self {
+            Address::Tcp(addr) => TcpListener::bind(addr).map(Listener::Tcp),
+            #[cfg(unix)]
+            Address::Unix(path) => {
+                if path.exists() {
+                    if UnixStream::connect(path).is_ok() {
+                        return Err(io::Error::new(
+                            io::ErrorKind::AddrInUse,
+                            format!("{} is in use", path.display()),
+                        ));
+                    }
+                    fs::remove_file(path)?;
+                }
+                let listener = UnixListener::bind(path)?;
+                Ok(Listener::Unix(listener, path.clone()))
+            }
+            #[cfg(not(unix))]
+            Address::Unix(_) => Err(unix_unsupported()),
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Tcp(addr) => write!(f, "{}", addr),
+            Address::Unix(path) => write!(f, "{}{}", UNIX_SCHEME, path.display()),
+        }
+    }
+}
+
+impl FromStr for Address {
+    type Err = io::Error;
+
+    /// Parses `unix:/path` or a TCP address, resolving the host names
+    fn from_str(s: &str) -> io::Result<Address> {
+        if let Some(path) = s.strip_prefix(UNIX_SCHEME) {
+            return Ok(Address::Unix(PathBuf::from(path)));
+        }
+        s.to_socket_addrs()?
+            .next()
+            .map(Address::Tcp)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("no address found for {}", s),
+                )
+            })
+    }
+}
+
+// written as in the configuration files and on the command line
+impl Serialize for Address {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Address {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Address, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+impl From<SocketAddr> for Address {
+    fn from(addr: SocketAddr) -> Address {
+        Address::Tcp(addr)
+    }
+}
+
+/// A value that can be turned into an `Address`
+///
+/// Strings are parsed, so `"unix:/path"` is a Unix socket and `"127.0.0.1:4000"`
+/// a TCP address.
+pub trait ToAddress {
+    /// Resolves the address
+    fn to_address(&self) -> io::Result<Address>;
+}
+
+impl ToAddress for Address {
+    fn to_address(&self) -> io::Result<Address> {
+        Ok(self.clone())
+    }
+}
+
+impl ToAddress for SocketAddr {
+    fn to_address(&self) -> io::Result<Address> {
+        Ok(Address::Tcp(*self))
+    }
+}
+
+impl ToAddress for str {
+    fn to_address(&self) -> io::Result<Address> {
+        self.parse()
+    }
+}
+
+impl ToAddress for String {
+    fn to_address(&self) -> io::Result<Address> {
+        self.parse()
+    }
+}
+
+impl<T: ToAddress + ?Sized> ToAddress for &T {
+    fn to_address(&self) -> io::Result<Address> {
+        (**self).to_address()
+    }
+}
+
+/// A socket accepting the connections of a server
+pub enum Listener {
+    /// Listening on TCP
+    Tcp(TcpListener),
+    /// Listening on a Unix socket, whose file is removed on drop
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    /// Waits for the next connection
+    pub fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Listener::Tcp(listener) => listener.accept().map(|(tcp, _)| Stream::Tcp(tcp)),
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => listener.accept().map(|(unix, _)| Stream::Unix(unix)),
+        }
+    }
+
+    /// The address the listener is bound to
+    pub fn local_addr(&self) -> io::Result<Address> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().map(Address::Tcp),
+            #[cfg(unix)]
+            Listener::Unix(_, path) => Ok(Address::Unix(path.clone())),
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        match self {
+            Listener::Tcp(_) => {}
+            #[cfg(unix)]
+            Listener::Unix(_, path) => {
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+}
+
+/// A connection accepted by a server or opened by a client
+///
+/// Its clones are the same connection, so one may be read while another is written.
+pub enum Stream {
+    /// A plaintext TCP connection
+    Tcp(TcpStream),
+    /// A TLS session over a TCP connection
+    Tls(TlsStream),
+    /// A Unix domain socket connection
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    /// Another handle to the same connection
+    pub fn try_clone(&self) -> io::Result<Stream> {
+        match self {
+            Stream::Tcp(tcp) => tcp.try_clone().map(Stream::Tcp),
+            Stream::Tls(tls) => tls.try_clone().map(Stream::Tls),
+            #[cfg(unix)]
+            Stream::Unix(unix) => unix.try_clone().map(Stream::Unix),
+        }
+    }
+
+    /// The address of the other end
+    ///
+    /// The clients of a Unix socket are usually unnamed, with an empty path.
+    pub fn peer_addr(&self) -> io::Result<Address> {
+        match self {
+            Stream::Tcp(tcp) => tcp.peer_addr().map(Address::Tcp),
+            Stream::Tls(tls) => tls.tcp().peer_addr().map(Address::Tcp),
+            #[cfg(unix)]
+            Stream::Unix(unix) => {
+                let addr = unix.peer_addr()?;
+                let path = addr.as_pathname().map(PathBuf::from).unwrap_or_default();
+                Ok(Address::Unix(path))
+            }
+        }
+    }
+
+    /// Sets how long a read may block before failing
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(tcp) => tcp.set_read_timeout(timeout),
+            Stream::Tls(tls) => tls.tcp().set_read_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(unix) => unix.set_read_timeout(timeout),
+        }
+    }
+
+    /// Sets how long a write may block before failing
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(tcp) => tcp.set_write_timeout(timeout),
+            Stream::Tls(tls) => tls.tcp().set_write_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(unix) => unix.set_write_timeout(timeout),
+        }
+    }
+
+    /// Shuts down the reading, writing or both halves of the connection
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Stream::Tcp(tcp) => tcp.shutdown(how),
+            Stream::Tls(tls) => tls.tcp().shutdown(how),
+            #[cfg(unix)]
+            Stream::Unix(unix) => unix.shutdown(how),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(tcp) => tcp.read(buf),
+            Stream::Tls(tls) => tls.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(unix) => unix.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(tcp) => tcp.write(buf),
+            Stream::Tls(tls) => tls.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(unix) => unix.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(tcp) => tcp.flush(),
+            Stream::Tls(tls) => tls.flush(),
+            #[cfg(unix)]
+            Stream::Unix(unix) => unix.flush(),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn unix_unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Unix domain sockets are not supported on this platform",
+    )
+}
@@ -0,0 +1,105 @@
+mod common;
+
+use common::TestServer;
+use kvs::{hash_token, AuthConfig, Credentials, KvsClient, KvsError, Result};
+use log::LevelFilter;
+use serde_json::json;
+use std::fs;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Runs a server with the kvs engine in the background, authenticating with `auth` if given.
+fn start_server(addr: &'static str, auth: Option<AuthConfig>) {
+    TestServer::new().start_with(addr, move |mut server| {
+        if let Some(auth) = auth {
+            server = server.with_auth(auth);
+        }
+        server.run(addr)
+    });
+}
+
+#[test]
+fn admin_commands() -> Result<()> {
+    let addr = "127.0.0.1:5401";
+    start_server(addr, None);
+
+    let mut client = KvsClient::connect(addr)?;
+    for i in 0..100 {
+        client.set(format!("key{}", i % 10), format!("value{}", i))?;
+    }
+    client.rm("key0".to_owned())?;
+    assert!(client.stats()?.garbage_bytes > 0);
+    let stats = client.compact()?;
+    assert_eq!(stats.garbage_bytes, 0);
+    assert_eq!(stats.keys, 9);
+    assert_eq!(stats.compactions, 1);
+    client.flush()?;
+    assert_eq!(client.get("key9".to_owned())?, Some("value99".to_owned()));
+
+    let mut other = KvsClient::connect(addr)?;
+    other.ping()?;
+    let stats = client.server_stats()?;
+    assert_eq!(stats.connections, 2);
+    assert!(stats.requests > 100);
+    assert_eq!(stats.engine.keys, 9);
+    let clients = client.clients()?;
+    assert_eq!(clients.len(), 2);
+    assert!(clients[0].requests > 100);
+    assert_eq!(clients[1].requests, 1);
+    drop(other);
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(client.clients()?.len(), 1);
+
+    let config = client.server_config()?;
+    assert_eq!(config.addr, addr.parse().unwrap());
+    assert!(!config.auth && !config.tls);
+    client.set_log_level(LevelFilter::Debug)?;
+    assert_eq!(client.server_config()?.log_level, "DEBUG");
+    client.set_log_level(LevelFilter::Info)?;
+    Ok(())
+}
+
+#[test]
+fn admin_permission() -> Result<()> {
+    let addr = "127.0.0.1:5402";
+    let temp_dir = TempDir::new().unwrap();
+    let config = json!({
+        "users": [
+            {
+                "name": "carol",
+                "tokens": [hash_token("carol-token")],
+                "grants": [{ "permissions": ["write"] }]
+            },
+            {
+                "name": "dave",
+                "tokens": [hash_token("dave-token")],
+                "grants": [{ "permissions": ["admin"] }]
+            }
+        ]
+    });
+    let path = temp_dir.path().join("auth.json");
+    fs::write(&path, config.to_string())?;
+    start_server(addr, Some(AuthConfig::load(&path)?));
+
+    let mut carol = KvsClient::connect(addr)?;
+    carol.authenticate(Credentials::Token("carol-token".to_owned()))?;
+    match carol.clients() {
+        Err(KvsError::Unauthorized(_)) => {}
+        other => panic!("expect Unauthorized, got {:?}", other),
+    }
+
+    let mut dave = KvsClient::connect(addr)?;
+    dave.authenticate(Credentials::Token("dave-token".to_owned()))?;
+    let users: Vec<_> = dave
+        .clients()?
+        .into_iter()
+        .map(|client| client.user)
+        .collect();
+    assert_eq!(
+        users,
+        vec![Some("carol".to_owned()), Some("dave".to_owned())]
+    );
+    assert!(dave.server_config()?.auth);
+    Ok(())
+}
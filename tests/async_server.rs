@@ -0,0 +1,110 @@
+mod common;
+
+use common::TestServer;
+use kvs::{AsyncKvsClient, KvsClient, KvsError, Op, Result};
+use serde_json::Value;
+use std::io::{BufReader, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+
+// Runs an async server on its own runtime for the rest of the test process.
+fn start_server(addr: &'static str, threads: u32) {
+    TestServer::new().with_threads(threads).start_async(addr);
+}
+
+// Idle connections do not hold the threads of the pool.
+#[test]
+fn idle_connections() -> Result<()> {
+    let addr = "127.0.0.1:4501";
+    start_server(addr, 2);
+
+    let mut idle = Vec::new();
+    for i in 0..8 {
+        let mut client = KvsClient::connect(addr)?;
+        client.set(format!("key{}", i), format!("value{}", i))?;
+        idle.push(client);
+    }
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get("key7".to_owned())?, Some("value7".to_owned()));
+
+    let replies = client
+        .pipeline()
+        .set("key1".to_owned(), "new".to_owned())
+        .get("key1".to_owned())
+        .get_many(vec!["key0".to_owned(), "key1".to_owned()])
+        .execute()?;
+    assert_eq!(replies.len(), 3);
+    assert_eq!(
+        client.get_many(vec!["key0".to_owned(), "key1".to_owned()])?,
+        vec![Some("value0".to_owned()), Some("new".to_owned())]
+    );
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn concurrent_requests() -> Result<()> {
+    let addr = "127.0.0.1:4502";
+    start_server(addr, 4);
+
+    let client = Arc::new(AsyncKvsClient::connect(addr).await?);
+    assert_eq!(client.protocol_version(), 1);
+    let mut handles = Vec::new();
+    for i in 0..100 {
+        let client = Arc::clone(&client);
+        handles.push(tokio::spawn(async move {
+            let key = format!("key{}", i);
+            client.set(key.clone(), format!("value{}", i)).await?;
+            client.get(key).await
+        }));
+    }
+    for (i, handle) in handles.into_iter().enumerate() {
+        assert_eq!(handle.await.unwrap()?, Some(format!("value{}", i)));
+    }
+
+    client.remove("key1".to_owned()).await?;
+    assert_eq!(client.get("key1".to_owned()).await?, None);
+    match client.remove("key1".to_owned()).await {
+        Err(KvsError::KeyNotFound) => {}
+        other => panic!("expect KeyNotFound, got {:?}", other),
+    }
+    Ok(())
+}
+
+// Clients speaking the unframed JSON protocol are still served.
+#[test]
+fn legacy_json_protocol() -> Result<()> {
+    let addr = "127.0.0.1:4503";
+    start_server(addr, 2);
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(br#"{"Set":{"key":"key1","value":"value1"}}"#)?;
+    let mut reader = serde_json::Deserializer::from_reader(BufReader::new(stream.try_clone()?))
+        .into_iter::<Value>();
+    assert_eq!(reader.next().unwrap()?, serde_json::json!({ "Ok": null }));
+
+    stream.write_all(br#"{"Get":{"key":"key1"}}"#)?;
+    assert_eq!(
+        reader.next().unwrap()?,
+        serde_json::json!({ "Ok": "value1" })
+    );
+    Ok(())
+}
+
+#[test]
+fn watch() -> Result<()> {
+    let addr = "127.0.0.1:4504";
+    start_server(addr, 2);
+
+    let mut events = KvsClient::connect(addr)?.watch("user:".to_owned())?;
+    let mut client = KvsClient::connect(addr)?;
+    client.set("other".to_owned(), "x".to_owned())?;
+    client.set("user:1".to_owned(), "alice".to_owned())?;
+    client.rm("user:1".to_owned())?;
+
+    let event = events.next().unwrap()?;
+    assert_eq!(event.key, "user:1");
+    assert_eq!(event.op, Op::Set("alice".to_owned()));
+    let event = events.next().unwrap()?;
+    assert_eq!(event.op, Op::Remove);
+    Ok(())
+}
@@ -0,0 +1,138 @@
+mod common;
+
+use common::TestServer;
+use kvs::{
+    hash_password, hash_token, AuthConfig, Credentials, KvStore, KvsClient, KvsClientPool,
+    KvsError, Result,
+};
+use serde_json::json;
+use std::fs;
+use tempfile::TempDir;
+
+// Runs a server with the kvs engine in the background, allowing:
+// * alice, with a password, to write the keys under `app/` and read all the keys
+// * bob, with a token, to administrate the whole keyspace
+fn start_server(addr: &'static str) {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = json!({
+        "users": [
+            {
+                "name": "alice",
+                "password": hash_password("secret").unwrap(),
+                "grants": [
+                    { "prefix": "app/", "permissions": ["write"] },
+                    { "prefix": "", "permissions": ["read"] }
+                ]
+            },
+            {
+                "name": "bob",
+                "tokens": [hash_token("bob-token")],
+                "grants": [{ "permissions": ["admin"] }]
+            }
+        ]
+    });
+    let config_path = temp_dir.path().join("auth.json");
+    fs::write(&config_path, config.to_string()).unwrap();
+    let auth = AuthConfig::load(&config_path).unwrap();
+
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    TestServer::with_engine(engine, temp_dir)
+        .start_with(addr, move |server| server.with_auth(auth).run(addr));
+}
+
+fn alice() -> Credentials {
+    Credentials::Password {
+        user: "alice".to_owned(),
+        password: "secret".to_owned(),
+    }
+}
+
+fn assert_unauthorized<T: std::fmt::Debug>(result: Result<T>) {
+    match result {
+        Err(KvsError::Unauthorized(_)) => {}
+        other => panic!("expect Unauthorized, got {:?}", other),
+    }
+}
+
+#[test]
+fn authentication_required() -> Result<()> {
+    let addr = "127.0.0.1:4701";
+    start_server(addr);
+
+    let mut client = KvsClient::connect(addr)?;
+    client.ping()?;
+    assert_unauthorized(client.get("key1".to_owned()));
+    assert_unauthorized(client.rm("key1".to_owned()));
+
+    assert_unauthorized(client.authenticate(Credentials::Password {
+        user: "alice".to_owned(),
+        password: "wrong".to_owned(),
+    }));
+    assert_unauthorized(client.authenticate(Credentials::Token("wrong".to_owned())));
+    assert_unauthorized(client.get("key1".to_owned()));
+
+    client.authenticate(alice())?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn grants() -> Result<()> {
+    let addr = "127.0.0.1:4702";
+    start_server(addr);
+
+    let mut alice_client = KvsClient::connect(addr)?;
+    alice_client.authenticate(alice())?;
+    alice_client.set("app/key1".to_owned(), "value1".to_owned())?;
+    alice_client.incr("app/counter".to_owned(), 1)?;
+    assert_unauthorized(alice_client.set("key1".to_owned(), "value1".to_owned()));
+    assert_unauthorized(alice_client.set_many(vec![
+        ("app/key2".to_owned(), "value2".to_owned()),
+        ("key2".to_owned(), "value2".to_owned()),
+    ]));
+    assert_unauthorized(alice_client.stats());
+
+    let mut bob_client = KvsClient::connect(addr)?;
+    bob_client.authenticate(Credentials::Token("bob-token".to_owned()))?;
+    bob_client.set("key1".to_owned(), "value1".to_owned())?;
+    bob_client.stats()?;
+
+    assert_eq!(
+        alice_client.get_many(vec!["app/key1".to_owned(), "key1".to_owned()])?,
+        vec![Some("value1".to_owned()), Some("value1".to_owned())]
+    );
+    assert_eq!(alice_client.get("app/key2".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn client_pool_credentials() -> Result<()> {
+    let addr = "127.0.0.1:4703";
+    start_server(addr);
+
+    let pool = KvsClientPool::new(vec![addr.parse().unwrap()]).with_credentials(alice());
+    pool.set("app/key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(pool.get("app/key1".to_owned())?, Some("value1".to_owned()));
+    assert_unauthorized(pool.rm("key1".to_owned()));
+    Ok(())
+}
+
+// The channels are granted like the keys with the same names.
+#[test]
+fn channel_grants() -> Result<()> {
+    let addr = "127.0.0.1:4704";
+    start_server(addr);
+
+    let mut client = KvsClient::connect(addr)?;
+    client.authenticate(alice())?;
+    assert_eq!(client.publish("app/news".to_owned(), "hello".to_owned())?, 0);
+    assert_unauthorized(client.publish("news".to_owned(), "hello".to_owned()));
+
+    let anonymous = KvsClient::connect(addr)?;
+    let subscription = anonymous.subscribe(vec!["app/news".to_owned()], Vec::new());
+    assert_unauthorized(subscription.map(|_| ()));
+    let mut subscriber = KvsClient::connect(addr)?;
+    subscriber.authenticate(alice())?;
+    let _messages = subscriber.subscribe(Vec::new(), vec!["app/*".to_owned()])?;
+    Ok(())
+}
@@ -0,0 +1,185 @@
+mod common;
+
+use common::wait_listening;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    hash_token, Address, AuthConfig, ClusterClient, ClusterConfig, Credentials, KvStore, KvsClient,
+    KvsError, KvsServer, Members, RaftNode, Result, ShutdownHandle, TcpTransport,
+};
+use serde_json::json;
+use std::fs;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+struct Node {
+    node: RaftNode,
+    shutdown: ShutdownHandle,
+    _temp_dir: TempDir,
+}
+
+// Runs a server for each member, as a node of their cluster.
+fn start_cluster(addrs: &[&str]) -> Vec<Node> {
+    start_cluster_with_auth(addrs, None)
+}
+
+// Runs a server for each member, authenticating its clients with `auth` if given,
+// the nodes included.
+fn start_cluster_with_auth(addrs: &[&str], auth: Option<(AuthConfig, Credentials)>) -> Vec<Node> {
+    let members: Members = addrs
+        .iter()
+        .enumerate()
+        .map(|(i, addr)| (i as u64 + 1, addr.parse().unwrap()))
+        .collect();
+    let mut nodes = Vec::new();
+    for (&id, addr) in &members {
+        let temp_dir = TempDir::new().unwrap();
+        let engine = KvStore::open(temp_dir.path()).unwrap();
+        let config = ClusterConfig::new(id, members.clone(), temp_dir.path().join("raft"));
+        let mut transport = TcpTransport::default();
+        if let Some((_, credentials)) = &auth {
+            transport = transport.with_credentials(credentials.clone());
+        }
+        let node = RaftNode::start(config, engine.clone(), transport).unwrap();
+        // the connections of the other nodes hold a thread each
+        let pool = SharedQueueThreadPool::new(8).unwrap();
+        let mut server = KvsServer::new(engine, pool).with_cluster(node.clone());
+        if let Some((config, _)) = &auth {
+            server = server.with_auth(config.clone());
+        }
+        let shutdown = server.shutdown_handle();
+        let server_addr = addr.clone();
+        thread::spawn(move || server.run(server_addr).unwrap());
+        wait_listening(&addr.to_string());
+        nodes.push(Node {
+            node,
+            shutdown,
+            _temp_dir: temp_dir,
+        });
+    }
+    nodes
+}
+
+fn addresses(addrs: &[&str]) -> Vec<Address> {
+    addrs.iter().map(|addr| addr.parse().unwrap()).collect()
+}
+
+fn wait_for_leader(client: &mut ClusterClient) -> u64 {
+    let start = Instant::now();
+    loop {
+        if let Ok(status) = client.status() {
+            if let Some(leader) = status.leader {
+                return leader;
+            }
+        }
+        assert!(
+            start.elapsed() < Duration::from_secs(5),
+            "no leader elected"
+        );
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+fn stop_all(nodes: &[Node]) {
+    for node in nodes {
+        node.shutdown.shutdown();
+        node.node.stop();
+    }
+}
+
+#[test]
+fn client_follows_leader() -> Result<()> {
+    let addrs = ["127.0.0.1:4901", "127.0.0.1:4902", "127.0.0.1:4903"];
+    let nodes = start_cluster(&addrs);
+    let mut client = ClusterClient::new(addresses(&addrs));
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(client.incr("counter".to_owned(), 2)?, 2);
+
+    // the leader goes away, the others elect a new one
+    let leader = wait_for_leader(&mut client);
+    let old = &nodes[leader as usize - 1];
+    old.shutdown.shutdown();
+    old.node.stop();
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(client.incr("counter".to_owned(), 1)?, 3);
+
+    let mut other = ClusterClient::new(addresses(&addrs));
+    assert_eq!(other.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_ne!(wait_for_leader(&mut other), leader);
+    stop_all(&nodes);
+    Ok(())
+}
+
+#[test]
+fn follower_redirects() -> Result<()> {
+    let addrs = ["127.0.0.1:4904", "127.0.0.1:4905", "127.0.0.1:4906"];
+    let nodes = start_cluster(&addrs);
+    let mut client = ClusterClient::new(addresses(&addrs));
+    client.set("key".to_owned(), "value".to_owned())?;
+    let leader = wait_for_leader(&mut client);
+
+    let follower = if leader == 1 { addrs[1] } else { addrs[0] };
+    let mut follower = KvsClient::connect(follower)?;
+    match follower.set("key".to_owned(), "changed".to_owned()) {
+        Err(KvsError::NotLeader(addr)) => assert_eq!(addr, addrs[leader as usize - 1]),
+        other => panic!("expect NotLeader, got {:?}", other),
+    }
+    let status = follower.cluster_status()?;
+    assert_eq!(status.leader, Some(leader));
+    assert_eq!(status.members.len(), 3);
+    stop_all(&nodes);
+    Ok(())
+}
+
+// An auth config granting admin to the holder of `token`
+fn admin_auth(dir: &TempDir, token: &str) -> AuthConfig {
+    let config = json!({
+        "users": [{
+            "name": "node",
+            "tokens": [hash_token(token)],
+            "grants": [{ "permissions": ["admin"] }]
+        }]
+    });
+    let path = dir.path().join("auth.json");
+    fs::write(&path, config.to_string()).unwrap();
+    AuthConfig::load(&path).unwrap()
+}
+
+#[test]
+fn cluster_with_authentication() -> Result<()> {
+    let addrs = ["127.0.0.1:4907", "127.0.0.1:4908", "127.0.0.1:4909"];
+    let temp_dir = TempDir::new().unwrap();
+    let credentials = Credentials::Token("node-token".to_owned());
+    let auth = admin_auth(&temp_dir, "node-token");
+    let nodes = start_cluster_with_auth(&addrs, Some((auth, credentials.clone())));
+
+    // a write only succeeds once the nodes, talking to each other, elect a leader
+    let start = Instant::now();
+    let leader = loop {
+        let written = addrs.iter().find(|addr| {
+            let mut client = KvsClient::connect(**addr).unwrap();
+            client.authenticate(credentials.clone()).unwrap();
+            client.set("key".to_owned(), "value".to_owned()).is_ok()
+        });
+        if let Some(&addr) = written {
+            break addr;
+        }
+        assert!(
+            start.elapsed() < Duration::from_secs(5),
+            "no leader elected"
+        );
+        thread::sleep(Duration::from_millis(50));
+    };
+
+    let mut client = KvsClient::connect(leader)?;
+    match client.get("key".to_owned()) {
+        Err(KvsError::Unauthorized(_)) => {}
+        other => panic!("expect Unauthorized, got {:?}", other),
+    }
+    client.authenticate(credentials)?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    stop_all(&nodes);
+    Ok(())
+}
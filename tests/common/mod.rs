@@ -0,0 +1,138 @@
+//! Helpers shared by the integration tests
+// each test crate uses its own part of them
+#![allow(dead_code)]
+
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{AsyncKvsServer, KvStore, KvsEngine, KvsServer, Limits, Result};
+use std::io::{self, Read};
+use std::net::{Shutdown, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// A server run in the background for the rest of the test process
+pub struct TestServer<E: KvsEngine> {
+    engine: E,
+    temp_dir: TempDir,
+    threads: u32,
+    limits: Limits,
+}
+
+impl TestServer<KvStore> {
+    /// A server with the kvs engine in a temporary directory, on a pool of 4 threads
+    pub fn new() -> Self {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let engine = KvStore::open(temp_dir.path()).unwrap();
+        TestServer::with_engine(engine, temp_dir)
+    }
+}
+
+impl<E: KvsEngine> TestServer<E> {
+    /// A server with `engine`, whose data is in `temp_dir`, on a pool of 4 threads
+    pub fn with_engine(engine: E, temp_dir: TempDir) -> Self {
+        TestServer {
+            engine,
+            temp_dir,
+            threads: 4,
+            limits: Limits::default(),
+        }
+    }
+
+    /// Sets the number of threads of the pool
+    pub fn with_threads(mut self, threads: u32) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    /// Sets the limits of the server
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// The engine of the server, to look at its data behind its back
+    pub fn engine(&self) -> E {
+        self.engine.clone()
+    }
+
+    /// Runs the server speaking the kvs protocol on `addr`
+    pub fn start(self, addr: &'static str) {
+        self.start_with(addr, move |server| server.run(addr));
+    }
+
+    /// Sets up the server, then has `run` finish and run it on `addr`
+    ///
+    /// It returns once the server accepts connections.
+    pub fn start_with<F>(self, addr: &'static str, run: F)
+    where
+        F: FnOnce(KvsServer<E, SharedQueueThreadPool>) -> Result<()> + Send + 'static,
+    {
+        let pool = SharedQueueThreadPool::new(self.threads).unwrap();
+        let server = KvsServer::new(self.engine, pool).with_limits(self.limits);
+        let temp_dir = self.temp_dir;
+        thread::spawn(move || {
+            let _temp_dir = temp_dir;
+            run(server).unwrap();
+        });
+        wait_listening(addr);
+    }
+
+    /// Runs the server on a runtime of its own, as an `AsyncKvsServer`, on `addr`
+    ///
+    /// The limits do not apply.
+    pub fn start_async(self, addr: &'static str) {
+        let pool = SharedQueueThreadPool::new(self.threads).unwrap();
+        let server = AsyncKvsServer::new(self.engine, pool);
+        let temp_dir = self.temp_dir;
+        thread::spawn(move || {
+            let _temp_dir = temp_dir;
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            runtime.block_on(server.run(addr)).unwrap();
+        });
+        wait_listening(addr);
+    }
+}
+
+/// Runs a server with the kvs engine in the background for each address
+pub fn start_servers(addrs: &[&'static str]) {
+    for &addr in addrs {
+        TestServer::new().start(addr);
+    }
+}
+
+/// Waits for a server to accept connections on `addr`, failing after 5 seconds
+///
+/// The server is done with the connection made to find out once it returns, so
+/// the connection does not count against `Limits::max_connections`.
+pub fn wait_listening(addr: &str) {
+    for _ in 0..500 {
+        if probe(addr).is_ok() {
+            return;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("no server accepts connections on {}", addr);
+}
+
+/// Connects to `addr`, then waits for the server to close the connection
+fn probe(addr: &str) -> io::Result<()> {
+    let mut rest = Vec::new();
+    match addr.strip_prefix("unix:") {
+        #[cfg(unix)]
+        Some(path) => {
+            let mut stream = UnixStream::connect(path)?;
+            stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+            stream.shutdown(Shutdown::Write)?;
+            let _ = stream.read_to_end(&mut rest);
+        }
+        _ => {
+            let mut stream = TcpStream::connect(addr)?;
+            stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+            stream.shutdown(Shutdown::Write)?;
+            let _ = stream.read_to_end(&mut rest);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,196 @@
+mod common;
+
+use common::TestServer;
+use kvs::{KvStore, KvsEngine};
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+
+fn start_server(addr: &'static str) -> KvStore {
+    let server = TestServer::new();
+    let engine = server.engine();
+    server.start_with(addr, move |server| server.run_http(addr));
+    engine
+}
+
+// Reads a response, returning its status, headers and body
+fn read_response<R: BufRead>(reader: &mut R) -> (u16, Vec<String>, String) {
+    let mut status_line = String::new();
+    reader.read_line(&mut status_line).unwrap();
+    let status = status_line.split(' ').nth(1).unwrap().parse().unwrap();
+    let mut headers = Vec::new();
+    let mut len = 0;
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        let line = line.trim_end().to_owned();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length: ") {
+            len = value.parse().unwrap();
+        }
+        headers.push(line);
+    }
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body).unwrap();
+    (status, headers, String::from_utf8(body).unwrap())
+}
+
+// Sends a single request on a new connection
+fn request(addr: &str, method: &str, target: &str, body: &str) -> (u16, String) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+        method,
+        target,
+        addr,
+        body.len(),
+        body
+    )
+    .unwrap();
+    let (status, _, body) = read_response(&mut BufReader::new(stream));
+    (status, body)
+}
+
+fn json_body(body: &str) -> Value {
+    serde_json::from_str(body).unwrap()
+}
+
+#[test]
+fn keys() {
+    let addr = "127.0.0.1:4401";
+    start_server(addr);
+
+    let (status, body) = request(addr, "GET", "/v1/keys/key1", "");
+    assert_eq!(status, 404);
+    assert_eq!(json_body(&body)["error"]["code"], "KeyNotFound");
+
+    assert_eq!(request(addr, "PUT", "/v1/keys/key1", "value 1").0, 204);
+    let (status, body) = request(addr, "GET", "/v1/keys/key1", "");
+    assert_eq!(status, 200);
+    assert_eq!(
+        json_body(&body),
+        json!({ "key": "key1", "value": "value 1" })
+    );
+
+    // keys are percent-decoded
+    assert_eq!(request(addr, "PUT", "/v1/keys/a%2Fb%20c", "x").0, 204);
+    let (_, body) = request(addr, "GET", "/v1/keys/a/b%20c", "");
+    assert_eq!(json_body(&body)["key"], "a/b c");
+
+    assert_eq!(request(addr, "DELETE", "/v1/keys/key1", "").0, 204);
+    assert_eq!(request(addr, "DELETE", "/v1/keys/key1", "").0, 404);
+    assert_eq!(request(addr, "POST", "/v1/keys/key1", "").0, 405);
+    assert_eq!(request(addr, "GET", "/v2/nothing", "").0, 404);
+}
+
+#[test]
+fn scan() {
+    let addr = "127.0.0.1:4402";
+    let engine = start_server(addr);
+    for i in 0..25 {
+        engine
+            .set(format!("user:{:02}", i), format!("{}", i))
+            .unwrap();
+    }
+    engine.set("other".to_owned(), "x".to_owned()).unwrap();
+
+    let mut keys = Vec::new();
+    let mut target = "/v1/keys?prefix=user%3A&limit=10".to_owned();
+    loop {
+        let (status, body) = request(addr, "GET", &target, "");
+        assert_eq!(status, 200);
+        let page = json_body(&body);
+        for item in page["items"].as_array().unwrap() {
+            keys.push(item["key"].as_str().unwrap().to_owned());
+        }
+        match page["next"].as_str() {
+            Some(next) => target = format!("/v1/keys?prefix=user:&limit=10&after={}", next),
+            None => break,
+        }
+    }
+    let expected: Vec<_> = (0..25).map(|i| format!("user:{:02}", i)).collect();
+    assert_eq!(keys, expected);
+
+    let (_, body) = request(addr, "GET", "/v1/keys?prefix=user:0&limit=2", "");
+    assert_eq!(
+        json_body(&body),
+        json!({
+            "items": [{ "key": "user:00", "value": "0" }, { "key": "user:01", "value": "1" }],
+            "next": "user:01",
+        })
+    );
+    assert_eq!(request(addr, "GET", "/v1/keys?limit=zero", "").0, 400);
+}
+
+#[test]
+fn batch() {
+    let addr = "127.0.0.1:4403";
+    start_server(addr);
+
+    let requests = json!([
+        { "Set": { "key": "key1", "value": "1" } },
+        { "Incr": { "key": "key1", "delta": 2 } },
+        { "MGet": { "keys": ["key1", "key2"] } },
+        { "Remove": { "key": "key2" } },
+    ]);
+    let (status, body) = request(addr, "POST", "/v1/batch", &requests.to_string());
+    assert_eq!(status, 200);
+    let responses = json_body(&body);
+    assert_eq!(responses[0], json!({ "Ok": "Done" }));
+    assert_eq!(responses[1], json!({ "Ok": { "Integer": 3 } }));
+    assert_eq!(responses[2], json!({ "Ok": { "Values": ["3", null] } }));
+    assert_eq!(responses[3]["Err"]["code"], "KeyNotFound");
+
+    assert_eq!(request(addr, "POST", "/v1/batch", "not json").0, 400);
+}
+
+#[test]
+fn health_and_metrics() {
+    let addr = "127.0.0.1:4404";
+    let engine = start_server(addr);
+    engine.set("key1".to_owned(), "value1".to_owned()).unwrap();
+
+    let (status, body) = request(addr, "GET", "/health", "");
+    assert_eq!(status, 200);
+    assert_eq!(json_body(&body), json!({ "status": "ok" }));
+
+    let (status, body) = request(addr, "GET", "/metrics", "");
+    assert_eq!(status, 200);
+    assert!(body.contains("# TYPE kvs_keys gauge\nkvs_keys 1\n"));
+    assert!(body.contains("kvs_compactions_total 0\n"));
+}
+
+// Several requests can share a connection.
+#[test]
+fn keep_alive() {
+    let addr = "127.0.0.1:4405";
+    start_server(addr);
+
+    let stream = TcpStream::connect(addr).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+    for i in 0..3 {
+        let body = format!("value{}", i);
+        write!(
+            writer,
+            "PUT /v1/keys/key HTTP/1.1\r\nContent-Length: {}\r\nExpect: 100-continue\r\n\r\n",
+            body.len()
+        )
+        .unwrap();
+        let (status, _, _) = read_response(&mut reader);
+        assert_eq!(status, 100);
+        writer.write_all(body.as_bytes()).unwrap();
+        let (status, headers, _) = read_response(&mut reader);
+        assert_eq!(status, 204);
+        assert!(headers.iter().any(|h| h == "Connection: keep-alive"));
+    }
+    writer
+        .write_all(b"GET /v1/keys/key HTTP/1.1\r\n\r\n")
+        .unwrap();
+    let (status, _, body) = read_response(&mut reader);
+    assert_eq!(status, 200);
+    assert_eq!(json_body(&body)["value"], "value2");
+}
@@ -1,4 +1,4 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{Event, KvStore, KvsEngine, Op, Result, SetCondition, SledKvsEngine};
 use std::sync::{Arc, Barrier};
 use std::thread;
 use tempfile::TempDir;
@@ -209,3 +209,376 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// The active log file should be sealed once it grows beyond the size limit,
+// and compaction should only rewrite the generations holding stale data.
+#[test]
+fn log_rolling_and_incremental_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_max_file_size(temp_dir.path(), 4096)?;
+
+    for key_id in 0..100 {
+        store.set(format!("cold{}", key_id), format!("value{}", key_id))?;
+    }
+    store.set("removed".to_owned(), "value".to_owned())?;
+    for iter in 0..100 {
+        store.set("warm".to_owned(), format!("{}", iter))?;
+    }
+    store.remove("removed".to_owned())?;
+
+    let log_count = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension() == Some("log".as_ref()))
+            .count()
+    };
+    assert!(log_count() > 1, "the active log file is not rolled");
+
+    for iter in 0..50_000 {
+        store.set("hot".to_owned(), format!("{}", iter))?;
+    }
+    let dir_size: u64 = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.metadata().ok())
+        .map(|metadata| metadata.len())
+        .sum();
+    assert!(dir_size < 1024 * 1024, "no compaction detected");
+    // compaction leaves alone the log of the first generation, filled with cold keys only
+    assert!(temp_dir.path().join("1.log").exists());
+
+    let check = |store: &KvStore| -> Result<()> {
+        for key_id in 0..100 {
+            assert_eq!(
+                store.get(format!("cold{}", key_id))?,
+                Some(format!("value{}", key_id))
+            );
+        }
+        assert_eq!(store.get("warm".to_owned())?, Some("99".to_owned()));
+        assert_eq!(store.get("hot".to_owned())?, Some("49999".to_owned()));
+        assert_eq!(store.get("removed".to_owned())?, None);
+        Ok(())
+    };
+    check(&store)?;
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open_with_max_file_size(temp_dir.path(), 4096)?;
+    check(&store)?;
+
+    Ok(())
+}
+
+// Reads racing with compactions should not fail, although the log files of
+// the keys may be deleted between the lookup and the read.
+#[test]
+fn get_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_max_file_size(temp_dir.path(), 4096)?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+
+    let writer = store.clone();
+    let handle = thread::spawn(move || -> Result<()> {
+        for iter in 0..2000 {
+            for key_id in 0..10 {
+                writer.set(format!("key{}", key_id), "value".to_owned())?;
+            }
+            if iter % 2 == 0 {
+                writer.compact()?;
+            }
+        }
+        Ok(())
+    });
+    while !handle.is_finished() {
+        // a fresh clone opens the log files again
+        let reader = store.clone();
+        for key_id in 0..10 {
+            reader.get(format!("key{}", key_id))?;
+        }
+    }
+    handle.join().unwrap()
+}
+
+// Compacting a store holding no stale data should not touch its log files.
+#[test]
+fn compaction_without_garbage() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let stats = store.stats()?;
+    store.compact()?;
+    assert_eq!(store.stats()?, stats);
+    assert_eq!(stats.generations, 1);
+    assert!(!temp_dir.path().join("2.log").exists());
+    Ok(())
+}
+
+// The tombstones carried over by compaction should be accounted for the same
+// way once the store is reopened.
+#[test]
+fn stats_after_compaction_and_reopening() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_max_file_size(temp_dir.path(), 4096)?;
+    for key_id in 0..200 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    for key_id in 150..200 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    store.compact()?;
+    let stats = store.stats()?;
+    assert_eq!(stats.garbage_bytes, 0);
+
+    drop(store);
+    let store = KvStore::open_with_max_file_size(temp_dir.path(), 4096)?;
+    let reopened = store.stats()?;
+    assert_eq!(reopened.keys, stats.keys);
+    assert_eq!(reopened.live_bytes, stats.live_bytes);
+    assert_eq!(reopened.garbage_bytes, stats.garbage_bytes);
+    assert_eq!(reopened.disk_bytes, stats.disk_bytes);
+    for key_id in 0..200 {
+        let expected = Some(format!("value{}", key_id)).filter(|_| key_id < 150);
+        assert_eq!(store.get(format!("key{}", key_id))?, expected);
+    }
+    Ok(())
+}
+
+// Live and stale bytes should be tracked across writes, compaction and reopening.
+#[test]
+fn stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let stats = store.stats()?;
+    assert_eq!(stats.keys, 0);
+    assert_eq!(stats.disk_bytes, 0);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.keys, 2);
+    assert_eq!(stats.garbage_bytes, 0);
+    assert_eq!(stats.live_bytes, stats.disk_bytes);
+
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.keys, 1);
+    assert!(stats.garbage_bytes > 0);
+    assert!(stats.garbage_ratio > 0.5);
+    assert_eq!(stats.compactions, 0);
+
+    // Open from disk again and check the accounting is rebuilt
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    let reopened = store.stats()?;
+    assert_eq!(reopened.keys, stats.keys);
+    assert_eq!(reopened.disk_bytes, stats.disk_bytes);
+    assert_eq!(reopened.garbage_bytes, stats.garbage_bytes);
+
+    for iter in 0..100_000 {
+        store.set("key1".to_owned(), format!("{}", iter))?;
+    }
+    let stats = store.stats()?;
+    assert!(stats.compactions > 0);
+    assert!(stats.last_compaction.is_some());
+    assert!(stats.garbage_bytes <= 1024 * 1024);
+
+    Ok(())
+}
+
+#[test]
+fn incr_decr_append() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(store.incr("counter".to_owned(), 5)?, 5);
+    assert_eq!(store.decr("counter".to_owned(), 7)?, -2);
+    assert_eq!(store.get("counter".to_owned())?, Some("-2".to_owned()));
+
+    store.set("text".to_owned(), "value".to_owned())?;
+    assert!(store.incr("text".to_owned(), 1).is_err());
+    assert_eq!(store.get("text".to_owned())?, Some("value".to_owned()));
+
+    store.set("max".to_owned(), format!("{}", i64::MAX))?;
+    assert!(store.incr("max".to_owned(), 1).is_err());
+
+    assert_eq!(store.append("list".to_owned(), "a".to_owned())?, 1);
+    assert_eq!(store.append("list".to_owned(), "bc".to_owned())?, 3);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("counter".to_owned())?, Some("-2".to_owned()));
+    assert_eq!(store.get("list".to_owned())?, Some("abc".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn concurrent_incr() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let mut handles = Vec::new();
+    for _ in 0..10 {
+        let store = store.clone();
+        let handle = thread::spawn(move || {
+            for _ in 0..100 {
+                store.incr("counter".to_owned(), 1).unwrap();
+            }
+        });
+        handles.push(handle);
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    assert_eq!(store.get("counter".to_owned())?, Some("1000".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn get_many_set_many() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_max_file_size(temp_dir.path(), 256)?;
+
+    store.set_many(
+        (0..20)
+            .map(|i| (format!("key{}", i), format!("value{}", i)))
+            .collect(),
+    )?;
+    // spread the keys over several generations
+    for i in (0..20).step_by(3) {
+        store.set(format!("key{}", i), format!("new{}", i))?;
+    }
+
+    let keys = vec![
+        "key19".to_owned(),
+        "missing".to_owned(),
+        "key0".to_owned(),
+        "key10".to_owned(),
+    ];
+    let expected = vec![
+        Some("value19".to_owned()),
+        None,
+        Some("new0".to_owned()),
+        Some("value10".to_owned()),
+    ];
+    assert_eq!(store.get_many(keys.clone())?, expected);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_many(keys)?, expected);
+
+    Ok(())
+}
+
+fn watch_prefix<E: KvsEngine>(engine: E) -> Result<()> {
+    let mut watcher = engine.watch("user:".to_owned())?;
+    let mut all = engine.watch(String::new())?;
+
+    engine.set("user:1".to_owned(), "alice".to_owned())?;
+    engine.set("order:1".to_owned(), "book".to_owned())?;
+    engine.set("user:1".to_owned(), "bob".to_owned())?;
+    engine.remove("user:1".to_owned())?;
+
+    let events: Vec<Event> = watcher.by_ref().take(3).collect();
+    let changes: Vec<(&str, &Op)> = events.iter().map(|e| (e.key.as_str(), &e.op)).collect();
+    assert_eq!(
+        changes,
+        vec![
+            ("user:1", &Op::Set("alice".to_owned())),
+            ("user:1", &Op::Set("bob".to_owned())),
+            ("user:1", &Op::Remove),
+        ]
+    );
+    assert!(events.windows(2).all(|w| w[0].seq < w[1].seq));
+
+    // the sequence numbers are shared by the watchers
+    let all: Vec<Event> = all.by_ref().take(4).collect();
+    assert_eq!(all[1].key, "order:1");
+    assert_eq!(
+        events.iter().map(|e| e.seq).collect::<Vec<_>>(),
+        vec![all[0].seq, all[2].seq, all[3].seq]
+    );
+    Ok(())
+}
+
+fn watch_lagging<E: KvsEngine>(engine: E) -> Result<()> {
+    let watcher = engine.watch(String::new())?;
+    for i in 0..2000 {
+        engine.set("key".to_owned(), format!("{}", i))?;
+    }
+    // the watcher is dropped once its backlog is full, ending after it
+    assert!(watcher.count() < 2000);
+    Ok(())
+}
+
+#[test]
+fn watch_kvs_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    watch_prefix(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn watch_sled_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    watch_prefix(SledKvsEngine::new(sled::open(temp_dir.path())?))
+}
+
+#[test]
+fn watch_lagging_kvs_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    watch_lagging(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn watch_lagging_sled_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    watch_lagging(SledKvsEngine::new(sled::open(temp_dir.path())?))
+}
+
+fn set_if_and_scan<E: KvsEngine>(engine: E) -> Result<()> {
+    assert!(engine.set_if("key".to_owned(), "a".to_owned(), SetCondition::Absent)?);
+    assert!(!engine.set_if("key".to_owned(), "b".to_owned(), SetCondition::Absent)?);
+    assert!(engine.set_if("key".to_owned(), "c".to_owned(), SetCondition::Present)?);
+    assert!(!engine.set_if("none".to_owned(), "d".to_owned(), SetCondition::Present)?);
+    assert_eq!(engine.get("key".to_owned())?, Some("c".to_owned()));
+    assert_eq!(engine.get("none".to_owned())?, None);
+
+    for i in 0..10 {
+        engine.set(format!("user:{}", i), "x".to_owned())?;
+    }
+    engine.set("zzz".to_owned(), "x".to_owned())?;
+
+    let first = engine.scan("user:".to_owned(), None, 4)?;
+    assert_eq!(first, vec!["user:0", "user:1", "user:2", "user:3"]);
+    let rest = engine.scan("user:".to_owned(), first.last().cloned(), 100)?;
+    assert_eq!(rest.len(), 6);
+    assert_eq!(rest.last().unwrap(), "user:9");
+    assert_eq!(
+        engine.scan("".to_owned(), Some("user:9".to_owned()), 10)?,
+        vec!["zzz"]
+    );
+    assert!(engine.scan("nothing".to_owned(), None, 10)?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn set_if_and_scan_kvs_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    set_if_and_scan(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn set_if_and_scan_sled_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    set_if_and_scan(SledKvsEngine::new(sled::open(temp_dir.path())?))
+}
@@ -0,0 +1,203 @@
+mod common;
+
+use common::TestServer;
+use kvs::{KvStore, KvsEngine, SledKvsEngine};
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// A memcached text protocol client returning the raw reply lines
+struct MemcacheClient {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl MemcacheClient {
+    fn connect(addr: &str) -> MemcacheClient {
+        let stream = TcpStream::connect(addr).unwrap();
+        MemcacheClient {
+            reader: BufReader::new(stream.try_clone().unwrap()),
+            writer: stream,
+        }
+    }
+
+    fn send(&mut self, command: &str) {
+        self.writer.write_all(command.as_bytes()).unwrap();
+    }
+
+    fn line(&mut self) -> String {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        assert!(line.ends_with("\r\n"), "unterminated line {:?}", line);
+        line.truncate(line.len() - 2);
+        line
+    }
+
+    // Sends a command answered with a single line
+    fn call(&mut self, command: &str) -> String {
+        self.send(command);
+        self.line()
+    }
+
+    // Sends a retrieval command, returning the lines up to `END`
+    fn get(&mut self, command: &str) -> Vec<String> {
+        self.send(command);
+        let mut lines = Vec::new();
+        loop {
+            let line = self.line();
+            if line == "END" {
+                return lines;
+            }
+            lines.push(line);
+        }
+    }
+}
+
+fn start_server<E: KvsEngine>(engine: E, temp_dir: TempDir, addr: &'static str) {
+    TestServer::with_engine(engine, temp_dir)
+        .start_with(addr, move |server| server.run_memcache(addr));
+}
+
+fn commands(addr: &str) {
+    let mut client = MemcacheClient::connect(addr);
+    assert!(client.get("get key1\r\n").is_empty());
+    assert_eq!(client.call("set key1 42 0 6\r\nvalue1\r\n"), "STORED");
+    assert_eq!(
+        client.get("get key1\r\n"),
+        vec!["VALUE key1 42 6", "value1"]
+    );
+
+    assert_eq!(client.call("add key1 0 0 1\r\nx\r\n"), "NOT_STORED");
+    assert_eq!(client.call("add key2 7 0 6\r\nvalue2\r\n"), "STORED");
+    assert_eq!(client.call("replace key3 0 0 1\r\nx\r\n"), "NOT_STORED");
+    assert_eq!(client.call("replace key2 8 0 3\r\nnew\r\n"), "STORED");
+    assert_eq!(
+        client.get("get key1 key2 key3\r\n"),
+        vec!["VALUE key1 42 6", "value1", "VALUE key2 8 3", "new"]
+    );
+
+    assert_eq!(client.call("delete key1\r\n"), "DELETED");
+    assert_eq!(client.call("delete key1\r\n"), "NOT_FOUND");
+
+    assert_eq!(client.call("incr counter 1\r\n"), "NOT_FOUND");
+    assert_eq!(client.call("set counter 0 0 2\r\n10\r\n"), "STORED");
+    assert_eq!(client.call("incr counter 5\r\n"), "15");
+    assert_eq!(client.call("decr counter 20\r\n"), "0");
+    assert!(client
+        .call("incr key2 1\r\n")
+        .starts_with("CLIENT_ERROR cannot increment"));
+
+    // noreply commands are silent
+    client.send("set quiet 0 0 1 noreply\r\nq\r\n");
+    assert_eq!(client.get("get quiet\r\n"), vec!["VALUE quiet 0 1", "q"]);
+
+    assert_eq!(client.call("bogus\r\n"), "ERROR");
+    assert!(client.call("version\r\n").starts_with("VERSION "));
+}
+
+#[test]
+fn commands_kvs_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    start_server(engine, temp_dir, "127.0.0.1:4301");
+    commands("127.0.0.1:4301");
+}
+
+#[test]
+fn commands_sled_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = SledKvsEngine::new(sled::open(temp_dir.path()).unwrap());
+    start_server(engine, temp_dir, "127.0.0.1:4302");
+    commands("127.0.0.1:4302");
+}
+
+#[test]
+fn cas() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    start_server(engine.clone(), temp_dir, "127.0.0.1:4303");
+
+    let mut client = MemcacheClient::connect("127.0.0.1:4303");
+    assert_eq!(client.call("cas key 0 0 1 1\r\nx\r\n"), "NOT_FOUND");
+    assert_eq!(client.call("set key 3 0 1\r\na\r\n"), "STORED");
+    let lines = client.get("gets key\r\n");
+    let unique: u64 = lines[0].rsplit(' ').next().unwrap().parse().unwrap();
+
+    assert_eq!(
+        client.call(&format!("cas key 3 0 1 {}\r\nb\r\n", unique)),
+        "STORED"
+    );
+    // the unique changes with every update
+    assert_eq!(
+        client.call(&format!("cas key 3 0 1 {}\r\nc\r\n", unique)),
+        "EXISTS"
+    );
+
+    // so does a change made through another protocol
+    let lines = client.get("gets key\r\n");
+    let unique: u64 = lines[0].rsplit(' ').next().unwrap().parse().unwrap();
+    engine.set("key".to_owned(), "d".to_owned()).unwrap();
+    assert_eq!(
+        client.call(&format!("cas key 3 0 1 {}\r\ne\r\n", unique)),
+        "EXISTS"
+    );
+    assert_eq!(client.get("get key\r\n")[1], "d");
+}
+
+#[test]
+fn expiry() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    start_server(engine.clone(), temp_dir, "127.0.0.1:4304");
+
+    let mut client = MemcacheClient::connect("127.0.0.1:4304");
+    assert_eq!(client.call("set short 0 1 1\r\na\r\n"), "STORED");
+    assert_eq!(client.call("set long 0 100 1\r\nb\r\n"), "STORED");
+    assert_eq!(client.call("set gone 0 -1 1\r\nc\r\n"), "STORED");
+    assert_eq!(client.call("set unread 0 1 1\r\ne\r\n"), "STORED");
+    assert!(client.get("get gone\r\n").is_empty());
+    assert_eq!(client.get("get short\r\n").len(), 2);
+
+    thread::sleep(Duration::from_millis(1100));
+    assert!(client.get("get short\r\n").is_empty());
+    assert_eq!(client.get("get long\r\n"), vec!["VALUE long 0 1", "b"]);
+    assert_eq!(client.call("add short 0 0 1\r\nd\r\n"), "STORED");
+    // the writes sweep the expired items no command touches out of the engine
+    assert_eq!(engine.get("unread".to_owned()).unwrap(), None);
+}
+
+// A rejected storage command skips its data block, which is not run as a command.
+#[test]
+fn rejected_data_block() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    start_server(engine, temp_dir, "127.0.0.1:4305");
+
+    let mut client = MemcacheClient::connect("127.0.0.1:4305");
+    let oversized = format!(
+        "set big 0 0 {0}\r\n{1}\r\n",
+        2 * 1024 * 1024,
+        "x".repeat(2 * 1024 * 1024)
+    );
+    assert_eq!(
+        client.call(&oversized),
+        "CLIENT_ERROR object too large for cache"
+    );
+    assert_eq!(client.call("set key 0 0 5\r\nvalue\r\n"), "STORED");
+
+    assert_eq!(
+        client.call("set key 0 soon 7\r\nget key\r\n"),
+        "CLIENT_ERROR bad command line format"
+    );
+    assert_eq!(client.get("get key\r\n"), vec!["VALUE key 0 5", "value"]);
+
+    // without a length the data block cannot be skipped, so the connection is closed
+    assert_eq!(
+        client.call("set key 0 0 many\r\nvalue\r\n"),
+        "CLIENT_ERROR bad data chunk"
+    );
+    let mut line = String::new();
+    assert_eq!(client.reader.read_line(&mut line).unwrap(), 0);
+}
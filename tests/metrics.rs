@@ -0,0 +1,132 @@
+mod common;
+
+use common::{wait_listening, TestServer};
+use kvs::{KvsClient, KvsError, Metrics, Result};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+// Runs a server with the kvs engine in the background, recording its metrics.
+fn start_server(addr: &'static str) -> Metrics {
+    let metrics = Metrics::new();
+    let exporter = metrics.clone();
+    TestServer::new().start_with(addr, move |server| server.with_metrics(exporter).run(addr));
+    metrics
+}
+
+// Waits for the metrics to contain `line`, failing after a second.
+fn wait_line(metrics: &Metrics, line: &str) {
+    for _ in 0..100 {
+        if metrics.render().lines().any(|l| l == line) {
+            return;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("{} not found in\n{}", line, metrics.render());
+}
+
+#[test]
+fn server_metrics() -> Result<()> {
+    let addr = "127.0.0.1:5501";
+    let metrics = start_server(addr);
+
+    let mut client = KvsClient::connect(addr)?;
+    for i in 0..3 {
+        client.set(format!("key{}", i), "value".to_owned())?;
+    }
+    client.get("key0".to_owned())?;
+    match client.rm("missing".to_owned()) {
+        Err(KvsError::KeyNotFound) => {}
+        other => panic!("expect KeyNotFound, got {:?}", other),
+    }
+
+    for line in &[
+        "kvs_requests_total{op=\"set\"} 3",
+        "kvs_requests_total{op=\"get\"} 1",
+        "kvs_requests_total{op=\"rm\"} 1",
+        "kvs_request_duration_seconds_count{op=\"set\"} 3",
+        "kvs_request_duration_seconds_bucket{op=\"set\",le=\"+Inf\"} 3",
+        "kvs_errors_total{code=\"KeyNotFound\"} 1",
+        "kvs_connections 1",
+        "kvs_keys 3",
+        "kvs_compactions_total 0",
+    ] {
+        wait_line(&metrics, line);
+    }
+    let body = metrics.render();
+    assert!(body.contains("# TYPE kvs_request_duration_seconds histogram\n"));
+    assert!(body.contains("kvs_pool_busy_threads{pool=\"127.0.0.1:5501\"} "));
+    assert!(body.contains("kvs_pool_queued_jobs{pool=\"127.0.0.1:5501\"} "));
+    assert!(body.contains("kvs_pool_panics_total{pool=\"127.0.0.1:5501\"} 0\n"));
+
+    drop(client);
+    wait_line(&metrics, "kvs_connections 0");
+    Ok(())
+}
+
+// Sends a single request to the metrics endpoint, returning the whole response.
+fn scrape(addr: &str, target: &str) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(
+        stream,
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        target, addr
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn metrics_endpoint() -> Result<()> {
+    let addr = "127.0.0.1:5502";
+    let metrics_addr = "127.0.0.1:5503";
+    let metrics = start_server(addr);
+    let exporter = metrics.clone();
+    thread::spawn(move || exporter.serve(metrics_addr).unwrap());
+    wait_listening(metrics_addr);
+
+    KvsClient::connect(addr)?.set("key".to_owned(), "value".to_owned())?;
+    wait_line(&metrics, "kvs_connections 0");
+
+    let response = scrape(metrics_addr, "/metrics");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("Content-Type: text/plain; version=0.0.4\r\n"));
+    assert!(response.contains("\nkvs_requests_total{op=\"set\"} 1\n"));
+    assert!(response.contains("\nkvs_keys 1\n"));
+
+    assert!(scrape(metrics_addr, "/v1/keys/key").starts_with("HTTP/1.1 404 "));
+    Ok(())
+}
+
+// A watching client going away frees the thread of the pool serving it.
+#[test]
+fn dropped_watcher() -> Result<()> {
+    let addr = "127.0.0.1:5504";
+    let metrics = start_server(addr);
+    let busy = format!("kvs_pool_busy_threads{{pool=\"{}\"}}", addr);
+
+    let watcher = KvsClient::connect(addr)?.watch("key".to_owned())?;
+    wait_line(&metrics, &format!("{} 1", busy));
+    drop(watcher);
+    wait_line(&metrics, &format!("{} 0", busy));
+    wait_line(&metrics, "kvs_connections 0");
+    Ok(())
+}
+
+// A subscribed client going away frees the thread of the pool serving it.
+#[test]
+fn dropped_subscriber() -> Result<()> {
+    let addr = "127.0.0.1:5505";
+    let metrics = start_server(addr);
+    let busy = format!("kvs_pool_busy_threads{{pool=\"{}\"}}", addr);
+
+    let subscriber = KvsClient::connect(addr)?.subscribe(vec![], vec!["news.[ab]*".to_owned()])?;
+    wait_line(&metrics, &format!("{} 1", busy));
+    drop(subscriber);
+    wait_line(&metrics, &format!("{} 0", busy));
+    wait_line(&metrics, "kvs_connections 0");
+    Ok(())
+}
@@ -0,0 +1,114 @@
+mod common;
+
+use common::{start_servers, TestServer};
+use kvs::{Address, KvsClient, KvsProxy, Op, ProxyConfig, Result};
+use std::collections::HashSet;
+use std::fs;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// All the keys stored on the server at `addr`.
+fn keys_on(addr: &str) -> HashSet<String> {
+    let mut client = KvsClient::connect(addr).unwrap();
+    client
+        .scan(String::new(), None, 100_000)
+        .unwrap()
+        .into_iter()
+        .collect()
+}
+
+#[test]
+fn routes_through_proxy() -> Result<()> {
+    let backends = ["127.0.0.1:5201", "127.0.0.1:5202", "127.0.0.1:5203"];
+    start_servers(&backends);
+    let temp_dir = TempDir::new().unwrap();
+    let path = temp_dir.path().join("proxy.json");
+    fs::write(
+        &path,
+        r#"{
+            "backends": ["127.0.0.1:5201", "127.0.0.1:5202"],
+            "rules": [{ "prefix": "session/", "backends": ["127.0.0.1:5203"] }]
+        }"#,
+    )?;
+    let proxy = KvsProxy::new(ProxyConfig::load(&path)?)?;
+    TestServer::with_engine(proxy.clone(), TempDir::new()?).start("127.0.0.1:5204");
+
+    let mut client = KvsClient::connect("127.0.0.1:5204")?;
+    for i in 0..100 {
+        client.set(format!("key{:02}", i), format!("value{}", i))?;
+        client.set(format!("session/{}", i), "user".to_owned())?;
+    }
+
+    // the sessions only go to their backend, the other keys are spread
+    let (first, second, sessions) = (
+        keys_on(backends[0]),
+        keys_on(backends[1]),
+        keys_on(backends[2]),
+    );
+    assert_eq!(sessions.len(), 100);
+    assert!(sessions.iter().all(|key| key.starts_with("session/")));
+    assert!(!first.is_empty() && !second.is_empty());
+    assert_eq!(first.len() + second.len(), 100);
+    for key in &first {
+        let backend: Address = backends[0].parse().unwrap();
+        assert_eq!(proxy.backend(key), &backend);
+    }
+
+    assert_eq!(client.get("key07".to_owned())?, Some("value7".to_owned()));
+    client.rm("key07".to_owned())?;
+    assert_eq!(client.incr("session/counter".to_owned(), 3)?, 3);
+    assert_eq!(client.append("key08".to_owned(), "!".to_owned())?, 7);
+    assert_eq!(
+        client.get_many(vec![
+            "key01".to_owned(),
+            "session/5".to_owned(),
+            "key07".to_owned(),
+            "key08".to_owned(),
+        ])?,
+        vec![
+            Some("value1".to_owned()),
+            Some("user".to_owned()),
+            None,
+            Some("value8!".to_owned()),
+        ]
+    );
+    client.set_many(vec![
+        ("multi1".to_owned(), "a".to_owned()),
+        ("session/multi".to_owned(), "b".to_owned()),
+    ])?;
+    assert_eq!(keys_on(backends[2]).len(), 102);
+
+    // the scans are merged in order
+    let page = client.scan("key".to_owned(), Some("key05".to_owned()), 3)?;
+    assert_eq!(page, vec!["key06", "key08", "key09"]);
+    assert_eq!(client.scan(String::new(), None, 1000)?.len(), 202);
+    assert_eq!(client.stats()?.keys, 202);
+    Ok(())
+}
+
+#[test]
+fn watch_through_proxy() -> Result<()> {
+    let backends = ["127.0.0.1:5205", "127.0.0.1:5206"];
+    start_servers(&backends);
+    let config = ProxyConfig::new(backends.iter().map(|addr| addr.parse().unwrap()).collect());
+    TestServer::with_engine(KvsProxy::new(config)?, TempDir::new()?).start("127.0.0.1:5207");
+
+    let mut watcher = KvsClient::connect("127.0.0.1:5207")?.watch("key".to_owned())?;
+    thread::sleep(Duration::from_millis(200));
+    let mut client = KvsClient::connect("127.0.0.1:5207")?;
+    for i in 0..10 {
+        client.set(format!("key{}", i), "value".to_owned())?;
+    }
+    client.set("other".to_owned(), "value".to_owned())?;
+
+    let mut keys = HashSet::new();
+    for seq in 1..=10 {
+        let event = watcher.next().unwrap()?;
+        assert_eq!(event.seq, seq);
+        assert!(matches!(event.op, Op::Set(_)));
+        keys.insert(event.key);
+    }
+    assert_eq!(keys.len(), 10);
+    Ok(())
+}
@@ -0,0 +1,128 @@
+mod common;
+
+use common::TestServer;
+use kvs::{ChannelMessage, KvsClient, KvsError, Result};
+use serde_json::Value;
+use std::io::{BufReader, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+// Runs a server with the kvs engine in the background.
+fn start_server(addr: &'static str) {
+    TestServer::new().start(addr);
+}
+
+fn message(channel: &str, pattern: Option<&str>, message: &str) -> ChannelMessage {
+    ChannelMessage {
+        channel: channel.to_owned(),
+        pattern: pattern.map(str::to_owned),
+        message: message.to_owned(),
+    }
+}
+
+#[test]
+fn publish_and_subscribe() -> Result<()> {
+    let addr = "127.0.0.1:5301";
+    start_server(addr);
+
+    let mut client = KvsClient::connect(addr)?;
+    assert!(client.server_capabilities().iter().any(|c| c == "pubsub"));
+    assert_eq!(client.publish("news".to_owned(), "lost".to_owned())?, 0);
+
+    let mut by_name = KvsClient::connect(addr)?.subscribe(vec!["news".to_owned()], Vec::new())?;
+    let mut by_pattern = KvsClient::connect(addr)?.subscribe(
+        vec!["news".to_owned()],
+        vec!["news.*".to_owned(), "alert?".to_owned()],
+    )?;
+    assert_eq!(client.publish("news".to_owned(), "first".to_owned())?, 2);
+    assert_eq!(
+        client.publish("news.sport".to_owned(), "goal".to_owned())?,
+        1
+    );
+    assert_eq!(client.publish("alert1".to_owned(), "fire".to_owned())?, 1);
+    assert_eq!(client.publish("alert12".to_owned(), "none".to_owned())?, 0);
+    assert_eq!(client.publish("news".to_owned(), "second".to_owned())?, 2);
+
+    assert_eq!(by_name.next().unwrap()?, message("news", None, "first"));
+    assert_eq!(by_name.next().unwrap()?, message("news", None, "second"));
+    assert_eq!(by_pattern.next().unwrap()?, message("news", None, "first"));
+    assert_eq!(
+        by_pattern.next().unwrap()?,
+        message("news.sport", Some("news.*"), "goal")
+    );
+    assert_eq!(
+        by_pattern.next().unwrap()?,
+        message("alert1", Some("alert?"), "fire")
+    );
+    assert_eq!(by_pattern.next().unwrap()?, message("news", None, "second"));
+
+    // the subscriptions are dropped once the server notices their connections are closed
+    drop(by_name);
+    let mut receivers = 2;
+    for _ in 0..50 {
+        receivers = client.publish("news".to_owned(), "more".to_owned())?;
+        if receivers == 1 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert_eq!(receivers, 1);
+
+    // the keys are not touched
+    client.set("news".to_owned(), "value".to_owned())?;
+    assert_eq!(client.get("news".to_owned())?, Some("value".to_owned()));
+    match KvsClient::connect(addr)?.subscribe(Vec::new(), Vec::new()) {
+        Err(KvsError::BadRequest(_)) => {}
+        other => panic!("expect BadRequest, got {:?}", other.map(|_| ())),
+    }
+    Ok(())
+}
+
+// Clients speaking the unframed JSON protocol get the messages as JSON values.
+#[test]
+fn legacy_subscriber() -> Result<()> {
+    let addr = "127.0.0.1:5302";
+    start_server(addr);
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(br#"{"Subscribe":{"channels":["news"],"patterns":[]}}"#)?;
+    let mut reader = serde_json::Deserializer::from_reader(BufReader::new(stream.try_clone()?))
+        .into_iter::<Value>();
+    assert_eq!(reader.next().unwrap()?, serde_json::json!({ "Ok": null }));
+
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.publish("news".to_owned(), "hello".to_owned())?, 1);
+    assert_eq!(
+        reader.next().unwrap()?,
+        serde_json::json!({ "channel": "news", "pattern": null, "message": "hello" })
+    );
+    Ok(())
+}
+
+#[test]
+fn glob_patterns() -> Result<()> {
+    let addr = "127.0.0.1:5303";
+    start_server(addr);
+
+    let mut client = KvsClient::connect(addr)?;
+    let mut subscriber = KvsClient::connect(addr)?
+        .subscribe(Vec::new(), vec!["log.[ew]*".to_owned(), "a\\*".to_owned()])?;
+    assert_eq!(client.publish("log.info".to_owned(), "none".to_owned())?, 0);
+    assert_eq!(
+        client.publish("log.error".to_owned(), "disk".to_owned())?,
+        1
+    );
+    assert_eq!(client.publish("ab".to_owned(), "none".to_owned())?, 0);
+    assert_eq!(client.publish("a*".to_owned(), "star".to_owned())?, 1);
+
+    assert_eq!(
+        subscriber.next().unwrap()?,
+        message("log.error", Some("log.[ew]*"), "disk")
+    );
+    assert_eq!(
+        subscriber.next().unwrap()?,
+        message("a*", Some("a\\*"), "star")
+    );
+    Ok(())
+}
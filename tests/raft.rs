@@ -0,0 +1,272 @@
+use kvs::{ClusterConfig, KvStore, KvsEngine, KvsError, Members, MemoryNetwork, RaftNode, Role};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// The nodes of a memory network are reached by id, the addresses are only told to the clients.
+fn members(ids: &[u64]) -> Members {
+    ids.iter()
+        .map(|&id| (id, format!("127.0.0.1:{}", 5100 + id).parse().unwrap()))
+        .collect()
+}
+
+// Starts node `id` on the network, with the timings shortened for the tests.
+fn start_node(
+    network: &MemoryNetwork,
+    id: u64,
+    members: Members,
+    dir: &Path,
+    snapshot_threshold: u64,
+) -> (RaftNode, KvStore) {
+    let engine = KvStore::open(dir).unwrap();
+    let mut config = ClusterConfig::new(id, members, dir.join("raft"));
+    config.heartbeat_interval = Duration::from_millis(20);
+    config.election_timeout = Duration::from_millis(150);
+    config.request_timeout = Duration::from_secs(1);
+    config.snapshot_threshold = snapshot_threshold;
+    let node = RaftNode::start(config, engine.clone(), network.clone()).unwrap();
+    network.add(&node);
+    (node, engine)
+}
+
+// Starts a cluster of nodes 1 to `size`, returning the nodes and their engines.
+fn start_cluster(
+    network: &MemoryNetwork,
+    size: u64,
+    snapshot_threshold: u64,
+) -> (Vec<(RaftNode, KvStore)>, Vec<TempDir>) {
+    let ids: Vec<u64> = (1..=size).collect();
+    let mut nodes = Vec::new();
+    let mut dirs = Vec::new();
+    for &id in &ids {
+        let dir = TempDir::new().unwrap();
+        nodes.push(start_node(
+            network,
+            id,
+            members(&ids),
+            dir.path(),
+            snapshot_threshold,
+        ));
+        dirs.push(dir);
+    }
+    (nodes, dirs)
+}
+
+// Waits for one of `nodes` to lead, skipping the node `except`.
+fn wait_for_leader(nodes: &[(RaftNode, KvStore)], except: Option<u64>) -> RaftNode {
+    let start = Instant::now();
+    loop {
+        let leader = nodes
+            .iter()
+            .map(|(node, _)| node)
+            .filter(|node| Some(node.id()) != except)
+            .filter(|node| node.status().role == Role::Leader)
+            .max_by_key(|node| node.status().term);
+        if let Some(leader) = leader {
+            return leader.clone();
+        }
+        assert!(
+            start.elapsed() < Duration::from_secs(5),
+            "no leader elected"
+        );
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+// Polls `key` in `engine` until it has `expected`, failing after a few seconds.
+fn wait_for(engine: &KvStore, key: &str, expected: Option<&str>) {
+    let start = Instant::now();
+    loop {
+        let value = engine.get(key.to_owned()).unwrap();
+        if value.as_deref() == expected {
+            return;
+        }
+        assert!(
+            start.elapsed() < Duration::from_secs(5),
+            "{} is {:?}, expect {:?}",
+            key,
+            value,
+            expected
+        );
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+// Polls the members seen by `node` until they are `expected`.
+fn wait_for_members(node: &RaftNode, expected: &Members) {
+    let start = Instant::now();
+    while &node.status().members != expected {
+        assert!(
+            start.elapsed() < Duration::from_secs(5),
+            "members are {:?}, expect {:?}",
+            node.status().members,
+            expected
+        );
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+fn stop_all(nodes: &[(RaftNode, KvStore)]) {
+    for (node, _) in nodes {
+        node.stop();
+    }
+}
+
+#[test]
+fn elects_leader_and_replicates() {
+    let network = MemoryNetwork::new();
+    let (nodes, _dirs) = start_cluster(&network, 3, 10_000);
+    let leader = wait_for_leader(&nodes, None);
+
+    leader.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    leader.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    leader.remove("key2".to_owned()).unwrap();
+    assert_eq!(
+        leader.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    for (_, engine) in &nodes {
+        wait_for(engine, "key1", Some("value1"));
+        wait_for(engine, "key2", None);
+    }
+
+    let leader_addr = members(&[1, 2, 3])[&leader.id()].to_string();
+    for (node, _) in nodes.iter().filter(|(node, _)| node.id() != leader.id()) {
+        match node.set("key1".to_owned(), "changed".to_owned()) {
+            Err(KvsError::NotLeader(addr)) => assert_eq!(addr, leader_addr),
+            other => panic!("expect NotLeader, got {:?}", other),
+        }
+        let status = node.status();
+        assert_eq!(status.role, Role::Follower);
+        assert_eq!(status.leader, Some(leader.id()));
+    }
+    stop_all(&nodes);
+}
+
+#[test]
+fn leader_failover() {
+    let network = MemoryNetwork::new();
+    let (nodes, _dirs) = start_cluster(&network, 3, 10_000);
+    let old_leader = wait_for_leader(&nodes, None);
+    let old_term = old_leader.status().term;
+    old_leader.set("key".to_owned(), "old".to_owned()).unwrap();
+
+    network.isolate(old_leader.id());
+    let new_leader = wait_for_leader(&nodes, Some(old_leader.id()));
+    assert!(new_leader.status().term > old_term);
+    new_leader.set("key".to_owned(), "new".to_owned()).unwrap();
+    // the isolated leader cannot commit anything
+    assert!(old_leader.set("key".to_owned(), "lost".to_owned()).is_err());
+
+    // its uncommitted entry is replaced by the ones of the new leader
+    network.heal(old_leader.id());
+    for (_, engine) in &nodes {
+        wait_for(engine, "key", Some("new"));
+    }
+    let leader = wait_for_leader(&nodes, None);
+    assert_eq!(
+        leader.get("key".to_owned()).unwrap(),
+        Some("new".to_owned())
+    );
+    stop_all(&nodes);
+}
+
+#[test]
+fn snapshot_catch_up() {
+    let network = MemoryNetwork::new();
+    let (nodes, dirs) = start_cluster(&network, 3, 50);
+    let leader = wait_for_leader(&nodes, None);
+    let (lagging, engine) = nodes
+        .iter()
+        .find(|(node, _)| node.id() != leader.id())
+        .unwrap();
+
+    network.isolate(lagging.id());
+    for i in 0..200 {
+        leader
+            .set(format!("key{}", i), format!("value{}", i))
+            .unwrap();
+    }
+    leader.remove("key0".to_owned()).unwrap();
+    network.heal(lagging.id());
+
+    // the entries it misses are compacted, so it installs a snapshot
+    wait_for(engine, "key199", Some("value199"));
+    wait_for(engine, "key100", Some("value100"));
+    wait_for(engine, "key0", None);
+    let index = nodes.iter().position(|(node, _)| node.id() == lagging.id());
+    assert!(dirs[index.unwrap()]
+        .path()
+        .join("raft")
+        .join("snapshot.json")
+        .exists());
+    stop_all(&nodes);
+}
+
+#[test]
+fn membership_changes() {
+    let network = MemoryNetwork::new();
+    let (mut nodes, _dirs) = start_cluster(&network, 3, 10_000);
+    let leader = wait_for_leader(&nodes, None);
+    leader.set("key".to_owned(), "value".to_owned()).unwrap();
+
+    // a new node starts without itself among the members
+    let dir = TempDir::new().unwrap();
+    let (node, engine) = start_node(&network, 4, members(&[1, 2, 3]), dir.path(), 10_000);
+    let addr = members(&[4])[&4].clone();
+    leader.add_node(4, addr).unwrap();
+    wait_for(&engine, "key", Some("value"));
+    wait_for_members(&leader, &members(&[1, 2, 3, 4]));
+    wait_for_members(&node, &members(&[1, 2, 3, 4]));
+    nodes.push((node, engine));
+
+    let removed = nodes
+        .iter()
+        .map(|(node, _)| node.id())
+        .find(|&id| id != leader.id() && id != 4)
+        .unwrap();
+    leader.remove_node(removed).unwrap();
+    let remaining: Vec<u64> = (1..=4).filter(|&id| id != removed).collect();
+    wait_for_members(&leader, &members(&remaining));
+    match leader.remove_node(removed) {
+        Err(KvsError::BadRequest(_)) => {}
+        other => panic!("expect BadRequest, got {:?}", other),
+    }
+
+    leader.set("key".to_owned(), "changed".to_owned()).unwrap();
+    for (node, engine) in &nodes {
+        if node.id() != removed {
+            wait_for(engine, "key", Some("changed"));
+        }
+    }
+    stop_all(&nodes);
+}
+
+#[test]
+fn restart_keeps_data() {
+    let network = MemoryNetwork::new();
+    let dir = TempDir::new().unwrap();
+    let (node, engine) = start_node(&network, 1, members(&[1]), dir.path(), 10);
+    let nodes = vec![(node.clone(), engine)];
+    wait_for_leader(&nodes, None);
+    for i in 0..25 {
+        node.set(format!("key{}", i), format!("value{}", i))
+            .unwrap();
+    }
+    node.stop();
+    drop(nodes);
+
+    // from the snapshot and the log
+    let network = MemoryNetwork::new();
+    let (node, engine) = start_node(&network, 1, members(&[1]), dir.path(), 10);
+    let nodes = vec![(node.clone(), engine)];
+    wait_for_leader(&nodes, None);
+    for i in 0..25 {
+        assert_eq!(
+            node.get(format!("key{}", i)).unwrap(),
+            Some(format!("value{}", i))
+        );
+    }
+    node.stop();
+}
@@ -0,0 +1,128 @@
+mod common;
+
+use common::TestServer;
+use kvs::{KvStore, KvsClient, KvsEngine, KvsError, Result};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// Runs a server with the kvs engine in the background, following `leader` if given.
+fn start_server(addr: &'static str, engine: KvStore, temp_dir: TempDir, leader: Option<&str>) {
+    let leader = leader.map(|leader| leader.parse().unwrap());
+    TestServer::with_engine(engine, temp_dir).start_with(addr, move |mut server| {
+        if let Some(leader) = leader {
+            server = server.with_leader(leader, None);
+        }
+        server.run(addr)
+    });
+}
+
+fn open_engine() -> (KvStore, TempDir) {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    (engine, temp_dir)
+}
+
+// Polls `key` on the server at `addr` until it has `expected`, failing after a few seconds.
+fn wait_for(addr: &str, key: &str, expected: Option<&str>) {
+    let mut client = KvsClient::connect(addr).unwrap();
+    let start = Instant::now();
+    loop {
+        let value = client.get(key.to_owned()).unwrap();
+        if value.as_deref() == expected {
+            return;
+        }
+        assert!(
+            start.elapsed() < Duration::from_secs(5),
+            "{} is {:?}, expect {:?}",
+            key,
+            value,
+            expected
+        );
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+fn assert_read_only<T: std::fmt::Debug>(result: Result<T>) {
+    match result {
+        Err(KvsError::ReadOnly(_)) => {}
+        other => panic!("expect ReadOnly, got {:?}", other),
+    }
+}
+
+#[test]
+fn follower_replicates() -> Result<()> {
+    let leader_addr = "127.0.0.1:4801";
+    let follower_addr = "127.0.0.1:4802";
+    let (engine, temp_dir) = open_engine();
+    start_server(leader_addr, engine, temp_dir, None);
+
+    let mut leader = KvsClient::connect(leader_addr)?;
+    for i in 0..2500 {
+        leader.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    let (engine, temp_dir) = open_engine();
+    start_server(follower_addr, engine, temp_dir, Some(leader_addr));
+
+    // the snapshot
+    wait_for(follower_addr, "key2499", Some("value2499"));
+    wait_for(follower_addr, "key0", Some("value0"));
+    // the changes
+    leader.set("key0".to_owned(), "changed".to_owned())?;
+    leader.rm("key1".to_owned())?;
+    leader.incr("counter".to_owned(), 3)?;
+    wait_for(follower_addr, "counter", Some("3"));
+    wait_for(follower_addr, "key0", Some("changed"));
+    wait_for(follower_addr, "key1", None);
+
+    let mut follower = KvsClient::connect(follower_addr)?;
+    assert_read_only(follower.set("key0".to_owned(), "value0".to_owned()));
+    assert_read_only(follower.rm("key0".to_owned()));
+    assert_read_only(follower.incr("counter".to_owned(), 1));
+    assert_eq!(follower.get("key0".to_owned())?, Some("changed".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn promote() -> Result<()> {
+    let leader_addr = "127.0.0.1:4803";
+    let follower_addr = "127.0.0.1:4804";
+    let (engine, temp_dir) = open_engine();
+    start_server(leader_addr, engine, temp_dir, None);
+    let (engine, temp_dir) = open_engine();
+    start_server(follower_addr, engine, temp_dir, Some(leader_addr));
+
+    let mut leader = KvsClient::connect(leader_addr)?;
+    leader.set("key1".to_owned(), "value1".to_owned())?;
+    wait_for(follower_addr, "key1", Some("value1"));
+
+    let mut follower = KvsClient::connect(follower_addr)?;
+    follower.promote()?;
+    follower.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(follower.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // the old leader is not followed anymore
+    leader.set("key1".to_owned(), "changed".to_owned())?;
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(follower.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// The keys a follower has but its leader does not are removed by the snapshot.
+#[test]
+fn snapshot_replaces_data() -> Result<()> {
+    let leader_addr = "127.0.0.1:4805";
+    let follower_addr = "127.0.0.1:4806";
+    let (engine, temp_dir) = open_engine();
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    start_server(leader_addr, engine, temp_dir, None);
+
+    let (engine, temp_dir) = open_engine();
+    engine.set("key1".to_owned(), "stale".to_owned())?;
+    engine.set("stale".to_owned(), "stale".to_owned())?;
+    start_server(follower_addr, engine, temp_dir, Some(leader_addr));
+
+    wait_for(follower_addr, "key1", Some("value1"));
+    wait_for(follower_addr, "stale", None);
+    Ok(())
+}
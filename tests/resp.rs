@@ -0,0 +1,354 @@
+mod common;
+
+use common::TestServer;
+use kvs::{KvStore, KvsEngine, Limits, SledKvsEngine};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// A reply of a RESP2 server
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<String>),
+    Array(Vec<Reply>),
+}
+
+fn bulk(s: &str) -> Reply {
+    Reply::Bulk(Some(s.to_owned()))
+}
+
+fn ok() -> Reply {
+    Reply::Simple("OK".to_owned())
+}
+
+// A minimal RESP2 client, sending commands as arrays of bulk strings
+struct RespClient {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl RespClient {
+    fn connect(addr: &str) -> RespClient {
+        let stream = TcpStream::connect(addr).unwrap();
+        RespClient {
+            reader: BufReader::new(stream.try_clone().unwrap()),
+            writer: stream,
+        }
+    }
+
+    fn send(&mut self, args: &[&str]) {
+        let mut buf = format!("*{}\r\n", args.len());
+        for arg in args {
+            buf += &format!("${}\r\n{}\r\n", arg.len(), arg);
+        }
+        self.writer.write_all(buf.as_bytes()).unwrap();
+    }
+
+    fn call(&mut self, args: &[&str]) -> Reply {
+        self.send(args);
+        self.read()
+    }
+
+    fn read(&mut self) -> Reply {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        let line = line.trim_end();
+        let (kind, rest) = line.split_at(1);
+        match kind {
+            "+" => Reply::Simple(rest.to_owned()),
+            "-" => Reply::Error(rest.to_owned()),
+            ":" => Reply::Integer(rest.parse().unwrap()),
+            "$" => {
+                let len: i64 = rest.parse().unwrap();
+                if len < 0 {
+                    return Reply::Bulk(None);
+                }
+                let mut buf = vec![0; len as usize + 2];
+                self.reader.read_exact(&mut buf).unwrap();
+                buf.truncate(len as usize);
+                Reply::Bulk(Some(String::from_utf8(buf).unwrap()))
+            }
+            "*" => {
+                let len: usize = rest.parse().unwrap();
+                Reply::Array((0..len).map(|_| self.read()).collect())
+            }
+            _ => panic!("unexpected reply {:?}", line),
+        }
+    }
+}
+
+fn start_server<E: KvsEngine>(engine: E, temp_dir: TempDir, addr: &'static str) {
+    start_server_with_limits(engine, temp_dir, addr, Limits::default());
+}
+
+fn start_server_with_limits<E: KvsEngine>(
+    engine: E,
+    temp_dir: TempDir,
+    addr: &'static str,
+    limits: Limits,
+) {
+    TestServer::with_engine(engine, temp_dir)
+        .with_limits(limits)
+        .start_with(addr, move |server| server.run_resp(addr));
+}
+
+fn commands(addr: &str) {
+    let mut client = RespClient::connect(addr);
+    assert_eq!(client.call(&["PING"]), Reply::Simple("PONG".to_owned()));
+    assert_eq!(client.call(&["ping", "hello"]), bulk("hello"));
+
+    assert_eq!(client.call(&["GET", "key1"]), Reply::Bulk(None));
+    assert_eq!(client.call(&["SET", "key1", "value1"]), ok());
+    assert_eq!(client.call(&["GET", "key1"]), bulk("value1"));
+    assert_eq!(
+        client.call(&["SET", "key1", "value2", "NX"]),
+        Reply::Bulk(None)
+    );
+    assert_eq!(client.call(&["SET", "key1", "value2", "XX"]), ok());
+    assert_eq!(
+        client.call(&["SET", "key2", "value2", "XX"]),
+        Reply::Bulk(None)
+    );
+    assert_eq!(client.call(&["SET", "key2", "value2", "NX"]), ok());
+    assert_eq!(client.call(&["GET", "key1"]), bulk("value2"));
+
+    assert_eq!(
+        client.call(&["EXISTS", "key1", "key2", "key3"]),
+        Reply::Integer(2)
+    );
+    assert_eq!(client.call(&["DEL", "key1", "key3"]), Reply::Integer(1));
+    assert_eq!(client.call(&["EXISTS", "key1"]), Reply::Integer(0));
+
+    assert_eq!(client.call(&["INCR", "counter"]), Reply::Integer(1));
+    assert_eq!(client.call(&["INCR", "counter"]), Reply::Integer(2));
+    match client.call(&["INCR", "key2"]) {
+        Reply::Error(e) => assert!(e.starts_with("ERR value is not an integer")),
+        reply => panic!("unexpected reply {:?}", reply),
+    }
+
+    assert_eq!(client.call(&["MSET", "a", "1", "b", "2"]), ok());
+    assert_eq!(
+        client.call(&["MGET", "a", "missing", "b"]),
+        Reply::Array(vec![bulk("1"), Reply::Bulk(None), bulk("2")])
+    );
+
+    match client.call(&["INFO"]) {
+        Reply::Bulk(Some(info)) => assert!(info.contains("db0:keys=")),
+        reply => panic!("unexpected reply {:?}", reply),
+    }
+    match client.call(&["FLUSHALL"]) {
+        Reply::Error(e) => assert!(e.starts_with("ERR unknown command")),
+        reply => panic!("unexpected reply {:?}", reply),
+    }
+    match client.call(&["GET"]) {
+        Reply::Error(e) => assert!(e.starts_with("ERR wrong number of arguments")),
+        reply => panic!("unexpected reply {:?}", reply),
+    }
+}
+
+#[test]
+fn commands_kvs_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    start_server(engine, temp_dir, "127.0.0.1:4201");
+    commands("127.0.0.1:4201");
+}
+
+#[test]
+fn commands_sled_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = SledKvsEngine::new(sled::open(temp_dir.path()).unwrap());
+    start_server(engine, temp_dir, "127.0.0.1:4202");
+    commands("127.0.0.1:4202");
+}
+
+#[test]
+fn scan() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    start_server(engine, temp_dir, "127.0.0.1:4203");
+
+    let mut client = RespClient::connect("127.0.0.1:4203");
+    for i in 0..25 {
+        client.call(&["SET", &format!("user:{:02}", i), "x"]);
+        client.call(&["SET", &format!("item:{:02}", i), "x"]);
+    }
+
+    let mut cursor = "0".to_owned();
+    let mut keys = Vec::new();
+    loop {
+        let reply = client.call(&["SCAN", &cursor, "MATCH", "user:*", "COUNT", "10"]);
+        match reply {
+            Reply::Array(mut parts) => {
+                match parts.pop().unwrap() {
+                    Reply::Array(found) => keys.extend(found),
+                    reply => panic!("unexpected reply {:?}", reply),
+                }
+                match parts.pop().unwrap() {
+                    Reply::Bulk(Some(next)) => cursor = next,
+                    reply => panic!("unexpected reply {:?}", reply),
+                }
+            }
+            reply => panic!("unexpected reply {:?}", reply),
+        }
+        if cursor == "0" {
+            break;
+        }
+    }
+    let expected: Vec<_> = (0..25).map(|i| bulk(&format!("user:{:02}", i))).collect();
+    assert_eq!(keys, expected);
+
+    let reply = client.call(&["SCAN", "0", "MATCH", "*:1?", "COUNT", "100"]);
+    match reply {
+        Reply::Array(parts) => assert_eq!(
+            parts[1],
+            Reply::Array(
+                (10..20)
+                    .map(|i| bulk(&format!("item:{}", i)))
+                    .chain((10..20).map(|i| bulk(&format!("user:{}", i))))
+                    .collect()
+            )
+        ),
+        reply => panic!("unexpected reply {:?}", reply),
+    }
+
+    match client.call(&["SCAN", "not a cursor"]) {
+        Reply::Error(e) => assert!(e.contains("invalid cursor")),
+        reply => panic!("unexpected reply {:?}", reply),
+    }
+}
+
+#[test]
+fn expiry() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    start_server(engine, temp_dir, "127.0.0.1:4204");
+
+    let mut client = RespClient::connect("127.0.0.1:4204");
+    assert_eq!(client.call(&["SET", "session", "abc", "PX", "200"]), ok());
+    assert_eq!(client.call(&["SET", "forever", "abc", "EX", "100"]), ok());
+    assert_eq!(client.call(&["SET", "forever", "abc"]), ok());
+    assert_eq!(client.call(&["GET", "session"]), bulk("abc"));
+    assert_eq!(
+        client.call(&["SET", "session", "def", "NX", "EX", "10"]),
+        Reply::Bulk(None)
+    );
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(client.call(&["GET", "session"]), Reply::Bulk(None));
+    assert_eq!(
+        client.call(&["EXISTS", "session", "forever"]),
+        Reply::Integer(1)
+    );
+    assert_eq!(client.call(&["SET", "session", "def", "NX"]), ok());
+
+    match client.call(&["SET", "key", "value", "EX", "0"]) {
+        Reply::Error(e) => assert!(e.starts_with("ERR invalid expire time")),
+        reply => panic!("unexpected reply {:?}", reply),
+    }
+}
+
+// Pipelined and inline commands are answered in order.
+#[test]
+fn pipeline_and_inline() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    start_server(engine, temp_dir, "127.0.0.1:4205");
+
+    let mut client = RespClient::connect("127.0.0.1:4205");
+    for i in 0..100 {
+        client.send(&["INCR", "counter"]);
+        client.send(&["SET", &format!("key{}", i), "value"]);
+    }
+    for i in 0..100 {
+        assert_eq!(client.read(), Reply::Integer(i + 1));
+        assert_eq!(client.read(), ok());
+    }
+
+    client.writer.write_all(b"GET counter\r\n").unwrap();
+    assert_eq!(client.read(), bulk("100"));
+    assert_eq!(client.call(&["QUIT"]), ok());
+}
+
+#[test]
+fn request_size_limit() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let limits = Limits {
+        max_request_size: 1024,
+        ..Limits::default()
+    };
+    start_server_with_limits(engine, temp_dir, "127.0.0.1:4206", limits);
+
+    let value = "x".repeat(600);
+    let mut client = RespClient::connect("127.0.0.1:4206");
+    assert_eq!(client.call(&["SET", "key", &value]), ok());
+    assert_eq!(
+        client.call(&["SET", &value, &value]),
+        Reply::Error("ERR Protocol error: request exceeds 1024 bytes".to_owned())
+    );
+
+    // a huge bulk length is rejected before any of it is sent
+    let mut client = RespClient::connect("127.0.0.1:4206");
+    client
+        .writer
+        .write_all(b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$4000000000\r\n")
+        .unwrap();
+    assert_eq!(
+        client.read(),
+        Reply::Error("ERR Protocol error: request exceeds 1024 bytes".to_owned())
+    );
+}
+
+#[test]
+fn glob_patterns() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    start_server(engine, temp_dir, "127.0.0.1:4207");
+
+    let mut client = RespClient::connect("127.0.0.1:4207");
+    let long_key = "a".repeat(100);
+    for key in &[
+        "hello",
+        "hallo",
+        "hxllo",
+        "h*llo",
+        "heeello",
+        long_key.as_str(),
+    ] {
+        client.call(&["SET", key, "x"]);
+    }
+    let keys = |client: &mut RespClient, pattern: &str| match client
+        .call(&["SCAN", "0", "MATCH", pattern, "COUNT", "100"])
+    {
+        Reply::Array(mut parts) => match parts.pop() {
+            Some(Reply::Array(keys)) => keys,
+            reply => panic!("unexpected reply {:?}", reply),
+        },
+        reply => panic!("unexpected reply {:?}", reply),
+    };
+    assert_eq!(
+        keys(&mut client, "h[ae]llo"),
+        vec![bulk("hallo"), bulk("hello")]
+    );
+    assert_eq!(
+        keys(&mut client, "h[^e]llo"),
+        vec![bulk("h*llo"), bulk("hallo"), bulk("hxllo")]
+    );
+    assert_eq!(keys(&mut client, "h\\*llo"), vec![bulk("h*llo")]);
+    assert_eq!(
+        keys(&mut client, "h*e*llo"),
+        vec![bulk("heeello"), bulk("hello")]
+    );
+    assert_eq!(keys(&mut client, "?al*"), vec![bulk("hallo")]);
+
+    // many stars which never match do not take exponential time
+    let pattern = format!("{}b", "a*".repeat(30));
+    assert_eq!(keys(&mut client, &pattern), Vec::new());
+    assert_eq!(keys(&mut client, "a*a*a*a*a*a"), vec![bulk(&long_key)]);
+}
@@ -0,0 +1,533 @@
+mod common;
+
+use common::{wait_listening, TestServer};
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    ErrorCode, KvStore, KvsClient, KvsClientPool, KvsEngine, KvsError, KvsServer, Limits, Reply,
+    Result, RetryPolicy,
+};
+use serde_json::Value;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Runs a server with the kvs engine in the background for the rest of the test process.
+fn start_server(addr: &'static str) {
+    TestServer::new().start(addr);
+}
+
+fn write_frame(stream: &mut TcpStream, opcode: u8, request_id: u32, payload: &[u8]) {
+    let len = payload.len() as u32 + 5;
+    stream.write_all(&len.to_be_bytes()).unwrap();
+    stream.write_all(&[opcode]).unwrap();
+    stream.write_all(&request_id.to_be_bytes()).unwrap();
+    stream.write_all(payload).unwrap();
+}
+
+fn read_frame(stream: &mut TcpStream) -> (u8, u32, Value) {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len).unwrap();
+    let mut frame = vec![0; u32::from_be_bytes(len) as usize];
+    stream.read_exact(&mut frame).unwrap();
+    let mut request_id = [0; 4];
+    request_id.copy_from_slice(&frame[1..5]);
+    let payload = serde_json::from_slice(&frame[5..]).unwrap();
+    (frame[0], u32::from_be_bytes(request_id), payload)
+}
+
+#[test]
+fn handshake() -> Result<()> {
+    let addr = "127.0.0.1:4101";
+    start_server(addr);
+
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.protocol_version(), 1);
+    assert!(client.server_capabilities().iter().any(|c| c == "watch"));
+
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Clients speaking the unframed JSON protocol are still served.
+#[test]
+fn legacy_json_protocol() -> Result<()> {
+    let addr = "127.0.0.1:4102";
+    start_server(addr);
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(br#"{"Set":{"key":"key1","value":"value1"}}"#)?;
+    let mut reader = serde_json::Deserializer::from_reader(BufReader::new(stream.try_clone()?))
+        .into_iter::<Value>();
+    assert_eq!(reader.next().unwrap()?, serde_json::json!({ "Ok": null }));
+
+    stream.write_all(br#"{"Get":{"key":"key1"}}"#)?;
+    assert_eq!(
+        reader.next().unwrap()?,
+        serde_json::json!({ "Ok": "value1" })
+    );
+    Ok(())
+}
+
+// A legacy connection may open with a request without fields, sent as a JSON string.
+#[test]
+fn legacy_unit_request() -> Result<()> {
+    let addr = "127.0.0.1:4117";
+    start_server(addr);
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(br#""Ping""#)?;
+    let mut reader = serde_json::Deserializer::from_reader(BufReader::new(stream.try_clone()?))
+        .into_iter::<Value>();
+    assert!(reader.next().unwrap()?.get("Ok").is_some());
+
+    stream.write_all(br#""Stats""#)?;
+    assert!(reader.next().unwrap()?.get("Ok").is_some());
+    Ok(())
+}
+
+// A malformed frame is answered with an error and does not break the connection.
+#[test]
+fn malformed_frame() -> Result<()> {
+    let addr = "127.0.0.1:4103";
+    start_server(addr);
+
+    let mut stream = TcpStream::connect(addr)?;
+    write_frame(&mut stream, 1, 0, br#"{"version":1,"capabilities":[]}"#);
+    let (opcode, _, hello) = read_frame(&mut stream);
+    assert_eq!(opcode, 1);
+    assert_eq!(hello["version"], 1);
+
+    write_frame(&mut stream, 2, 7, b"not json");
+    let (opcode, request_id, _) = read_frame(&mut stream);
+    assert_eq!((opcode, request_id), (5, 7));
+
+    write_frame(&mut stream, 42, 8, b"{}");
+    let (opcode, request_id, _) = read_frame(&mut stream);
+    assert_eq!((opcode, request_id), (5, 8));
+
+    write_frame(&mut stream, 2, 9, br#"{"Get":{"key":"key1"}}"#);
+    let (opcode, request_id, resp) = read_frame(&mut stream);
+    assert_eq!((opcode, request_id), (3, 9));
+    assert_eq!(resp, serde_json::json!({ "Ok": { "Value": null } }));
+    Ok(())
+}
+
+// The server refuses clients that do not start with a handshake.
+#[test]
+fn missing_handshake() -> Result<()> {
+    let addr = "127.0.0.1:4104";
+    start_server(addr);
+
+    let mut stream = TcpStream::connect(addr)?;
+    write_frame(&mut stream, 2, 1, br#"{"Get":{"key":"key1"}}"#);
+    let (opcode, _, _) = read_frame(&mut stream);
+    assert_eq!(opcode, 5);
+    let mut rest = Vec::new();
+    BufReader::new(stream).read_until(0, &mut rest)?;
+    assert!(rest.is_empty(), "the connection is not closed");
+    Ok(())
+}
+
+// Pipelined requests come back in order, and the requests on a key run in order.
+#[test]
+fn pipeline() -> Result<()> {
+    let addr = "127.0.0.1:4105";
+    start_server(addr);
+
+    let mut client = KvsClient::connect(addr)?;
+    let mut pipeline = client.pipeline();
+    for i in 0..200 {
+        pipeline = pipeline
+            .set(format!("key{}", i % 10), format!("{}", i))
+            .incr("counter".to_owned(), 1);
+    }
+    let replies = pipeline
+        .get_many((0..10).map(|i| format!("key{}", i)).collect())
+        .rm("missing".to_owned())
+        .append("counter".to_owned(), "!".to_owned())
+        .execute()?;
+
+    assert_eq!(replies.len(), 403);
+    for i in 0..200 {
+        assert_eq!(replies[2 * i].as_ref().unwrap(), &Reply::Done);
+        assert_eq!(
+            replies[2 * i + 1].as_ref().unwrap(),
+            &Reply::Integer(i as i64 + 1)
+        );
+    }
+    assert_eq!(
+        replies[400].as_ref().unwrap(),
+        &Reply::Values((190..200).map(|i| Some(format!("{}", i))).collect())
+    );
+    assert!(replies[401].is_err());
+    assert_eq!(replies[402].as_ref().unwrap(), &Reply::Length(4));
+
+    // the connection is still usable after a pipeline
+    assert_eq!(client.get("counter".to_owned())?, Some("200!".to_owned()));
+    Ok(())
+}
+
+// A pipeline larger than the socket buffers reads the responses while it sends.
+#[test]
+fn large_pipeline() -> Result<()> {
+    let addr = "127.0.0.1:4118";
+    start_server(addr);
+
+    let value = "x".repeat(16 * 1024);
+    let mut client = KvsClient::connect(addr)?;
+    let mut pipeline = client.pipeline();
+    for i in 0..1000 {
+        pipeline = pipeline
+            .set(format!("key{}", i), value.clone())
+            .get(format!("key{}", i));
+    }
+    let replies = pipeline.execute()?;
+
+    assert_eq!(replies.len(), 2000);
+    for i in 0..1000 {
+        assert_eq!(replies[2 * i].as_ref().unwrap(), &Reply::Done);
+        assert_eq!(
+            replies[2 * i + 1].as_ref().unwrap(),
+            &Reply::Value(Some(value.clone()))
+        );
+    }
+    Ok(())
+}
+
+// Pipelines on many connections make progress even when they outnumber the pool threads.
+#[test]
+fn concurrent_pipelines() -> Result<()> {
+    let addr = "127.0.0.1:4106";
+    start_server(addr);
+
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            thread::spawn(move || {
+                let mut client = KvsClient::connect(addr).unwrap();
+                let mut pipeline = client.pipeline();
+                for i in 0..100 {
+                    pipeline = pipeline.set(format!("key{}_{}", thread_id, i), format!("{}", i));
+                }
+                let replies = pipeline.execute().unwrap();
+                assert!(replies.iter().all(|reply| reply.is_ok()));
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get("key7_99".to_owned())?, Some("99".to_owned()));
+    Ok(())
+}
+
+// Errors reach the client with their kind, not only their message.
+#[test]
+fn typed_errors() -> Result<()> {
+    let addr = "127.0.0.1:4107";
+    start_server(addr);
+
+    let mut client = KvsClient::connect(addr)?;
+    assert!(matches!(
+        client.rm("missing".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    client.set("text".to_owned(), "abc".to_owned())?;
+    assert!(matches!(
+        client.incr("text".to_owned(), 1),
+        Err(KvsError::NotAnInteger)
+    ));
+    client.set("max".to_owned(), i64::MAX.to_string())?;
+    assert!(matches!(
+        client.incr("max".to_owned(), 1),
+        Err(KvsError::Overflow)
+    ));
+
+    // the error code is also on the wire
+    let mut stream = TcpStream::connect(addr)?;
+    write_frame(&mut stream, 1, 0, br#"{"version":1,"capabilities":[]}"#);
+    read_frame(&mut stream);
+    write_frame(&mut stream, 2, 1, br#"{"Remove":{"key":"missing"}}"#);
+    let (opcode, _, resp) = read_frame(&mut stream);
+    assert_eq!(opcode, 3);
+    assert_eq!(
+        resp["Err"]["code"],
+        serde_json::to_value(ErrorCode::KeyNotFound).unwrap()
+    );
+
+    // the I/O errors of the server are not taken for those of the connection
+    let err = KvsError::from_code(ErrorCode::Io, "disk full".to_owned());
+    assert!(matches!(err, KvsError::Storage(_)));
+    assert_eq!(err.code(), ErrorCode::Io);
+    Ok(())
+}
+
+// Shutting down lets the open connections finish and returns from `run`.
+#[test]
+fn graceful_shutdown() -> Result<()> {
+    let addr = "127.0.0.1:4108";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path())?;
+    let server = KvsServer::new(engine, SharedQueueThreadPool::new(4)?);
+    let handle = server.shutdown_handle();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || tx.send(server.run(addr)).unwrap());
+    wait_listening(addr);
+
+    let mut idle = KvsClient::connect(addr)?;
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    idle.set("key2".to_owned(), "value2".to_owned())?;
+
+    handle.shutdown();
+    rx.recv_timeout(Duration::from_secs(5)).unwrap()?;
+    assert!(KvsClient::connect(addr).is_err());
+    assert!(client.get("key1".to_owned()).is_err());
+
+    let engine = KvStore::open(temp_dir.path())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Connections still busy after the shutdown timeout are closed.
+#[test]
+fn shutdown_timeout() -> Result<()> {
+    let addr = "127.0.0.1:4109";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path())?;
+    let server = KvsServer::new(engine, SharedQueueThreadPool::new(4)?)
+        .with_shutdown_timeout(Duration::from_millis(200));
+    let handle = server.shutdown_handle();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || tx.send(server.run(addr)).unwrap());
+    wait_listening(addr);
+
+    let _events = KvsClient::connect(addr)?.watch("key".to_owned())?;
+    handle.shutdown();
+    rx.recv_timeout(Duration::from_secs(5)).unwrap()?;
+    Ok(())
+}
+
+// Runs a server with the given limits in the background.
+fn start_server_with_limits(addr: &'static str, limits: Limits) {
+    TestServer::new().with_limits(limits).start(addr);
+}
+
+#[test]
+fn max_connections() -> Result<()> {
+    let addr = "127.0.0.1:4110";
+    let limits = Limits {
+        max_connections: Some(2),
+        ..Limits::default()
+    };
+    start_server_with_limits(addr, limits);
+
+    let mut client1 = KvsClient::connect(addr)?;
+    let _client2 = KvsClient::connect(addr)?;
+    match KvsClient::connect(addr) {
+        Err(KvsError::Overloaded(_)) => {}
+        other => panic!("expect Overloaded, got {:?}", other.err()),
+    }
+    client1.set("key1".to_owned(), "value1".to_owned())?;
+
+    drop(client1);
+    thread::sleep(Duration::from_millis(100));
+    let mut client3 = KvsClient::connect(addr)?;
+    assert_eq!(client3.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// A connection that sends nothing is closed after the idle timeout.
+#[test]
+fn idle_timeout() -> Result<()> {
+    let addr = "127.0.0.1:4111";
+    let limits = Limits {
+        idle_timeout: Some(Duration::from_millis(300)),
+        max_connections: Some(1),
+        ..Limits::default()
+    };
+    start_server_with_limits(addr, limits);
+
+    let mut stream = TcpStream::connect(addr)?;
+    let mut buf = [0; 16];
+    assert_eq!(stream.read(&mut buf)?, 0);
+
+    // the slot of the idle connection is available again
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    thread::sleep(Duration::from_millis(500));
+    assert!(client.get("key1".to_owned()).is_err());
+    Ok(())
+}
+
+// A legacy request sent too slowly is cut off after the read timeout, not the idle one.
+#[test]
+fn legacy_read_timeout() -> Result<()> {
+    let addr = "127.0.0.1:4119";
+    let limits = Limits {
+        idle_timeout: Some(Duration::from_secs(5)),
+        read_timeout: Some(Duration::from_millis(300)),
+        ..Limits::default()
+    };
+    start_server_with_limits(addr, limits);
+
+    // waiting longer than the read timeout between requests is fine
+    let mut stream = TcpStream::connect(addr)?;
+    let mut reader = serde_json::Deserializer::from_reader(BufReader::new(stream.try_clone()?))
+        .into_iter::<Value>();
+    stream.write_all(br#"{"Set":{"key":"key1","value":"value1"}}"#)?;
+    assert_eq!(reader.next().unwrap()?, serde_json::json!({ "Ok": null }));
+    thread::sleep(Duration::from_millis(500));
+    stream.write_all(br#"{"Get":{"key":"key1"}}"#)?;
+    assert_eq!(
+        reader.next().unwrap()?,
+        serde_json::json!({ "Ok": "value1" })
+    );
+
+    // but not in the middle of one
+    stream.set_read_timeout(Some(Duration::from_secs(3)))?;
+    stream.write_all(br#"{"Get":"#)?;
+    let mut buf = [0; 16];
+    assert_eq!(stream.read(&mut buf)?, 0);
+    Ok(())
+}
+
+#[test]
+fn max_request_size() -> Result<()> {
+    let addr = "127.0.0.1:4112";
+    let limits = Limits {
+        max_request_size: 1024,
+        ..Limits::default()
+    };
+    start_server_with_limits(addr, limits);
+
+    let mut client = KvsClient::connect(addr)?;
+    match client.set("key1".to_owned(), "x".repeat(2000)) {
+        Err(KvsError::BadRequest(_)) => {}
+        other => panic!("expect BadRequest, got {:?}", other),
+    }
+    // the connection goes on after the rejected request
+    client.set("key1".to_owned(), "x".repeat(500))?;
+    assert_eq!(client.get("key1".to_owned())?, Some("x".repeat(500)));
+
+    // so do the legacy ones, which are closed
+    let mut stream = TcpStream::connect(addr)?;
+    let request = format!(
+        r#"{{"Set":{{"key":"key1","value":"{}"}}}}"#,
+        "x".repeat(2000)
+    );
+    stream.write_all(request.as_bytes())?;
+    let mut reader =
+        serde_json::Deserializer::from_reader(BufReader::new(stream)).into_iter::<Value>();
+    let resp = reader.next().unwrap()?;
+    assert!(resp["Err"].as_str().unwrap().contains("exceeds 1024 bytes"));
+    assert!(reader.next().is_none());
+    Ok(())
+}
+
+// Requests queued past their deadline are not run.
+#[test]
+fn deadlines() -> Result<()> {
+    let addr = "127.0.0.1:4113";
+    start_server(addr);
+
+    let mut client = KvsClient::connect(addr)?;
+    assert!(client.server_capabilities().iter().any(|c| c == "deadline"));
+    client.set_request_timeout(Some(Duration::from_secs(5)));
+    client.set("key1".to_owned(), "value1".to_owned())?;
+
+    client.set_request_timeout(Some(Duration::from_millis(0)));
+    match client.set("key1".to_owned(), "value2".to_owned()) {
+        Err(KvsError::DeadlineExceeded) => {}
+        other => panic!("expect DeadlineExceeded, got {:?}", other),
+    }
+    client.set_request_timeout(None);
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Connections closed by the server are replaced, and reads are retried.
+#[test]
+fn client_pool_reconnect() -> Result<()> {
+    let addr = "127.0.0.1:4114";
+    let limits = Limits {
+        idle_timeout: Some(Duration::from_millis(300)),
+        ..Limits::default()
+    };
+    start_server_with_limits(addr, limits);
+
+    let pool = KvsClientPool::new(vec![addr.parse().unwrap()])
+        .with_health_check_interval(Duration::from_secs(60))
+        .with_retry_policy(RetryPolicy {
+            max_retries: 2,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(100),
+        });
+    pool.warm_up(2)?;
+    pool.set("key1".to_owned(), "value1".to_owned())?;
+    pool.set("key2".to_owned(), "value2".to_owned())?;
+
+    // the idle connections are closed by the server, unknown to the pool
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(pool.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(
+        pool.scan("key".to_owned(), None, 10)?,
+        vec!["key1".to_owned(), "key2".to_owned()]
+    );
+
+    // clones share the connections
+    let clone = pool.clone();
+    let handle = thread::spawn(move || clone.get("key2".to_owned()));
+    assert_eq!(handle.join().unwrap()?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// A server that cannot be reached is skipped for the next address.
+#[test]
+fn client_pool_failover() -> Result<()> {
+    let addr = "127.0.0.1:4115";
+    start_server(addr);
+
+    let pool = KvsClientPool::new(vec![
+        "127.0.0.1:4116".parse().unwrap(),
+        addr.parse().unwrap(),
+    ]);
+    pool.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(pool.get("key1".to_owned())?, Some("value1".to_owned()));
+    pool.rm("key1".to_owned())?;
+    assert_eq!(pool.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn unix_socket() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("kvs.sock");
+    let addr = format!("unix:{}", path.display());
+    let engine = KvStore::open(temp_dir.path())?;
+    let server = KvsServer::new(engine, SharedQueueThreadPool::new(4)?);
+    let handle = server.shutdown_handle();
+    let server_addr = addr.clone();
+    let server = thread::spawn(move || server.run(server_addr));
+    wait_listening(&addr);
+
+    let mut client = KvsClient::connect(&addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    let pool = KvsClientPool::new(vec![addr.parse()?]);
+    assert_eq!(pool.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    drop(client);
+    drop(pool);
+    handle.shutdown();
+    server.join().unwrap()?;
+    // the socket file goes away with the server
+    assert!(!path.exists());
+    Ok(())
+}
@@ -0,0 +1,150 @@
+mod common;
+
+use common::start_servers;
+use kvs::{Address, HashRing, KvsClient, Result, ShardedKvsClient};
+use std::collections::HashMap;
+
+fn addresses(addrs: &[&str]) -> Vec<Address> {
+    addrs.iter().map(|addr| addr.parse().unwrap()).collect()
+}
+
+// All the keys stored on the server at `addr`.
+fn keys_on(addr: &Address) -> Vec<String> {
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.scan(String::new(), None, 100_000).unwrap()
+}
+
+#[test]
+fn ring_spreads_keys_and_moves_few() {
+    let addrs = addresses(&["127.0.0.1:5001", "127.0.0.1:5002", "127.0.0.1:5003"]);
+    let ring = HashRing::new(addrs.clone(), 160);
+    let mut counts: HashMap<Address, usize> = HashMap::new();
+    for i in 0..3000 {
+        *counts
+            .entry(ring.server_for(&format!("key{}", i)).unwrap().clone())
+            .or_default() += 1;
+    }
+    for addr in &addrs {
+        let count = counts[addr];
+        assert!(count > 600 && count < 1400, "{} owns {} keys", addr, count);
+    }
+
+    // the order of the servers does not matter
+    let reversed = HashRing::new(addrs.iter().rev().cloned().collect(), 160);
+    let mut larger = ring.clone();
+    let added: Address = "127.0.0.1:5004".parse().unwrap();
+    assert!(larger.add(added.clone()));
+    assert!(!larger.add(added.clone()));
+    let mut moved = 0;
+    for i in 0..3000 {
+        let key = format!("key{}", i);
+        assert_eq!(ring.server_for(&key), reversed.server_for(&key));
+        let owner = larger.server_for(&key).unwrap();
+        if owner != ring.server_for(&key).unwrap() {
+            // only to the new server
+            assert_eq!(owner, &added);
+            moved += 1;
+        }
+    }
+    assert!(moved > 400 && moved < 1100, "{} keys moved", moved);
+
+    assert!(larger.remove(&added));
+    for i in 0..3000 {
+        let key = format!("key{}", i);
+        assert_eq!(larger.server_for(&key), ring.server_for(&key));
+    }
+}
+
+#[test]
+fn sharded_client() -> Result<()> {
+    let addrs = ["127.0.0.1:5001", "127.0.0.1:5002", "127.0.0.1:5003"];
+    start_servers(&addrs);
+    let mut client = ShardedKvsClient::new(addresses(&addrs));
+
+    for i in 0..300 {
+        client.set(format!("key{:03}", i), format!("value{}", i))?;
+    }
+    for addr in addresses(&addrs) {
+        let keys = keys_on(&addr);
+        assert!(!keys.is_empty());
+        for key in keys {
+            assert_eq!(client.ring().server_for(&key), Some(&addr));
+        }
+    }
+
+    assert_eq!(client.get("key007".to_owned())?, Some("value7".to_owned()));
+    client.rm("key007".to_owned())?;
+    assert_eq!(client.incr("counter".to_owned(), 5)?, 5);
+    assert_eq!(client.append("key008".to_owned(), "!".to_owned())?, 7);
+
+    let keys = vec![
+        "key001".to_owned(),
+        "key007".to_owned(),
+        "key299".to_owned(),
+        "key008".to_owned(),
+        "missing".to_owned(),
+    ];
+    assert_eq!(
+        client.get_many(keys)?,
+        vec![
+            Some("value1".to_owned()),
+            None,
+            Some("value299".to_owned()),
+            Some("value8!".to_owned()),
+            None,
+        ]
+    );
+    client.set_many(vec![
+        ("multi1".to_owned(), "a".to_owned()),
+        ("multi2".to_owned(), "b".to_owned()),
+        ("multi3".to_owned(), "c".to_owned()),
+    ])?;
+    assert_eq!(client.get("multi3".to_owned())?, Some("c".to_owned()));
+
+    // the scans are merged in order
+    let page = client.scan("key".to_owned(), Some("key004".to_owned()), 5)?;
+    assert_eq!(page, vec!["key005", "key006", "key008", "key009", "key010"]);
+    let all = client.scan("key".to_owned(), None, 1000)?;
+    assert_eq!(all.len(), 299);
+    Ok(())
+}
+
+#[test]
+fn add_and_remove_servers() -> Result<()> {
+    let addrs = ["127.0.0.1:5005", "127.0.0.1:5006", "127.0.0.1:5007"];
+    start_servers(&addrs);
+    let mut client = ShardedKvsClient::new(addresses(&addrs[..2]));
+    for i in 0..500 {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    let added: Address = addrs[2].parse().unwrap();
+    let moved = client.add_server(added.clone())?;
+    let on_added = keys_on(&added);
+    assert_eq!(moved, on_added.len() as u64);
+    assert!(moved > 50 && moved < 350, "{} keys moved", moved);
+    for addr in addresses(&addrs) {
+        for key in keys_on(&addr) {
+            assert_eq!(client.ring().server_for(&key), Some(&addr));
+        }
+    }
+    assert_eq!(client.add_server(added.clone())?, 0);
+
+    let removed: Address = addrs[0].parse().unwrap();
+    client.remove_server(&removed)?;
+    assert!(keys_on(&removed).is_empty());
+    for i in 0..500 {
+        assert_eq!(
+            client.get(format!("key{}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+    let remaining = addresses(&addrs[1..]);
+    let total: usize = remaining.iter().map(|addr| keys_on(addr).len()).sum();
+    assert_eq!(total, 500);
+
+    // the keys stay where they are, and the client keeps working
+    assert!(client.rebalance(HashRing::new(Vec::new(), 1)).is_err());
+    assert_eq!(client.get("key0".to_owned())?, Some("value0".to_owned()));
+    Ok(())
+}
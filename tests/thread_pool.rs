@@ -1,5 +1,7 @@
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
 
 use kvs::thread_pool::*;
 use kvs::Result;
@@ -68,3 +70,43 @@ fn rayon_thread_pool_spawn_counter() -> Result<()> {
 fn shared_queue_thread_pool_panic_task() -> Result<()> {
     spawn_panic_task::<SharedQueueThreadPool>()
 }
+
+// Waits for the stats of `pool` to match `expected`, failing after a second.
+fn wait_stats<P: ThreadPool>(pool: &P, expected: PoolStats) {
+    for _ in 0..100 {
+        if pool.stats() == expected {
+            return;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(pool.stats(), expected);
+}
+
+#[test]
+fn shared_queue_thread_pool_stats() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(2)?;
+    let mut senders = Vec::new();
+    for _ in 0..3 {
+        let (tx, rx) = mpsc::channel::<()>();
+        senders.push(tx);
+        pool.spawn(move || {
+            let _ = rx.recv();
+        });
+    }
+    let stats = |queued, busy, panics| PoolStats {
+        queued,
+        busy,
+        panics,
+    };
+    wait_stats(&pool, stats(1, 2, 0));
+    drop(senders);
+    wait_stats(&pool, stats(0, 0, 0));
+
+    pool.spawn(|| {
+        panic_control::disable_hook_in_current_thread();
+        panic!();
+    });
+    wait_stats(&pool, stats(0, 0, 1));
+    // the thread of the job panicked is replaced
+    spawn_counter(pool)
+}
@@ -0,0 +1,119 @@
+mod common;
+
+use common::TestServer;
+use kvs::{KvsClient, Result, TlsClientConfig, TlsServerConfig};
+use rcgen::{BasicConstraints, Certificate, CertificateParams, DistinguishedName, DnType, IsCa};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+// Self-signed CA and the certificates it signs for the server and a client, as PEM files.
+struct Certs {
+    dir: TempDir,
+}
+
+impl Certs {
+    fn generate() -> Certs {
+        let dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut ca_params = params("kvs test CA");
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = Certificate::from_params(ca_params).unwrap();
+        fs::write(dir.path().join("ca.pem"), ca.serialize_pem().unwrap()).unwrap();
+
+        for name in &["server", "client"] {
+            let cert = Certificate::from_params(params(name)).unwrap();
+            let pem = cert.serialize_pem_with_signer(&ca).unwrap();
+            fs::write(dir.path().join(format!("{}.pem", name)), pem).unwrap();
+            let key = cert.serialize_private_key_pem();
+            fs::write(dir.path().join(format!("{}.key", name)), key).unwrap();
+        }
+        Certs { dir }
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.path().join(name)
+    }
+
+    fn server(&self, client_ca: Option<&Path>) -> TlsServerConfig {
+        TlsServerConfig::from_pem_files(
+            &self.path("server.pem"),
+            &self.path("server.key"),
+            client_ca,
+        )
+        .unwrap()
+    }
+
+    fn client(&self, with_cert: bool) -> TlsClientConfig {
+        let cert = self.path("client.pem");
+        let key = self.path("client.key");
+        let identity = if with_cert {
+            Some((cert.as_path(), key.as_path()))
+        } else {
+            None
+        };
+        TlsClientConfig::from_pem_files(&self.path("ca.pem"), "localhost", identity).unwrap()
+    }
+}
+
+// The parameters of a certificate for localhost named `name`
+fn params(name: &str) -> CertificateParams {
+    let mut params = CertificateParams::new(vec!["localhost".to_owned()]);
+    let mut dn = DistinguishedName::new();
+    dn.push(DnType::CommonName, name);
+    params.distinguished_name = dn;
+    params
+}
+
+// Runs a server with the kvs engine over TLS in the background.
+fn start_server(addr: &'static str, tls: TlsServerConfig) {
+    TestServer::new().start_with(addr, move |server| server.with_tls(tls).run(addr));
+}
+
+#[test]
+fn tls() -> Result<()> {
+    let addr = "127.0.0.1:4601";
+    let certs = Certs::generate();
+    start_server(addr, certs.server(None));
+
+    let mut client = KvsClient::connect_tls(addr, &certs.client(false))?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    let pipelined = client
+        .pipeline()
+        .get("key1".to_owned())
+        .set("key2".to_owned(), "x".repeat(100_000))
+        .get("key2".to_owned())
+        .execute()?;
+    assert_eq!(pipelined.len(), 3);
+    assert_eq!(client.get("key2".to_owned())?, Some("x".repeat(100_000)));
+
+    // plaintext clients are refused
+    assert!(KvsClient::connect(addr).is_err());
+    Ok(())
+}
+
+#[test]
+fn mutual_tls() -> Result<()> {
+    let addr = "127.0.0.1:4602";
+    let certs = Certs::generate();
+    start_server(addr, certs.server(Some(&certs.path("ca.pem"))));
+
+    let mut client = KvsClient::connect_tls(addr, &certs.client(true))?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // a client without certificate is refused
+    assert!(KvsClient::connect_tls(addr, &certs.client(false)).is_err());
+    Ok(())
+}
+
+// A server whose certificate is not signed by a trusted CA is refused.
+#[test]
+fn untrusted_server() -> Result<()> {
+    let addr = "127.0.0.1:4603";
+    let certs = Certs::generate();
+    let other = Certs::generate();
+    start_server(addr, other.server(None));
+
+    assert!(KvsClient::connect_tls(addr, &certs.client(false)).is_err());
+    Ok(())
+}